@@ -0,0 +1,252 @@
+//! The machines we have written so far are flat. Every state is spelled out by hand. That is fine
+//! for a single light switch, but modeling a whole building full of switches that way means
+//! writing out the cartesian product of every room and every switch.
+//!
+//! Instead, we can build bigger machines out of smaller ones. In this module we write a few
+//! combinators that take existing state machines and compose them into new ones that still
+//! implement the same `StateMachine` trait:
+//! * `Parallel` runs two independent machines side by side.
+//! * `Fleet` runs any number of identical, independent devices.
+//! * `Nested` places a child machine inside each state of a parent machine, statechart style.
+
+use super::StateMachine;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// A transition for one of two machines.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/// Two independent state machines running side by side. The state is a pair of states,
+/// and each transition drives exactly one of the two machines.
+pub struct Parallel<A, B>(PhantomData<(A, B)>);
+
+impl<A, B> StateMachine for Parallel<A, B>
+where
+    A: StateMachine,
+    B: StateMachine,
+    A::State: Clone,
+    B::State: Clone,
+{
+    type State = (A::State, B::State);
+    type Transition = Either<A::Transition, B::Transition>;
+
+    fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        let (a, b) = starting_state;
+        match t {
+            Either::Left(t) => (A::next_state(a, t), b.clone()),
+            Either::Right(t) => (a.clone(), B::next_state(b, t)),
+        }
+    }
+
+    fn begin_block(starting_state: &Self::State, height: u64) -> Self::State {
+        let (a, b) = starting_state;
+        (A::begin_block(a, height), B::begin_block(b, height))
+    }
+
+    fn human_name() -> String {
+        format!("{} alongside {}", A::human_name(), B::human_name())
+    }
+}
+
+/// A transition addressed to a single device in a fleet.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Addressed<T> {
+    /// The index of the device in the fleet.
+    pub device: usize,
+    /// The transition for that device.
+    pub t: T,
+}
+
+/// Any number of identical, independent devices. Transitions addressed to a device
+/// that does not exist are ignored.
+pub struct Fleet<M>(PhantomData<M>);
+
+impl<M> StateMachine for Fleet<M>
+where
+    M: StateMachine,
+    M::State: Clone,
+{
+    type State = Vec<M::State>;
+    type Transition = Addressed<M::Transition>;
+
+    fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        let mut devices = starting_state.clone();
+        if let Some(device) = devices.get_mut(t.device) {
+            *device = M::next_state(device, &t.t);
+        }
+        devices
+    }
+
+    fn begin_block(starting_state: &Self::State, height: u64) -> Self::State {
+        starting_state
+            .iter()
+            .map(|device| M::begin_block(device, height))
+            .collect()
+    }
+
+    fn human_name() -> String {
+        format!("Fleet of {}", M::human_name())
+    }
+}
+
+/// The state of a nested machine. The parent is in some state, and every parent
+/// state that has ever been visited has its own child machine state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NestedState<P: Hash + Eq, C> {
+    /// The current state of the parent machine.
+    pub outer: P,
+    /// The state of the child machine inside each parent state. Parent states that
+    /// have never been visited are not present, and their child starts from the default.
+    pub inner: HashMap<P, C>,
+}
+
+impl<P: Hash + Eq, C: Default> NestedState<P, C> {
+    /// Start in the given parent state, with its child machine in the default state.
+    pub fn new(outer: P) -> Self {
+        NestedState {
+            outer,
+            inner: HashMap::new(),
+        }
+    }
+}
+
+impl<P: Hash + Eq, C: Default + Clone> NestedState<P, C> {
+    /// The state of the child machine inside the given parent state.
+    pub fn child(&self, outer: &P) -> C {
+        self.inner.get(outer).cloned().unwrap_or_default()
+    }
+}
+
+/// A transition in a nested machine either moves the parent machine, or drives
+/// the child machine inside the current parent state.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NestedTransition<PT, CT> {
+    Outer(PT),
+    Inner(CT),
+}
+
+/// A statechart-style hierarchical machine. Each state of the parent machine contains
+/// its own instance of the child machine. Moving the parent between states leaves each
+/// child untouched, so a child resumes where it left off when its parent state is re-entered.
+pub struct Nested<P, C>(PhantomData<(P, C)>);
+
+impl<P, C> StateMachine for Nested<P, C>
+where
+    P: StateMachine,
+    C: StateMachine,
+    P::State: Hash + Eq + Clone,
+    C::State: Default + Clone,
+{
+    type State = NestedState<P::State, C::State>;
+    type Transition = NestedTransition<P::Transition, C::Transition>;
+
+    fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        let mut state = starting_state.clone();
+        match t {
+            NestedTransition::Outer(t) => state.outer = P::next_state(&state.outer, t),
+            NestedTransition::Inner(t) => {
+                let child = C::next_state(&state.child(&state.outer), t);
+                state.inner.insert(state.outer.clone(), child);
+            }
+        }
+        state
+    }
+
+    /// Every child that has been visited sees the new block, even if its parent state is not
+    /// the current one. Children that have never been visited start from the default later.
+    fn begin_block(starting_state: &Self::State, height: u64) -> Self::State {
+        NestedState {
+            outer: P::begin_block(&starting_state.outer, height),
+            inner: starting_state
+                .inner
+                .iter()
+                .map(|(outer, child)| (outer.clone(), C::begin_block(child, height)))
+                .collect(),
+        }
+    }
+
+    fn human_name() -> String {
+        format!("{} within {}", C::human_name(), P::human_name())
+    }
+}
+
+/// The rooms of a small building.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Room {
+    Kitchen,
+    Bedroom,
+    Bathroom,
+}
+
+/// A person walking around a building. The state is the room they are in, and they can
+/// walk directly to any other room. Combined with `Nested` this models a building where
+/// each room has its own set of switches.
+pub struct Building;
+
+impl StateMachine for Building {
+    type State = Room;
+    type Transition = Room;
+
+    fn next_state(_: &Room, t: &Room) -> Room {
+        *t
+    }
+}
+
+#[test]
+fn sm_composition_parallel_machines_are_independent() {
+    use super::p1_switches::LightSwitch;
+    use super::p2_laundry_machine::{ClothesAction, ClothesMachine, ClothesState};
+
+    type Bedroom = Parallel<LightSwitch, ClothesMachine>;
+
+    let start = (false, ClothesState::Clean(3));
+    let end = Bedroom::next_state(&start, &Either::Left(()));
+    assert_eq!(end, (true, ClothesState::Clean(3)));
+
+    let end = Bedroom::next_state(&end, &Either::Right(ClothesAction::Wear));
+    assert_eq!(end, (true, ClothesState::Dirty(2)));
+}
+
+#[test]
+fn sm_composition_fleet_addresses_one_device() {
+    use super::p1_switches::LightSwitch;
+
+    let start = vec![false; 3];
+    let end = Fleet::<LightSwitch>::next_state(&start, &Addressed { device: 1, t: () });
+    assert_eq!(end, vec![false, true, false]);
+
+    let end = Fleet::<LightSwitch>::next_state(&end, &Addressed { device: 7, t: () });
+    assert_eq!(end, vec![false, true, false]);
+}
+
+#[test]
+fn sm_composition_nested_rooms_remember_their_switches() {
+    use super::p1_switches::{Toggle, TwoSwitches, WeirdSwitchMachine};
+
+    type House = Nested<Building, WeirdSwitchMachine>;
+
+    let start = NestedState::new(Room::Kitchen);
+    let transitions = [
+        NestedTransition::Inner(Toggle::FirstSwitch),
+        NestedTransition::Inner(Toggle::SecondSwitch),
+        NestedTransition::Outer(Room::Bedroom),
+        NestedTransition::Inner(Toggle::SecondSwitch),
+        NestedTransition::Outer(Room::Kitchen),
+        NestedTransition::Inner(Toggle::FirstSwitch),
+    ];
+    let end = transitions
+        .iter()
+        .fold(start, |state, t| House::next_state(&state, t));
+
+    assert_eq!(end.outer, Room::Kitchen);
+    // Turning off the kitchen's first switch took its second switch with it...
+    assert_eq!(end.child(&Room::Kitchen), TwoSwitches::default());
+    // ...but the bedroom's second switch is still on.
+    assert_ne!(end.child(&Room::Bedroom), TwoSwitches::default());
+    assert_eq!(end.child(&Room::Bathroom), TwoSwitches::default());
+}
@@ -0,0 +1,651 @@
+//! Our users trade the assets that are issued on our chain. Rather than matching buyers with
+//! sellers through an order book, a decentralized exchange can hold pools of liquidity and let
+//! anyone trade against them at a price set by a formula.
+//!
+//! This module builds on the accounted currency from earlier in the chapter. Users hold balances
+//! of several assets, and each pair of assets may have a constant-product liquidity pool. A pool
+//! holding reserves `x` and `y` will trade any amount as long as the product `x * y` does not
+//! decrease. Every trade pays a small fee into the pool, so the product actually grows over time,
+//! and that growth is shared by the liquidity providers in proportion to their pool shares.
+//!
+//! All of the pricing math multiplies balances together, which easily overflows `u64`. It is done
+//! with `u128` intermediates and checked arithmetic. A transaction whose math would overflow is
+//! rejected rather than panicking or wrapping.
+
+use super::invariants::{ArbitraryTransition, Invariant, Invariants, Rng};
+use super::{StateMachine, User};
+use std::collections::HashMap;
+
+/// Every swap pays this many thousandths of the input amount into the pool as a fee.
+pub const FEE_PER_THOUSAND: u64 = 3;
+
+/// Assets are identified by a number.
+pub type AssetId = u32;
+
+/// This state machine models a decentralized exchange with constant-product liquidity pools.
+pub struct Dex;
+
+/// A liquidity pool for a pair of assets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pool {
+    /// The reserve of the pair's first asset, which has the lower id.
+    pub reserve_a: u64,
+    /// The reserve of the pair's second asset, which has the higher id.
+    pub reserve_b: u64,
+    /// The shares of each liquidity provider. Users with no shares are removed.
+    pub shares: HashMap<User, u64>,
+    /// The sum of all shares.
+    pub total_shares: u64,
+}
+
+impl Pool {
+    /// The product of the reserves, which trades may never decrease.
+    pub fn product(&self) -> u128 {
+        self.reserve_a as u128 * self.reserve_b as u128
+    }
+}
+
+/// The state of the exchange.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct DexState {
+    /// The balance of each user in each asset. Like in the accounted currency, there is an
+    /// existential deposit of 1, so zero balances are removed.
+    balances: HashMap<(User, AssetId), u64>,
+    /// The pools, keyed by their pair of assets with the lower id first.
+    pools: HashMap<(AssetId, AssetId), Pool>,
+}
+
+impl DexState {
+    pub fn balance(&self, who: User, asset: AssetId) -> u64 {
+        self.balances.get(&(who, asset)).copied().unwrap_or(0)
+    }
+
+    /// The pool for the given pair of assets, in either order.
+    pub fn pool(&self, asset: AssetId, other: AssetId) -> Option<&Pool> {
+        self.pools.get(&pair(asset, other)?)
+    }
+
+    /// The total amount of an asset held by users and pools.
+    fn total_issuance(&self, asset: AssetId) -> u128 {
+        let held: u128 = self
+            .balances
+            .iter()
+            .filter(|((_, a), _)| *a == asset)
+            .map(|(_, b)| *b as u128)
+            .sum();
+        let pooled: u128 = self
+            .pools
+            .iter()
+            .map(|((a, b), pool)| {
+                (if *a == asset { pool.reserve_a } else { 0 }) as u128
+                    + (if *b == asset { pool.reserve_b } else { 0 }) as u128
+            })
+            .sum();
+        held + pooled
+    }
+
+    fn withdraw(&mut self, who: User, asset: AssetId, amount: u64) -> Option<()> {
+        let remaining = self.balance(who, asset).checked_sub(amount)?;
+        if remaining == 0 {
+            self.balances.remove(&(who, asset));
+        } else {
+            self.balances.insert((who, asset), remaining);
+        }
+        Some(())
+    }
+
+    fn deposit(&mut self, who: User, asset: AssetId, amount: u64) -> Option<()> {
+        let new_balance = self.balance(who, asset).checked_add(amount)?;
+        if new_balance > 0 {
+            self.balances.insert((who, asset), new_balance);
+        }
+        Some(())
+    }
+}
+
+/// The pool key for two assets, or None if they are the same asset.
+fn pair(asset: AssetId, other: AssetId) -> Option<(AssetId, AssetId)> {
+    match asset.cmp(&other) {
+        std::cmp::Ordering::Less => Some((asset, other)),
+        std::cmp::Ordering::Equal => None,
+        std::cmp::Ordering::Greater => Some((other, asset)),
+    }
+}
+
+/// Calculate `a * b / c`, rounding down, or None if the result does not fit in a u64.
+fn mul_div(a: u64, b: u64, c: u64) -> Option<u64> {
+    let result = (a as u128 * b as u128).checked_div(c as u128)?;
+    result.try_into().ok()
+}
+
+/// Calculate `a * b / c`, rounding up, or None if the result does not fit in a u64.
+fn mul_div_ceil(a: u64, b: u64, c: u64) -> Option<u64> {
+    let c = c as u128;
+    if c == 0 {
+        return None;
+    }
+    let result = (a as u128 * b as u128).div_ceil(c);
+    result.try_into().ok()
+}
+
+/// The largest integer whose square does not exceed n.
+fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = x / 2 + 1;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+/// The amount paid out when swapping `amount_in` into a pool holding the given reserves.
+/// The fee is taken from the input before applying the constant-product formula.
+fn swap_output(amount_in: u64, reserve_in: u64, reserve_out: u64) -> Option<u64> {
+    let in_with_fee = amount_in as u128 * (1000 - FEE_PER_THOUSAND) as u128;
+    let numerator = in_with_fee.checked_mul(reserve_out as u128)?;
+    let denominator = (reserve_in as u128 * 1000).checked_add(in_with_fee)?;
+    (numerator / denominator).try_into().ok()
+}
+
+/// The transitions that users can make on the exchange.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DexTransaction {
+    /// Issue new tokens of an asset to the given user.
+    Issue {
+        who: User,
+        asset: AssetId,
+        amount: u64,
+    },
+    /// Open a pool for a pair of assets that does not have one yet. The deposited amounts set
+    /// the initial price, and the creator receives `sqrt(amount * other_amount)` shares.
+    CreatePool {
+        creator: User,
+        asset: AssetId,
+        amount: u64,
+        other: AssetId,
+        other_amount: u64,
+    },
+    /// Deposit `amount` of `asset` into its pool with `other`, along with however much of `other`
+    /// keeps the price unchanged. Rejected if that would take more than `max_other_amount`.
+    AddLiquidity {
+        who: User,
+        asset: AssetId,
+        amount: u64,
+        other: AssetId,
+        max_other_amount: u64,
+    },
+    /// Redeem pool shares for a proportional part of both reserves.
+    RemoveLiquidity {
+        who: User,
+        asset: AssetId,
+        other: AssetId,
+        shares: u64,
+    },
+    /// Sell `amount_in` of one asset for another. Rejected if it would pay out less than
+    /// `min_amount_out`, which protects the trader from the price moving before the
+    /// transaction is included.
+    Swap {
+        who: User,
+        asset_in: AssetId,
+        amount_in: u64,
+        asset_out: AssetId,
+        min_amount_out: u64,
+    },
+}
+
+impl StateMachine for Dex {
+    type State = DexState;
+    type Transition = DexTransaction;
+
+    fn next_state(starting_state: &DexState, t: &DexTransaction) -> DexState {
+        let mut state = starting_state.clone();
+        match apply(&mut state, t) {
+            Some(()) => state,
+            None => starting_state.clone(),
+        }
+    }
+}
+
+/// Apply a transaction to the state, or return None if it is invalid. The state may be
+/// partially modified when this returns None, so it must be discarded.
+fn apply(state: &mut DexState, t: &DexTransaction) -> Option<()> {
+    match *t {
+        DexTransaction::Issue { who, asset, amount } => state.deposit(who, asset, amount),
+        DexTransaction::CreatePool {
+            creator,
+            asset,
+            amount,
+            other,
+            other_amount,
+        } => {
+            let key = pair(asset, other)?;
+            if state.pools.contains_key(&key) {
+                return None;
+            }
+            let shares = isqrt(amount as u128 * other_amount as u128) as u64;
+            if shares == 0 {
+                return None;
+            }
+            state.withdraw(creator, asset, amount)?;
+            state.withdraw(creator, other, other_amount)?;
+            let (reserve_a, reserve_b) = if key.0 == asset {
+                (amount, other_amount)
+            } else {
+                (other_amount, amount)
+            };
+            state.pools.insert(
+                key,
+                Pool {
+                    reserve_a,
+                    reserve_b,
+                    shares: HashMap::from([(creator, shares)]),
+                    total_shares: shares,
+                },
+            );
+            Some(())
+        }
+        DexTransaction::AddLiquidity {
+            who,
+            asset,
+            amount,
+            other,
+            max_other_amount,
+        } => {
+            let key = pair(asset, other)?;
+            let pool = state.pools.get(&key)?.clone();
+            let (reserve, other_reserve) = if key.0 == asset {
+                (pool.reserve_a, pool.reserve_b)
+            } else {
+                (pool.reserve_b, pool.reserve_a)
+            };
+            // Shares round down and the other deposit rounds up, so that existing providers
+            // never lose out to rounding.
+            let shares = mul_div(amount, pool.total_shares, reserve)?;
+            let other_amount = mul_div_ceil(amount, other_reserve, reserve)?;
+            if shares == 0 || other_amount > max_other_amount {
+                return None;
+            }
+            state.withdraw(who, asset, amount)?;
+            state.withdraw(who, other, other_amount)?;
+            let (add_a, add_b) = if key.0 == asset {
+                (amount, other_amount)
+            } else {
+                (other_amount, amount)
+            };
+            let pool = state.pools.get_mut(&key)?;
+            pool.reserve_a = pool.reserve_a.checked_add(add_a)?;
+            pool.reserve_b = pool.reserve_b.checked_add(add_b)?;
+            pool.total_shares = pool.total_shares.checked_add(shares)?;
+            *pool.shares.entry(who).or_insert(0) += shares;
+            Some(())
+        }
+        DexTransaction::RemoveLiquidity {
+            who,
+            asset,
+            other,
+            shares,
+        } => {
+            let key = pair(asset, other)?;
+            let pool = state.pools.get_mut(&key)?;
+            let owned = pool.shares.get(&who).copied().unwrap_or(0);
+            if shares == 0 || shares > owned {
+                return None;
+            }
+            let out_a = mul_div(pool.reserve_a, shares, pool.total_shares)?;
+            let out_b = mul_div(pool.reserve_b, shares, pool.total_shares)?;
+            pool.reserve_a -= out_a;
+            pool.reserve_b -= out_b;
+            pool.total_shares -= shares;
+            if owned == shares {
+                pool.shares.remove(&who);
+            } else {
+                pool.shares.insert(who, owned - shares);
+            }
+            if pool.total_shares == 0 {
+                state.pools.remove(&key);
+            }
+            state.deposit(who, key.0, out_a)?;
+            state.deposit(who, key.1, out_b)
+        }
+        DexTransaction::Swap {
+            who,
+            asset_in,
+            amount_in,
+            asset_out,
+            min_amount_out,
+        } => {
+            let key = pair(asset_in, asset_out)?;
+            let pool = state.pools.get_mut(&key)?;
+            let (reserve_in, reserve_out) = if key.0 == asset_in {
+                (&mut pool.reserve_a, &mut pool.reserve_b)
+            } else {
+                (&mut pool.reserve_b, &mut pool.reserve_a)
+            };
+            let amount_out = swap_output(amount_in, *reserve_in, *reserve_out)?;
+            if amount_out == 0 || amount_out < min_amount_out {
+                return None;
+            }
+            *reserve_in = reserve_in.checked_add(amount_in)?;
+            // Cannot underflow, because the formula always pays out less than the reserve.
+            *reserve_out -= amount_out;
+            state.withdraw(who, asset_in, amount_in)?;
+            state.deposit(who, asset_out, amount_out)
+        }
+    }
+}
+
+impl Invariants for Dex {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "swaps never decrease x * y",
+                holds: |pre, t, post| match t {
+                    DexTransaction::Swap {
+                        asset_in,
+                        asset_out,
+                        ..
+                    } => match (
+                        pre.pool(*asset_in, *asset_out),
+                        post.pool(*asset_in, *asset_out),
+                    ) {
+                        (Some(before), Some(after)) => after.product() >= before.product(),
+                        _ => true,
+                    },
+                    _ => true,
+                },
+            },
+            Invariant {
+                name: "liquidity changes never reduce the reserves backing each share",
+                holds: |pre, t, post| {
+                    let liquidity = matches!(
+                        t,
+                        DexTransaction::AddLiquidity { .. }
+                            | DexTransaction::RemoveLiquidity { .. }
+                    );
+                    !liquidity
+                        || pre
+                            .pools
+                            .iter()
+                            .all(|(key, before)| match post.pools.get(key) {
+                                Some(after) => {
+                                    after.reserve_a as u128 * before.total_shares as u128
+                                        >= before.reserve_a as u128 * after.total_shares as u128
+                                        && after.reserve_b as u128 * before.total_shares as u128
+                                            >= before.reserve_b as u128 * after.total_shares as u128
+                                }
+                                None => true,
+                            })
+                },
+            },
+            Invariant {
+                name: "assets are only created by issuance",
+                holds: |pre, t, post| {
+                    let assets = pre.balances.keys().map(|(_, a)| *a).chain(
+                        post.balances
+                            .keys()
+                            .map(|(_, a)| *a)
+                            .chain(pre.pools.keys().flat_map(|(a, b)| [*a, *b])),
+                    );
+                    assets.collect::<Vec<_>>().into_iter().all(|asset| match t {
+                        DexTransaction::Issue { asset: issued, .. } if *issued == asset => {
+                            post.total_issuance(asset) >= pre.total_issuance(asset)
+                        }
+                        _ => post.total_issuance(asset) == pre.total_issuance(asset),
+                    })
+                },
+            },
+            Invariant {
+                name: "pool shares add up to the total",
+                holds: |_, _, post| {
+                    post.pools.values().all(|pool| {
+                        pool.total_shares > 0
+                            && pool.shares.values().all(|s| *s > 0)
+                            && pool.shares.values().map(|s| *s as u128).sum::<u128>()
+                                == pool.total_shares as u128
+                    })
+                },
+            },
+            Invariant {
+                name: "users with no tokens are removed",
+                holds: |_, _, post| post.balances.values().all(|b| *b > 0),
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for Dex {
+    fn arbitrary_transition(rng: &mut Rng, _: &DexState) -> DexTransaction {
+        let users = [User::Alice, User::Bob, User::Charlie];
+        let who = *rng.pick(&users);
+        let asset = rng.below(3) as AssetId;
+        let other = rng.below(3) as AssetId;
+        let mut amount = || match rng.below(10) {
+            0 => 0,
+            1 => u64::MAX,
+            _ => rng.below(2_000),
+        };
+        let (amount, other_amount) = (amount(), amount());
+        match rng.below(5) {
+            0 => DexTransaction::Issue { who, asset, amount },
+            1 => DexTransaction::CreatePool {
+                creator: who,
+                asset,
+                amount,
+                other,
+                other_amount,
+            },
+            2 => DexTransaction::AddLiquidity {
+                who,
+                asset,
+                amount,
+                other,
+                max_other_amount: other_amount,
+            },
+            3 => DexTransaction::RemoveLiquidity {
+                who,
+                asset,
+                other,
+                shares: amount,
+            },
+            _ => DexTransaction::Swap {
+                who,
+                asset_in: asset,
+                amount_in: amount,
+                asset_out: other,
+                min_amount_out: other_amount / 4,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+fn run(state: DexState, transitions: &[DexTransaction]) -> DexState {
+    transitions
+        .iter()
+        .fold(state, |state, t| Dex::next_state(&state, t))
+}
+
+/// Alice has opened a pool with 1000 of asset 0 and 4000 of asset 1. Bob holds 1000 of each.
+#[cfg(test)]
+fn open_pool() -> DexState {
+    use DexTransaction::*;
+
+    run(
+        DexState::default(),
+        &[
+            Issue {
+                who: User::Alice,
+                asset: 0,
+                amount: 1_000,
+            },
+            Issue {
+                who: User::Alice,
+                asset: 1,
+                amount: 4_000,
+            },
+            Issue {
+                who: User::Bob,
+                asset: 0,
+                amount: 1_000,
+            },
+            Issue {
+                who: User::Bob,
+                asset: 1,
+                amount: 1_000,
+            },
+            CreatePool {
+                creator: User::Alice,
+                asset: 1,
+                amount: 4_000,
+                other: 0,
+                other_amount: 1_000,
+            },
+        ],
+    )
+}
+
+#[test]
+fn sm_dex_create_pool() {
+    let state = open_pool();
+    let pool = state.pool(0, 1).unwrap();
+    assert_eq!((pool.reserve_a, pool.reserve_b), (1_000, 4_000));
+    assert_eq!(pool.total_shares, 2_000);
+    assert_eq!(state.balance(User::Alice, 0), 0);
+
+    // A pair can only have one pool.
+    let again = DexTransaction::CreatePool {
+        creator: User::Bob,
+        asset: 0,
+        amount: 10,
+        other: 1,
+        other_amount: 10,
+    };
+    assert_eq!(Dex::next_state(&state, &again), state);
+}
+
+#[test]
+fn sm_dex_swap_pays_fee_and_respects_slippage_limit() {
+    let swap = |min_amount_out| DexTransaction::Swap {
+        who: User::Bob,
+        asset_in: 0,
+        amount_in: 100,
+        asset_out: 1,
+        min_amount_out,
+    };
+    let state = open_pool();
+
+    // Without a fee Bob would get 4000 - 4_000_000 / 1100 = 363.
+    let traded = Dex::next_state(&state, &swap(300));
+    assert_eq!(traded.balance(User::Bob, 0), 900);
+    assert_eq!(traded.balance(User::Bob, 1), 1_362);
+    let pool = traded.pool(1, 0).unwrap();
+    assert_eq!((pool.reserve_a, pool.reserve_b), (1_100, 3_638));
+    assert!(pool.product() > state.pool(0, 1).unwrap().product());
+
+    assert_eq!(Dex::next_state(&state, &swap(363)), state);
+}
+
+#[test]
+fn sm_dex_liquidity_shares() {
+    use DexTransaction::*;
+
+    let add = |max_other_amount| AddLiquidity {
+        who: User::Bob,
+        asset: 0,
+        amount: 100,
+        other: 1,
+        max_other_amount,
+    };
+    let state = open_pool();
+    assert_eq!(Dex::next_state(&state, &add(399)), state);
+
+    let state = Dex::next_state(&state, &add(400));
+    assert_eq!(state.pool(0, 1).unwrap().shares[&User::Bob], 200);
+    assert_eq!(state.balance(User::Bob, 1), 600);
+
+    let state = run(
+        state,
+        &[
+            RemoveLiquidity {
+                who: User::Alice,
+                asset: 0,
+                other: 1,
+                shares: 2_000,
+            },
+            RemoveLiquidity {
+                who: User::Bob,
+                asset: 1,
+                other: 0,
+                shares: 200,
+            },
+        ],
+    );
+    assert!(state.pool(0, 1).is_none());
+    assert_eq!(state.balance(User::Alice, 0), 1_000);
+    assert_eq!(state.balance(User::Alice, 1), 4_000);
+    assert_eq!(state.balance(User::Bob, 0), 1_000);
+    assert_eq!(state.balance(User::Bob, 1), 1_000);
+}
+
+#[test]
+fn sm_dex_rejects_overflowing_math() {
+    use DexTransaction::*;
+
+    let state = run(
+        DexState::default(),
+        &[
+            Issue {
+                who: User::Alice,
+                asset: 0,
+                amount: u64::MAX,
+            },
+            Issue {
+                who: User::Alice,
+                asset: 1,
+                amount: u64::MAX,
+            },
+            CreatePool {
+                creator: User::Alice,
+                asset: 0,
+                amount: u64::MAX / 2,
+                other: 1,
+                other_amount: u64::MAX / 2,
+            },
+        ],
+    );
+    let pool = state.pool(0, 1).unwrap().clone();
+    assert_eq!(pool.total_shares, u64::MAX / 2);
+
+    let swap = Swap {
+        who: User::Alice,
+        asset_in: 0,
+        amount_in: u64::MAX / 2 + 1,
+        asset_out: 1,
+        min_amount_out: 0,
+    };
+    assert_eq!(Dex::next_state(&state, &swap), state);
+}
+
+#[test]
+fn sm_dex_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    let funded = run(
+        DexState::default(),
+        &[User::Alice, User::Bob, User::Charlie]
+            .iter()
+            .flat_map(|who| {
+                (0..3).map(|asset| DexTransaction::Issue {
+                    who: *who,
+                    asset,
+                    amount: 10_000,
+                })
+            })
+            .collect::<Vec<_>>(),
+    );
+    fuzz::<Dex>(&funded, &FuzzConfig::default()).unwrap();
+}
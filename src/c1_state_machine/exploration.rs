@@ -0,0 +1,184 @@
+//! Some state machines, like our light switches and laundry, only have a handful of states and
+//! transitions. For such finite machines we can go further than testing individual transitions.
+//! We can explore every state reachable from some initial state, and look at the machine as a
+//! whole graph.
+//!
+//! Looking at the whole graph reveals design problems that individual tests miss. Some states
+//! may be unreachable, and others may be dead ends that can never be left, like tattered clothes.
+//! The graph can also be exported in Graphviz's DOT format to draw diagrams of the machine.
+
+use super::StateMachine;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug, Write};
+use std::hash::Hash;
+
+/// A state machine with a finite set of transitions that can all be listed.
+pub trait FiniteStateMachine: StateMachine {
+    /// Every transition that this machine can undergo.
+    fn all_transitions() -> Vec<Self::Transition>;
+}
+
+/// The graph of every state reachable from an initial state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateGraph<S, T> {
+    /// Every reachable state, in the order it was discovered. The first is the initial state.
+    states: Vec<S>,
+    /// Every transition as (from state index, transition, to state index).
+    edges: Vec<(usize, T, usize)>,
+}
+
+/// Explore every state reachable from the given initial state, breadth first.
+///
+/// The machine must only be able to reach finitely many states from the initial state,
+/// otherwise exploration never ends.
+pub fn explore<SM>(initial: SM::State) -> StateGraph<SM::State, SM::Transition>
+where
+    SM: FiniteStateMachine,
+    SM::State: Clone + Eq + Hash,
+    SM::Transition: Clone,
+{
+    let transitions = SM::all_transitions();
+    let mut index = HashMap::from([(initial.clone(), 0)]);
+    let mut states = vec![initial];
+    let mut edges = Vec::new();
+    let mut queue = VecDeque::from([0]);
+
+    while let Some(from) = queue.pop_front() {
+        for t in &transitions {
+            let next = SM::next_state(&states[from], t);
+            let to = match index.get(&next) {
+                Some(to) => *to,
+                None => {
+                    let to = states.len();
+                    index.insert(next.clone(), to);
+                    states.push(next);
+                    queue.push_back(to);
+                    to
+                }
+            };
+            edges.push((from, t.clone(), to));
+        }
+    }
+
+    StateGraph { states, edges }
+}
+
+impl<S: PartialEq, T> StateGraph<S, T> {
+    /// Every reachable state. The first is the initial state.
+    pub fn states(&self) -> &[S] {
+        &self.states
+    }
+
+    /// Every transition as (from state, transition, to state).
+    pub fn edges(&self) -> impl Iterator<Item = (&S, &T, &S)> {
+        self.edges
+            .iter()
+            .map(|(from, t, to)| (&self.states[*from], t, &self.states[*to]))
+    }
+
+    /// Whether the given state is reachable.
+    pub fn contains(&self, state: &S) -> bool {
+        self.states.contains(state)
+    }
+
+    /// States that can never be left. Every transition out of them leads back to themselves.
+    pub fn dead_ends(&self) -> Vec<&S> {
+        (0..self.states.len())
+            .filter(|i| self.is_dead_end(*i))
+            .map(|i| &self.states[i])
+            .collect()
+    }
+
+    /// The given candidate states that are not reachable from the initial state.
+    pub fn unreachable<'a>(&self, candidates: &'a [S]) -> Vec<&'a S> {
+        candidates.iter().filter(|s| !self.contains(s)).collect()
+    }
+
+    fn is_dead_end(&self, state: usize) -> bool {
+        self.edges
+            .iter()
+            .filter(|(from, _, _)| *from == state)
+            .all(|(_, _, to)| *to == state)
+    }
+}
+
+impl<S: PartialEq + Debug, T: Debug> StateGraph<S, T> {
+    /// Render the graph in Graphviz's DOT format. The initial state is drawn bold
+    /// and dead ends are drawn with a double border.
+    pub fn to_dot(&self, name: &str) -> String {
+        let mut dot = format!("digraph {} {{\n", quoted(name));
+        for (i, state) in self.states.iter().enumerate() {
+            let mut attributes = format!("label={}", quoted(&format!("{state:?}")));
+            if i == 0 {
+                attributes.push_str(", style=bold");
+            }
+            if self.is_dead_end(i) {
+                attributes.push_str(", shape=doublecircle");
+            }
+            writeln!(dot, "    s{i} [{attributes}];").expect("writing to a string never fails");
+        }
+        for (from, t, to) in &self.edges {
+            let label = quoted(&format!("{t:?}"));
+            writeln!(dot, "    s{from} -> s{to} [label={label}];")
+                .expect("writing to a string never fails");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Quote a string for use as a DOT identifier or label.
+fn quoted(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[test]
+fn sm_exploration_light_switch_to_dot() {
+    use super::p1_switches::LightSwitch;
+
+    let graph = explore::<LightSwitch>(false);
+    assert_eq!(graph.states(), &[false, true]);
+    assert!(graph.dead_ends().is_empty());
+
+    let expected = "digraph \"LightSwitch\" {
+    s0 [label=\"false\", style=bold];
+    s1 [label=\"true\"];
+    s0 -> s1 [label=\"()\"];
+    s1 -> s0 [label=\"()\"];
+}
+";
+    assert_eq!(graph.to_dot("LightSwitch"), expected);
+}
+
+#[test]
+fn sm_exploration_weird_switches_reach_every_state() {
+    use super::p1_switches::{TwoSwitches, WeirdSwitchMachine};
+
+    let graph = explore::<WeirdSwitchMachine>(TwoSwitches::default());
+    assert_eq!(graph.states().len(), 4);
+    assert_eq!(graph.edges().count(), 8);
+    assert!(graph.dead_ends().is_empty());
+}
+
+#[test]
+fn sm_exploration_tattered_clothes_are_a_dead_end() {
+    use super::p2_laundry_machine::{ClothesMachine, ClothesState};
+
+    let graph = explore::<ClothesMachine>(ClothesState::Clean(3));
+    assert_eq!(graph.dead_ends(), vec![&ClothesState::Tattered]);
+
+    // Every transition wears clothes out, so they never get their life back.
+    let candidates = [
+        ClothesState::Wet(2),
+        ClothesState::Clean(4),
+        ClothesState::Wet(3),
+    ];
+    assert_eq!(
+        graph.unreachable(&candidates),
+        vec![&ClothesState::Clean(4), &ClothesState::Wet(3)]
+    );
+
+    let dot = graph.to_dot("Laundry");
+    assert!(dot.contains("[label=\"Tattered\", shape=doublecircle];"));
+    assert!(dot.contains("[label=\"Clean(3)\", style=bold];"));
+}
@@ -0,0 +1,391 @@
+//! Consensus rules change over time. Difficulty needs adjusting, authorities come and go, and
+//! whole new consensus engines are adopted. Hard-coding each change into the client means every
+//! node operator has to upgrade their binary in lockstep, which is slow and error prone.
+//!
+//! Instead, a chain can govern its own parameters. In this module, a council votes on referenda
+//! that each propose a single parameter change, to be enacted at a given future block height.
+//! Passed referenda are recorded in the state as scheduled changes, which the client reads and
+//! applies to its consensus engine from the scheduled height onward.
+//!
+//! This state machine needs to know the current block height so that changes are only ever
+//! scheduled for the future. It learns the height as each block begins.
+
+use super::invariants::{ArbitraryTransition, BlockInvariant, Invariant, Invariants, Rng};
+use super::{StateMachine, User};
+use std::collections::HashMap;
+
+/// This state machine models a council that governs the consensus parameters.
+pub struct Governance;
+
+/// A change to one of the consensus parameters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParameterChange {
+    /// Set the proof of work threshold.
+    PowThreshold(u64),
+    /// Replace the set of authorities.
+    Authorities(Vec<User>),
+    /// Set the height at which the chain switches from proof of work to proof of authority.
+    ForkHeight(u64),
+}
+
+impl ParameterChange {
+    /// Whether the chain could keep going after the change. No header hash is below a threshold
+    /// of zero, and nobody can sign for an empty authority set.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            ParameterChange::PowThreshold(threshold) => *threshold > 0,
+            ParameterChange::Authorities(authorities) => !authorities.is_empty(),
+            ParameterChange::ForkHeight(_) => true,
+        }
+    }
+}
+
+/// A parameter change that has passed, and takes effect at the given height.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledChange {
+    /// The first block height at which the change applies.
+    pub at: u64,
+    pub change: ParameterChange,
+}
+
+/// A proposed parameter change that the council is voting on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Referendum {
+    pub change: ParameterChange,
+    /// The first block height at which the change would apply if it passes.
+    pub enact_at: u64,
+    /// How each council member has voted so far. True is in favour.
+    pub votes: HashMap<User, bool>,
+}
+
+/// The state of the governance system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GovernanceState {
+    /// The height of the current block.
+    height: u64,
+    /// The users who may propose and vote on referenda.
+    council: Vec<User>,
+    /// Referenda that are still being voted on, by id.
+    referenda: HashMap<u64, Referendum>,
+    /// The id of the next referendum.
+    next_id: u64,
+    /// Every change that has passed, in the order they passed.
+    scheduled: Vec<ScheduledChange>,
+}
+
+impl GovernanceState {
+    /// Start at genesis with the given council.
+    pub fn new(council: Vec<User>) -> Self {
+        GovernanceState {
+            height: 0,
+            council,
+            referenda: HashMap::new(),
+            next_id: 0,
+            scheduled: Vec::new(),
+        }
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn referendum(&self, id: u64) -> Option<&Referendum> {
+        self.referenda.get(&id)
+    }
+
+    /// Every parameter change that has passed, in the order they passed. Changes scheduled
+    /// for the same height are applied in this order too.
+    pub fn scheduled(&self) -> &[ScheduledChange] {
+        &self.scheduled
+    }
+
+    /// More than half of the council must agree for a referendum to be decided.
+    fn majority(&self) -> usize {
+        self.council.len() / 2 + 1
+    }
+}
+
+/// The transitions of the governance system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GovernanceTransaction {
+    /// A council member proposes a parameter change to be enacted at a future height. Changes
+    /// that would halt the chain are ignored.
+    Propose {
+        proposer: User,
+        change: ParameterChange,
+        enact_at: u64,
+    },
+    /// A council member votes on a referendum, replacing any earlier vote. As soon as a majority
+    /// is in favour the change is scheduled, and as soon as a majority is against it is dropped.
+    Vote {
+        voter: User,
+        referendum: u64,
+        aye: bool,
+    },
+}
+
+impl StateMachine for Governance {
+    type State = GovernanceState;
+    type Transition = GovernanceTransaction;
+
+    fn next_state(starting_state: &GovernanceState, t: &GovernanceTransaction) -> GovernanceState {
+        let mut state = starting_state.clone();
+        match t {
+            GovernanceTransaction::Propose {
+                proposer,
+                change,
+                enact_at,
+            } => {
+                if state.council.contains(proposer) && *enact_at > state.height && change.is_valid()
+                {
+                    state.referenda.insert(
+                        state.next_id,
+                        Referendum {
+                            change: change.clone(),
+                            enact_at: *enact_at,
+                            votes: HashMap::new(),
+                        },
+                    );
+                    state.next_id += 1;
+                }
+            }
+            GovernanceTransaction::Vote {
+                voter,
+                referendum,
+                aye,
+            } => {
+                let majority = state.majority();
+                if !state.council.contains(voter) {
+                    return state;
+                }
+                let Some(r) = state.referenda.get_mut(referendum) else {
+                    return state;
+                };
+                r.votes.insert(*voter, *aye);
+                let ayes = r.votes.values().filter(|aye| **aye).count();
+                let nays = r.votes.len() - ayes;
+                if ayes >= majority {
+                    let r = state
+                        .referenda
+                        .remove(referendum)
+                        .expect("referendum was just found");
+                    state.scheduled.push(ScheduledChange {
+                        at: r.enact_at,
+                        change: r.change,
+                    });
+                } else if nays >= majority {
+                    state.referenda.remove(referendum);
+                }
+            }
+        }
+        state
+    }
+
+    /// Referenda that can no longer be enacted in time are dropped. Heights that do not
+    /// increase are ignored.
+    fn begin_block(starting_state: &GovernanceState, height: u64) -> GovernanceState {
+        let mut state = starting_state.clone();
+        if height > state.height {
+            state.height = height;
+            state.referenda.retain(|_, r| r.enact_at > height);
+        }
+        state
+    }
+}
+
+impl Invariants for Governance {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "changes are only ever scheduled for the future",
+                holds: |pre, _, post| {
+                    post.scheduled[pre.scheduled.len()..]
+                        .iter()
+                        .all(|s| s.at > post.height)
+                },
+            },
+            Invariant {
+                name: "scheduled changes are never forgotten",
+                holds: |pre, _, post| post.scheduled.starts_with(&pre.scheduled),
+            },
+            Invariant {
+                name: "only valid changes are voted on or scheduled",
+                holds: |_, _, post| {
+                    post.referenda.values().all(|r| r.change.is_valid())
+                        && post.scheduled.iter().all(|s| s.change.is_valid())
+                },
+            },
+            Invariant {
+                name: "transactions never change the height",
+                holds: |pre, _, post| post.height == pre.height,
+            },
+            Invariant {
+                name: "only council members vote",
+                holds: |_, _, post| {
+                    post.referenda
+                        .values()
+                        .all(|r| r.votes.keys().all(|v| post.council.contains(v)))
+                },
+            },
+        ]
+    }
+
+    fn block_invariants() -> Vec<BlockInvariant<Self>> {
+        vec![
+            BlockInvariant {
+                name: "the height follows the block height",
+                holds: |pre, height, post| post.height == pre.height.max(height),
+            },
+            BlockInvariant {
+                name: "scheduled changes are never forgotten",
+                holds: |pre, _, post| post.scheduled == pre.scheduled,
+            },
+            BlockInvariant {
+                name: "only referenda that can still be enacted remain",
+                holds: |_, _, post| post.referenda.values().all(|r| r.enact_at > post.height),
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for Governance {
+    fn arbitrary_transition(rng: &mut Rng, state: &GovernanceState) -> GovernanceTransaction {
+        let users = [User::Alice, User::Bob, User::Charlie];
+        match rng.below(3) {
+            0 => GovernanceTransaction::Propose {
+                proposer: *rng.pick(&users),
+                change: match rng.below(3) {
+                    0 => ParameterChange::PowThreshold(if rng.chance(1, 2) {
+                        rng.next_u64()
+                    } else {
+                        0
+                    }),
+                    1 => ParameterChange::Authorities(
+                        users.iter().copied().filter(|_| rng.chance(1, 2)).collect(),
+                    ),
+                    _ => ParameterChange::ForkHeight(rng.below(100)),
+                },
+                enact_at: state.height + rng.below(10),
+            },
+            _ => GovernanceTransaction::Vote {
+                voter: *rng.pick(&users),
+                referendum: rng.below(state.next_id + 1),
+                aye: rng.chance(2, 3),
+            },
+        }
+    }
+
+    fn new_block_chance() -> (u64, u64) {
+        (1, 4)
+    }
+}
+
+#[cfg(test)]
+fn run(state: GovernanceState, transitions: &[GovernanceTransaction]) -> GovernanceState {
+    transitions
+        .iter()
+        .fold(state, |state, t| Governance::next_state(&state, t))
+}
+
+#[cfg(test)]
+fn council() -> GovernanceState {
+    GovernanceState::new(vec![User::Alice, User::Bob, User::Charlie])
+}
+
+#[test]
+fn sm_governance_majority_schedules_change() {
+    use GovernanceTransaction::*;
+
+    let state = run(
+        Governance::begin_block(&council(), 5),
+        &[
+            Propose {
+                proposer: User::Alice,
+                change: ParameterChange::PowThreshold(1000),
+                enact_at: 20,
+            },
+            Vote {
+                voter: User::Alice,
+                referendum: 0,
+                aye: true,
+            },
+        ],
+    );
+    assert!(state.scheduled().is_empty());
+
+    let state = run(
+        state,
+        &[Vote {
+            voter: User::Charlie,
+            referendum: 0,
+            aye: true,
+        }],
+    );
+    assert_eq!(
+        state.scheduled(),
+        &[ScheduledChange {
+            at: 20,
+            change: ParameterChange::PowThreshold(1000),
+        }]
+    );
+    assert!(state.referendum(0).is_none());
+}
+
+#[test]
+fn sm_governance_rejected_and_expired_referenda_are_dropped() {
+    use GovernanceTransaction::*;
+
+    let propose = |enact_at| Propose {
+        proposer: User::Bob,
+        change: ParameterChange::ForkHeight(50),
+        enact_at,
+    };
+    let vote = |voter, referendum, aye| Vote {
+        voter,
+        referendum,
+        aye,
+    };
+    let state = run(
+        council(),
+        &[
+            propose(10),
+            propose(30),
+            vote(User::Alice, 0, false),
+            vote(User::Bob, 0, false),
+        ],
+    );
+    let state = run(
+        Governance::begin_block(&state, 30),
+        &[
+            // Both are gone, so these votes do nothing.
+            vote(User::Alice, 1, true),
+            vote(User::Bob, 1, true),
+            // Proposals to change the past are ignored.
+            propose(30),
+            // As are proposals that would halt the chain.
+            Propose {
+                proposer: User::Bob,
+                change: ParameterChange::PowThreshold(0),
+                enact_at: 40,
+            },
+            Propose {
+                proposer: User::Bob,
+                change: ParameterChange::Authorities(Vec::new()),
+                enact_at: 40,
+            },
+        ],
+    );
+    assert_eq!(state.height(), 30);
+    assert!(state.referendum(0).is_none());
+    assert!(state.referendum(1).is_none());
+    assert!(state.referendum(2).is_none());
+    assert!(state.referendum(3).is_none());
+    assert!(state.scheduled().is_empty());
+}
+
+#[test]
+fn sm_governance_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    fuzz::<Governance>(&council(), &FuzzConfig::default()).unwrap();
+}
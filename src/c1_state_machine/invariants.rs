@@ -0,0 +1,386 @@
+//! The tests we have written so far are example-based. They check the cases we happened to think
+//! of, and nothing else. Bugs tend to hide in the cases we did not think of.
+//!
+//! In this module we build a small property-based testing harness. A state machine declares some
+//! invariants that must hold across every transition it makes. For example, an accounted currency
+//! should never change the total supply except through minting and burning. A generator then
+//! produces long random sequences of transitions and checks the invariants after every step.
+//! When a violation is found, the offending trace is shrunk to a minimal sequence of transitions
+//! that still reproduces it, which makes the bug much easier to understand.
+//!
+//! Machines that react to the passage of time also see new blocks start between transitions,
+//! and declare separate invariants that must hold across each block start.
+
+use super::StateMachine;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+/// A tiny deterministic pseudo-random number generator (SplitMix64).
+///
+/// We avoid pulling in an external crate here. All we need is something fast and reproducible
+/// so that a failing seed can be re-run to get the exact same trace.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// Create a new generator from the given seed.
+    pub fn seeded(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    /// Draw the next raw 64 bit value.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Draw a value uniformly-ish from `0..n`. Panics if `n` is zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        assert!(n > 0, "cannot draw from an empty range");
+        self.next_u64() % n
+    }
+
+    /// Returns true with probability `numerator / denominator`.
+    pub fn chance(&mut self, numerator: u64, denominator: u64) -> bool {
+        self.below(denominator) < numerator
+    }
+
+    /// Pick a random element of a non-empty slice.
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+}
+
+/// A property that must hold every time the state machine makes a transition.
+///
+/// The property is given the starting state, the transition, and the resulting state, so it can
+/// express both facts about a single state and relationships between consecutive states.
+pub struct Invariant<SM: StateMachine> {
+    /// A short human-readable description used when reporting violations.
+    pub name: &'static str,
+    /// Returns whether the property holds for this transition.
+    pub holds: fn(&SM::State, &SM::Transition, &SM::State) -> bool,
+}
+
+/// A property that must hold every time a new block starts.
+///
+/// The property is given the state before the block, the height of the new block, and the state
+/// at the start of the block.
+pub struct BlockInvariant<SM: StateMachine> {
+    /// A short human-readable description used when reporting violations.
+    pub name: &'static str,
+    /// Returns whether the property holds for this block start.
+    pub holds: fn(&SM::State, u64, &SM::State) -> bool,
+}
+
+/// A state machine that declares the invariants it promises to uphold.
+pub trait Invariants: StateMachine + Sized {
+    /// All of the invariants that must hold across every transition.
+    fn invariants() -> Vec<Invariant<Self>>;
+
+    /// All of the invariants that must hold across every block start. Only machines that
+    /// override `StateMachine::begin_block` need any.
+    fn block_invariants() -> Vec<BlockInvariant<Self>> {
+        Vec::new()
+    }
+}
+
+/// A state machine whose transitions can be randomly generated.
+///
+/// The current state is passed in so that generators can produce interesting transitions,
+/// such as spending bills that actually exist. Generators should still occasionally produce
+/// nonsense transitions, because the machine must handle those gracefully too.
+pub trait ArbitraryTransition: StateMachine {
+    /// Generate a random transition to apply to the given state.
+    fn arbitrary_transition(rng: &mut Rng, state: &Self::State) -> Self::Transition;
+
+    /// The chance, as a `(numerator, denominator)` fraction, that each step of a trace starts a
+    /// new block rather than applying a transition. Machines that override `StateMachine::begin_block` should make this non-zero
+    /// so that their block starts are exercised too.
+    fn new_block_chance() -> (u64, u64) {
+        (0, 1)
+    }
+}
+
+/// Controls how hard the harness looks for counterexamples.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuzzConfig {
+    /// Seed for the random number generator. The same seed always produces the same traces.
+    pub seed: u64,
+    /// How many independent traces to generate, each starting from the initial state.
+    pub runs: usize,
+    /// How many steps, either transitions or block starts, to apply in each trace.
+    pub steps: usize,
+}
+
+impl Default for FuzzConfig {
+    fn default() -> Self {
+        FuzzConfig {
+            seed: 0,
+            runs: 200,
+            steps: 50,
+        }
+    }
+}
+
+/// The ways in which a transition can go wrong.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The named invariant did not hold.
+    Invariant(&'static str),
+    /// The state machine panicked while calculating the next state.
+    Panic(String),
+}
+
+impl Violation {
+    /// Whether two violations are the same bug. Panic messages may differ between traces
+    /// (for example when they include values) so all panics are considered alike.
+    fn same_kind(&self, other: &Violation) -> bool {
+        match (self, other) {
+            (Violation::Invariant(a), Violation::Invariant(b)) => a == b,
+            (Violation::Panic(_), Violation::Panic(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A single step in a trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step<T> {
+    /// Start the next block. The harness numbers blocks from 1, treating the initial state as
+    /// the genesis state.
+    NewBlock,
+    /// Apply a transition within the current block.
+    Transition(T),
+}
+
+/// A minimal sequence of steps that, applied to the initial state, causes a violation.
+pub struct Counterexample<SM: StateMachine> {
+    /// What went wrong at the final step of the trace.
+    pub violation: Violation,
+    /// The shrunk trace. The violation happens when applying the last step.
+    pub trace: Vec<Step<SM::Transition>>,
+}
+
+impl<SM: StateMachine> fmt::Debug for Counterexample<SM>
+where
+    SM::Transition: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Counterexample")
+            .field("violation", &self.violation)
+            .field("trace", &self.trace)
+            .finish()
+    }
+}
+
+/// Apply a single step, checking for panics and invariant violations. The height is that of
+/// the current block, and is advanced when a new block starts.
+fn checked_step<SM: Invariants>(
+    state: &SM::State,
+    height: &mut u64,
+    step: &Step<SM::Transition>,
+) -> Result<SM::State, Violation>
+where
+    SM::State: Clone,
+{
+    let next = panic::catch_unwind(AssertUnwindSafe(|| match step {
+        Step::NewBlock => SM::begin_block(state, *height + 1),
+        Step::Transition(t) => SM::next_state(state, t),
+    }))
+    .map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".into());
+        Violation::Panic(message)
+    })?;
+
+    let broken = match step {
+        Step::NewBlock => {
+            *height += 1;
+            SM::block_invariants()
+                .into_iter()
+                .find(|invariant| !(invariant.holds)(state, *height, &next))
+                .map(|broken| broken.name)
+        }
+        Step::Transition(t) => SM::invariants()
+            .into_iter()
+            .find(|invariant| !(invariant.holds)(state, t, &next))
+            .map(|broken| broken.name),
+    };
+    match broken {
+        Some(name) => Err(Violation::Invariant(name)),
+        None => Ok(next),
+    }
+}
+
+/// Replay a trace from the initial state. Returns the index of the first failing
+/// step and the violation it caused, if any.
+fn replay<SM: Invariants>(
+    initial: &SM::State,
+    trace: &[Step<SM::Transition>],
+) -> Option<(usize, Violation)>
+where
+    SM::State: Clone,
+{
+    let mut state = initial.clone();
+    let mut height = 0;
+    for (i, step) in trace.iter().enumerate() {
+        match checked_step::<SM>(&state, &mut height, step) {
+            Ok(next) => state = next,
+            Err(violation) => return Some((i, violation)),
+        }
+    }
+    None
+}
+
+/// Shrink a failing trace by repeatedly removing steps as long as the
+/// same kind of violation still occurs.
+fn shrink<SM: Invariants>(
+    initial: &SM::State,
+    mut trace: Vec<Step<SM::Transition>>,
+    mut violation: Violation,
+) -> Counterexample<SM>
+where
+    SM::State: Clone,
+    SM::Transition: Clone,
+{
+    let mut i = 0;
+    while i < trace.len() {
+        let mut candidate = trace.clone();
+        candidate.remove(i);
+        match replay::<SM>(initial, &candidate) {
+            Some((failed_at, v)) if v.same_kind(&violation) => {
+                candidate.truncate(failed_at + 1);
+                trace = candidate;
+                violation = v;
+                // Removing a step may unlock further removals earlier in the trace.
+                i = 0;
+            }
+            _ => i += 1,
+        }
+    }
+
+    Counterexample { violation, trace }
+}
+
+/// Search for a sequence of transitions and block starts that violates one of the machine's
+/// invariants or makes it panic. Returns the shrunk counterexample if one is found.
+pub fn fuzz<SM>(initial: &SM::State, config: &FuzzConfig) -> Result<(), Counterexample<SM>>
+where
+    SM: Invariants + ArbitraryTransition,
+    SM::State: Clone,
+    SM::Transition: Clone,
+{
+    let mut rng = Rng::seeded(config.seed);
+    let (numerator, denominator) = SM::new_block_chance();
+
+    for _ in 0..config.runs {
+        let mut state = initial.clone();
+        let mut height = 0;
+        let mut trace = Vec::new();
+
+        for _ in 0..config.steps {
+            let step = if numerator > 0 && rng.chance(numerator, denominator) {
+                Step::NewBlock
+            } else {
+                Step::Transition(SM::arbitrary_transition(&mut rng, &state))
+            };
+            trace.push(step.clone());
+            match checked_step::<SM>(&state, &mut height, &step) {
+                Ok(next) => state = next,
+                Err(violation) => return Err(shrink::<SM>(initial, trace, violation)),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A deliberately broken counter used to test the harness itself.
+/// It claims to never climb above 3, but happily counts past it.
+#[cfg(test)]
+struct LeakyCounter;
+
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum CounterOp {
+    Up,
+    Down,
+    Reset,
+}
+
+#[cfg(test)]
+impl StateMachine for LeakyCounter {
+    type State = u8;
+    type Transition = CounterOp;
+
+    fn next_state(starting_state: &u8, t: &CounterOp) -> u8 {
+        match t {
+            CounterOp::Up => starting_state.checked_add(1).expect("counter overflowed"),
+            CounterOp::Down => starting_state.saturating_sub(1),
+            CounterOp::Reset => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Invariants for LeakyCounter {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![Invariant {
+            name: "counter never climbs above 3",
+            holds: |pre, _, post| *post <= (*pre).max(3),
+        }]
+    }
+}
+
+#[cfg(test)]
+impl ArbitraryTransition for LeakyCounter {
+    fn arbitrary_transition(rng: &mut Rng, _: &u8) -> CounterOp {
+        rng.pick(&[
+            CounterOp::Up,
+            CounterOp::Up,
+            CounterOp::Down,
+            CounterOp::Reset,
+        ])
+        .clone()
+    }
+}
+
+#[test]
+fn sm_invariants_rng_is_deterministic() {
+    let mut a = Rng::seeded(42);
+    let mut b = Rng::seeded(42);
+    for _ in 0..100 {
+        assert_eq!(a.next_u64(), b.next_u64());
+    }
+    assert_ne!(Rng::seeded(1).next_u64(), Rng::seeded(2).next_u64());
+}
+
+#[test]
+fn sm_invariants_finds_and_shrinks_violation() {
+    let counterexample = fuzz::<LeakyCounter>(&0, &FuzzConfig::default()).unwrap_err();
+
+    assert_eq!(
+        counterexample.violation,
+        Violation::Invariant("counter never climbs above 3")
+    );
+    assert_eq!(
+        counterexample.trace,
+        vec![Step::Transition(CounterOp::Up); 4]
+    );
+}
+
+#[test]
+fn sm_invariants_reports_panics() {
+    // Starting at the top of the range, a single `Up` overflows the u8.
+    let counterexample = fuzz::<LeakyCounter>(&u8::MAX, &FuzzConfig::default()).unwrap_err();
+
+    assert!(matches!(counterexample.violation, Violation::Panic(_)));
+    assert_eq!(counterexample.trace, vec![Step::Transition(CounterOp::Up)]);
+}
@@ -1,12 +1,35 @@
 //! This module is all about modeling phenomena and systems as state machines. We begin with a few simple
 //! examples, and then proceed to build bigger and more complex state machines all implementing the same simple interface.
 
+mod composition;
+mod dex;
+mod exploration;
+mod governance;
+mod invariants;
+mod name_service;
 mod p1_switches;
 mod p2_laundry_machine;
 mod p3_atm;
 mod p4_accounted_currency;
 mod p5_digital_cash;
 mod p6_open_ended;
+mod payment_channel;
+mod reversible;
+mod staking;
+
+// Re-export the accounted currency so it can be used as a realistic state machine in the Client chapter.
+pub use p4_accounted_currency::{AccountedCurrency, AccountingTransaction};
+// Re-export the deterministic random number generator so that simulations elsewhere are reproducible.
+pub use invariants::Rng;
+// Re-export the staking system so that the consensus chapter can elect authorities with it.
+pub use staking::{Staking, StakingState, StakingTransaction};
+// Re-export governance so that consensus parameters can be upgraded on chain.
+pub use governance::{
+    Governance, GovernanceState, GovernanceTransaction, ParameterChange, ScheduledChange,
+};
+
+use crate::codec::{decode_tag, Decode, Encode, Error};
+use crate::json::{self, FromJson, Json};
 
 /// A state machine - Generic over the transition type
 pub trait StateMachine {
@@ -19,6 +42,20 @@ pub trait StateMachine {
     /// Calculate the resulting state when this state undergoes the given transition
     fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State;
 
+    /// Calculate the state at the start of a new block, before any of its transitions are applied.
+    ///
+    /// Some machines depend on the passage of time: deadlines expire, eras end, and scheduled
+    /// changes come into effect. They cannot learn the time from their transitions, because any
+    /// user can submit a transition with whatever contents they like. Instead, the client calls
+    /// this for every block it imports or authors, with the height of that block, which consensus
+    /// has already checked. By default the state is unchanged.
+    fn begin_block(starting_state: &Self::State, _height: u64) -> Self::State
+    where
+        Self::State: Clone,
+    {
+        starting_state.clone()
+    }
+
     /// A human-readable name for this state machine. This may be used in user-facing
     /// programs such as the repl described below. This is not in any way related to
     /// the correctness of the state machine.
@@ -28,12 +65,40 @@ pub trait StateMachine {
 }
 
 /// A set of play users for experimenting with the multi-user state machines
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
 pub enum User {
     Alice,
     Bob,
     Charlie,
 }
 
+impl Encode for User {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        dest.push(*self as u8);
+    }
+}
+
+impl Decode for User {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(User::Alice),
+            1 => Ok(User::Bob),
+            2 => Ok(User::Charlie),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+impl FromJson for User {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        match j.as_str()? {
+            "Alice" => Ok(User::Alice),
+            "Bob" => Ok(User::Bob),
+            "Charlie" => Ok(User::Charlie),
+            other => Err(json::Error::UnknownVariant(other.into())),
+        }
+    }
+}
+
 //TODO Some kind of main program that allows users to interact with their state machine in a repl-like way.
 // Might require From<String> implementation for the transition type.
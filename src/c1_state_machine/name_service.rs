@@ -0,0 +1,755 @@
+//! One of the ideas listed in the open ended module is a land ownership registry. Here we build
+//! its most common on-chain cousin, a name service. Users rent human-readable names for a fee,
+//! and point them at a resolver record such as an address or a content hash.
+//!
+//! Names are rented for a number of blocks rather than owned forever, so that abandoned names are
+//! eventually released for someone else to use. Expiry, claims, and auctions all move forward as
+//! each block begins.
+//!
+//! Registering a free name does not grant it immediately. The claim matures after a short period,
+//! and if anybody else tries to register the same name in the meantime the name is contested.
+//! Contested names are sold in a sealed-bid auction. Bidders first commit to a hash of their bid,
+//! backed by a deposit that hides the true amount, and only reveal the bid once bidding is over.
+//! The highest bidder wins and pays the second highest bid, so nobody gains by bidding anything
+//! other than what the name is really worth to them.
+
+use super::invariants::{ArbitraryTransition, BlockInvariant, Invariant, Invariants, Rng};
+use super::{StateMachine, User};
+use std::collections::HashMap;
+
+/// The rent for a name, per block.
+pub const FEE_PER_BLOCK: u64 = 1;
+/// How many blocks a claim must go uncontested before the name is registered.
+pub const CLAIM_PERIOD: u64 = 3;
+/// How many blocks an auction accepts sealed bids for.
+pub const BIDDING_PERIOD: u64 = 5;
+/// How many blocks bidders have to reveal their bids after bidding ends.
+pub const REVEAL_PERIOD: u64 = 5;
+/// How many blocks an auctioned name is registered for. The winning bid must at least cover
+/// the rent for this term.
+pub const AUCTION_TERM: u64 = 100;
+
+/// This state machine models a name service with expiring registrations and auctions.
+pub struct NameService;
+
+/// A registered name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Registration {
+    pub owner: User,
+    /// The first height at which the name is no longer registered.
+    pub expires: u64,
+    /// The record that the name resolves to, if any.
+    pub resolver: Option<String>,
+}
+
+/// An uncontested claim on a free name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Claim {
+    pub claimant: User,
+    /// How many blocks the name is registered for once the claim matures.
+    pub blocks: u64,
+    /// The rent, held in escrow until the claim matures or is contested.
+    pub fee: u64,
+    /// The height at which the claim matures.
+    pub matures: u64,
+}
+
+/// A sealed bid in an auction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SealedBid {
+    /// The hash of the name, bid, and salt. See `commitment`.
+    pub commitment: u64,
+    /// Tokens held in escrow to back the bid. Must be at least the bid.
+    pub deposit: u64,
+    /// The bid, once it has been revealed.
+    pub revealed: Option<u64>,
+}
+
+/// An auction for a contested name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Auction {
+    /// The first height at which bids are no longer accepted and may be revealed.
+    pub bidding_ends: u64,
+    /// The height at which the auction is settled. Bids not revealed by then forfeit their deposit.
+    pub reveal_ends: u64,
+    pub bids: HashMap<User, SealedBid>,
+}
+
+/// The state of the name service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameState {
+    /// The height of the current block.
+    height: u64,
+    /// Free tokens of each user. Users with no tokens are removed.
+    balances: HashMap<User, u64>,
+    /// Rent and forfeited deposits.
+    treasury: u64,
+    names: HashMap<String, Registration>,
+    claims: HashMap<String, Claim>,
+    auctions: HashMap<String, Auction>,
+}
+
+impl NameState {
+    /// Start at genesis with the given balances.
+    pub fn new(balances: HashMap<User, u64>) -> Self {
+        NameState {
+            height: 0,
+            balances: balances.into_iter().filter(|(_, b)| *b > 0).collect(),
+            treasury: 0,
+            names: HashMap::new(),
+            claims: HashMap::new(),
+            auctions: HashMap::new(),
+        }
+    }
+
+    pub fn balance(&self, who: User) -> u64 {
+        self.balances.get(&who).copied().unwrap_or(0)
+    }
+
+    pub fn treasury(&self) -> u64 {
+        self.treasury
+    }
+
+    pub fn registration(&self, name: &str) -> Option<&Registration> {
+        self.names.get(name)
+    }
+
+    pub fn claim(&self, name: &str) -> Option<&Claim> {
+        self.claims.get(name)
+    }
+
+    pub fn auction(&self, name: &str) -> Option<&Auction> {
+        self.auctions.get(name)
+    }
+
+    /// The record the name resolves to, if it is registered and has one.
+    pub fn resolve(&self, name: &str) -> Option<&str> {
+        self.names.get(name)?.resolver.as_deref()
+    }
+
+    /// Every token in the system, whether free, paid as rent, or held in escrow.
+    fn total_tokens(&self) -> u128 {
+        let free: u128 = self.balances.values().map(|b| *b as u128).sum();
+        let claimed: u128 = self.claims.values().map(|c| c.fee as u128).sum();
+        let deposited: u128 = self
+            .auctions
+            .values()
+            .flat_map(|a| a.bids.values())
+            .map(|b| b.deposit as u128)
+            .sum();
+        free + self.treasury as u128 + claimed + deposited
+    }
+
+    fn is_owner(&self, who: User, name: &str) -> bool {
+        self.names.get(name).is_some_and(|r| r.owner == who)
+    }
+
+    fn withdraw(&mut self, who: User, amount: u64) -> Option<()> {
+        let remaining = self.balance(who).checked_sub(amount)?;
+        if remaining == 0 {
+            self.balances.remove(&who);
+        } else {
+            self.balances.insert(who, remaining);
+        }
+        Some(())
+    }
+
+    /// Return escrowed tokens to a user. Cannot overflow, because users cannot send tokens to
+    /// each other, so nobody ever holds more than they started with.
+    fn refund(&mut self, who: User, amount: u64) {
+        if amount > 0 {
+            *self.balances.entry(who).or_insert(0) += amount;
+        }
+    }
+
+    /// Pay tokens into the treasury. Once the treasury is full any further payments are burned,
+    /// so neither starting a block nor paying a fee can fail because of it.
+    fn pay_treasury(&mut self, amount: u64) {
+        self.treasury = self.treasury.saturating_add(amount);
+    }
+
+    /// Release expired names, register matured claims, and settle finished auctions.
+    fn on_new_block(&mut self) {
+        let height = self.height;
+        self.names.retain(|_, r| r.expires > height);
+
+        let (matured, pending) = std::mem::take(&mut self.claims)
+            .into_iter()
+            .partition(|(_, c)| c.matures <= height);
+        self.claims = pending;
+        for (name, claim) in matured {
+            self.pay_treasury(claim.fee);
+            self.names.insert(
+                name,
+                Registration {
+                    owner: claim.claimant,
+                    expires: height.saturating_add(claim.blocks),
+                    resolver: None,
+                },
+            );
+        }
+
+        let (finished, open) = std::mem::take(&mut self.auctions)
+            .into_iter()
+            .partition(|(_, a)| a.reveal_ends <= height);
+        self.auctions = open;
+        for (name, auction) in finished {
+            self.settle(name, auction);
+        }
+    }
+
+    /// Award the name to the highest revealed bidder at the second highest price, refund
+    /// everybody else, and keep the deposits of bidders who never revealed.
+    fn settle(&mut self, name: String, auction: Auction) {
+        let mut revealed: Vec<(u64, User)> = auction
+            .bids
+            .iter()
+            .filter_map(|(who, bid)| bid.revealed.map(|amount| (amount, *who)))
+            .collect();
+        // Highest bid first, with ties going to the lower user.
+        revealed.sort_by(|(a, x), (b, y)| b.cmp(a).then(x.cmp(y)));
+        let winner = revealed.first().map(|(_, who)| *who);
+        let price = revealed
+            .get(1)
+            .map_or(AUCTION_TERM * FEE_PER_BLOCK, |(amount, _)| *amount);
+
+        for (who, bid) in auction.bids {
+            if Some(who) == winner {
+                self.pay_treasury(price);
+                self.refund(who, bid.deposit - price);
+            } else if bid.revealed.is_some() {
+                self.refund(who, bid.deposit);
+            } else {
+                self.pay_treasury(bid.deposit);
+            }
+        }
+        if let Some(owner) = winner {
+            self.names.insert(
+                name,
+                Registration {
+                    owner,
+                    expires: self.height.saturating_add(AUCTION_TERM),
+                    resolver: None,
+                },
+            );
+        }
+    }
+}
+
+/// The commitment that hides a bid until it is revealed. The salt stops other bidders from
+/// simply trying every likely bid amount.
+pub fn commitment(name: &str, bid: u64, salt: u64) -> u64 {
+    crate::hash(&(name, bid, salt))
+}
+
+/// The transitions that users can make in the name service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NameTransaction {
+    /// Claim a free name for the given number of blocks, paying the rent into escrow. If somebody
+    /// else already has a pending claim on the name, it goes to auction instead and both
+    /// claimants are refunded.
+    Register {
+        who: User,
+        name: String,
+        blocks: u64,
+    },
+    /// Extend the owner's registration by the given number of blocks.
+    Renew {
+        who: User,
+        name: String,
+        blocks: u64,
+    },
+    /// Give a name to another user.
+    Transfer { who: User, name: String, to: User },
+    /// Set or clear the record that a name resolves to.
+    SetResolver {
+        who: User,
+        name: String,
+        record: Option<String>,
+    },
+    /// Place a sealed bid while an auction is accepting bids. Each user bids at most once.
+    Bid {
+        who: User,
+        name: String,
+        commitment: u64,
+        deposit: u64,
+    },
+    /// Reveal a bid after bidding has ended. The bid must match the commitment, be covered by
+    /// the deposit, and at least pay the rent for the auction term.
+    Reveal {
+        who: User,
+        name: String,
+        bid: u64,
+        salt: u64,
+    },
+}
+
+impl StateMachine for NameService {
+    type State = NameState;
+    type Transition = NameTransaction;
+
+    fn next_state(starting_state: &NameState, t: &NameTransaction) -> NameState {
+        let mut state = starting_state.clone();
+        match apply(&mut state, t) {
+            Some(()) => state,
+            None => starting_state.clone(),
+        }
+    }
+
+    /// Heights that do not increase are ignored.
+    fn begin_block(starting_state: &NameState, height: u64) -> NameState {
+        let mut state = starting_state.clone();
+        if height > state.height {
+            state.height = height;
+            state.on_new_block();
+        }
+        state
+    }
+}
+
+/// Apply a transaction to the state, or return None if it is invalid. The state may be
+/// partially modified when this returns None, so it must be discarded.
+fn apply(state: &mut NameState, t: &NameTransaction) -> Option<()> {
+    match t {
+        NameTransaction::Register { who, name, blocks } => {
+            if *blocks == 0 || state.names.contains_key(name) || state.auctions.contains_key(name) {
+                return None;
+            }
+            match state.claims.remove(name) {
+                Some(claim) if claim.claimant != *who => {
+                    state.refund(claim.claimant, claim.fee);
+                    let bidding_ends = state.height.saturating_add(BIDDING_PERIOD);
+                    state.auctions.insert(
+                        name.clone(),
+                        Auction {
+                            bidding_ends,
+                            reveal_ends: bidding_ends.saturating_add(REVEAL_PERIOD),
+                            bids: HashMap::new(),
+                        },
+                    );
+                }
+                Some(_) => return None,
+                None => {
+                    let fee = blocks.checked_mul(FEE_PER_BLOCK)?;
+                    state.withdraw(*who, fee)?;
+                    state.claims.insert(
+                        name.clone(),
+                        Claim {
+                            claimant: *who,
+                            blocks: *blocks,
+                            fee,
+                            matures: state.height.saturating_add(CLAIM_PERIOD),
+                        },
+                    );
+                }
+            }
+        }
+        NameTransaction::Renew { who, name, blocks } => {
+            if *blocks == 0 || !state.is_owner(*who, name) {
+                return None;
+            }
+            let fee = blocks.checked_mul(FEE_PER_BLOCK)?;
+            let registration = state.names.get_mut(name)?;
+            registration.expires = registration.expires.checked_add(*blocks)?;
+            state.withdraw(*who, fee)?;
+            state.pay_treasury(fee);
+        }
+        NameTransaction::Transfer { who, name, to } => {
+            if !state.is_owner(*who, name) {
+                return None;
+            }
+            let registration = state.names.get_mut(name)?;
+            registration.owner = *to;
+            registration.resolver = None;
+        }
+        NameTransaction::SetResolver { who, name, record } => {
+            if !state.is_owner(*who, name) {
+                return None;
+            }
+            state.names.get_mut(name)?.resolver = record.clone();
+        }
+        NameTransaction::Bid {
+            who,
+            name,
+            commitment,
+            deposit,
+        } => {
+            let height = state.height;
+            let auction = state.auctions.get(name)?;
+            if height >= auction.bidding_ends || auction.bids.contains_key(who) {
+                return None;
+            }
+            state.withdraw(*who, *deposit)?;
+            state.auctions.get_mut(name)?.bids.insert(
+                *who,
+                SealedBid {
+                    commitment: *commitment,
+                    deposit: *deposit,
+                    revealed: None,
+                },
+            );
+        }
+        NameTransaction::Reveal {
+            who,
+            name,
+            bid,
+            salt,
+        } => {
+            let height = state.height;
+            let auction = state.auctions.get_mut(name)?;
+            if height < auction.bidding_ends || *bid < AUCTION_TERM * FEE_PER_BLOCK {
+                return None;
+            }
+            let sealed = auction.bids.get_mut(who)?;
+            if sealed.revealed.is_some()
+                || sealed.commitment != commitment(name, *bid, *salt)
+                || sealed.deposit < *bid
+            {
+                return None;
+            }
+            sealed.revealed = Some(*bid);
+        }
+    }
+    Some(())
+}
+
+impl Invariants for NameService {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "tokens are only destroyed by a full treasury, and never created",
+                holds: |pre, _, post| {
+                    pre.total_tokens() == post.total_tokens()
+                        || (post.total_tokens() < pre.total_tokens() && post.treasury == u64::MAX)
+                },
+            },
+            Invariant {
+                name: "registered names have not expired",
+                holds: |_, _, post| post.names.values().all(|r| r.expires > post.height),
+            },
+            Invariant {
+                name: "each name is either registered, claimed, auctioned, or free",
+                holds: |_, _, post| {
+                    let in_use = |name: &String| {
+                        [
+                            post.names.contains_key(name),
+                            post.claims.contains_key(name),
+                            post.auctions.contains_key(name),
+                        ]
+                        .iter()
+                        .filter(|used| **used)
+                        .count()
+                    };
+                    post.claims
+                        .keys()
+                        .chain(post.auctions.keys())
+                        .all(|name| in_use(name) == 1)
+                },
+            },
+            Invariant {
+                name: "only the owner changes a name",
+                holds: |pre, t, post| match t {
+                    NameTransaction::Renew { who, name, .. }
+                    | NameTransaction::Transfer { who, name, .. }
+                    | NameTransaction::SetResolver { who, name, .. } => {
+                        pre.is_owner(*who, name) || pre.names.get(name) == post.names.get(name)
+                    }
+                    _ => true,
+                },
+            },
+            Invariant {
+                name: "users with no tokens are removed",
+                holds: |_, _, post| post.balances.values().all(|b| *b > 0),
+            },
+            Invariant {
+                name: "transactions never change the height",
+                holds: |pre, _, post| pre.height == post.height,
+            },
+        ]
+    }
+
+    fn block_invariants() -> Vec<BlockInvariant<Self>> {
+        vec![
+            BlockInvariant {
+                name: "tokens are only destroyed by a full treasury, and never created",
+                holds: |pre, _, post| {
+                    pre.total_tokens() == post.total_tokens()
+                        || (post.total_tokens() < pre.total_tokens() && post.treasury == u64::MAX)
+                },
+            },
+            BlockInvariant {
+                name: "the height follows the block height",
+                holds: |pre, height, post| post.height == pre.height.max(height),
+            },
+            BlockInvariant {
+                name: "registered names have not expired",
+                holds: |_, _, post| post.names.values().all(|r| r.expires > post.height),
+            },
+            BlockInvariant {
+                name: "claims and auctions are settled once they end",
+                holds: |_, _, post| {
+                    post.claims.values().all(|c| c.matures > post.height)
+                        && post.auctions.values().all(|a| a.reveal_ends > post.height)
+                },
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for NameService {
+    fn arbitrary_transition(rng: &mut Rng, _: &NameState) -> NameTransaction {
+        let users = [User::Alice, User::Bob, User::Charlie];
+        let who = *rng.pick(&users);
+        let name = rng.pick(&["alice.dot", "bob.dot"]).to_string();
+        // Few enough bids and salts that reveals sometimes match their commitments.
+        let bid = *rng.pick(&[0, AUCTION_TERM, 150, 400]);
+        let salt = rng.below(2);
+        match rng.below(6) {
+            0 => NameTransaction::Register {
+                who,
+                name,
+                blocks: rng.below(50),
+            },
+            1 => NameTransaction::Renew {
+                who,
+                name,
+                blocks: rng.below(50),
+            },
+            2 => NameTransaction::Transfer {
+                who,
+                name,
+                to: *rng.pick(&users),
+            },
+            3 => NameTransaction::SetResolver {
+                who,
+                name,
+                record: rng.chance(1, 2).then(|| "QmHash".into()),
+            },
+            4 => NameTransaction::Bid {
+                commitment: commitment(&name, bid, salt),
+                who,
+                name,
+                deposit: bid + rng.below(2) * 100,
+            },
+            _ => NameTransaction::Reveal {
+                who,
+                name,
+                bid,
+                salt,
+            },
+        }
+    }
+
+    fn new_block_chance() -> (u64, u64) {
+        (1, 4)
+    }
+}
+
+#[cfg(test)]
+fn run(state: NameState, transitions: &[NameTransaction]) -> NameState {
+    transitions
+        .iter()
+        .fold(state, |state, t| NameService::next_state(&state, t))
+}
+
+#[cfg(test)]
+fn funded() -> NameState {
+    NameState::new(HashMap::from([
+        (User::Alice, 1_000),
+        (User::Bob, 1_000),
+        (User::Charlie, 1_000),
+    ]))
+}
+
+#[test]
+fn sm_names_register_renew_and_expire() {
+    use NameTransaction::*;
+
+    let name = || "alice.dot".to_string();
+    let state = run(
+        funded(),
+        &[Register {
+            who: User::Alice,
+            name: name(),
+            blocks: 10,
+        }],
+    );
+    let state = run(
+        NameService::begin_block(&state, 3),
+        &[
+            SetResolver {
+                who: User::Alice,
+                name: name(),
+                record: Some("5GrwvaEF".into()),
+            },
+            // Only the owner may touch the name.
+            SetResolver {
+                who: User::Bob,
+                name: name(),
+                record: None,
+            },
+        ],
+    );
+    assert_eq!(state.registration(&name()).unwrap().expires, 13);
+    assert_eq!(state.resolve(&name()), Some("5GrwvaEF"));
+    assert_eq!(state.balance(User::Alice), 990);
+
+    let renewed = run(
+        state,
+        &[Renew {
+            who: User::Alice,
+            name: name(),
+            blocks: 5,
+        }],
+    );
+    let renewed = NameService::begin_block(&renewed, 17);
+    assert_eq!(renewed.registration(&name()).unwrap().owner, User::Alice);
+
+    let expired = NameService::begin_block(&renewed, 18);
+    assert!(expired.registration(&name()).is_none());
+    assert_eq!(expired.treasury(), 15);
+}
+
+#[test]
+fn sm_names_transfer_clears_resolver() {
+    use NameTransaction::*;
+
+    let name = || "bob.dot".to_string();
+    let state = run(
+        funded(),
+        &[Register {
+            who: User::Bob,
+            name: name(),
+            blocks: 10,
+        }],
+    );
+    let state = run(
+        NameService::begin_block(&state, 3),
+        &[
+            SetResolver {
+                who: User::Bob,
+                name: name(),
+                record: Some("bob".into()),
+            },
+            Transfer {
+                who: User::Bob,
+                name: name(),
+                to: User::Charlie,
+            },
+        ],
+    );
+    let registration = state.registration(&name()).unwrap();
+    assert_eq!(registration.owner, User::Charlie);
+    assert_eq!(registration.resolver, None);
+}
+
+#[test]
+fn sm_names_contested_name_goes_to_auction() {
+    use NameTransaction::*;
+
+    let name = || "alice.dot".to_string();
+    let bid = |who, bid, deposit| Bid {
+        who,
+        name: name(),
+        commitment: commitment("alice.dot", bid, 7),
+        deposit,
+    };
+    let reveal = |who, bid| Reveal {
+        who,
+        name: name(),
+        bid,
+        salt: 7,
+    };
+    let state = run(
+        funded(),
+        &[
+            Register {
+                who: User::Alice,
+                name: name(),
+                blocks: 10,
+            },
+            Register {
+                who: User::Bob,
+                name: name(),
+                blocks: 10,
+            },
+        ],
+    );
+    assert!(state.claim(&name()).is_none());
+    assert_eq!(state.auction(&name()).unwrap().reveal_ends, 10);
+    assert_eq!(state.balance(User::Alice), 1_000);
+
+    let state = run(
+        state,
+        &[
+            bid(User::Alice, 300, 500),
+            bid(User::Bob, 200, 500),
+            bid(User::Charlie, 400, 400),
+            // Reveals are only accepted once bidding is over.
+            reveal(User::Alice, 300),
+        ],
+    );
+    let state = run(
+        NameService::begin_block(&state, 5),
+        &[
+            reveal(User::Alice, 300),
+            reveal(User::Bob, 200),
+            // Wrong bids do not match the commitment.
+            reveal(User::Charlie, 401),
+        ],
+    );
+    let state = NameService::begin_block(&state, 10);
+    let registration = state.registration(&name()).unwrap();
+    assert_eq!(registration.owner, User::Alice);
+    assert_eq!(registration.expires, 10 + AUCTION_TERM);
+    // Alice pays Bob's bid, and Charlie forfeits his deposit by not revealing.
+    assert_eq!(state.balance(User::Alice), 800);
+    assert_eq!(state.balance(User::Bob), 1_000);
+    assert_eq!(state.balance(User::Charlie), 600);
+    assert_eq!(state.treasury(), 600);
+}
+
+#[test]
+fn sm_names_full_treasury_does_not_overflow() {
+    use NameTransaction::*;
+
+    let register = |who, name: &str, blocks| Register {
+        who,
+        name: name.into(),
+        blocks,
+    };
+    let state = NameState::new(HashMap::from([(User::Alice, u64::MAX), (User::Bob, 10)]));
+    let state = run(
+        state,
+        &[
+            register(User::Alice, "alice.dot", u64::MAX),
+            register(User::Bob, "bob.dot", 5),
+        ],
+    );
+    let state = NameService::begin_block(&state, CLAIM_PERIOD);
+    assert_eq!(state.treasury(), u64::MAX);
+    assert_eq!(state.registration("bob.dot").unwrap().owner, User::Bob);
+
+    // Renewals still work, but the fee is burned because the treasury cannot hold it.
+    let renewed = NameService::next_state(
+        &state,
+        &Renew {
+            who: User::Bob,
+            name: "bob.dot".into(),
+            blocks: 1,
+        },
+    );
+    assert_eq!(renewed.treasury(), u64::MAX);
+    assert_eq!(renewed.balance(User::Bob), 4);
+    assert_eq!(
+        renewed.registration("bob.dot").unwrap().expires,
+        state.registration("bob.dot").unwrap().expires + 1
+    );
+}
+
+#[test]
+fn sm_names_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    fuzz::<NameService>(&funded(), &FuzzConfig::default()).unwrap();
+}
@@ -2,6 +2,9 @@
 //! In these examples, we use actually switch boards as the state machine. The state is,
 //! well, just the state of the switches.
 
+use super::exploration::FiniteStateMachine;
+use super::invariants::{ArbitraryTransition, Invariant, Invariants, Rng};
+use super::reversible::Reversible;
 use super::StateMachine;
 
 /// This state machine models a single light switch.
@@ -24,13 +27,14 @@ impl StateMachine for LightSwitch {
 pub struct WeirdSwitchMachine;
 
 /// The state is now two switches instead of one so we use a struct.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Default)]
 pub struct TwoSwitches {
     first_switch: bool,
     second_switch: bool,
 }
 
 /// Now there are two switches so we need a proper type for the transition.
+#[derive(Debug, Clone)]
 pub enum Toggle {
     FirstSwitch,
     SecondSwitch,
@@ -65,6 +69,77 @@ impl StateMachine for WeirdSwitchMachine {
     }
 }
 
+/// Toggling is its own inverse, so there is nothing to record.
+impl Reversible for LightSwitch {
+    type Diff = ();
+
+    fn next_state_with_diff(starting_state: &bool, t: &()) -> (bool, ()) {
+        (Self::next_state(starting_state, t), ())
+    }
+
+    fn revert(state: &bool, _: &()) -> bool {
+        !*state
+    }
+}
+
+impl Invariants for LightSwitch {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "every toggle flips the switch",
+                holds: |pre, _, post| pre != post,
+            },
+            Invariant {
+                name: "reverting restores the starting state",
+                holds: |pre, t, post| {
+                    let (next, diff) = Self::next_state_with_diff(pre, t);
+                    next == *post && Self::revert(post, &diff) == *pre
+                },
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for LightSwitch {
+    fn arbitrary_transition(_: &mut Rng, _: &bool) {}
+}
+
+impl Invariants for WeirdSwitchMachine {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "second switch is off whenever the first switch goes off",
+                holds: |pre, _, post| !pre.first_switch || post.first_switch || !post.second_switch,
+            },
+            Invariant {
+                name: "toggling the second switch leaves the first alone",
+                holds: |pre, t, post| {
+                    matches!(t, Toggle::FirstSwitch) || pre.first_switch == post.first_switch
+                },
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for WeirdSwitchMachine {
+    fn arbitrary_transition(rng: &mut Rng, _: &TwoSwitches) -> Toggle {
+        rng.pick(&[Toggle::FirstSwitch, Toggle::SecondSwitch])
+            .clone()
+    }
+}
+
+impl FiniteStateMachine for LightSwitch {
+    fn all_transitions() -> Vec<()> {
+        vec![()]
+    }
+}
+
+impl FiniteStateMachine for WeirdSwitchMachine {
+    fn all_transitions() -> Vec<Toggle> {
+        vec![Toggle::FirstSwitch, Toggle::SecondSwitch]
+    }
+}
+
 #[test]
 fn sm_1_light_switch_toggles_off() {
     assert!(!LightSwitch::next_state(&true, &()));
@@ -156,3 +231,21 @@ fn sm_1_two_switches_second_goes_off() {
         }
     );
 }
+
+#[test]
+fn sm_1_light_switch_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    fuzz::<LightSwitch>(&false, &FuzzConfig::default()).unwrap();
+}
+
+#[test]
+fn sm_1_two_switches_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    let start = TwoSwitches {
+        first_switch: false,
+        second_switch: false,
+    };
+    fuzz::<WeirdSwitchMachine>(&start, &FuzzConfig::default()).unwrap();
+}
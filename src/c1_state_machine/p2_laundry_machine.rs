@@ -2,6 +2,8 @@
 //! ready to be worn again. Or course washing and wearing clothes takes its toll on the clothes, and
 //! eventually they get tattered.
 
+use super::exploration::FiniteStateMachine;
+use super::invariants::{ArbitraryTransition, Invariant, Invariants, Rng};
 use super::StateMachine;
 
 /// This state machine models the typical life cycle of clothes as they make their way through the laundry
@@ -9,7 +11,7 @@ use super::StateMachine;
 pub struct ClothesMachine;
 
 /// Models a piece of clothing throughout its lifecycle.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub enum ClothesState {
     /// Clean clothes ready to be worn. With some given life left.
     Clean(u64),
@@ -24,6 +26,7 @@ pub enum ClothesState {
 }
 
 /// Something you can do with clothes
+#[derive(Debug, Clone)]
 pub enum ClothesAction {
     /// Wearing clothes decreases their life by 1 and makes them dirty.
     Wear,
@@ -63,6 +66,50 @@ impl StateMachine for ClothesMachine {
     }
 }
 
+impl ClothesState {
+    /// How much life the clothes have left. Tattered clothes have none.
+    fn life(&self) -> u64 {
+        match self {
+            ClothesState::Clean(x) | ClothesState::Dirty(x) | ClothesState::Wet(x) => *x,
+            ClothesState::Tattered => 0,
+        }
+    }
+}
+
+impl Invariants for ClothesMachine {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "every action wears the clothes down",
+                holds: |pre, _, post| *pre == ClothesState::Tattered || post.life() < pre.life(),
+            },
+            Invariant {
+                name: "tattered clothes stay tattered",
+                holds: |pre, _, post| {
+                    *pre != ClothesState::Tattered || *post == ClothesState::Tattered
+                },
+            },
+            Invariant {
+                name: "clothes with no life left are tattered",
+                holds: |_, _, post| post.life() > 0 || *post == ClothesState::Tattered,
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for ClothesMachine {
+    fn arbitrary_transition(rng: &mut Rng, _: &ClothesState) -> ClothesAction {
+        rng.pick(&[ClothesAction::Wear, ClothesAction::Wash, ClothesAction::Dry])
+            .clone()
+    }
+}
+
+impl FiniteStateMachine for ClothesMachine {
+    fn all_transitions() -> Vec<ClothesAction> {
+        vec![ClothesAction::Wear, ClothesAction::Wash, ClothesAction::Dry]
+    }
+}
+
 #[test]
 fn sm_2_wear_clean_clothes() {
     let start = ClothesState::Clean(4);
@@ -230,3 +277,10 @@ fn sm_2_dry_dirty_until_tattered() {
     let expected = ClothesState::Tattered;
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_2_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    fuzz::<ClothesMachine>(&ClothesState::Clean(20), &FuzzConfig::default()).unwrap();
+}
@@ -1,10 +1,22 @@
 //! The automated teller machine gives you cash after you swipe your card and enter your pin.
 //! The atm may fail to give you cash if it is empty or you haven't swiped your card, or you have
 //! entered the wrong pin.
+//!
+//! Each card belongs to an account held at the bank. Withdrawals are bounded by both the cash
+//! in the machine and the account balance, and cash can be deposited back into the account.
+//! Entering the wrong pin too many times in a row locks the card.
 
+use super::invariants::{ArbitraryTransition, Invariant, Invariants, Rng};
 use super::StateMachine;
+use std::collections::HashMap;
 use std::fmt;
 
+/// Identifies a bank card, and therefore the account it is linked to.
+pub type CardId = u64;
+
+/// How many wrong pins may be entered in a row before the card is locked.
+pub const MAX_PIN_ATTEMPTS: u8 = 3;
+
 /// The keys on the ATM keypad
 #[derive(Hash, Debug, PartialEq, Eq, Clone)]
 pub enum Key {
@@ -13,6 +25,8 @@ pub enum Key {
     Three,
     Four,
     Enter,
+    /// Abandons the current session and returns the card.
+    Cancel,
 }
 
 impl fmt::Display for Key {
@@ -22,18 +36,49 @@ impl fmt::Display for Key {
             Self::Two => write!(f, "2"),
             Self::Three => write!(f, "3"),
             Self::Four => write!(f, "4"),
-            Self::Enter => write!(f, ""), // panic?
+            Self::Enter | Self::Cancel => write!(f, ""),
         }
     }
 }
 
 /// Something you can do to the ATM
+#[derive(Debug, Clone)]
 pub enum Action {
-    /// Swipe your card at the ATM. The attached value is the hash of the pin
-    /// that should be keyed in on the keypad next.
-    SwipeCard(u64),
+    /// Swipe your card at the ATM. The ATM looks up the linked account and
+    /// waits for the account's pin to be keyed in on the keypad next.
+    SwipeCard(CardId),
     /// Press a key on the keypad
     PressKey(Key),
+    /// Feed cash into the deposit slot. The cash is credited to the account of the
+    /// authenticated card. Outside of an authenticated session the cash is returned.
+    DepositCash(u64),
+}
+
+/// A bank account linked to a single card.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Account {
+    /// The hash of the pin that unlocks this account.
+    pin_hash: u64,
+    /// How much money the account holds.
+    balance: u64,
+    /// How many wrong pins have been entered since the last correct one.
+    failed_attempts: u8,
+}
+
+impl Account {
+    /// Open a new account with the given pin hash and starting balance.
+    pub fn new(pin_hash: u64, balance: u64) -> Self {
+        Account {
+            pin_hash,
+            balance,
+            failed_attempts: 0,
+        }
+    }
+
+    /// Whether too many wrong pins have been entered for this card to be used.
+    pub fn is_locked(&self) -> bool {
+        self.failed_attempts >= MAX_PIN_ATTEMPTS
+    }
 }
 
 /// The various states of authentication possible with the ATM
@@ -41,30 +86,66 @@ pub enum Action {
 enum Auth {
     /// No session has begun yet. Waiting for the user to swipe their card
     Waiting,
-    /// The user has swiped their card, providing the enclosed PIN hash.
+    /// The user has swiped the enclosed card.
     /// Waiting for the user to key in their pin
-    Authenticating(u64),
-    /// The user has authenticated. Waiting for them to key in the amount
-    /// of cash to withdraw
-    Authenticated,
-}
-
-/// The ATM. When a card is swiped, the ATM learns the correct pin's hash.
-/// It waits for you to key in your pin. You can press as many numeric keys as
-/// you like followed by enter. If the pin is incorrect, your card is returned
-/// and the ATM automatically goes back to the main menu. If your pin is correct,
-/// the ATM waits for you to key in an amount of money to withdraw. Withdraws
-/// are bounded only by the cash in the machine (there is no account balance).
+    Authenticating(CardId),
+    /// The user has authenticated with the enclosed card. Waiting for them to
+    /// key in the amount of cash to withdraw, or to deposit cash.
+    Authenticated(CardId),
+}
+
+/// The ATM. When a known, unlocked card is swiped, the ATM waits for you to key
+/// in your pin. You can press as many numeric keys as you like followed by enter.
+/// If the pin is incorrect, your card is returned, the failed attempt is counted
+/// against the card, and the ATM goes back to the main menu. If your pin is correct,
+/// the ATM waits for you to key in an amount of money to withdraw. Withdrawals are
+/// bounded by both the cash in the machine and the account balance. At any point
+/// in a session, `Cancel` returns your card.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Atm {
     /// How much money is in the ATM
     cash_inside: u64,
+    /// The accounts of every card this ATM knows about
+    accounts: HashMap<CardId, Account>,
     /// The machine's authentication status.
-    expected_pin_hash: Auth,
+    auth: Auth,
     /// All the keys that have been pressed since the last `Enter`
     keystroke_register: Vec<Key>,
 }
 
+impl Atm {
+    /// Create an idle ATM holding the given cash and serving the given accounts.
+    pub fn new(cash_inside: u64, accounts: HashMap<CardId, Account>) -> Self {
+        Atm {
+            cash_inside,
+            accounts,
+            auth: Auth::Waiting,
+            keystroke_register: Vec::new(),
+        }
+    }
+
+    /// The balance of the account linked to the given card, if the card is known.
+    pub fn balance(&self, card: CardId) -> Option<u64> {
+        self.accounts.get(&card).map(|account| account.balance)
+    }
+
+    /// Interpret the keystroke register as an amount of money.
+    fn keyed_amount(&self) -> u64 {
+        self.keystroke_register
+            .iter()
+            .map(|key| key.to_string())
+            .collect::<String>()
+            .parse::<u64>()
+            .unwrap_or(0)
+    }
+
+    /// End the current session and return the card.
+    fn end_session(&mut self) {
+        self.auth = Auth::Waiting;
+        self.keystroke_register.clear();
+    }
+}
+
 impl StateMachine for Atm {
     // Notice that we are using the same type for the state as we are using for the machine this time.
     type State = Self;
@@ -74,287 +155,342 @@ impl StateMachine for Atm {
         use Action::*;
         use Auth::*;
 
-        let Atm {
-            cash_inside,
-            expected_pin_hash,
-            keystroke_register,
-        } = starting_state.clone();
-
-        match (&expected_pin_hash, t) {
-            (Waiting | Authenticating(_), SwipeCard(pin)) => Self {
-                cash_inside,
-                expected_pin_hash: Authenticating(*pin),
-                keystroke_register: keystroke_register.clone(),
-            },
-            (Waiting, PressKey(_)) => starting_state.clone(),
-            (Authenticating(pin), PressKey(key)) => {
-                if key == &Key::Enter {
-                    let in_pin = crate::hash(&keystroke_register);
-                    let keystroke_register = vec![];
-                    if in_pin == *pin {
-                        Self {
-                            cash_inside,
-                            expected_pin_hash: Authenticated,
-                            keystroke_register,
-                        }
+        let mut atm = starting_state.clone();
+
+        match (&starting_state.auth, t) {
+            // A card swiped mid-transaction is ignored until the current session ends.
+            (Authenticated(_), SwipeCard(_)) => {}
+            // Swiping a card starts a fresh session, as long as the card is usable.
+            (Waiting | Authenticating(_), SwipeCard(card)) => {
+                atm.end_session();
+                if atm.accounts.get(card).is_some_and(|a| !a.is_locked()) {
+                    atm.auth = Authenticating(*card);
+                }
+            }
+            (Waiting, PressKey(_) | DepositCash(_)) => {}
+            (Authenticating(_) | Authenticated(_), PressKey(Key::Cancel)) => atm.end_session(),
+            (Authenticating(card), PressKey(Key::Enter)) => {
+                let entered = crate::hash(&atm.keystroke_register);
+                let card = *card;
+                atm.end_session();
+                if let Some(account) = atm.accounts.get_mut(&card) {
+                    if entered == account.pin_hash {
+                        account.failed_attempts = 0;
+                        atm.auth = Authenticated(card);
                     } else {
-                        Self {
-                            cash_inside,
-                            expected_pin_hash: Waiting,
-                            keystroke_register,
-                        }
-                    }
-                } else {
-                    let mut keys = keystroke_register.clone();
-                    keys.push(key.clone());
-                    Self {
-                        cash_inside,
-                        expected_pin_hash,
-                        keystroke_register: keys,
+                        account.failed_attempts = account.failed_attempts.saturating_add(1);
                     }
                 }
             }
-            (Authenticated, PressKey(key)) => {
-                if key == &Key::Enter {
-                    if keystroke_register.is_empty() {
-                        return starting_state.clone();
-                    }
+            (Authenticated(card), PressKey(Key::Enter)) => {
+                if atm.keystroke_register.is_empty() {
+                    return atm;
+                }
 
-                    let amount = keystroke_register
-                        .iter()
-                        .map(|key| key.to_string())
-                        .collect::<String>()
-                        .parse::<u64>()
-                        .unwrap_or(0);
-                    let keystroke_register = vec![];
-                    if amount > cash_inside || amount == 0 {
-                        Self {
-                            cash_inside,
-                            keystroke_register,
-                            expected_pin_hash: Waiting,
-                        }
-                    } else {
-                        let new_cash = cash_inside - amount;
-                        Self {
-                            cash_inside: new_cash,
-                            keystroke_register,
-                            expected_pin_hash: Waiting,
-                        }
+                let amount = atm.keyed_amount();
+                let card = *card;
+                atm.end_session();
+                if let Some(account) = atm.accounts.get_mut(&card) {
+                    if amount != 0 && amount <= atm.cash_inside && amount <= account.balance {
+                        account.balance -= amount;
+                        atm.cash_inside -= amount;
                     }
-                } else {
-                    let mut keys = keystroke_register.clone();
-                    keys.push(key.clone());
-                    Self {
-                        cash_inside,
-                        expected_pin_hash,
-                        keystroke_register: keys,
+                }
+            }
+            (Authenticating(_) | Authenticated(_), PressKey(key)) => {
+                atm.keystroke_register.push(key.clone());
+            }
+            (Authenticating(_), DepositCash(_)) => {}
+            (Authenticated(card), DepositCash(amount)) => {
+                let cash_inside = atm.cash_inside.checked_add(*amount);
+                if let (Some(cash_inside), Some(account)) =
+                    (cash_inside, atm.accounts.get_mut(card))
+                {
+                    if let Some(balance) = account.balance.checked_add(*amount) {
+                        account.balance = balance;
+                        atm.cash_inside = cash_inside;
                     }
                 }
             }
-            _ => unimplemented!(),
         }
+
+        atm
+    }
+}
+
+/// The total of every account balance. Summed as a u128 so it can never overflow.
+fn total_balance(atm: &Atm) -> u128 {
+    atm.accounts.values().map(|a| a.balance as u128).sum()
+}
+
+impl Invariants for Atm {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "cash in the machine moves with account balances",
+                holds: |pre, _, post| {
+                    post.cash_inside as i128 - pre.cash_inside as i128
+                        == total_balance(post) as i128 - total_balance(pre) as i128
+                },
+            },
+            Invariant {
+                name: "cash only leaves after an authenticated enter",
+                holds: |pre, t, post| {
+                    post.cash_inside >= pre.cash_inside
+                        || (matches!(pre.auth, Auth::Authenticated(_))
+                            && matches!(t, Action::PressKey(Key::Enter)))
+                },
+            },
+            Invariant {
+                name: "the keystroke register is cleared by enter",
+                holds: |pre, t, post| {
+                    !matches!(t, Action::PressKey(Key::Enter))
+                        || pre.auth == Auth::Waiting
+                        || post.keystroke_register.is_empty()
+                },
+            },
+            Invariant {
+                name: "locked cards are never in a session",
+                holds: |_, _, post| match post.auth {
+                    Auth::Waiting => true,
+                    Auth::Authenticating(card) | Auth::Authenticated(card) => {
+                        !post.accounts[&card].is_locked()
+                    }
+                },
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for Atm {
+    fn arbitrary_transition(rng: &mut Rng, _: &Atm) -> Action {
+        match rng.below(12) {
+            // Cards 1 and 2 exist in the fuzzing bank. Card 3 does not.
+            0 | 1 => Action::SwipeCard(1 + rng.below(3)),
+            2 => Action::DepositCash(match rng.below(5) {
+                0 => u64::MAX - rng.below(10),
+                _ => rng.below(50),
+            }),
+            _ => {
+                let keys = [
+                    Key::One,
+                    Key::Two,
+                    Key::Three,
+                    Key::Four,
+                    Key::Enter,
+                    Key::Enter,
+                    Key::Cancel,
+                ];
+                Action::PressKey(rng.pick(&keys).clone())
+            }
+        }
+    }
+}
+
+// ========== Helpers ==========
+
+/// The hash of the pin 1234, which unlocks card 1.
+fn pin_1234() -> u64 {
+    crate::hash(&vec![Key::One, Key::Two, Key::Three, Key::Four])
+}
+
+/// A bank with two cards. Card 1 has pin 1234 and 50 in the bank.
+/// Card 2 has pin 1 and 5 in the bank.
+fn bank() -> HashMap<CardId, Account> {
+    HashMap::from([
+        (1, Account::new(pin_1234(), 50)),
+        (2, Account::new(crate::hash(&vec![Key::One]), 5)),
+    ])
+}
+
+/// An ATM with 10 cash inside, serving the test bank, in the given session state.
+fn atm(auth: Auth, keystroke_register: Vec<Key>) -> Atm {
+    Atm {
+        cash_inside: 10,
+        accounts: bank(),
+        auth,
+        keystroke_register,
     }
 }
 
 #[test]
 fn sm_3_simple_swipe_card() {
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Waiting,
-        keystroke_register: Vec::new(),
-    };
-    let end = Atm::next_state(&start, &Action::SwipeCard(1234));
-    let expected = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
-        keystroke_register: Vec::new(),
-    };
+    let start = atm(Auth::Waiting, Vec::new());
+    let end = Atm::next_state(&start, &Action::SwipeCard(1));
+    let expected = atm(Auth::Authenticating(1), Vec::new());
 
     assert_eq!(end, expected);
 }
 
 #[test]
-fn sm_3_swipe_card_again_part_way_through() {
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
-        keystroke_register: Vec::new(),
-    };
-    let end = Atm::next_state(&start, &Action::SwipeCard(1234));
-    let expected = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
-        keystroke_register: Vec::new(),
-    };
+fn sm_3_swipe_unknown_card() {
+    let start = atm(Auth::Waiting, Vec::new());
+    let end = Atm::next_state(&start, &Action::SwipeCard(99));
 
-    assert_eq!(end, expected);
+    assert_eq!(end, start);
+}
 
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
-        keystroke_register: vec![Key::One, Key::Three],
-    };
-    let end = Atm::next_state(&start, &Action::SwipeCard(1234));
-    let expected = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
-        keystroke_register: vec![Key::One, Key::Three],
-    };
+#[test]
+fn sm_3_swipe_card_again_part_way_through() {
+    let start = atm(Auth::Authenticating(1), vec![Key::One, Key::Three]);
+    let end = Atm::next_state(&start, &Action::SwipeCard(2));
+    let expected = atm(Auth::Authenticating(2), Vec::new());
 
     assert_eq!(end, expected);
 }
 
 #[test]
 fn sm_3_press_key_before_card_swipe() {
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Waiting,
-        keystroke_register: Vec::new(),
-    };
+    let start = atm(Auth::Waiting, Vec::new());
     let end = Atm::next_state(&start, &Action::PressKey(Key::One));
-    let expected = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Waiting,
-        keystroke_register: Vec::new(),
-    };
 
-    assert_eq!(end, expected);
+    assert_eq!(end, start);
 }
 
 #[test]
 fn sm_3_enter_single_digit_of_pin() {
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
-        keystroke_register: Vec::new(),
-    };
+    let start = atm(Auth::Authenticating(1), Vec::new());
     let end = Atm::next_state(&start, &Action::PressKey(Key::One));
-    let expected = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
-        keystroke_register: vec![Key::One],
-    };
+    let expected = atm(Auth::Authenticating(1), vec![Key::One]);
 
     assert_eq!(end, expected);
 
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
-        keystroke_register: vec![Key::One],
-    };
-    let end1 = Atm::next_state(&start, &Action::PressKey(Key::Two));
-    let expected1 = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
-        keystroke_register: vec![Key::One, Key::Two],
-    };
+    let end1 = Atm::next_state(&end, &Action::PressKey(Key::Two));
+    let expected1 = atm(Auth::Authenticating(1), vec![Key::One, Key::Two]);
 
     assert_eq!(end1, expected1);
 }
 
 #[test]
 fn sm_3_enter_wrong_pin() {
-    // Create hash of pin
-    let pin = vec![Key::One, Key::Two, Key::Three, Key::Four];
-    let pin_hash = crate::hash(&pin);
-
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(pin_hash),
-        keystroke_register: vec![Key::Three, Key::Three, Key::Three, Key::Three],
-    };
+    let start = atm(
+        Auth::Authenticating(1),
+        vec![Key::Three, Key::Three, Key::Three, Key::Three],
+    );
     let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
-    let expected = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Waiting,
-        keystroke_register: Vec::new(),
-    };
+    let mut expected = atm(Auth::Waiting, Vec::new());
+    expected.accounts.get_mut(&1).unwrap().failed_attempts = 1;
 
     assert_eq!(end, expected);
 }
 
 #[test]
 fn sm_3_enter_correct_pin() {
-    // Create hash of pin
-    let pin = vec![Key::One, Key::Two, Key::Three, Key::Four];
-    let pin_hash = crate::hash(&pin);
-
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(pin_hash),
-        keystroke_register: vec![Key::One, Key::Two, Key::Three, Key::Four],
-    };
+    let mut start = atm(
+        Auth::Authenticating(1),
+        vec![Key::One, Key::Two, Key::Three, Key::Four],
+    );
+    start.accounts.get_mut(&1).unwrap().failed_attempts = 2;
     let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
-    let expected = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
-        keystroke_register: Vec::new(),
-    };
+    // A correct pin forgives earlier mistakes.
+    let expected = atm(Auth::Authenticated(1), Vec::new());
 
     assert_eq!(end, expected);
 }
 
+#[test]
+fn sm_3_card_locks_after_too_many_wrong_pins() {
+    let mut state = atm(Auth::Waiting, Vec::new());
+    for _ in 0..MAX_PIN_ATTEMPTS {
+        state = Atm::next_state(&state, &Action::SwipeCard(1));
+        state = Atm::next_state(&state, &Action::PressKey(Key::Four));
+        state = Atm::next_state(&state, &Action::PressKey(Key::Enter));
+    }
+    assert!(state.accounts[&1].is_locked());
+
+    // Even swiping the card is refused now.
+    let end = Atm::next_state(&state, &Action::SwipeCard(1));
+    assert_eq!(end.auth, Auth::Waiting);
+}
+
 #[test]
 fn sm_3_enter_single_digit_of_withdraw_amount() {
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
-        keystroke_register: Vec::new(),
-    };
+    let start = atm(Auth::Authenticated(1), Vec::new());
     let end = Atm::next_state(&start, &Action::PressKey(Key::One));
-    let expected = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
-        keystroke_register: vec![Key::One],
-    };
+    let expected = atm(Auth::Authenticated(1), vec![Key::One]);
 
     assert_eq!(end, expected);
 
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
-        keystroke_register: vec![Key::One],
-    };
-    let end1 = Atm::next_state(&start, &Action::PressKey(Key::Four));
-    let expected1 = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
-        keystroke_register: vec![Key::One, Key::Four],
-    };
+    let end1 = Atm::next_state(&end, &Action::PressKey(Key::Four));
+    let expected1 = atm(Auth::Authenticated(1), vec![Key::One, Key::Four]);
 
     assert_eq!(end1, expected1);
 }
 
 #[test]
-fn sm_3_try_to_withdraw_too_much() {
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
-        keystroke_register: vec![Key::One, Key::Four],
-    };
+fn sm_3_try_to_withdraw_more_than_machine_holds() {
+    let start = atm(Auth::Authenticated(1), vec![Key::One, Key::Four]);
     let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
-    let expected = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Waiting,
-        keystroke_register: Vec::new(),
-    };
+    let expected = atm(Auth::Waiting, Vec::new());
+
+    assert_eq!(end, expected);
+}
+
+#[test]
+fn sm_3_try_to_withdraw_more_than_balance() {
+    let start = atm(Auth::Authenticated(2), vec![Key::Four, Key::Four]);
+    let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
+    let expected = atm(Auth::Waiting, Vec::new());
 
     assert_eq!(end, expected);
 }
 
 #[test]
 fn sm_3_withdraw_acceptable_amount() {
-    let start = Atm {
-        cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
-        keystroke_register: vec![Key::One],
-    };
+    let start = atm(Auth::Authenticated(1), vec![Key::Four]);
     let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
-    let expected = Atm {
-        cash_inside: 9,
-        expected_pin_hash: Auth::Waiting,
-        keystroke_register: Vec::new(),
-    };
+    let mut expected = atm(Auth::Waiting, Vec::new());
+    expected.cash_inside = 6;
+    expected.accounts.get_mut(&1).unwrap().balance = 46;
+
+    assert_eq!(end, expected);
+}
+
+#[test]
+fn sm_3_deposit_credits_account() {
+    let start = atm(Auth::Authenticated(2), Vec::new());
+    let end = Atm::next_state(&start, &Action::DepositCash(20));
+    let mut expected = atm(Auth::Authenticated(2), Vec::new());
+    expected.cash_inside = 30;
+    expected.accounts.get_mut(&2).unwrap().balance = 25;
+
+    assert_eq!(end, expected);
+    assert_eq!(end.balance(2), Some(25));
+}
+
+#[test]
+fn sm_3_deposit_without_session_is_returned() {
+    let start = atm(Auth::Authenticating(2), Vec::new());
+    let end = Atm::next_state(&start, &Action::DepositCash(20));
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_3_cancel_returns_card() {
+    let start = atm(Auth::Authenticated(1), vec![Key::One]);
+    let end = Atm::next_state(&start, &Action::PressKey(Key::Cancel));
+    let expected = atm(Auth::Waiting, Vec::new());
+
+    assert_eq!(end, expected);
+
+    let start = atm(Auth::Authenticating(1), vec![Key::One]);
+    let end = Atm::next_state(&start, &Action::PressKey(Key::Cancel));
 
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_3_swipe_card_while_authenticated_is_ignored() {
+    let start = atm(Auth::Authenticated(1), vec![Key::One]);
+    let end = Atm::next_state(&start, &Action::SwipeCard(2));
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_3_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    fuzz::<Atm>(&Atm::new(1_000, bank()), &FuzzConfig::default()).unwrap();
+}
@@ -8,7 +8,10 @@
 //! In this module we design a state machine that tracks the currency balances of several users.
 //! Each user is associated with an account balance and users are able to send money to other users.
 
+use super::invariants::{ArbitraryTransition, Invariant, Invariants, Rng};
+use super::reversible::Reversible;
 use super::{StateMachine, User};
+use crate::codec::{decode_tag, Decode, Encode, Error};
 use std::collections::HashMap;
 
 /// This state machine models a multi-user currency system. It tracks the balance of each
@@ -24,6 +27,7 @@ pub struct AccountedCurrency;
 type Balances = HashMap<User, u64>;
 
 /// The state transitions that users can make in an accounted currency system
+#[derive(Debug, Clone)]
 pub enum AccountingTransaction {
     /// Create some new money for the given minter in the given amount
     Mint { minter: User, amount: u64 },
@@ -50,13 +54,14 @@ impl StateMachine for AccountedCurrency {
         let mut balances = starting_state.clone();
         match t {
             Mint { minter, amount } => {
+                // Minting nothing must not touch an existing account, and minting past
+                // the maximum balance is rejected rather than silently truncated.
                 if *amount == 0 {
-                    balances.remove(minter);
-                } else {
-                    balances
-                        .entry(*minter)
-                        .and_modify(|val| *val += *amount)
-                        .or_insert(*amount);
+                    return balances;
+                }
+                let current = balances.get(minter).copied().unwrap_or(0);
+                if let Some(new_balance) = current.checked_add(*amount) {
+                    balances.insert(*minter, new_balance);
                 }
                 balances
             }
@@ -87,16 +92,21 @@ impl StateMachine for AccountedCurrency {
                     }
                     let new_sndr_b = sndr_b.saturating_sub(*amount);
 
+                    // A receiver balance that would overflow would destroy funds, so reject it.
+                    let rcvr_b = balances.get(receiver).copied().unwrap_or(0);
+                    let Some(new_rcvr_b) = rcvr_b.checked_add(*amount) else {
+                        return balances;
+                    };
+
                     if new_sndr_b == 0 {
                         balances.remove(sender);
                     } else {
                         balances.insert(*sender, new_sndr_b);
                     }
 
-                    balances
-                        .entry(*receiver)
-                        .and_modify(|value| *value = value.saturating_add(*amount))
-                        .or_insert(*amount);
+                    if new_rcvr_b != 0 {
+                        balances.insert(*receiver, new_rcvr_b);
+                    }
                 }
 
                 balances
@@ -105,6 +115,159 @@ impl StateMachine for AccountedCurrency {
     }
 }
 
+/// A transition only ever touches the accounts it names, so its diff is just the
+/// previous balance of each of those accounts. `None` means the account did not exist.
+impl Reversible for AccountedCurrency {
+    type Diff = Vec<(User, Option<u64>)>;
+
+    fn next_state_with_diff(
+        starting_state: &Balances,
+        t: &AccountingTransaction,
+    ) -> (Balances, Self::Diff) {
+        use AccountingTransaction::*;
+
+        let touched = match t {
+            Mint { minter, .. } => vec![*minter],
+            Burn { burner, .. } => vec![*burner],
+            Transfer {
+                sender, receiver, ..
+            } => vec![*sender, *receiver],
+        };
+        let diff = touched
+            .into_iter()
+            .map(|user| (user, starting_state.get(&user).copied()))
+            .collect();
+
+        (Self::next_state(starting_state, t), diff)
+    }
+
+    fn revert(state: &Balances, diff: &Self::Diff) -> Balances {
+        let mut balances = state.clone();
+        // Restore in reverse so that the earliest recorded value wins if a user repeats.
+        for (user, previous) in diff.iter().rev() {
+            match previous {
+                Some(balance) => balances.insert(*user, *balance),
+                None => balances.remove(user),
+            };
+        }
+        balances
+    }
+}
+
+/// The total amount of money in circulation. Summed as a u128 so it can never overflow.
+fn total_supply(balances: &Balances) -> u128 {
+    balances.values().map(|b| *b as u128).sum()
+}
+
+impl Invariants for AccountedCurrency {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "total supply only changes by minting and burning",
+                holds: |pre, t, post| {
+                    let (before, after) = (total_supply(pre), total_supply(post));
+                    match t {
+                        AccountingTransaction::Mint { amount, .. } => {
+                            after == before || after == before + *amount as u128
+                        }
+                        AccountingTransaction::Burn { burner, amount } => {
+                            let burnable = pre.get(burner).copied().unwrap_or(0).min(*amount);
+                            after == before - burnable as u128
+                        }
+                        AccountingTransaction::Transfer { .. } => after == before,
+                    }
+                },
+            },
+            Invariant {
+                name: "accounts with no balance are removed",
+                holds: |_, _, post| post.values().all(|b| *b > 0),
+            },
+            Invariant {
+                name: "reverting restores the starting state",
+                holds: |pre, t, post| {
+                    let (next, diff) = Self::next_state_with_diff(pre, t);
+                    next == *post && Self::revert(post, &diff) == *pre
+                },
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for AccountedCurrency {
+    fn arbitrary_transition(rng: &mut Rng, _: &Balances) -> AccountingTransaction {
+        let users = [User::Alice, User::Bob, User::Charlie];
+        // Mostly small amounts, with the occasional huge one to probe for overflows.
+        let amount = match rng.below(10) {
+            0 => 0,
+            1 => u64::MAX - rng.below(100),
+            _ => rng.below(100),
+        };
+        match rng.below(3) {
+            0 => AccountingTransaction::Mint {
+                minter: *rng.pick(&users),
+                amount,
+            },
+            1 => AccountingTransaction::Burn {
+                burner: *rng.pick(&users),
+                amount,
+            },
+            _ => AccountingTransaction::Transfer {
+                sender: *rng.pick(&users),
+                receiver: *rng.pick(&users),
+                amount,
+            },
+        }
+    }
+}
+
+impl Encode for AccountingTransaction {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            AccountingTransaction::Mint { minter, amount } => {
+                dest.push(0);
+                minter.encode_to(dest);
+                amount.encode_to(dest);
+            }
+            AccountingTransaction::Burn { burner, amount } => {
+                dest.push(1);
+                burner.encode_to(dest);
+                amount.encode_to(dest);
+            }
+            AccountingTransaction::Transfer {
+                sender,
+                receiver,
+                amount,
+            } => {
+                dest.push(2);
+                sender.encode_to(dest);
+                receiver.encode_to(dest);
+                amount.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl Decode for AccountingTransaction {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(AccountingTransaction::Mint {
+                minter: User::decode(input)?,
+                amount: u64::decode(input)?,
+            }),
+            1 => Ok(AccountingTransaction::Burn {
+                burner: User::decode(input)?,
+                amount: u64::decode(input)?,
+            }),
+            2 => Ok(AccountingTransaction::Transfer {
+                sender: User::decode(input)?,
+                receiver: User::decode(input)?,
+                amount: u64::decode(input)?,
+            }),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
 #[test]
 fn sm_4_mint_creates_account() {
     let start = HashMap::new();
@@ -379,3 +542,70 @@ fn sm_4_transfer() {
 
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_4_empty_mint_keeps_existing_account() {
+    let start = HashMap::from([(User::Alice, 100)]);
+    let end = AccountedCurrency::next_state(
+        &start,
+        &AccountingTransaction::Mint {
+            minter: User::Alice,
+            amount: 0,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_4_overflowing_mint_fails() {
+    let start = HashMap::from([(User::Alice, u64::MAX - 1)]);
+    let end = AccountedCurrency::next_state(
+        &start,
+        &AccountingTransaction::Mint {
+            minter: User::Alice,
+            amount: 2,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_4_overflowing_transfer_fails() {
+    let start = HashMap::from([(User::Alice, 10), (User::Bob, u64::MAX)]);
+    let end = AccountedCurrency::next_state(
+        &start,
+        &AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 10,
+        },
+    );
+
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_4_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    fuzz::<AccountedCurrency>(&HashMap::new(), &FuzzConfig::default()).unwrap();
+}
+
+#[test]
+fn sm_4_codec_round_trip() {
+    use crate::codec::decode_all;
+
+    let t = AccountingTransaction::Transfer {
+        sender: User::Alice,
+        receiver: User::Charlie,
+        amount: 300,
+    };
+    assert_eq!(t.encode(), vec![2, 0, 2, 0x2c, 0x01, 0, 0, 0, 0, 0, 0]);
+    let decoded: AccountingTransaction = decode_all(&t.encode()).unwrap();
+    assert_eq!(decoded.encode(), t.encode());
+
+    let balances: Balances = HashMap::from([(User::Bob, 5), (User::Alice, 7)]);
+    assert_eq!(decode_all(&balances.encode()), Ok(balances));
+}
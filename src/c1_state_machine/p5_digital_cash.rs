@@ -3,7 +3,11 @@
 //! cash bills. Each bill has an amount and an owner, and can be spent in its entirety.
 //! When a state transition spends bills, new bills are created in lesser or equal amount.
 
+use super::invariants::{ArbitraryTransition, Invariant, Invariants, Rng};
+use super::reversible::Reversible;
 use super::{StateMachine, User};
+use crate::codec::{decode_tag, Decode, Encode, Error};
+use crate::json::{self, FromJson, Json};
 use std::collections::HashSet;
 
 /// This state machine models a multi-user currency system. It tracks a set of bills in
@@ -74,6 +78,7 @@ impl<const N: usize> From<[Bill; N]> for State {
 }
 
 /// The state transitions that users can make in a digital cash system
+#[derive(Debug, Clone)]
 pub enum CashTransaction {
     /// Mint a single new bill owned by the minter
     Mint { minter: User, amount: u64 },
@@ -167,6 +172,255 @@ impl StateMachine for DigitalCashSystem {
     }
 }
 
+/// Everything needed to undo a single digital cash transition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CashDiff {
+    /// Bills that the transition removed from circulation.
+    spent: Vec<Bill>,
+    /// Bills that the transition created.
+    created: Vec<Bill>,
+    /// The serial counter before the transition.
+    previous_serial: u64,
+}
+
+/// Only the bills named in the transition can change, so the diff records which of
+/// those actually left or entered circulation.
+impl Reversible for DigitalCashSystem {
+    type Diff = CashDiff;
+
+    fn next_state_with_diff(starting_state: &State, t: &CashTransaction) -> (State, CashDiff) {
+        let next = Self::next_state(starting_state, t);
+
+        let (spent, created) = match t {
+            CashTransaction::Mint { minter, amount } => {
+                let minted = Bill {
+                    owner: *minter,
+                    amount: *amount,
+                    serial: starting_state.next_serial,
+                };
+                (vec![], vec![minted])
+            }
+            CashTransaction::Transfer { spends, receives } => (spends.clone(), receives.clone()),
+        };
+        let diff = CashDiff {
+            spent: spent
+                .into_iter()
+                .filter(|b| starting_state.bills.contains(b) && !next.bills.contains(b))
+                .collect(),
+            created: created
+                .into_iter()
+                .filter(|b| !starting_state.bills.contains(b) && next.bills.contains(b))
+                .collect(),
+            previous_serial: starting_state.next_serial,
+        };
+
+        (next, diff)
+    }
+
+    fn revert(state: &State, diff: &CashDiff) -> State {
+        let mut previous = state.clone();
+        for bill in &diff.created {
+            previous.bills.remove(bill);
+        }
+        previous.bills.extend(diff.spent.iter().cloned());
+        previous.next_serial = diff.previous_serial;
+        previous
+    }
+}
+
+impl State {
+    /// The total value of all circulating bills. Summed as a u128 so it can never overflow.
+    fn total_supply(&self) -> u128 {
+        self.bills.iter().map(|bill| bill.amount as u128).sum()
+    }
+}
+
+impl Invariants for DigitalCashSystem {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "only minting creates money",
+                holds: |pre, t, post| match t {
+                    CashTransaction::Mint { amount, .. } => {
+                        post.total_supply() == pre.total_supply() + *amount as u128
+                    }
+                    CashTransaction::Transfer { .. } => post.total_supply() <= pre.total_supply(),
+                },
+            },
+            Invariant {
+                name: "serial numbers never go backwards",
+                holds: |pre, _, post| post.next_serial >= pre.next_serial,
+            },
+            Invariant {
+                name: "every bill has a unique, already issued serial",
+                holds: |_, _, post| {
+                    let serials = post.bills.iter().map(|b| b.serial).collect::<HashSet<_>>();
+                    serials.len() == post.bills.len()
+                        && serials.iter().all(|serial| *serial < post.next_serial)
+                },
+            },
+            Invariant {
+                name: "reverting restores the starting state",
+                holds: |pre, t, post| {
+                    let (next, diff) = Self::next_state_with_diff(pre, t);
+                    next == *post && Self::revert(post, &diff) == *pre
+                },
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for DigitalCashSystem {
+    fn arbitrary_transition(rng: &mut Rng, state: &State) -> CashTransaction {
+        let users = [User::Alice, User::Bob, User::Charlie];
+        if state.bills.is_empty() || rng.chance(1, 4) {
+            return CashTransaction::Mint {
+                minter: *rng.pick(&users),
+                amount: rng.below(100),
+            };
+        }
+
+        // Sort the bills so that generation does not depend on HashSet iteration order.
+        let mut circulating = state.bills.iter().cloned().collect::<Vec<_>>();
+        circulating.sort_by_key(|bill| bill.serial);
+
+        let mut spends = circulating
+            .into_iter()
+            .filter(|_| rng.chance(1, 2))
+            .collect::<Vec<_>>();
+        if rng.chance(1, 10) {
+            // Occasionally try to spend a bill that does not exist.
+            spends.push(Bill {
+                owner: *rng.pick(&users),
+                amount: rng.below(100),
+                serial: rng.below(state.next_serial + 1),
+            });
+        }
+
+        let available = spends.iter().map(|bill| bill.amount).sum::<u64>();
+        let receives = (0..rng.below(4))
+            .map(|i| Bill {
+                owner: *rng.pick(&users),
+                amount: rng.below(available / 2 + 2),
+                serial: if rng.chance(1, 10) {
+                    rng.below(state.next_serial + 5)
+                } else {
+                    state.next_serial + i
+                },
+            })
+            .collect();
+
+        CashTransaction::Transfer { spends, receives }
+    }
+}
+
+impl Encode for Bill {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.owner.encode_to(dest);
+        self.amount.encode_to(dest);
+        self.serial.encode_to(dest);
+    }
+}
+
+impl Decode for Bill {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(Bill {
+            owner: User::decode(input)?,
+            amount: u64::decode(input)?,
+            serial: u64::decode(input)?,
+        })
+    }
+}
+
+impl Encode for State {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.bills.encode_to(dest);
+        self.next_serial.encode_to(dest);
+    }
+}
+
+impl Decode for State {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(State {
+            bills: HashSet::decode(input)?,
+            next_serial: u64::decode(input)?,
+        })
+    }
+}
+
+impl Encode for CashTransaction {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            CashTransaction::Mint { minter, amount } => {
+                dest.push(0);
+                minter.encode_to(dest);
+                amount.encode_to(dest);
+            }
+            CashTransaction::Transfer { spends, receives } => {
+                dest.push(1);
+                spends.encode_to(dest);
+                receives.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl Decode for CashTransaction {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(CashTransaction::Mint {
+                minter: User::decode(input)?,
+                amount: u64::decode(input)?,
+            }),
+            1 => Ok(CashTransaction::Transfer {
+                spends: Vec::decode(input)?,
+                receives: Vec::decode(input)?,
+            }),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+impl FromJson for Bill {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        Ok(Bill {
+            owner: j.parse_field("owner")?,
+            amount: j.parse_field("amount")?,
+            serial: j.parse_field("serial")?,
+        })
+    }
+}
+
+/// A genesis state is written in a chain spec as the list of bills in circulation:
+/// `{ "bills": [{ "owner": "Alice", "amount": 10, "serial": 0 }, ...] }`
+///
+/// The next serial number may optionally be given as `next_serial`. By default it
+/// is one more than the highest serial in circulation.
+impl FromJson for State {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        let bills: Vec<Bill> = j.parse_field("bills")?;
+        let unused_serial = bills.iter().map(|b| b.serial + 1).max().unwrap_or(0);
+        let next_serial = match j.get("next_serial")? {
+            Some(n) => u64::from_json(n)?,
+            None => unused_serial,
+        };
+        if next_serial < unused_serial {
+            return Err(json::Error::Invalid(
+                "next_serial has already been used by a bill".into(),
+            ));
+        }
+
+        let mut state = State::new();
+        for bill in bills {
+            if !state.bills.insert(bill) {
+                return Err(json::Error::Invalid("bills must be unique".into()));
+            }
+        }
+        state.set_serial(next_serial);
+        Ok(state)
+    }
+}
+
 #[test]
 fn sm_5_mint_new_cash() {
     let start = State::new();
@@ -748,3 +1002,77 @@ fn sm_5_spending_from_charlie_to_all() {
     expected.set_serial(62);
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_5_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    fuzz::<DigitalCashSystem>(&State::new(), &FuzzConfig::default()).unwrap();
+}
+
+#[test]
+fn sm_5_codec_round_trip() {
+    use crate::codec::decode_all;
+
+    let bills = [
+        Bill {
+            owner: User::Alice,
+            amount: 20,
+            serial: 0,
+        },
+        Bill {
+            owner: User::Bob,
+            amount: 5,
+            serial: 1,
+        },
+        Bill {
+            owner: User::Charlie,
+            amount: 7,
+            serial: 2,
+        },
+    ];
+    let forward: State = bills.iter().cloned().collect();
+    let backward: State = bills.iter().rev().cloned().collect();
+
+    // The bills are a set, so the order they were added in must not matter
+    assert_eq!(forward.encode(), backward.encode());
+    assert_eq!(forward.encoded_hash(), backward.encoded_hash());
+    assert_eq!(decode_all(&forward.encode()), Ok(forward));
+
+    let t = CashTransaction::Transfer {
+        spends: vec![bills[0].clone()],
+        receives: bills[1..].to_vec(),
+    };
+    let decoded: CashTransaction = decode_all(&t.encode()).unwrap();
+    assert_eq!(decoded.encode(), t.encode());
+}
+
+#[test]
+fn sm_5_genesis_from_chain_spec() {
+    let genesis: State = json::from_str(
+        r#"{ "bills": [
+            { "owner": "Alice", "amount": 20, "serial": 0 },
+            { "owner": "Bob", "amount": 5, "serial": 3 }
+        ] }"#,
+    )
+    .unwrap();
+
+    let mut expected = State::from([
+        Bill {
+            owner: User::Alice,
+            amount: 20,
+            serial: 0,
+        },
+        Bill {
+            owner: User::Bob,
+            amount: 5,
+            serial: 3,
+        },
+    ]);
+    expected.set_serial(4);
+    assert_eq!(genesis, expected);
+
+    let reused_serial =
+        r#"{ "bills": [{ "owner": "Bob", "amount": 5, "serial": 3 }], "next_serial": 2 }"#;
+    assert!(json::from_str::<State>(reused_serial).is_err());
+}
@@ -20,7 +20,10 @@
 //!   * Web of Trust
 //!   * Reputation System
 
+use super::invariants::{ArbitraryTransition, Invariant, Invariants, Rng};
 use super::{StateMachine, User};
+use crate::codec::{decode_tag, Decode, Encode, Error};
+use crate::json::{self, FromJson, Json};
 use std::collections::HashMap;
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
@@ -47,7 +50,7 @@ struct Tcr {
     registry: Vec<Proposal>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 enum Transitions {
     SubmitProposal {
         prop: Proposal,
@@ -150,30 +153,20 @@ impl StateMachine for Tcr {
                     users.extend(init.proposals[prop].votes_against.clone());
 
                     for (user, stake) in users {
-                        let _ = Self::top_up_balance(&mut new_state, &user, &stake);
+                        Self::top_up_balance(&mut new_state, &user, &stake);
                     }
 
                     new_state.proposals.remove(prop);
                 } else if total_for > total_against {
                     //for wins
                     let users = init.proposals[prop].votes_for.clone();
-                    let top_up = total_against.saturating_div(users.len() as u32);
-
-                    for (user, stake) in users {
-                        let new_stake = top_up + stake;
-                        let _ = Self::top_up_balance(&mut new_state, &user, &new_stake);
-                    }
+                    Self::pay_out_winners(&mut new_state, users, total_against);
                     new_state.registry.push(*prop);
                     new_state.proposals.remove(prop);
                 } else {
                     // against wins
                     let users = init.proposals[prop].votes_against.clone();
-                    let top_up = total_for.saturating_div(users.len() as u32);
-
-                    for (user, stake) in users {
-                        let new_stake = top_up + stake;
-                        let _ = Self::top_up_balance(&mut new_state, &user, &new_stake);
-                    }
+                    Self::pay_out_winners(&mut new_state, users, total_for);
                     new_state.proposals.remove(prop);
                 }
             }
@@ -195,12 +188,77 @@ impl Tcr {
         }
     }
 
-    fn top_up_balance(state: &mut Tcr, user: &User, stake: &Tokens) -> Result<(), &'static str> {
-        if let Some(bal) = state.balances.get(user) {
-            state.balances.insert(*user, bal.saturating_add(*stake));
-            Ok(())
-        } else {
-            Err("User not found")
+    /// Return each winner's stake along with an equal share of the losing side's stake.
+    /// The losing stake rarely divides evenly, so the remainder is handed out one token at a
+    /// time in user order. This keeps the total number of tokens constant.
+    fn pay_out_winners(state: &mut Tcr, winners: Votes, losing_stake: Tokens) {
+        let mut winners = winners.into_iter().collect::<Vec<_>>();
+        winners.sort();
+
+        let share = losing_stake / winners.len() as u32;
+        let remainder = losing_stake % winners.len() as u32;
+
+        for (i, (user, stake)) in winners.into_iter().enumerate() {
+            let dust = if (i as u32) < remainder { 1 } else { 0 };
+            Self::top_up_balance(state, &user, &(stake + share + dust));
+        }
+    }
+
+    /// Credit tokens to a user. Users without a balance get one, so that no payout is lost.
+    fn top_up_balance(state: &mut Tcr, user: &User, stake: &Tokens) {
+        let bal = state.balances.entry(*user).or_insert(0);
+        *bal = bal.saturating_add(*stake);
+    }
+}
+
+/// All tokens in the system, whether sitting in a balance or staked on a proposal.
+fn total_tokens(state: &Tcr) -> u64 {
+    let staked = state
+        .proposals
+        .values()
+        .flat_map(|p| p.votes_for.values().chain(p.votes_against.values()));
+    state
+        .balances
+        .values()
+        .chain(staked)
+        .map(|tokens| *tokens as u64)
+        .sum()
+}
+
+impl Invariants for Tcr {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "tokens are neither created nor destroyed",
+                holds: |pre, _, post| total_tokens(pre) == total_tokens(post),
+            },
+            Invariant {
+                name: "a proposal is never both pending and registered",
+                holds: |_, _, post| {
+                    post.registry
+                        .iter()
+                        .all(|p| !post.proposals.contains_key(p))
+                },
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for Tcr {
+    fn arbitrary_transition(rng: &mut Rng, _: &Tcr) -> Transitions {
+        let props = [
+            Proposal::Prop1,
+            Proposal::Prop2,
+            Proposal::Prop3,
+            Proposal::Prop4,
+        ];
+        let users = [User::Alice, User::Bob, User::Charlie];
+        let (prop, user, stake) = (*rng.pick(&props), *rng.pick(&users), rng.below(60) as u32);
+        match rng.below(4) {
+            0 => Transitions::SubmitProposal { prop, user, stake },
+            1 => Transitions::VoteFor { prop, user, stake },
+            2 => Transitions::VoteAgainst { prop, user, stake },
+            _ => Transitions::Resolve { prop },
         }
     }
 }
@@ -216,6 +274,139 @@ fn initial_state() -> Tcr {
 
 // ========== SubmitProposal Tests ==========
 
+impl Encode for Proposal {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        dest.push(*self as u8);
+    }
+}
+
+impl Decode for Proposal {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(Proposal::Prop1),
+            1 => Ok(Proposal::Prop2),
+            2 => Ok(Proposal::Prop3),
+            3 => Ok(Proposal::Prop4),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for ProposalState {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.votes_for.encode_to(dest);
+        self.votes_against.encode_to(dest);
+    }
+}
+
+impl Decode for ProposalState {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(ProposalState {
+            votes_for: Votes::decode(input)?,
+            votes_against: Votes::decode(input)?,
+        })
+    }
+}
+
+impl Encode for Tcr {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.balances.encode_to(dest);
+        self.proposals.encode_to(dest);
+        self.registry.encode_to(dest);
+    }
+}
+
+impl Decode for Tcr {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(Tcr {
+            balances: HashMap::decode(input)?,
+            proposals: HashMap::decode(input)?,
+            registry: Vec::decode(input)?,
+        })
+    }
+}
+
+impl Encode for Transitions {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            Transitions::SubmitProposal { prop, user, stake } => {
+                dest.push(0);
+                prop.encode_to(dest);
+                user.encode_to(dest);
+                stake.encode_to(dest);
+            }
+            Transitions::VoteFor { prop, user, stake } => {
+                dest.push(1);
+                prop.encode_to(dest);
+                user.encode_to(dest);
+                stake.encode_to(dest);
+            }
+            Transitions::VoteAgainst { prop, user, stake } => {
+                dest.push(2);
+                prop.encode_to(dest);
+                user.encode_to(dest);
+                stake.encode_to(dest);
+            }
+            Transitions::Resolve { prop } => {
+                dest.push(3);
+                prop.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl Decode for Transitions {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let tag = decode_tag(input)?;
+        if tag > 3 {
+            return Err(Error::InvalidTag(tag));
+        }
+        let prop = Proposal::decode(input)?;
+        if tag == 3 {
+            return Ok(Transitions::Resolve { prop });
+        }
+        let user = User::decode(input)?;
+        let stake = Tokens::decode(input)?;
+        Ok(match tag {
+            0 => Transitions::SubmitProposal { prop, user, stake },
+            1 => Transitions::VoteFor { prop, user, stake },
+            _ => Transitions::VoteAgainst { prop, user, stake },
+        })
+    }
+}
+
+impl FromJson for Proposal {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        match j.as_str()? {
+            "Prop1" => Ok(Proposal::Prop1),
+            "Prop2" => Ok(Proposal::Prop2),
+            "Prop3" => Ok(Proposal::Prop3),
+            "Prop4" => Ok(Proposal::Prop4),
+            other => Err(json::Error::UnknownVariant(other.into())),
+        }
+    }
+}
+
+/// A genesis registry is written in a chain spec as the token balances and the proposals
+/// that are already listed: `{ "balances": { "Alice": 100 }, "registry": ["Prop1"] }`.
+/// No proposals are open for voting at genesis.
+impl FromJson for Tcr {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        let balances: HashMap<User, Tokens> = j.parse_field("balances")?;
+        let registry: Vec<Proposal> = j.parse_field("registry")?;
+        if (1..registry.len()).any(|i| registry[..i].contains(&registry[i])) {
+            return Err(json::Error::Invalid(
+                "registry entries must be unique".into(),
+            ));
+        }
+        Ok(Tcr {
+            balances,
+            proposals: HashMap::new(),
+            registry,
+        })
+    }
+}
+
 #[test]
 fn submit_proposal_fails_already_in_proposals() {
     let mut start = initial_state();
@@ -735,3 +926,111 @@ fn resolve_succeeds_against_wins() {
     };
     assert_eq!(end, expected);
 }
+
+// ========== Invariant Tests ==========
+
+#[test]
+fn resolve_shares_uneven_stake_without_losing_tokens() {
+    let start = Tcr {
+        balances: HashMap::from([(User::Alice, 90), (User::Bob, 90), (User::Charlie, 95)]),
+        proposals: HashMap::from([(
+            Proposal::Prop1,
+            ProposalState {
+                votes_for: HashMap::from([(User::Alice, 10), (User::Bob, 10)]),
+                votes_against: HashMap::from([(User::Charlie, 5)]),
+            },
+        )]),
+        registry: vec![],
+    };
+    let end = Tcr::next_state(
+        &start,
+        &Transitions::Resolve {
+            prop: Proposal::Prop1,
+        },
+    );
+    let expected = Tcr {
+        balances: HashMap::from([(User::Alice, 103), (User::Bob, 102), (User::Charlie, 95)]),
+        proposals: HashMap::new(),
+        registry: vec![Proposal::Prop1],
+    };
+    assert_eq!(end, expected);
+}
+
+#[test]
+fn resolve_pays_winners_without_a_balance() {
+    let start = Tcr {
+        balances: HashMap::from([(User::Bob, 100)]),
+        proposals: HashMap::from([(
+            Proposal::Prop1,
+            ProposalState {
+                votes_for: HashMap::from([(User::Alice, 10)]),
+                votes_against: HashMap::from([(User::Bob, 5)]),
+            },
+        )]),
+        registry: vec![],
+    };
+    let end = Tcr::next_state(
+        &start,
+        &Transitions::Resolve {
+            prop: Proposal::Prop1,
+        },
+    );
+    assert_eq!(
+        end.balances,
+        HashMap::from([(User::Alice, 15), (User::Bob, 100)])
+    );
+}
+
+#[test]
+fn tcr_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    fuzz::<Tcr>(&initial_state(), &FuzzConfig::default()).unwrap();
+}
+
+#[test]
+fn tcr_codec_round_trip() {
+    use crate::codec::decode_all;
+
+    let start = Tcr {
+        balances: HashMap::from([(User::Alice, 100), (User::Bob, 50)]),
+        proposals: HashMap::new(),
+        registry: vec![Proposal::Prop3],
+    };
+    let transitions = [
+        Transitions::SubmitProposal {
+            prop: Proposal::Prop1,
+            user: User::Alice,
+            stake: 10,
+        },
+        Transitions::VoteAgainst {
+            prop: Proposal::Prop1,
+            user: User::Bob,
+            stake: 20,
+        },
+    ];
+    let end = transitions.iter().fold(start, |state, t| {
+        assert_eq!(decode_all(&t.encode()).as_ref(), Ok(t));
+        Tcr::next_state(&state, t)
+    });
+
+    assert_eq!(decode_all(&end.encode()), Ok(end));
+}
+
+#[test]
+fn tcr_genesis_from_chain_spec() {
+    let genesis: Tcr =
+        json::from_str(r#"{ "balances": { "Alice": 100, "Bob": 7 }, "registry": ["Prop2"] }"#)
+            .unwrap();
+    let expected = Tcr {
+        balances: HashMap::from([(User::Alice, 100), (User::Bob, 7)]),
+        proposals: HashMap::new(),
+        registry: vec![Proposal::Prop2],
+    };
+    assert_eq!(genesis, expected);
+
+    assert_eq!(
+        json::from_str::<Tcr>(r#"{ "balances": {}, "registry": ["Prop5"] }"#),
+        Err(json::Error::UnknownVariant("Prop5".into()))
+    );
+}
@@ -0,0 +1,640 @@
+//! Every transaction on a blockchain costs every node some work, so paying for something a tiny
+//! amount at a time is far too expensive to do on chain. Payment channels move those payments off
+//! chain, and only use the chain to open the channel and to settle the final balances. This is
+//! known as the layer-2 pattern.
+//!
+//! One user opens a channel to another by locking a deposit on chain. From then on the two of them
+//! pay each other by co-signing balance updates that they keep to themselves. Each update has a
+//! higher sequence number than the last. When they are done, they submit the last update together
+//! to close the channel cooperatively.
+//!
+//! If one party disappears, the other can close the channel on their own by submitting the latest
+//! update they hold. That starts a dispute window, during which the counterparty may prove that a
+//! newer update exists. Submitting a stale update is an attempt to steal, so it is punished by
+//! awarding the whole channel to the counterparty. The dispute window is counted in blocks, and
+//! channels whose window has ended pay out as the next block begins.
+//!
+//! Throughout this chapter, a user's name stands in for their signature, just as the consensus
+//! authorities do in the consensus chapter. A real chain would check cryptographic signatures.
+
+use super::invariants::{ArbitraryTransition, BlockInvariant, Invariant, Invariants, Rng};
+use super::{StateMachine, User};
+use std::collections::HashMap;
+
+/// How many blocks the counterparty has to dispute a unilateral close.
+pub const DISPUTE_PERIOD: u64 = 10;
+
+/// Channels are identified by a number.
+pub type ChannelId = u64;
+
+/// This state machine models two-party payment channels on top of a simple currency.
+pub struct PaymentChannels;
+
+/// An off-chain agreement on how a channel's deposit is split.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelBalance {
+    pub channel: ChannelId,
+    /// Later updates have higher sequence numbers. The balance at opening has sequence 0.
+    pub sequence: u64,
+    /// The amount that belongs to the user who opened the channel.
+    pub opener: u64,
+    /// The amount that belongs to the counterparty.
+    pub counterparty: u64,
+}
+
+/// A balance update along with the users who signed it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedBalance {
+    pub balance: ChannelBalance,
+    pub signatures: Vec<User>,
+}
+
+impl ChannelBalance {
+    /// Sign the update as the given users.
+    pub fn signed_by(self, signers: &[User]) -> SignedBalance {
+        SignedBalance {
+            balance: self,
+            signatures: signers.to_vec(),
+        }
+    }
+}
+
+/// The lifecycle of a channel that is still on chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelStatus {
+    /// Both parties are making payments off chain.
+    Open,
+    /// One party has closed the channel with the given balance, which pays out when the dispute
+    /// window ends unless the counterparty proves it is stale.
+    Closing {
+        closer: User,
+        balance: ChannelBalance,
+        dispute_ends: u64,
+    },
+}
+
+/// A payment channel between two users.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Channel {
+    pub opener: User,
+    pub counterparty: User,
+    /// The tokens locked in the channel. Every balance update must split exactly this much.
+    pub capacity: u64,
+    pub status: ChannelStatus,
+}
+
+impl Channel {
+    fn is_participant(&self, who: User) -> bool {
+        who == self.opener || who == self.counterparty
+    }
+
+    /// Whether the update is for this channel, splits its capacity, and is signed by both parties.
+    fn accepts(&self, id: ChannelId, update: &SignedBalance) -> bool {
+        let balance = &update.balance;
+        balance.channel == id
+            && balance.opener as u128 + balance.counterparty as u128 == self.capacity as u128
+            && update.signatures.contains(&self.opener)
+            && update.signatures.contains(&self.counterparty)
+    }
+}
+
+/// The state of the currency and its payment channels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelState {
+    /// The height of the current block.
+    height: u64,
+    /// Tokens of each user that are not locked in a channel. Users with no tokens are removed.
+    balances: HashMap<User, u64>,
+    channels: HashMap<ChannelId, Channel>,
+    /// The id of the next channel to be opened.
+    next_id: ChannelId,
+}
+
+impl ChannelState {
+    /// Start at genesis with the given balances.
+    pub fn new(balances: HashMap<User, u64>) -> Self {
+        ChannelState {
+            height: 0,
+            balances: balances.into_iter().filter(|(_, b)| *b > 0).collect(),
+            channels: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn balance(&self, who: User) -> u64 {
+        self.balances.get(&who).copied().unwrap_or(0)
+    }
+
+    pub fn channel(&self, id: ChannelId) -> Option<&Channel> {
+        self.channels.get(&id)
+    }
+
+    /// Every token in the system, whether free or locked in a channel.
+    fn total_tokens(&self) -> u128 {
+        let free: u128 = self.balances.values().map(|b| *b as u128).sum();
+        let locked: u128 = self.channels.values().map(|c| c.capacity as u128).sum();
+        free + locked
+    }
+
+    /// The balances that both parties of a channel would have after it pays out the given
+    /// balance. Tokens move between users through channels, so a user can end up holding more
+    /// than fits in a balance. Returns None if either party would.
+    fn payout(&self, id: ChannelId, balance: &ChannelBalance) -> Option<[(User, u64); 2]> {
+        let channel = self.channels.get(&id)?;
+        Some([
+            (
+                channel.opener,
+                self.balance(channel.opener).checked_add(balance.opener)?,
+            ),
+            (
+                channel.counterparty,
+                self.balance(channel.counterparty)
+                    .checked_add(balance.counterparty)?,
+            ),
+        ])
+    }
+
+    /// Close a channel, paying out the given balance. Returns None, changing nothing, if the
+    /// channel does not exist or either party cannot hold their payout.
+    fn pay_out(&mut self, id: ChannelId, balance: &ChannelBalance) -> Option<()> {
+        let payout = self.payout(id, balance)?;
+        self.channels.remove(&id);
+        for (who, amount) in payout {
+            if amount > 0 {
+                self.balances.insert(who, amount);
+            }
+        }
+        Some(())
+    }
+}
+
+/// The transitions of the payment channel system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelTransaction {
+    /// Open a channel to the counterparty, locking the deposit. The whole deposit initially
+    /// belongs to the opener.
+    Open {
+        opener: User,
+        counterparty: User,
+        deposit: u64,
+    },
+    /// Close an open channel immediately with a final balance signed by both parties.
+    CooperativeClose {
+        channel: ChannelId,
+        last: SignedBalance,
+    },
+    /// One party closes an open channel on their own with the latest update they hold,
+    /// or with the opening balance if there were no updates.
+    UnilateralClose {
+        who: User,
+        channel: ChannelId,
+        last: Option<SignedBalance>,
+    },
+    /// During the dispute window, the counterparty proves that the closer submitted a stale
+    /// balance by presenting a newer one. The closer forfeits the whole channel.
+    Dispute {
+        who: User,
+        channel: ChannelId,
+        newer: SignedBalance,
+    },
+}
+
+impl StateMachine for PaymentChannels {
+    type State = ChannelState;
+    type Transition = ChannelTransaction;
+
+    fn next_state(starting_state: &ChannelState, t: &ChannelTransaction) -> ChannelState {
+        let mut state = starting_state.clone();
+        match t {
+            ChannelTransaction::Open {
+                opener,
+                counterparty,
+                deposit,
+            } => {
+                let available = state.balance(*opener);
+                if opener == counterparty || *deposit == 0 || *deposit > available {
+                    return state;
+                }
+                if available == *deposit {
+                    state.balances.remove(opener);
+                } else {
+                    state.balances.insert(*opener, available - deposit);
+                }
+                state.channels.insert(
+                    state.next_id,
+                    Channel {
+                        opener: *opener,
+                        counterparty: *counterparty,
+                        capacity: *deposit,
+                        status: ChannelStatus::Open,
+                    },
+                );
+                state.next_id += 1;
+            }
+            ChannelTransaction::CooperativeClose { channel, last } => {
+                let Some(c) = state.channels.get(channel) else {
+                    return state;
+                };
+                if c.status == ChannelStatus::Open && c.accepts(*channel, last) {
+                    let _ = state.pay_out(*channel, &last.balance);
+                }
+            }
+            ChannelTransaction::UnilateralClose { who, channel, last } => {
+                let dispute_ends = state.height.saturating_add(DISPUTE_PERIOD);
+                let Some(c) = state.channels.get_mut(channel) else {
+                    return state;
+                };
+                if c.status != ChannelStatus::Open || !c.is_participant(*who) {
+                    return state;
+                }
+                let balance = match last {
+                    Some(update) if c.accepts(*channel, update) => update.balance.clone(),
+                    Some(_) => return state,
+                    None => ChannelBalance {
+                        channel: *channel,
+                        sequence: 0,
+                        opener: c.capacity,
+                        counterparty: 0,
+                    },
+                };
+                c.status = ChannelStatus::Closing {
+                    closer: *who,
+                    balance,
+                    dispute_ends,
+                };
+            }
+            ChannelTransaction::Dispute {
+                who,
+                channel,
+                newer,
+            } => {
+                let Some(c) = state.channels.get(channel) else {
+                    return state;
+                };
+                let ChannelStatus::Closing {
+                    closer, balance, ..
+                } = &c.status
+                else {
+                    return state;
+                };
+                if c.is_participant(*who)
+                    && who != closer
+                    && c.accepts(*channel, newer)
+                    && newer.balance.sequence > balance.sequence
+                {
+                    let penalty = if *who == c.opener {
+                        ChannelBalance {
+                            opener: c.capacity,
+                            counterparty: 0,
+                            ..newer.balance.clone()
+                        }
+                    } else {
+                        ChannelBalance {
+                            opener: 0,
+                            counterparty: c.capacity,
+                            ..newer.balance.clone()
+                        }
+                    };
+                    let _ = state.pay_out(*channel, &penalty);
+                }
+            }
+        }
+        state
+    }
+
+    /// Channels whose dispute window has ended pay out. A channel whose parties cannot hold
+    /// their payout stays closing, and pays out in a later block once they can.
+    ///
+    /// Blocks must begin one at a time, so any height other than the next one is ignored.
+    /// Jumping ahead would otherwise settle closing channels before the counterparty has had
+    /// the whole dispute window to respond.
+    fn begin_block(starting_state: &ChannelState, height: u64) -> ChannelState {
+        let mut state = starting_state.clone();
+        if state.height.checked_add(1) != Some(height) {
+            return state;
+        }
+        state.height = height;
+        let settled: Vec<(ChannelId, ChannelBalance)> = state
+            .channels
+            .iter()
+            .filter_map(|(id, c)| match &c.status {
+                ChannelStatus::Closing {
+                    balance,
+                    dispute_ends,
+                    ..
+                } if *dispute_ends <= height => Some((*id, balance.clone())),
+                _ => None,
+            })
+            .collect();
+        for (id, balance) in settled {
+            let _ = state.pay_out(id, &balance);
+        }
+        state
+    }
+}
+
+impl Invariants for PaymentChannels {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "tokens are never created or destroyed",
+                holds: |pre, _, post| pre.total_tokens() == post.total_tokens(),
+            },
+            Invariant {
+                name: "closing balances split exactly the channel capacity",
+                holds: |_, _, post| {
+                    post.channels.values().all(|c| match &c.status {
+                        ChannelStatus::Open => true,
+                        ChannelStatus::Closing { balance, .. } => {
+                            balance.opener as u128 + balance.counterparty as u128
+                                == c.capacity as u128
+                        }
+                    })
+                },
+            },
+            Invariant {
+                name: "users with no tokens are removed",
+                holds: |_, _, post| post.balances.values().all(|b| *b > 0),
+            },
+            Invariant {
+                name: "transactions never change the height",
+                holds: |pre, _, post| pre.height == post.height,
+            },
+        ]
+    }
+
+    fn block_invariants() -> Vec<BlockInvariant<Self>> {
+        vec![
+            BlockInvariant {
+                name: "tokens are never created or destroyed when a block starts",
+                holds: |pre, _, post| pre.total_tokens() == post.total_tokens(),
+            },
+            BlockInvariant {
+                name: "blocks begin one at a time",
+                holds: |pre, height, post| {
+                    (post.height == height && height == pre.height + 1) || post == pre
+                },
+            },
+            BlockInvariant {
+                name: "closing channels pay out once the dispute window ends",
+                holds: |_, _, post| {
+                    post.channels.iter().all(|(id, c)| match &c.status {
+                        ChannelStatus::Open => true,
+                        ChannelStatus::Closing {
+                            balance,
+                            dispute_ends,
+                            ..
+                        } => *dispute_ends > post.height || post.payout(*id, balance).is_none(),
+                    })
+                },
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for PaymentChannels {
+    fn arbitrary_transition(rng: &mut Rng, state: &ChannelState) -> ChannelTransaction {
+        let users = [User::Alice, User::Bob, User::Charlie];
+        let who = *rng.pick(&users);
+        let channel = rng.below(state.next_id + 1);
+        let capacity = state.channel(channel).map_or(100, |c| c.capacity);
+        let to_opener = rng.below(capacity + 1);
+        let signers: Vec<User> = users.iter().copied().filter(|_| rng.chance(3, 4)).collect();
+        let update = ChannelBalance {
+            channel,
+            sequence: rng.below(5),
+            opener: to_opener,
+            counterparty: capacity - to_opener + rng.below(3) / 2,
+        }
+        .signed_by(&signers);
+        match rng.below(4) {
+            0 => ChannelTransaction::Open {
+                opener: who,
+                counterparty: *rng.pick(&users),
+                deposit: rng.below(150),
+            },
+            1 => ChannelTransaction::CooperativeClose {
+                channel,
+                last: update,
+            },
+            2 => ChannelTransaction::UnilateralClose {
+                who,
+                channel,
+                last: rng.chance(3, 4).then_some(update),
+            },
+            _ => ChannelTransaction::Dispute {
+                who,
+                channel,
+                newer: update,
+            },
+        }
+    }
+
+    fn new_block_chance() -> (u64, u64) {
+        (1, 3)
+    }
+}
+
+#[cfg(test)]
+fn run(state: ChannelState, transitions: &[ChannelTransaction]) -> ChannelState {
+    transitions
+        .iter()
+        .fold(state, |state, t| PaymentChannels::next_state(&state, t))
+}
+
+/// Alice has opened channel 0 to Bob with a deposit of 100.
+#[cfg(test)]
+fn alice_to_bob() -> ChannelState {
+    let state = ChannelState::new(HashMap::from([(User::Alice, 150), (User::Bob, 10)]));
+    run(
+        state,
+        &[ChannelTransaction::Open {
+            opener: User::Alice,
+            counterparty: User::Bob,
+            deposit: 100,
+        }],
+    )
+}
+
+/// Begin every block up to and including the given height.
+#[cfg(test)]
+fn advance_to(state: ChannelState, height: u64) -> ChannelState {
+    (state.height + 1..=height).fold(state, |state, height| {
+        PaymentChannels::begin_block(&state, height)
+    })
+}
+
+#[cfg(test)]
+fn update(sequence: u64, to_bob: u64) -> ChannelBalance {
+    ChannelBalance {
+        channel: 0,
+        sequence,
+        opener: 100 - to_bob,
+        counterparty: to_bob,
+    }
+}
+
+#[test]
+fn sm_channels_cooperative_close() {
+    let state = alice_to_bob();
+    assert_eq!(state.balance(User::Alice), 50);
+    assert_eq!(state.channel(0).unwrap().capacity, 100);
+
+    // Both parties must sign the final balance.
+    let half_signed = ChannelTransaction::CooperativeClose {
+        channel: 0,
+        last: update(3, 30).signed_by(&[User::Alice]),
+    };
+    assert_eq!(PaymentChannels::next_state(&state, &half_signed), state);
+
+    let closed = PaymentChannels::next_state(
+        &state,
+        &ChannelTransaction::CooperativeClose {
+            channel: 0,
+            last: update(3, 30).signed_by(&[User::Alice, User::Bob]),
+        },
+    );
+    assert!(closed.channel(0).is_none());
+    assert_eq!(closed.balance(User::Alice), 120);
+    assert_eq!(closed.balance(User::Bob), 40);
+}
+
+#[test]
+fn sm_channels_unilateral_close_pays_out_after_dispute_window() {
+    use ChannelTransaction::*;
+
+    let state = run(
+        advance_to(alice_to_bob(), 5),
+        &[UnilateralClose {
+            who: User::Bob,
+            channel: 0,
+            last: Some(update(2, 60).signed_by(&[User::Alice, User::Bob])),
+        }],
+    );
+    let state = advance_to(state, 14);
+    assert!(matches!(
+        state.channel(0).unwrap().status,
+        ChannelStatus::Closing {
+            dispute_ends: 15,
+            ..
+        }
+    ));
+
+    let settled = PaymentChannels::begin_block(&state, 15);
+    assert!(settled.channel(0).is_none());
+    assert_eq!(settled.balance(User::Alice), 90);
+    assert_eq!(settled.balance(User::Bob), 70);
+}
+
+#[test]
+fn sm_channels_stale_close_is_penalized() {
+    use ChannelTransaction::*;
+
+    let both = [User::Alice, User::Bob];
+    // Alice has paid Bob 60, but tries to close with the update where she had only paid 20.
+    let state = run(
+        alice_to_bob(),
+        &[UnilateralClose {
+            who: User::Alice,
+            channel: 0,
+            last: Some(update(1, 20).signed_by(&both)),
+        }],
+    );
+    let dispute = |sequence| Dispute {
+        who: User::Bob,
+        channel: 0,
+        newer: update(sequence, 60).signed_by(&both),
+    };
+
+    // An update that is not newer proves nothing.
+    assert_eq!(PaymentChannels::next_state(&state, &dispute(1)), state);
+
+    let punished = PaymentChannels::next_state(&state, &dispute(2));
+    assert!(punished.channel(0).is_none());
+    assert_eq!(punished.balance(User::Alice), 50);
+    assert_eq!(punished.balance(User::Bob), 110);
+}
+
+#[test]
+fn sm_channels_height_jump_cannot_skip_the_dispute_window() {
+    use ChannelTransaction::*;
+
+    let both = [User::Alice, User::Bob];
+    let state = run(
+        alice_to_bob(),
+        &[UnilateralClose {
+            who: User::Alice,
+            channel: 0,
+            last: Some(update(1, 20).signed_by(&both)),
+        }],
+    );
+
+    // Jumping straight to the last possible height does not settle the stale balance.
+    let jumped = PaymentChannels::begin_block(&state, u64::MAX);
+    assert_eq!(jumped, state);
+
+    // So Bob still has the whole window to dispute it.
+    let state = advance_to(state, DISPUTE_PERIOD - 1);
+    let punished = PaymentChannels::next_state(
+        &state,
+        &Dispute {
+            who: User::Bob,
+            channel: 0,
+            newer: update(2, 60).signed_by(&both),
+        },
+    );
+    assert!(punished.channel(0).is_none());
+    assert_eq!(punished.balance(User::Bob), 110);
+}
+
+#[test]
+fn sm_channels_payouts_never_overflow() {
+    use ChannelTransaction::*;
+
+    // Bob already holds as many tokens as a balance can, so cannot be paid anything more.
+    let state = ChannelState::new(HashMap::from([(User::Alice, 100), (User::Bob, u64::MAX)]));
+    let state = run(
+        state,
+        &[
+            Open {
+                opener: User::Alice,
+                counterparty: User::Bob,
+                deposit: 100,
+            },
+            UnilateralClose {
+                who: User::Alice,
+                channel: 0,
+                last: Some(update(1, 40).signed_by(&[User::Alice, User::Bob])),
+            },
+        ],
+    );
+    let state = advance_to(state, DISPUTE_PERIOD);
+    assert!(state.channel(0).is_some());
+    assert_eq!(state.balance(User::Bob), u64::MAX);
+
+    // Once Bob has spent some tokens, the channel pays out in the next block.
+    let state = run(
+        state,
+        &[Open {
+            opener: User::Bob,
+            counterparty: User::Charlie,
+            deposit: 50,
+        }],
+    );
+    let state = advance_to(state, DISPUTE_PERIOD + 1);
+    assert!(state.channel(0).is_none());
+    assert_eq!(state.balance(User::Alice), 60);
+    assert_eq!(state.balance(User::Bob), u64::MAX - 10);
+}
+
+#[test]
+fn sm_channels_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    let state = ChannelState::new(HashMap::from([
+        (User::Alice, 300),
+        (User::Bob, 300),
+        (User::Charlie, 300),
+    ]));
+    fuzz::<PaymentChannels>(&state, &FuzzConfig::default()).unwrap();
+}
@@ -0,0 +1,166 @@
+//! When a blockchain client re-orgs to a different fork, it must roll its state back to the
+//! common ancestor before executing the new fork's blocks. The simplest way to support that
+//! is to keep a complete copy of the state for every block, but real-world states are large
+//! and that quickly becomes unaffordable.
+//!
+//! Instead, a state machine may optionally describe each transition by a compact diff that
+//! records just enough information to undo it. A client can then keep a single copy of the
+//! state plus a stack of per-block diffs, and revert blocks by applying the diffs in reverse.
+
+use super::StateMachine;
+
+/// A state machine whose transitions can be undone.
+///
+/// The defining property is that reverting a transition's diff on the resulting state gives
+/// back the exact starting state:
+///
+/// `revert(next_state_with_diff(s, t).0, diff) == s`
+pub trait Reversible: StateMachine {
+    /// A compact description of what a single transition changed.
+    type Diff;
+
+    /// Calculate the resulting state along with the diff needed to undo the transition.
+    /// The resulting state must be identical to the one returned by `next_state`.
+    fn next_state_with_diff(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> (Self::State, Self::Diff);
+
+    /// Undo a transition by applying its diff to the state that the transition produced.
+    fn revert(state: &Self::State, diff: &Self::Diff) -> Self::State;
+}
+
+/// A single evolving state along with the diffs of every block applied to it.
+///
+/// This is the bookkeeping a client needs to follow a chain with only one copy of the state,
+/// while still being able to re-org back to any block it has applied.
+pub struct Journal<SM: Reversible> {
+    /// The state after the most recently applied block.
+    state: SM::State,
+    /// The hash of each applied block along with the diffs of its transitions, oldest first.
+    blocks: Vec<(u64, Vec<SM::Diff>)>,
+}
+
+impl<SM: Reversible> Journal<SM> {
+    /// Start a new journal from the given genesis state.
+    pub fn new(genesis_state: SM::State) -> Self {
+        Journal {
+            state: genesis_state,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// The current state.
+    pub fn state(&self) -> &SM::State {
+        &self.state
+    }
+
+    /// How many blocks can currently be reverted.
+    pub fn depth(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// The hash of the most recently applied block, if any.
+    pub fn head(&self) -> Option<u64> {
+        self.blocks.last().map(|(hash, _)| *hash)
+    }
+
+    /// Execute all the transitions of a block and record their diffs.
+    pub fn apply_block(&mut self, block_hash: u64, transitions: &[SM::Transition]) {
+        let mut diffs = Vec::with_capacity(transitions.len());
+        for t in transitions {
+            let (next, diff) = SM::next_state_with_diff(&self.state, t);
+            self.state = next;
+            diffs.push(diff);
+        }
+        self.blocks.push((block_hash, diffs));
+    }
+
+    /// Revert the most recently applied block. Returns its hash, or None if there
+    /// are no blocks left to revert.
+    pub fn revert_block(&mut self) -> Option<u64> {
+        let (hash, diffs) = self.blocks.pop()?;
+        for diff in diffs.iter().rev() {
+            self.state = SM::revert(&self.state, diff);
+        }
+        Some(hash)
+    }
+
+    /// Revert blocks until the block with the given hash is the head. Typically this is
+    /// the common ancestor during a re-org. Returns false, leaving the journal untouched,
+    /// if the block is not in the journal.
+    pub fn revert_to(&mut self, block_hash: u64) -> bool {
+        if !self.blocks.iter().any(|(hash, _)| *hash == block_hash) {
+            return false;
+        }
+        while self.head() != Some(block_hash) {
+            self.revert_block();
+        }
+        true
+    }
+}
+
+#[test]
+fn sm_reversible_journal_reverts_blocks() {
+    use super::p4_accounted_currency::{AccountedCurrency, AccountingTransaction};
+    use super::User;
+    use std::collections::HashMap;
+
+    let mut journal = Journal::<AccountedCurrency>::new(HashMap::from([(User::Alice, 100)]));
+    journal.apply_block(
+        1,
+        &[AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 40,
+        }],
+    );
+    let after_first = journal.state().clone();
+
+    journal.apply_block(
+        2,
+        &[
+            AccountingTransaction::Mint {
+                minter: User::Charlie,
+                amount: 7,
+            },
+            AccountingTransaction::Burn {
+                burner: User::Bob,
+                amount: 100,
+            },
+        ],
+    );
+    journal.apply_block(
+        3,
+        &[AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Charlie,
+            amount: 60,
+        }],
+    );
+    assert_eq!(journal.depth(), 3);
+
+    assert!(journal.revert_to(1));
+    assert_eq!(journal.head(), Some(1));
+    assert_eq!(journal.state(), &after_first);
+
+    assert_eq!(journal.revert_block(), Some(1));
+    assert_eq!(journal.state(), &HashMap::from([(User::Alice, 100)]));
+    assert_eq!(journal.revert_block(), None);
+}
+
+#[test]
+fn sm_reversible_journal_ignores_unknown_block() {
+    use super::p1_switches::LightSwitch;
+
+    let mut journal = Journal::<LightSwitch>::new(false);
+    journal.apply_block(1, &[(), ()]);
+    journal.apply_block(2, &[()]);
+
+    assert!(!journal.revert_to(7));
+    assert_eq!(journal.depth(), 2);
+    assert!(*journal.state());
+
+    assert!(journal.revert_to(1));
+    assert!(!*journal.state());
+}
@@ -0,0 +1,526 @@
+//! The Proof of Authority engines in the consensus chapter trust a fixed list of authorities.
+//! Public chains usually want that list to be open to anyone willing to put their own money at
+//! risk. This is known as Proof of Stake. The consensus logic is unchanged, but the authority set
+//! is elected on-chain by a state machine like the one in this module.
+//!
+//! Users bond tokens to become validator candidates, or bond tokens and nominate a candidate they
+//! trust to back them instead. Time is divided into eras of a fixed number of blocks. At the start
+//! of every era, the candidates with the most backing stake are elected as the authorities for that
+//! era. Unbonded tokens stay
+//! locked for a few eras so that misbehaving authorities can still be punished after they leave.
+//!
+//! Authorities that sign two conflicting blocks in the same turn can be reported with the offence
+//! shown by a proof of their equivocation. A proof alone does not show that the offender was
+//! allowed to sign, so the report is ignored unless the offender was elected for the era of the
+//! conflicting blocks. The offender loses part of their bonded and unlocking stake, and is no
+//! longer a candidate in future elections until they choose to validate again.
+
+use super::invariants::{ArbitraryTransition, BlockInvariant, Invariant, Invariants, Rng};
+use super::{StateMachine, User};
+use crate::c3_consensus::{ConsensusAuthority, EquivocationProof, Header, Offence};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The number of eras that unbonded tokens stay locked before they become free again.
+pub const BONDING_DURATION: u64 = 2;
+
+/// The percentage of an equivocating authority's stake that is destroyed.
+pub const SLASH_PERCENT: u64 = 10;
+
+/// This state machine models a Proof of Stake election.
+pub struct Staking;
+
+/// Tokens that have been unbonded and are waiting to become free.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unlocking {
+    pub who: User,
+    pub amount: u64,
+    /// The era at which the tokens become free.
+    pub era: u64,
+}
+
+/// The state of a staking system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StakingState {
+    /// Tokens that can be bonded. Users with no free tokens are removed.
+    free: HashMap<User, u64>,
+    /// Tokens that are bonded. Users with no bonded tokens are removed.
+    bonded: HashMap<User, u64>,
+    /// Tokens that have been unbonded but are still locked.
+    unlocking: Vec<Unlocking>,
+    /// The candidate that each nominator backs with their bonded tokens. Bonded users who
+    /// are not nominating are themselves validator candidates.
+    nominations: HashMap<User, User>,
+    /// The current era.
+    era: u64,
+    /// The number of blocks in each era.
+    era_length: u64,
+    /// How many authorities are elected each era.
+    validator_count: usize,
+    /// The authorities elected for each recent era, sorted. Eras are kept for as long as their
+    /// authorities can still be punished, which is as long as their unbonded tokens stay locked.
+    elections: BTreeMap<u64, Vec<User>>,
+    /// Users who were slashed and are not candidates until they validate again.
+    chilled: HashSet<User>,
+    /// Every offence that has been punished, as (offender, turn), so that each is only punished once.
+    offences: HashSet<(User, u64)>,
+}
+
+impl StakingState {
+    /// Start in era 0 with the given free balances and no authorities. The era length must match
+    /// that of the consensus engine that enacts the elections.
+    pub fn new(free: HashMap<User, u64>, validator_count: usize, era_length: u64) -> Self {
+        assert!(era_length > 0, "eras must contain at least one block");
+        StakingState {
+            free: free.into_iter().filter(|(_, b)| *b > 0).collect(),
+            bonded: HashMap::new(),
+            unlocking: Vec::new(),
+            nominations: HashMap::new(),
+            era: 0,
+            era_length,
+            validator_count,
+            elections: BTreeMap::from([(0, Vec::new())]),
+            chilled: HashSet::new(),
+            offences: HashSet::new(),
+        }
+    }
+
+    pub fn era(&self) -> u64 {
+        self.era
+    }
+
+    /// The authorities elected for the current era.
+    pub fn elected(&self) -> &[User] {
+        self.elections.get(&self.era).map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether the offence can be punished: the offender was elected for the era the offence
+    /// happened in, that era is recent enough to remember, and the offence is not punished yet.
+    pub fn is_punishable(&self, offence: &Offence) -> bool {
+        let offender = User::from(offence.offender());
+        let era = offence.height() / self.era_length;
+        self.elections
+            .get(&era)
+            .is_some_and(|elected| elected.contains(&offender))
+            && !self.offences.contains(&(offender, offence.turn()))
+    }
+
+    pub fn free(&self, who: User) -> u64 {
+        self.free.get(&who).copied().unwrap_or(0)
+    }
+
+    pub fn bonded(&self, who: User) -> u64 {
+        self.bonded.get(&who).copied().unwrap_or(0)
+    }
+
+    /// Whether the user is a validator candidate, that is, they have bonded, are not nominating,
+    /// and have not been chilled by a slash.
+    pub fn is_candidate(&self, who: User) -> bool {
+        self.bonded.contains_key(&who)
+            && !self.nominations.contains_key(&who)
+            && !self.chilled.contains(&who)
+    }
+
+    /// The total stake backing a candidate: their own bond plus the bonds of their nominators.
+    /// Users who are not candidates have no backing.
+    pub fn backing(&self, candidate: User) -> u64 {
+        if !self.is_candidate(candidate) {
+            return 0;
+        }
+        self.nominations
+            .iter()
+            .filter(|(_, target)| **target == candidate)
+            .map(|(nominator, _)| self.bonded(*nominator))
+            .fold(self.bonded(candidate), u64::saturating_add)
+    }
+
+    /// Every token in the system, whether free, bonded, or unlocking.
+    fn total_tokens(&self) -> u128 {
+        let free: u128 = self.free.values().map(|b| *b as u128).sum();
+        let bonded: u128 = self.bonded.values().map(|b| *b as u128).sum();
+        let unlocking: u128 = self.unlocking.iter().map(|u| u.amount as u128).sum();
+        free + bonded + unlocking
+    }
+
+    /// Elect the candidates with the most backing. Ties are broken in favour of the earlier user.
+    fn elect(&self) -> Vec<User> {
+        let mut candidates: Vec<(u64, User)> = self
+            .bonded
+            .keys()
+            .map(|who| (self.backing(*who), *who))
+            .filter(|(backing, _)| *backing > 0)
+            .collect();
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        let mut elected: Vec<User> = candidates
+            .into_iter()
+            .take(self.validator_count)
+            .map(|(_, who)| who)
+            .collect();
+        elected.sort();
+        elected
+    }
+
+    /// Destroy `SLASH_PERCENT` of the offender's bonded and unlocking stake and chill them.
+    fn slash(&mut self, offender: User) {
+        let slashed = |amount: u64| amount - (amount as u128 * SLASH_PERCENT as u128 / 100) as u64;
+        if let Some(bonded) = self.bonded.remove(&offender) {
+            if slashed(bonded) > 0 {
+                self.bonded.insert(offender, slashed(bonded));
+            } else {
+                self.nominations.remove(&offender);
+            }
+        }
+        for unlocking in self.unlocking.iter_mut().filter(|u| u.who == offender) {
+            unlocking.amount = slashed(unlocking.amount);
+        }
+        self.unlocking.retain(|u| u.amount > 0);
+        self.chilled.insert(offender);
+    }
+}
+
+/// Move tokens out of one balance map and into another. Does nothing if there are not enough
+/// tokens to move.
+fn move_tokens(from: &mut HashMap<User, u64>, to: &mut HashMap<User, u64>, who: User, amount: u64) {
+    let available = from.get(&who).copied().unwrap_or(0);
+    let existing = to.get(&who).copied().unwrap_or(0);
+    if amount == 0 || available < amount || existing.checked_add(amount).is_none() {
+        return;
+    }
+    if available == amount {
+        from.remove(&who);
+    } else {
+        from.insert(who, available - amount);
+    }
+    to.insert(who, existing + amount);
+}
+
+/// The transitions that users can make in a staking system.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StakingTransaction {
+    /// Bond some free tokens.
+    Bond { who: User, amount: u64 },
+    /// Start unbonding some bonded tokens. They become free after `BONDING_DURATION` eras.
+    Unbond { who: User, amount: u64 },
+    /// Back the given candidate with all of the nominator's bonded tokens.
+    Nominate { nominator: User, candidate: User },
+    /// Stop nominating, and become a validator candidate instead.
+    Validate { who: User },
+    /// Report an authority that signed two conflicting headers. Each offence is only punished once,
+    /// and only if the offender was an authority when it happened.
+    ReportEquivocation(Offence),
+}
+
+impl StateMachine for Staking {
+    type State = StakingState;
+    type Transition = StakingTransaction;
+
+    fn next_state(starting_state: &StakingState, t: &StakingTransaction) -> StakingState {
+        let mut state = starting_state.clone();
+        match t {
+            StakingTransaction::Bond { who, amount } => {
+                move_tokens(&mut state.free, &mut state.bonded, *who, *amount);
+            }
+            StakingTransaction::Unbond { who, amount } => {
+                let mut unbonded = HashMap::new();
+                move_tokens(&mut state.bonded, &mut unbonded, *who, *amount);
+                if !unbonded.is_empty() {
+                    state.unlocking.push(Unlocking {
+                        who: *who,
+                        amount: *amount,
+                        era: state.era + BONDING_DURATION,
+                    });
+                }
+                if !state.bonded.contains_key(who) {
+                    state.nominations.remove(who);
+                }
+            }
+            StakingTransaction::Nominate {
+                nominator,
+                candidate,
+            } => {
+                if state.bonded.contains_key(nominator) && nominator != candidate {
+                    state.nominations.insert(*nominator, *candidate);
+                }
+            }
+            StakingTransaction::Validate { who } => {
+                state.nominations.remove(who);
+                state.chilled.remove(who);
+            }
+            StakingTransaction::ReportEquivocation(offence) => {
+                if state.is_punishable(offence) {
+                    let offender = User::from(offence.offender());
+                    state.offences.insert((offender, offence.turn()));
+                    state.slash(offender);
+                }
+            }
+        }
+        state
+    }
+
+    /// A new era begins with the first block whose height is a multiple of the era length, the
+    /// same boundaries that `ElectedPoa` uses. Matured unlocking tokens are freed, new
+    /// authorities are elected, and elections too old to punish are forgotten.
+    fn begin_block(starting_state: &StakingState, height: u64) -> StakingState {
+        let mut state = starting_state.clone();
+        let era = height / state.era_length;
+        if era <= state.era {
+            return state;
+        }
+        state.era = era;
+        let (matured, locked) = state
+            .unlocking
+            .into_iter()
+            .partition(|u| u.era <= state.era);
+        state.unlocking = locked;
+        for Unlocking { who, amount, .. } in matured {
+            // Cannot overflow, because users cannot move tokens to each other, so no user ever
+            // holds more tokens than they started with.
+            *state.free.entry(who).or_insert(0) += amount;
+        }
+        let elected = state.elect();
+        state.elections.insert(era, elected);
+        state.elections = state
+            .elections
+            .split_off(&era.saturating_sub(BONDING_DURATION));
+        state
+    }
+}
+
+impl Invariants for Staking {
+    fn invariants() -> Vec<Invariant<Self>> {
+        vec![
+            Invariant {
+                name: "tokens are only destroyed by slashing, and never created",
+                holds: |pre, t, post| match t {
+                    StakingTransaction::ReportEquivocation(_) => {
+                        post.total_tokens() <= pre.total_tokens()
+                    }
+                    _ => post.total_tokens() == pre.total_tokens(),
+                },
+            },
+            Invariant {
+                name: "punishable offenders are no longer candidates",
+                holds: |pre, t, post| match t {
+                    StakingTransaction::ReportEquivocation(offence) => {
+                        !pre.is_punishable(offence) || !post.is_candidate(offence.offender().into())
+                    }
+                    _ => true,
+                },
+            },
+            Invariant {
+                name: "reports that cannot be punished change nothing",
+                holds: |pre, t, post| match t {
+                    StakingTransaction::ReportEquivocation(offence) => {
+                        pre.is_punishable(offence) || pre == post
+                    }
+                    _ => true,
+                },
+            },
+            Invariant {
+                name: "authorities only change at era boundaries",
+                holds: |pre, _, post| pre.elections == post.elections,
+            },
+            Invariant {
+                name: "no more than the validator count are elected",
+                holds: |_, _, post| post.elected().len() <= post.validator_count,
+            },
+            Invariant {
+                name: "users with no tokens are removed",
+                holds: |_, _, post| {
+                    post.free
+                        .values()
+                        .chain(post.bonded.values())
+                        .all(|b| *b > 0)
+                },
+            },
+            Invariant {
+                name: "only bonded users nominate",
+                holds: |_, _, post| post.nominations.keys().all(|n| post.bonded.contains_key(n)),
+            },
+        ]
+    }
+
+    fn block_invariants() -> Vec<BlockInvariant<Self>> {
+        vec![
+            BlockInvariant {
+                name: "tokens are never created or destroyed when a block starts",
+                holds: |pre, _, post| post.total_tokens() == pre.total_tokens(),
+            },
+            BlockInvariant {
+                name: "the era follows the block height",
+                holds: |pre, height, post| post.era == pre.era.max(height / post.era_length),
+            },
+            BlockInvariant {
+                name: "authorities only change at era boundaries",
+                holds: |pre, _, post| pre.era != post.era || pre.elections == post.elections,
+            },
+            BlockInvariant {
+                name: "no more than the validator count are elected",
+                holds: |_, _, post| post.elected().len() <= post.validator_count,
+            },
+        ]
+    }
+}
+
+impl ArbitraryTransition for Staking {
+    fn arbitrary_transition(rng: &mut Rng, state: &StakingState) -> StakingTransaction {
+        let users = [User::Alice, User::Bob, User::Charlie];
+        let who = *rng.pick(&users);
+        let amount = match rng.below(10) {
+            0 => 0,
+            1 => u64::MAX,
+            _ => rng.below(60),
+        };
+        match rng.below(5) {
+            0 => StakingTransaction::Bond { who, amount },
+            1 => StakingTransaction::Unbond { who, amount },
+            2 => StakingTransaction::Nominate {
+                nominator: who,
+                candidate: *rng.pick(&users),
+            },
+            3 => StakingTransaction::Validate { who },
+            _ => {
+                let authorities = [
+                    ConsensusAuthority::Alice,
+                    ConsensusAuthority::Bob,
+                    ConsensusAuthority::Charlie,
+                ];
+                let signer = *rng.pick(&authorities);
+                let height = rng.below((state.era + 1) * state.era_length);
+                let header =
+                    |state_root| Header::partial(0, height, state_root, 0, 0).with_digest(signer);
+                let proof = EquivocationProof::new(header(0), header(1)).expect("headers conflict");
+                StakingTransaction::ReportEquivocation(proof.offence())
+            }
+        }
+    }
+
+    fn new_block_chance() -> (u64, u64) {
+        (1, 3)
+    }
+}
+
+#[cfg(test)]
+fn run(state: StakingState, transitions: &[StakingTransaction]) -> StakingState {
+    transitions
+        .iter()
+        .fold(state, |state, t| Staking::next_state(&state, t))
+}
+
+/// Start the first block of the next era.
+#[cfg(test)]
+fn new_era(state: StakingState) -> StakingState {
+    let height = (state.era + 1) * state.era_length;
+    Staking::begin_block(&state, height)
+}
+
+#[cfg(test)]
+fn three_users() -> StakingState {
+    StakingState::new(
+        HashMap::from([(User::Alice, 100), (User::Bob, 100), (User::Charlie, 100)]),
+        2,
+        2,
+    )
+}
+
+#[test]
+fn sm_staking_elects_top_stakers_at_era_boundary() {
+    use StakingTransaction::*;
+
+    let state = run(
+        three_users(),
+        &[
+            Bond {
+                who: User::Alice,
+                amount: 10,
+            },
+            Bond {
+                who: User::Bob,
+                amount: 30,
+            },
+            Bond {
+                who: User::Charlie,
+                amount: 20,
+            },
+        ],
+    );
+    assert!(state.elected().is_empty());
+
+    // Blocks within the era change nothing.
+    let state = Staking::begin_block(&state, 1);
+    assert_eq!(state.era(), 0);
+    assert!(state.elected().is_empty());
+
+    let state = Staking::begin_block(&state, 2);
+    assert_eq!(state.era(), 1);
+    assert_eq!(state.elected(), &[User::Bob, User::Charlie]);
+}
+
+#[test]
+fn sm_staking_nominations_back_candidates() {
+    use StakingTransaction::*;
+
+    let state = run(
+        three_users(),
+        &[
+            Bond {
+                who: User::Alice,
+                amount: 10,
+            },
+            Bond {
+                who: User::Bob,
+                amount: 30,
+            },
+            Bond {
+                who: User::Charlie,
+                amount: 50,
+            },
+            // Charlie prefers to back Alice than to validate.
+            Nominate {
+                nominator: User::Charlie,
+                candidate: User::Alice,
+            },
+        ],
+    );
+    let state = new_era(state);
+    assert_eq!(state.backing(User::Alice), 60);
+    assert_eq!(state.backing(User::Charlie), 0);
+    assert_eq!(state.elected(), &[User::Alice, User::Bob]);
+}
+
+#[test]
+fn sm_staking_unbonded_tokens_stay_locked() {
+    use StakingTransaction::*;
+
+    let state = run(
+        three_users(),
+        &[Bond {
+            who: User::Alice,
+            amount: 40,
+        }],
+    );
+    let state = run(
+        new_era(state),
+        &[Unbond {
+            who: User::Alice,
+            amount: 40,
+        }],
+    );
+    assert_eq!(state.free(User::Alice), 60);
+    assert_eq!(state.bonded(User::Alice), 0);
+    // Still an authority until the era ends.
+    assert_eq!(state.elected(), &[User::Alice]);
+
+    let state = new_era(state);
+    assert!(state.elected().is_empty());
+    assert_eq!(state.free(User::Alice), 60);
+
+    let state = new_era(state);
+    assert_eq!(state.free(User::Alice), 100);
+}
+
+#[test]
+fn sm_staking_invariants_hold() {
+    use super::invariants::{fuzz, FuzzConfig};
+
+    fuzz::<Staking>(&three_users(), &FuzzConfig::default()).unwrap();
+}
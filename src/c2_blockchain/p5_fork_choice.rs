@@ -55,11 +55,15 @@ impl ForkChoice for LongestChainRule {
 /// The best chain is the one with the most accumulated work.
 ///
 /// In Proof of Work chains, each block contains a certain amount of "work".
-/// Roughly speaking, the lower a block's hash is, the more work it contains,
-/// because finding a block with a low hash requires, on average, trying more
-/// nonces. Modeling the amount of work required to achieve a particular hash
-/// is out of scope for this exercise, so we will use the not-really-right-but
-/// conceptually-good-enough formula `work = THRESHOLD - block_hash`
+/// Real chains measure it as the number of hashes a block is _expected_ to take,
+/// `2^64 / target`, where the target is the threshold the block was mined against.
+/// A lucky block with a very low hash is worth no more than any other block with the
+/// same target, so a single lucky block can never outweigh a long chain.
+///
+/// Our headers do not record which target they were mined against, so in this exercise
+/// we use the not-really-right-but-conceptually-good-enough formula
+/// `work = THRESHOLD - block_hash`. Be aware that it rewards luck. The client chapter
+/// calculates work properly, from targets, in `c4_client::p3_fork_choice::HeaviestChain`.
 pub struct HeaviestChainRule;
 
 /// Mutates a block (and its embedded header) to contain more PoW difficulty.
@@ -13,6 +13,7 @@
 
 type Hash = u64;
 use super::p3_consensus::THRESHOLD;
+use crate::codec::{Decode, Encode, Error};
 use crate::hash;
 
 /// In this section we will use sum and product together to be our state. While this is only a doubling of state size
@@ -107,6 +108,60 @@ impl Block {
     }
 }
 
+impl Encode for State {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.sum.encode_to(dest);
+        self.product.encode_to(dest);
+    }
+}
+
+impl Decode for State {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(State {
+            sum: u64::decode(input)?,
+            product: u64::decode(input)?,
+        })
+    }
+}
+
+impl Encode for Header {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.parent.encode_to(dest);
+        self.height.encode_to(dest);
+        self.extrinsics_root.encode_to(dest);
+        self.state_root.encode_to(dest);
+        self.consensus_digest.encode_to(dest);
+    }
+}
+
+impl Decode for Header {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(Header {
+            parent: Hash::decode(input)?,
+            height: u64::decode(input)?,
+            extrinsics_root: Hash::decode(input)?,
+            state_root: Hash::decode(input)?,
+            consensus_digest: u64::decode(input)?,
+        })
+    }
+}
+
+impl Encode for Block {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.header.encode_to(dest);
+        self.body.encode_to(dest);
+    }
+}
+
+impl Decode for Block {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(Block {
+            header: Header::decode(input)?,
+            body: Vec::decode(input)?,
+        })
+    }
+}
+
 /// Create an invalid child block of the given block. The returned block should have an
 /// incorrect state root. Although the child block is invalid, the header should be valid.
 ///
@@ -269,3 +324,25 @@ fn bc_6_student_invalid_block_really_is_invalid() {
     // Make sure that the block is not valid when executed.
     assert!(!gb.verify_sub_chain(&state, &[b1]));
 }
+
+#[test]
+fn bc_6_codec_round_trip() {
+    use crate::codec::decode_all;
+
+    let state = State { sum: 6, product: 9 };
+    assert_eq!(decode_all(&state.encode()), Ok(state));
+
+    let block = Block {
+        header: Header {
+            parent: 1,
+            height: 2,
+            extrinsics_root: 3,
+            state_root: 4,
+            consensus_digest: 5,
+        },
+        body: vec![1, 2, 3],
+    };
+    let encoded = block.encode();
+    assert_eq!(encoded.len(), 5 * 8 + 1 + 3 * 8);
+    assert_eq!(decode_all(&encoded), Ok(block));
+}
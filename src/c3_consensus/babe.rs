@@ -0,0 +1,434 @@
+//! Round robin by slot throttles authorities and survives an authority going offline, but everyone
+//! knows well in advance exactly who will author each slot. An attacker can knock that one node
+//! offline right before its slot, and keep doing so to halt the chain.
+//!
+//! BABE (Blind Assignment for Blockchain Extension) keeps the slot leaders secret until they author.
+//! In each slot, every authority evaluates a verifiable random function, or VRF, over the epoch's
+//! randomness and the slot number. Only the authority's own key can evaluate it, but anyone can
+//! check the result using the proof that comes with it. If the output is below a threshold, the
+//! authority has won the slot and may author a "primary" block. Authorities with more stake get a
+//! higher threshold, and so win more slots.
+//!
+//! Sometimes nobody wins a slot. So that the chain still makes progress, each slot also has a
+//! secondary author, chosen round robin by the epoch randomness, who may author a "secondary" block.
+//!
+//! The epoch randomness must not be predictable long in advance, or we are back to public leaders.
+//! So every primary block folds its VRF output into an accumulator, and the accumulator at the end
+//! of one epoch becomes the randomness of the next.
+//!
+//! We have no real cryptography in this tutorial. Just as a `ConsensusAuthority` stands in for a
+//! signature, a hash of the authority, randomness and slot stands in for the VRF output. Verifying
+//! the "proof" means recomputing that hash.
+
+use super::equivocation::SignedDigest;
+use super::timestamp::slot_at;
+use super::{Consensus, ConsensusAuthority, Header};
+use crate::codec::{decode_tag, Decode, Encode, Error};
+
+/// The output of an authority's VRF for one slot, along with the proof that it is correct.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct VrfProof {
+    pub output: u64,
+}
+
+impl VrfProof {
+    /// Evaluate the VRF as the given authority. Only that authority could do this for real.
+    pub fn evaluate(author: ConsensusAuthority, randomness: u64, slot: u64) -> Self {
+        VrfProof {
+            output: crate::hash(&(author, randomness, slot)),
+        }
+    }
+
+    /// Check that this is the authority's VRF output for the slot. Anyone can do this.
+    pub fn verify(&self, author: ConsensusAuthority, randomness: u64, slot: u64) -> bool {
+        *self == Self::evaluate(author, randomness, slot)
+    }
+}
+
+/// How the author of a block claims the right to author in its slot.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SlotClaim {
+    /// The author won the slot lottery, as proven by its VRF output.
+    Primary(VrfProof),
+    /// The author is the slot's secondary author.
+    Secondary,
+}
+
+/// The digest of a BABE block.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BabeDigest {
+    pub slot: u64,
+    pub author: ConsensusAuthority,
+    pub claim: SlotClaim,
+    /// The randomness of the block's epoch, which the slot lottery was run with.
+    pub randomness: u64,
+    /// The VRF outputs of the epoch so far, up to and including this block's.
+    pub accumulator: u64,
+}
+
+impl SignedDigest for BabeDigest {
+    fn signer(&self) -> ConsensusAuthority {
+        self.author
+    }
+
+    /// Several authorities may win the same slot, but each may only author once in it.
+    fn turn(&self, _: u64) -> u64 {
+        self.slot
+    }
+}
+
+impl Encode for VrfProof {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.output.encode_to(dest);
+    }
+}
+
+impl Decode for VrfProof {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(VrfProof {
+            output: u64::decode(input)?,
+        })
+    }
+}
+
+impl Encode for SlotClaim {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            SlotClaim::Primary(proof) => {
+                dest.push(0);
+                proof.encode_to(dest);
+            }
+            SlotClaim::Secondary => dest.push(1),
+        }
+    }
+}
+
+impl Decode for SlotClaim {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(SlotClaim::Primary(VrfProof::decode(input)?)),
+            1 => Ok(SlotClaim::Secondary),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for BabeDigest {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.slot.encode_to(dest);
+        self.author.encode_to(dest);
+        self.claim.encode_to(dest);
+        self.randomness.encode_to(dest);
+        self.accumulator.encode_to(dest);
+    }
+}
+
+impl Decode for BabeDigest {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(BabeDigest {
+            slot: u64::decode(input)?,
+            author: ConsensusAuthority::decode(input)?,
+            claim: SlotClaim::decode(input)?,
+            randomness: u64::decode(input)?,
+            accumulator: u64::decode(input)?,
+        })
+    }
+}
+
+/// A Proof of Stake consensus engine with a secret, stake-weighted slot lottery.
+pub struct Babe {
+    /// Every authority along with its stake.
+    authorities: Vec<(ConsensusAuthority, u64)>,
+    /// The number of slots in each epoch.
+    epoch_length: u64,
+    /// The length of each slot, in milliseconds.
+    slot_duration: u64,
+    /// The chance, in thousandths, that an authority holding all of the stake wins a slot.
+    primary_per_thousand: u64,
+    /// The authority that this node authors blocks as.
+    signer: ConsensusAuthority,
+}
+
+impl Babe {
+    pub fn new(
+        authorities: Vec<(ConsensusAuthority, u64)>,
+        epoch_length: u64,
+        slot_duration: u64,
+        primary_per_thousand: u64,
+        signer: ConsensusAuthority,
+    ) -> Self {
+        assert!(!authorities.is_empty(), "BABE needs at least one authority");
+        assert!(
+            epoch_length > 0 && slot_duration > 0,
+            "epochs and slots must not be empty"
+        );
+        Babe {
+            authorities,
+            epoch_length,
+            slot_duration,
+            primary_per_thousand: primary_per_thousand.min(1000),
+            signer,
+        }
+    }
+
+    /// The digest of the genesis block, which seeds the first epoch's randomness.
+    pub fn genesis_digest(&self, randomness: u64) -> BabeDigest {
+        BabeDigest {
+            slot: 0,
+            author: self.authorities[0].0,
+            claim: SlotClaim::Secondary,
+            randomness,
+            accumulator: randomness,
+        }
+    }
+
+    /// The epoch that the given slot belongs to.
+    pub fn epoch_of(&self, slot: u64) -> u64 {
+        slot / self.epoch_length
+    }
+
+    /// The VRF output below which the given authority wins a slot. Proportional to its stake.
+    pub fn threshold(&self, authority: ConsensusAuthority) -> u64 {
+        let total: u128 = self
+            .authorities
+            .iter()
+            .map(|(_, stake)| *stake as u128)
+            .sum();
+        let stake = self
+            .authorities
+            .iter()
+            .find(|(a, _)| *a == authority)
+            .map_or(0, |(_, stake)| *stake as u128);
+        if total == 0 {
+            return 0;
+        }
+        (u64::MAX as u128 * self.primary_per_thousand as u128 / 1000 * stake / total) as u64
+    }
+
+    /// The authority who may author a secondary block in the given slot.
+    pub fn secondary_author(&self, randomness: u64, slot: u64) -> ConsensusAuthority {
+        let index = crate::hash(&(randomness, slot)) % self.authorities.len() as u64;
+        self.authorities[index as usize].0
+    }
+
+    /// The randomness and accumulator that a child of the given parent, in the given slot,
+    /// starts from. A new epoch takes its randomness from everything accumulated before it.
+    fn epoch_randomness(&self, parent: &BabeDigest, slot: u64) -> (u64, u64) {
+        let epoch = self.epoch_of(slot);
+        if epoch == self.epoch_of(parent.slot) {
+            return (parent.randomness, parent.accumulator);
+        }
+        let randomness = crate::hash(&(parent.accumulator, epoch));
+        (randomness, randomness)
+    }
+
+    /// Check whether the given authority may author in the slot, and if so how.
+    fn claim_slot(
+        &self,
+        author: ConsensusAuthority,
+        randomness: u64,
+        slot: u64,
+    ) -> Option<SlotClaim> {
+        let proof = VrfProof::evaluate(author, randomness, slot);
+        if proof.output < self.threshold(author) {
+            Some(SlotClaim::Primary(proof))
+        } else if self.secondary_author(randomness, slot) == author {
+            Some(SlotClaim::Secondary)
+        } else {
+            None
+        }
+    }
+}
+
+impl Consensus for Babe {
+    type Digest = BabeDigest;
+
+    /// Check that the slot matches the timestamp and comes after the parent's, that the epoch
+    /// randomness was carried over correctly, and that the author's claim on the slot is valid.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        if digest.slot <= parent_digest.slot
+            || digest.slot != slot_at(header.timestamp, self.slot_duration)
+        {
+            return false;
+        }
+        let (randomness, accumulator) = self.epoch_randomness(parent_digest, digest.slot);
+        if digest.randomness != randomness {
+            return false;
+        }
+        match digest.claim {
+            SlotClaim::Primary(proof) => {
+                proof.verify(digest.author, randomness, digest.slot)
+                    && proof.output < self.threshold(digest.author)
+                    && digest.accumulator == crate::hash(&(accumulator, proof.output))
+            }
+            SlotClaim::Secondary => {
+                self.secondary_author(randomness, digest.slot) == digest.author
+                    && digest.accumulator == accumulator
+            }
+        }
+    }
+
+    /// Author in the slot of the partial header's timestamp, if this node won it or is its
+    /// secondary author.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let slot = slot_at(partial_header.timestamp, self.slot_duration);
+        if slot <= parent_digest.slot {
+            return None;
+        }
+        let (randomness, accumulator) = self.epoch_randomness(parent_digest, slot);
+        let claim = self.claim_slot(self.signer, randomness, slot)?;
+        let accumulator = match claim {
+            SlotClaim::Primary(proof) => crate::hash(&(accumulator, proof.output)),
+            SlotClaim::Secondary => accumulator,
+        };
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: BabeDigest {
+                slot,
+                author: self.signer,
+                claim,
+                randomness,
+                accumulator,
+            },
+        })
+    }
+
+    fn human_name() -> String {
+        "BABE".into()
+    }
+}
+
+#[cfg(test)]
+fn babe_as(signer: ConsensusAuthority) -> Babe {
+    use ConsensusAuthority::*;
+    Babe::new(
+        vec![(Alice, 300), (Bob, 100), (Charlie, 0)],
+        10,
+        1_000,
+        500,
+        signer,
+    )
+}
+
+/// Every block that any of the authorities could author in the given slot.
+#[cfg(test)]
+fn authored_in_slot(parent: &BabeDigest, slot: u64) -> Vec<Header<BabeDigest>> {
+    use ConsensusAuthority::*;
+    [Alice, Bob, Charlie]
+        .into_iter()
+        .filter_map(|author| {
+            babe_as(author).seal(parent, Header::partial(0, slot, 0, 0, slot * 1_000 + 1))
+        })
+        .collect()
+}
+
+#[test]
+fn stake_weighted_slot_lottery() {
+    use ConsensusAuthority::*;
+    let engine = babe_as(Alice);
+    let genesis = engine.genesis_digest(7);
+
+    let mut primaries = std::collections::HashMap::new();
+    let mut secondaries = 0;
+    for slot in 1..=1000 {
+        let blocks = authored_in_slot(&genesis, slot);
+        // Every slot has a secondary author, so no slot goes empty.
+        assert!(!blocks.is_empty());
+        for block in blocks {
+            assert!(engine.validate(&genesis, &block));
+            match block.consensus_digest.claim {
+                SlotClaim::Primary(_) => {
+                    *primaries.entry(block.consensus_digest.author).or_insert(0) += 1
+                }
+                SlotClaim::Secondary => secondaries += 1,
+            }
+        }
+    }
+
+    // Alice holds three quarters of the stake, so wins about 375 of the slots, and Bob about 125.
+    // Charlie has no stake and never wins, but still takes secondary slots.
+    let alice = primaries[&Alice];
+    let bob = primaries[&Bob];
+    assert!((300..450).contains(&alice), "alice won {alice}");
+    assert!((80..170).contains(&bob), "bob won {bob}");
+    assert!(!primaries.contains_key(&Charlie));
+    assert!(secondaries > 0);
+}
+
+#[test]
+fn slot_claims_cannot_be_forged() {
+    use ConsensusAuthority::*;
+    let engine = babe_as(Alice);
+    let genesis = engine.genesis_digest(7);
+    let in_slot = |slot: u64| Header::partial(0, slot, 0, 0, slot * 1_000 + 1);
+
+    let slot = (1..)
+        .find(|slot| {
+            matches!(
+                babe_as(Bob).seal(&genesis, in_slot(*slot)),
+                Some(Header {
+                    consensus_digest: BabeDigest {
+                        claim: SlotClaim::Primary(_),
+                        ..
+                    },
+                    ..
+                })
+            )
+        })
+        .unwrap();
+    let block = babe_as(Bob).seal(&genesis, in_slot(slot)).unwrap();
+    assert!(engine.validate(&genesis, &block));
+
+    // Charlie cannot present Bob's winning output as his own.
+    let mut stolen = block.clone();
+    stolen.consensus_digest.author = Charlie;
+    assert!(!engine.validate(&genesis, &stolen));
+
+    // Nor can Bob claim the slot against different randomness.
+    let mut rerolled = block.clone();
+    rerolled.consensus_digest.randomness += 1;
+    assert!(!engine.validate(&genesis, &rerolled));
+
+    // Slots must match the timestamp, and always move forward.
+    let mut early = block.clone();
+    early.timestamp -= 1_000;
+    assert!(!engine.validate(&genesis, &early));
+    assert!(!engine.validate(&block.consensus_digest, &block));
+    assert_eq!(
+        babe_as(Bob).seal(&block.consensus_digest, in_slot(slot)),
+        None
+    );
+}
+
+#[test]
+fn epoch_randomness_accumulates_vrf_outputs() {
+    let engine = babe_as(ConsensusAuthority::Alice);
+    let genesis = engine.genesis_digest(7);
+
+    // Two chains through the first epoch with different primary authors.
+    let follow = |pick: usize| {
+        let mut parent = genesis;
+        for slot in 1..engine.epoch_length {
+            let blocks = authored_in_slot(&parent, slot);
+            parent = blocks[pick.min(blocks.len() - 1)].consensus_digest;
+            assert_eq!(parent.randomness, 7);
+        }
+        authored_in_slot(&parent, engine.epoch_length)[0].consensus_digest
+    };
+    let first = follow(0);
+    let last = follow(2);
+
+    // The next epoch's randomness depends on the VRF outputs that went into each chain.
+    assert_eq!(engine.epoch_of(first.slot), 1);
+    assert_ne!(first.randomness, 7);
+    assert_ne!(first.randomness, last.randomness);
+}
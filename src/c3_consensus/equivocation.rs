@@ -0,0 +1,252 @@
+//! Authority-based consensus engines trust each authority to sign at most one block in each of
+//! its turns. An authority that signs two different headers for the same height or slot is said
+//! to equivocate. Equivocation lets a malicious authority fork the chain at will, showing one
+//! branch to some nodes and another branch to the rest.
+//!
+//! Equivocation cannot be prevented, but it can be detected after the fact. The two conflicting
+//! headers, both signed by the same authority for the same turn, prove the offence. Such a proof
+//! can be submitted on chain, where the staking system punishes the offender.
+//!
+//! Signatures in this tutorial are only names, so anyone can produce two conflicting headers in
+//! anyone's name. A proof only shows that the headers conflict. Whoever acts on it must still
+//! check that the offender really was an authority when the headers were signed.
+
+use super::{ConsensusAuthority, Header};
+use std::collections::HashMap;
+
+/// A consensus digest that carries an authority's signature.
+pub trait SignedDigest {
+    /// The authority that signed the header.
+    fn signer(&self) -> ConsensusAuthority;
+
+    /// The turn that the header was signed for. Each authority must sign at most one header per
+    /// turn. By default the turn is the block height, but slot-based engines use their slot.
+    fn turn(&self, height: u64) -> u64 {
+        height
+    }
+}
+
+impl SignedDigest for ConsensusAuthority {
+    fn signer(&self) -> ConsensusAuthority {
+        *self
+    }
+}
+
+/// Proof that an authority signed two different headers for the same turn.
+///
+/// Proofs can only be constructed from conflicting headers, but nothing checks that the signer
+/// was allowed to sign them. See the module docs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EquivocationProof<D> {
+    first: Header<D>,
+    second: Header<D>,
+}
+
+impl<D: SignedDigest + Eq> EquivocationProof<D> {
+    /// Build a proof from two headers. Returns None unless the headers are different, but were
+    /// signed by the same authority for the same turn.
+    pub fn new(first: Header<D>, second: Header<D>) -> Option<Self> {
+        let conflicting = first != second
+            && first.consensus_digest.signer() == second.consensus_digest.signer()
+            && turn(&first) == turn(&second);
+        conflicting.then_some(EquivocationProof { first, second })
+    }
+
+    /// The authority that equivocated.
+    pub fn offender(&self) -> ConsensusAuthority {
+        self.first.consensus_digest.signer()
+    }
+
+    /// The turn in which the authority equivocated.
+    pub fn turn(&self) -> u64 {
+        turn(&self.first)
+    }
+
+    /// The two conflicting headers.
+    pub fn headers(&self) -> (&Header<D>, &Header<D>) {
+        (&self.first, &self.second)
+    }
+
+    /// The offence that this proof shows, independent of the engine's digest type.
+    pub fn offence(&self) -> Offence {
+        Offence {
+            offender: self.offender(),
+            turn: self.turn(),
+            height: self.first.height,
+        }
+    }
+}
+
+/// What an equivocation proof shows, without the headers themselves. Offences look the same
+/// whichever engine the proof came from, so they can be reported to a single staking system.
+/// They can only be obtained from a proof.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Offence {
+    offender: ConsensusAuthority,
+    turn: u64,
+    height: u64,
+}
+
+impl Offence {
+    /// The authority that equivocated.
+    pub fn offender(&self) -> ConsensusAuthority {
+        self.offender
+    }
+
+    /// The turn in which the authority equivocated.
+    pub fn turn(&self) -> u64 {
+        self.turn
+    }
+
+    /// The height of the first conflicting header. This decides which authority set the offender
+    /// should have belonged to.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+}
+
+fn turn<D: SignedDigest>(header: &Header<D>) -> u64 {
+    header.consensus_digest.turn(header.height)
+}
+
+/// Watches imported headers for authorities that sign more than once in a turn.
+pub struct EquivocationDetector<D> {
+    /// The first header seen from each authority in each turn.
+    seen: HashMap<(ConsensusAuthority, u64), Header<D>>,
+}
+
+impl<D> Default for EquivocationDetector<D> {
+    fn default() -> Self {
+        EquivocationDetector {
+            seen: HashMap::new(),
+        }
+    }
+}
+
+impl<D: SignedDigest + Clone + Eq> EquivocationDetector<D> {
+    /// Note a header that passed consensus validation. If its signer already signed a different
+    /// header in the same turn, returns a proof of the equivocation.
+    pub fn observe(&mut self, header: &Header<D>) -> Option<EquivocationProof<D>> {
+        let key = (header.consensus_digest.signer(), turn(header));
+        match self.seen.get(&key) {
+            Some(first) => EquivocationProof::new(first.clone(), header.clone()),
+            None => {
+                self.seen.insert(key, header.clone());
+                None
+            }
+        }
+    }
+
+    /// Forget headers from turns before the given one. Equivocations that old can no longer
+    /// be punished, so there is no point in remembering them.
+    pub fn prune_before(&mut self, turn: u64) {
+        self.seen.retain(|(_, t), _| *t >= turn);
+    }
+}
+
+#[cfg(test)]
+fn signed_header(
+    height: u64,
+    state_root: u64,
+    signer: ConsensusAuthority,
+) -> Header<ConsensusAuthority> {
+    Header {
+        parent: 0,
+        height,
+        state_root,
+        extrinsics_root: 0,
+        timestamp: 0,
+        consensus_digest: signer,
+    }
+}
+
+#[test]
+fn detector_catches_double_signing() {
+    use ConsensusAuthority::*;
+
+    let mut detector = EquivocationDetector::default();
+    assert!(detector.observe(&signed_header(1, 10, Alice)).is_none());
+    // Seeing the same header twice is fine, as is another authority signing at the same height.
+    assert!(detector.observe(&signed_header(1, 10, Alice)).is_none());
+    assert!(detector.observe(&signed_header(1, 20, Bob)).is_none());
+    assert!(detector.observe(&signed_header(2, 20, Alice)).is_none());
+
+    let proof = detector.observe(&signed_header(1, 20, Alice)).unwrap();
+    assert_eq!(proof.offender(), Alice);
+    assert_eq!(proof.turn(), 1);
+    assert_eq!(proof.headers().0.state_root, 10);
+
+    detector.prune_before(2);
+    assert!(detector.observe(&signed_header(1, 30, Alice)).is_none());
+}
+
+#[test]
+fn proofs_require_conflicting_headers() {
+    use ConsensusAuthority::*;
+
+    let header = signed_header(1, 10, Alice);
+    assert!(EquivocationProof::new(header.clone(), header.clone()).is_none());
+    assert!(EquivocationProof::new(header.clone(), signed_header(1, 20, Bob)).is_none());
+    assert!(EquivocationProof::new(header.clone(), signed_header(2, 20, Alice)).is_none());
+    assert!(EquivocationProof::new(header, signed_header(1, 20, Alice)).is_some());
+}
+
+#[test]
+fn staking_slashes_reported_equivocation() {
+    use crate::c1_state_machine::{Staking, StakingState, StakingTransaction, StateMachine, User};
+    use std::collections::HashMap;
+
+    let staking = StakingState::new(HashMap::from([(User::Alice, 100), (User::Bob, 100)]), 2, 10);
+    let staking = [
+        StakingTransaction::Bond {
+            who: User::Alice,
+            amount: 100,
+        },
+        StakingTransaction::Bond {
+            who: User::Bob,
+            amount: 50,
+        },
+    ]
+    .iter()
+    .fold(staking, |state, t| Staking::next_state(&state, t));
+    let staking = Staking::begin_block(&staking, 10);
+    assert_eq!(staking.elected(), &[User::Alice, User::Bob]);
+
+    let report = |height, signer| {
+        let proof = EquivocationProof::new(
+            signed_header(height, 10, signer),
+            signed_header(height, 20, signer),
+        )
+        .unwrap();
+        StakingTransaction::ReportEquivocation(proof.offence())
+    };
+
+    // Anyone can build conflicting headers in someone else's name. They only count if that
+    // someone was an authority for the era that the headers belong to.
+    assert_eq!(
+        Staking::next_state(&staking, &report(7, ConsensusAuthority::Alice)),
+        staking
+    );
+    assert_eq!(
+        Staking::next_state(&staking, &report(17, ConsensusAuthority::Charlie)),
+        staking
+    );
+
+    let report = report(17, ConsensusAuthority::Alice);
+    let slashed = Staking::next_state(&staking, &report);
+    assert_eq!(slashed.bonded(User::Alice), 90);
+    assert!(!slashed.is_candidate(User::Alice));
+
+    // The same offence cannot be punished twice.
+    assert_eq!(Staking::next_state(&slashed, &report), slashed);
+
+    // Alice is left out of the next election until she validates again.
+    let next_era = Staking::begin_block(&slashed, 20);
+    assert_eq!(next_era.elected(), &[User::Bob]);
+    let revalidated = Staking::next_state(
+        &next_era,
+        &StakingTransaction::Validate { who: User::Alice },
+    );
+    let revalidated = Staking::begin_block(&revalidated, 30);
+    assert_eq!(revalidated.elected(), &[User::Alice, User::Bob]);
+}
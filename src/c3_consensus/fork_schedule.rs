@@ -0,0 +1,353 @@
+//! `Forked` changes the rules exactly once, and only knows its engines by type, so they cannot be
+//! configured with thresholds or authority lists. Real chains fork again and again, each time with
+//! new parameters or entirely new rules.
+//!
+//! A `ForkSchedule` holds a list of configured engines, each with the height at which it activates.
+//! Every block is checked by the engine that is active at its height. The engines are given as a
+//! nested tuple list, as in the `interleaved` module, and the digest is the digest of whichever
+//! engine sealed the block.
+//!
+//! At each fork the first block under the new rules has a parent sealed under the old rules, whose
+//! digest the new engine cannot read. So each engine is configured with an initial digest, which is
+//! the parent digest its first block is checked against. That is where the new rules pick up any
+//! state they need, like the starting difficulty or the first slot.
+//!
+//! Schedules can be configured in a chain spec, so that a network can plan its forks without
+//! recompiling the client. The spec lists one fork per engine, each configuring its engine as
+//! usual, along with the height `at` which it activates and its `initial_digest`.
+//!
+//! ```json
+//! {
+//!     "engine": "fork_schedule",
+//!     "forks": [
+//!         { "at": 0, "engine": "pow", "threshold": 1000, "initial_digest": 0 },
+//!         { "at": 100, "engine": "poa", "authorities": ["Alice"], "initial_digest": "Alice" }
+//!     ]
+//! }
+//! ```
+
+use super::interleaved::Engines;
+use super::{expect_engine, Consensus, GenesisDigest, Header};
+use crate::json::{self, FromJson, Json};
+
+/// A consensus engine that changes its rules at a list of activation heights.
+pub struct ForkSchedule<E: Engines> {
+    engines: E,
+    /// The height at which each engine activates, in the same order as the engines.
+    activations: Vec<u64>,
+    /// The parent digest that each engine's first block is checked against.
+    initial: E::Latest,
+}
+
+impl<E: Engines> ForkSchedule<E> {
+    /// Create a schedule in which the engine at each index activates at the height at the same
+    /// index. The first engine applies from genesis, and later activations must be increasing.
+    pub fn new(engines: E, activations: Vec<u64>, initial: E::Latest) -> Self {
+        if let Err(e) = check_activations::<E>(&activations) {
+            panic!("{e}");
+        }
+        ForkSchedule {
+            engines,
+            activations,
+            initial,
+        }
+    }
+
+    /// The index of the engine whose rules apply at the given height.
+    pub fn active_at(&self, height: u64) -> usize {
+        self.activations
+            .iter()
+            .rposition(|activation| *activation <= height)
+            .unwrap_or(0)
+    }
+
+    /// The height at which the engine at the given index activates.
+    pub fn activation_height(&self, index: usize) -> Option<u64> {
+        self.activations.get(index).copied()
+    }
+
+    /// The next fork after the given height, as its activation height and engine index.
+    pub fn next_fork(&self, height: u64) -> Option<(u64, usize)> {
+        let index = self.active_at(height) + 1;
+        self.activation_height(index)
+            .map(|activation| (activation, index))
+    }
+
+    /// The latest digest of every engine, as seen by a child of the given parent. The parent's
+    /// own engine continues from the parent's digest, and every other engine starts from its
+    /// initial digest.
+    fn latest(&self, parent_digest: &E::Digest) -> E::Latest {
+        E::update(&self.initial, parent_digest)
+    }
+}
+
+/// Check that there is one activation height per engine, that the first engine applies from
+/// genesis, and that later activations are increasing.
+fn check_activations<E: Engines>(activations: &[u64]) -> Result<(), &'static str> {
+    if activations.len() != E::count() {
+        return Err("every engine needs an activation height");
+    }
+    if activations.first() != Some(&0) {
+        return Err("the first engine must apply from genesis");
+    }
+    if !activations.windows(2).all(|pair| pair[0] < pair[1]) {
+        return Err("activation heights must be increasing");
+    }
+    Ok(())
+}
+
+/// A list of engines that can be configured from the forks of a chain spec.
+pub trait ForksFromJson: Engines + Sized {
+    /// Configure each engine, and read its initial digest, from the fork at the same index.
+    fn from_forks(forks: &[Json]) -> Result<(Self, Self::Latest), json::Error>;
+}
+
+impl ForksFromJson for () {
+    fn from_forks(forks: &[Json]) -> Result<((), ()), json::Error> {
+        match forks {
+            [] => Ok(((), ())),
+            _ => Err(json::Error::Invalid(
+                "the chain spec has more forks than the chain has engines".into(),
+            )),
+        }
+    }
+}
+
+impl<A, Rest> ForksFromJson for (A, Rest)
+where
+    A: Consensus + FromJson,
+    A::Digest: FromJson,
+    Rest: ForksFromJson,
+{
+    fn from_forks(forks: &[Json]) -> Result<(Self, Self::Latest), json::Error> {
+        let (fork, rest) = forks.split_first().ok_or_else(|| {
+            json::Error::Invalid("the chain spec has fewer forks than the chain has engines".into())
+        })?;
+        let (engines, initial) = Rest::from_forks(rest)?;
+        Ok((
+            (A::from_json(fork)?, engines),
+            (fork.parse_field("initial_digest")?, initial),
+        ))
+    }
+}
+
+/// Configured in a chain spec as `{ "engine": "fork_schedule", "forks": [...] }`, as shown in
+/// the module docs.
+impl<E: ForksFromJson> FromJson for ForkSchedule<E> {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        expect_engine(j, "fork_schedule")?;
+        let forks = j.field("forks")?.as_array()?;
+        let activations = forks
+            .iter()
+            .map(|fork| fork.parse_field("at"))
+            .collect::<Result<Vec<u64>, _>>()?;
+        check_activations::<E>(&activations).map_err(|e| json::Error::Invalid(e.into()))?;
+        let (engines, initial) = E::from_forks(forks)?;
+        Ok(ForkSchedule {
+            engines,
+            activations,
+            initial,
+        })
+    }
+}
+
+impl<E: Engines> Consensus for ForkSchedule<E> {
+    type Digest = E::Digest;
+
+    /// Check the header with the engine that is active at its height.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let partial = Header {
+            parent: header.parent,
+            height: header.height,
+            state_root: header.state_root,
+            extrinsics_root: header.extrinsics_root,
+            timestamp: header.timestamp,
+            consensus_digest: (),
+        };
+        self.engines.validate(
+            self.active_at(header.height),
+            &self.latest(parent_digest),
+            &partial,
+            &header.consensus_digest,
+        )
+    }
+
+    /// Seal the header with the engine that is active at its height.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let consensus_digest = self.engines.seal(
+            self.active_at(partial_header.height),
+            &self.latest(parent_digest),
+            partial_header.clone(),
+        )?;
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest,
+        })
+    }
+
+    fn human_name() -> String {
+        "Fork Schedule".into()
+    }
+}
+
+/// Genesis counts as a block of the first engine, carrying its initial digest. The first child is
+/// then checked against the initial digest of every engine, just like at a fork.
+impl<E: Engines> GenesisDigest for ForkSchedule<E> {
+    fn genesis_digest(&self) -> E::Digest {
+        E::first(&self.initial).expect("every schedule has an engine from genesis")
+    }
+}
+
+#[cfg(test)]
+type ThreeEras = (
+    super::RetargetingPow,
+    (super::ThresholdPoa, (super::UnclePow, ())),
+);
+
+/// Proof of work from genesis, then a single authority from height 4, then proof of work with
+/// uncles and an easier threshold from height 7.
+#[cfg(test)]
+fn three_eras() -> ForkSchedule<ThreeEras> {
+    use super::{
+        ConsensusAuthority, RetargetingPow, ThresholdPoa, ThresholdSeal, UncleDigest, UnclePow,
+    };
+    use crate::c1_state_machine::User;
+
+    let pow = RetargetingPow {
+        interval: 2,
+        block_time: 1_000,
+        initial_target: u64::MAX / 4,
+        rules: super::TimestampRules::new(super::SystemClock),
+    };
+    let poa = ThresholdPoa::new(
+        vec![ConsensusAuthority::Alice],
+        1,
+        ConsensusAuthority::Alice,
+    );
+    let uncles = UnclePow::new(u64::MAX / 2, User::Bob);
+    let initial = (
+        pow.genesis_digest(0),
+        (
+            ThresholdSeal::default(),
+            (
+                UncleDigest {
+                    nonce: 0,
+                    beneficiary: User::Bob,
+                    uncles: Vec::new(),
+                },
+                (),
+            ),
+        ),
+    );
+    ForkSchedule::new((pow, (poa, (uncles, ()))), vec![0, 4, 7], initial)
+}
+
+#[cfg(test)]
+fn seal_chain(
+    engine: &ForkSchedule<ThreeEras>,
+    length: u64,
+) -> Vec<Header<<ThreeEras as Engines>::Digest>> {
+    let mut parent = engine.genesis_digest();
+    let mut chain = Vec::new();
+    for height in 1..=length {
+        let partial = Header::partial(0, height, 0, 0, height * 1_000);
+        let header = engine.seal(&parent, partial).unwrap();
+        assert!(engine.validate(&parent, &header));
+        parent = header.consensus_digest.clone();
+        chain.push(header);
+    }
+    chain
+}
+
+#[test]
+fn schedule_reports_which_rules_apply() {
+    let engine = three_eras();
+    assert_eq!(engine.active_at(0), 0);
+    assert_eq!(engine.active_at(3), 0);
+    assert_eq!(engine.active_at(4), 1);
+    assert_eq!(engine.active_at(6), 1);
+    assert_eq!(engine.active_at(7), 2);
+    assert_eq!(engine.active_at(1_000), 2);
+
+    assert_eq!(engine.activation_height(1), Some(4));
+    assert_eq!(engine.activation_height(3), None);
+    assert_eq!(engine.next_fork(0), Some((4, 1)));
+    assert_eq!(engine.next_fork(4), Some((7, 2)));
+    assert_eq!(engine.next_fork(7), None);
+}
+
+#[test]
+fn blocks_follow_the_rules_active_at_their_height() {
+    use super::interleaved::Either;
+
+    let engine = three_eras();
+    let chain = seal_chain(&engine, 9);
+    for header in &chain {
+        let index = match &header.consensus_digest {
+            Either::Head(_) => 0,
+            Either::Tail(Either::Head(_)) => 1,
+            Either::Tail(Either::Tail(_)) => 2,
+        };
+        assert_eq!(index, engine.active_at(header.height));
+    }
+
+    // Blocks sealed under the old rules are no longer valid once the new rules are active,
+    // and blocks sealed under the new rules are not valid before.
+    let mut late = chain[2].clone();
+    late.height = 4;
+    assert!(!engine.validate(&chain[2].consensus_digest, &late));
+    let mut early = chain[3].clone();
+    early.height = 3;
+    assert!(!engine.validate(&chain[1].consensus_digest, &early));
+
+    // Within an era, engines still see their own parent digests. Proof of work retargeted at
+    // height 2, which carried over into height 3.
+    let (Either::Head(second), Either::Head(third)) =
+        (&chain[1].consensus_digest, &chain[2].consensus_digest)
+    else {
+        panic!("blocks 2 and 3 are proof of work");
+    };
+    assert_eq!(second.window_start, 2_000);
+    assert_eq!(third.window_start, 2_000);
+}
+
+#[test]
+fn schedule_from_chain_spec() {
+    use super::{ConsensusAuthority, Pow, SimplePoa};
+
+    let text = r#"{
+        "engine": "fork_schedule",
+        "forks": [
+            { "at": 0, "engine": "pow", "threshold": 1000, "initial_digest": 0 },
+            { "at": 100, "engine": "poa", "authorities": ["Alice"], "initial_digest": "Bob" }
+        ]
+    }"#;
+    let engine: ForkSchedule<(Pow, (SimplePoa, ()))> = json::from_str(text).unwrap();
+    assert_eq!(engine.engines.0.threshold(), 1000);
+    assert_eq!(
+        engine.engines.1 .0.authorities,
+        vec![ConsensusAuthority::Alice]
+    );
+    assert_eq!(engine.activations, vec![0, 100]);
+    assert_eq!(engine.initial, (0, (ConsensusAuthority::Bob, ())));
+
+    // Forks must match the engines one to one, in order, and activate at increasing heights.
+    let out_of_order = text.replace("\"at\": 100", "\"at\": 0");
+    assert!(matches!(
+        json::from_str::<ForkSchedule<(Pow, (SimplePoa, ()))>>(&out_of_order),
+        Err(json::Error::Invalid(_))
+    ));
+    assert!(matches!(
+        json::from_str::<ForkSchedule<(Pow, ())>>(text),
+        Err(json::Error::Invalid(_))
+    ));
+    assert!(json::from_str::<ForkSchedule<(SimplePoa, (Pow, ()))>>(text).is_err());
+}
@@ -0,0 +1,184 @@
+//! The forking engine hard-codes a change of consensus rules at a fixed height. Every change
+//! requires a new client release, and every node operator has to upgrade before the fork height.
+//!
+//! Here the consensus parameters are governed on chain instead. The governance state machine
+//! schedules parameter changes for future block heights, and the client reads those scheduled
+//! changes from its state and hands them to this engine. The engine itself never changes shape:
+//! it starts out as proof of work, and switches to proof of authority at the fork height. The
+//! difficulty, the authorities, and the fork height itself are all up for a vote.
+
+use super::p5_interleave::PowOrPoaDigest;
+use super::{Consensus, ConsensusAuthority, Header};
+use crate::c1_state_machine::{GovernanceState, ParameterChange, ScheduledChange};
+
+/// The consensus parameters that governance can change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsensusParameters {
+    /// Proof of work blocks must hash below this threshold.
+    pub pow_threshold: u64,
+    /// The authorities who may sign proof of authority blocks.
+    pub authorities: Vec<ConsensusAuthority>,
+    /// The first height at which blocks are sealed by authorities instead of work.
+    pub fork_height: u64,
+}
+
+impl ConsensusParameters {
+    fn apply(&mut self, change: &ParameterChange) {
+        match change {
+            ParameterChange::PowThreshold(threshold) => self.pow_threshold = *threshold,
+            ParameterChange::Authorities(users) => {
+                self.authorities = users.iter().copied().map(Into::into).collect()
+            }
+            ParameterChange::ForkHeight(height) => self.fork_height = *height,
+        }
+    }
+}
+
+/// A consensus engine whose parameters are changed by on-chain governance.
+pub struct Governed {
+    /// The parameters at genesis, before any change was enacted.
+    genesis: ConsensusParameters,
+    /// The changes scheduled by governance, in the order they passed.
+    changes: Vec<ScheduledChange>,
+    /// The authority that this node seals proof of authority blocks as.
+    signer: ConsensusAuthority,
+}
+
+impl Governed {
+    pub fn new(genesis: ConsensusParameters, signer: ConsensusAuthority) -> Self {
+        Governed {
+            genesis,
+            changes: Vec::new(),
+            signer,
+        }
+    }
+
+    /// Take on the changes scheduled in the given governance state.
+    ///
+    /// The client calls this after importing each block. The engine is rebuilt from the genesis
+    /// parameters every time, so following a different fork of the chain simply means syncing
+    /// with that fork's state.
+    pub fn sync(&mut self, governance: &GovernanceState) {
+        self.changes = governance.scheduled().to_vec();
+    }
+
+    /// The parameters in force for the block at the given height.
+    pub fn params_at(&self, height: u64) -> ConsensusParameters {
+        let mut params = self.genesis.clone();
+        for scheduled in self.changes.iter().filter(|s| s.at <= height) {
+            params.apply(&scheduled.change);
+        }
+        params
+    }
+}
+
+impl Consensus for Governed {
+    type Digest = PowOrPoaDigest;
+
+    /// Before the fork height a block needs enough work, and from then on it needs the signature
+    /// of a current authority.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let params = self.params_at(header.height);
+        match header.consensus_digest {
+            PowOrPoaDigest::Pow(_) if header.height < params.fork_height => {
+                header.hash() < params.pow_threshold
+            }
+            PowOrPoaDigest::Poa(signer) if header.height >= params.fork_height => {
+                params.authorities.contains(&signer)
+            }
+            _ => false,
+        }
+    }
+
+    /// Mine or sign the block, depending on which side of the fork it falls.
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        let params = self.params_at(partial_header.height);
+        let mut header = Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: PowOrPoaDigest::Poa(self.signer),
+        };
+        if header.height >= params.fork_height {
+            return params.authorities.contains(&self.signer).then_some(header);
+        }
+        for nonce in 0..=u64::MAX {
+            header.consensus_digest = PowOrPoaDigest::Pow(nonce);
+            if header.hash() < params.pow_threshold {
+                return Some(header);
+            }
+        }
+        None
+    }
+
+    fn human_name() -> String {
+        "Governed PoW to PoA".into()
+    }
+}
+
+#[test]
+fn governance_upgrades_consensus_from_scheduled_height() {
+    use crate::c1_state_machine::{Governance, GovernanceTransaction, StateMachine, User};
+
+    let mut engine = Governed::new(
+        ConsensusParameters {
+            pow_threshold: u64::MAX / 4,
+            authorities: vec![ConsensusAuthority::Alice],
+            fork_height: u64::MAX,
+        },
+        ConsensusAuthority::Bob,
+    );
+    let parent = PowOrPoaDigest::Pow(0);
+    let mined = engine
+        .seal(&parent, Header::partial(0, 10, 0, 0, 0))
+        .unwrap();
+    assert!(engine.validate(&parent, &mined));
+
+    let council = GovernanceState::new(vec![User::Alice, User::Bob, User::Charlie]);
+    let governance = [
+        GovernanceTransaction::Propose {
+            proposer: User::Alice,
+            change: ParameterChange::ForkHeight(10),
+            enact_at: 5,
+        },
+        GovernanceTransaction::Propose {
+            proposer: User::Alice,
+            change: ParameterChange::Authorities(vec![User::Bob]),
+            enact_at: 8,
+        },
+    ]
+    .into_iter()
+    .chain((0..2).flat_map(|referendum| {
+        [User::Alice, User::Charlie].map(|voter| GovernanceTransaction::Vote {
+            voter,
+            referendum,
+            aye: true,
+        })
+    }))
+    .fold(council, |state, t| Governance::next_state(&state, &t));
+    engine.sync(&governance);
+
+    // Blocks before the changes are unaffected.
+    assert_eq!(engine.params_at(4).fork_height, u64::MAX);
+    assert_eq!(engine.params_at(7).fork_height, 10);
+    assert_eq!(
+        engine.params_at(8).authorities,
+        vec![ConsensusAuthority::Bob]
+    );
+
+    // From the fork height on, mined blocks are rejected and Bob signs instead.
+    assert!(!engine.validate(&parent, &mined));
+    let signed = engine
+        .seal(&parent, Header::partial(0, 10, 0, 0, 0))
+        .unwrap();
+    assert_eq!(
+        signed.consensus_digest,
+        PowOrPoaDigest::Poa(ConsensusAuthority::Bob)
+    );
+    assert!(engine.validate(&parent, &signed));
+    assert!(engine
+        .seal(&parent, Header::partial(0, 9, 0, 0, 0))
+        .is_some_and(|header| matches!(header.consensus_digest, PowOrPoaDigest::Pow(_))));
+}
@@ -0,0 +1,380 @@
+//! `AlternatingPowPoa` interleaves exactly two engines, in a fixed pattern, with a digest enum
+//! written by hand. Here we generalize it to any number of engines and any schedule.
+//!
+//! The engines are given as a nested tuple list, like `(Pow, (SimplePoa, ()))`, so that each one
+//! can have its own type. The list derives the digest from its engines: a nested `Either` with one
+//! variant per engine, for the engine that sealed the block.
+//!
+//! Engines check their headers against their parent's digest. But with interleaving, the parent
+//! may well have been sealed by a different engine, whose digest means nothing to this one. A
+//! slot-based engine still needs its previous slot, and a retargeting engine still needs its
+//! previous target. So every block also carries the latest digest of every engine, starting from
+//! the genesis block, and each engine is given its own latest digest as the parent digest.
+
+use super::{Consensus, Header};
+use crate::codec::{decode_tag, Decode, Encode, Error};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// The digest of one of two engines. Nesting these gives one variant per engine.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Either<A, B> {
+    Head(A),
+    Tail(B),
+}
+
+/// The digest at the end of an engine list. No block can be sealed by the end of the list.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum End {}
+
+/// A list of consensus engines, built as a nested tuple ending in `()`.
+pub trait Engines {
+    /// The digest of whichever engine sealed a block.
+    type Digest: Clone + Debug + Eq + Hash + Encode;
+    /// The latest digest of every engine in the list.
+    type Latest: Clone + Debug + Eq + Hash + Encode;
+
+    /// The number of engines in the list.
+    fn count() -> usize;
+
+    /// Validate a header sealed by the engine at the given index.
+    fn validate(
+        &self,
+        index: usize,
+        latest: &Self::Latest,
+        header: &Header<()>,
+        digest: &Self::Digest,
+    ) -> bool;
+
+    /// Seal a header with the engine at the given index.
+    fn seal(
+        &self,
+        index: usize,
+        latest: &Self::Latest,
+        partial_header: Header<()>,
+    ) -> Option<Self::Digest>;
+
+    /// Record a new digest as the latest digest of the engine that made it.
+    fn update(latest: &Self::Latest, digest: &Self::Digest) -> Self::Latest;
+
+    /// The latest digest of the first engine in the list, or None if the list is empty.
+    fn first(latest: &Self::Latest) -> Option<Self::Digest>;
+}
+
+impl Engines for () {
+    type Digest = End;
+    type Latest = ();
+
+    fn count() -> usize {
+        0
+    }
+
+    fn validate(&self, _: usize, _: &(), _: &Header<()>, _: &End) -> bool {
+        false
+    }
+
+    fn seal(&self, _: usize, _: &(), _: Header<()>) -> Option<End> {
+        None
+    }
+
+    fn update(_: &(), digest: &End) {
+        match *digest {}
+    }
+
+    fn first(_: &()) -> Option<End> {
+        None
+    }
+}
+
+/// A copy of the header with the given digest in place of its own.
+fn with_digest<D, Old>(header: &Header<Old>, consensus_digest: D) -> Header<D> {
+    Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: header.extrinsics_root,
+        timestamp: header.timestamp,
+        consensus_digest,
+    }
+}
+
+impl<A: Consensus, Rest: Engines> Engines for (A, Rest) {
+    type Digest = Either<A::Digest, Rest::Digest>;
+    type Latest = (A::Digest, Rest::Latest);
+
+    fn count() -> usize {
+        1 + Rest::count()
+    }
+
+    fn validate(
+        &self,
+        index: usize,
+        latest: &Self::Latest,
+        header: &Header<()>,
+        digest: &Self::Digest,
+    ) -> bool {
+        match (index, digest) {
+            (0, Either::Head(digest)) => self
+                .0
+                .validate(&latest.0, &with_digest(header, digest.clone())),
+            (index, Either::Tail(digest)) if index > 0 => {
+                self.1.validate(index - 1, &latest.1, header, digest)
+            }
+            _ => false,
+        }
+    }
+
+    fn seal(
+        &self,
+        index: usize,
+        latest: &Self::Latest,
+        partial_header: Header<()>,
+    ) -> Option<Self::Digest> {
+        match index {
+            0 => self
+                .0
+                .seal(&latest.0, partial_header)
+                .map(|header| Either::Head(header.consensus_digest)),
+            _ => self
+                .1
+                .seal(index - 1, &latest.1, partial_header)
+                .map(Either::Tail),
+        }
+    }
+
+    fn update(latest: &Self::Latest, digest: &Self::Digest) -> Self::Latest {
+        match digest {
+            Either::Head(digest) => (digest.clone(), latest.1.clone()),
+            Either::Tail(digest) => (latest.0.clone(), Rest::update(&latest.1, digest)),
+        }
+    }
+
+    fn first(latest: &Self::Latest) -> Option<Self::Digest> {
+        Some(Either::Head(latest.0.clone()))
+    }
+}
+
+/// The digest of an interleaved block.
+#[derive(Hash, Debug, PartialEq, Eq, Clone)]
+pub struct InterleavedDigest<D, L> {
+    /// The digest of the engine that sealed this block. The genesis block is not sealed.
+    pub sealed: Option<D>,
+    /// The latest digest of every engine, including this block's own.
+    pub latest: L,
+}
+
+/// A consensus engine that delegates each block to one of several engines, according to a
+/// schedule from block height to the index of the engine in the list.
+pub struct Interleaved<E, S> {
+    engines: E,
+    schedule: S,
+}
+
+impl<E: Engines, S: Fn(u64) -> usize> Interleaved<E, S> {
+    pub fn new(engines: E, schedule: S) -> Self {
+        Interleaved { engines, schedule }
+    }
+
+    /// The digest of the genesis block, which gives every engine its starting digest.
+    pub fn genesis_digest(&self, latest: E::Latest) -> InterleavedDigest<E::Digest, E::Latest> {
+        InterleavedDigest {
+            sealed: None,
+            latest,
+        }
+    }
+}
+
+impl<E: Engines, S: Fn(u64) -> usize> Consensus for Interleaved<E, S> {
+    type Digest = InterleavedDigest<E::Digest, E::Latest>;
+
+    /// Check that the scheduled engine sealed the header, against that engine's latest digest,
+    /// and that the latest digests were carried over correctly.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let Some(sealed) = &header.consensus_digest.sealed else {
+            return false;
+        };
+        let index = (self.schedule)(header.height);
+        let partial = with_digest(header, ());
+        self.engines
+            .validate(index, &parent_digest.latest, &partial, sealed)
+            && header.consensus_digest.latest == E::update(&parent_digest.latest, sealed)
+    }
+
+    /// Seal the header with the scheduled engine.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let index = (self.schedule)(partial_header.height);
+        let sealed = self
+            .engines
+            .seal(index, &parent_digest.latest, partial_header.clone())?;
+        let latest = E::update(&parent_digest.latest, &sealed);
+        Some(with_digest(
+            &partial_header,
+            InterleavedDigest {
+                sealed: Some(sealed),
+                latest,
+            },
+        ))
+    }
+
+    fn human_name() -> String {
+        "Interleaved".into()
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for Either<A, B> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            Either::Head(a) => {
+                dest.push(0);
+                a.encode_to(dest);
+            }
+            Either::Tail(b) => {
+                dest.push(1);
+                b.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for Either<A, B> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(Either::Head(A::decode(input)?)),
+            1 => Ok(Either::Tail(B::decode(input)?)),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for End {
+    fn encode_to(&self, _: &mut Vec<u8>) {
+        match *self {}
+    }
+}
+
+impl Decode for End {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Err(Error::InvalidTag(decode_tag(input)?))
+    }
+}
+
+impl<D: Encode, L: Encode> Encode for InterleavedDigest<D, L> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.sealed.encode_to(dest);
+        self.latest.encode_to(dest);
+    }
+}
+
+impl<D: Decode, L: Decode> Decode for InterleavedDigest<D, L> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(InterleavedDigest {
+            sealed: Option::decode(input)?,
+            latest: L::decode(input)?,
+        })
+    }
+}
+
+#[cfg(test)]
+type PowAndPoa = (super::RetargetingPow, (super::ThresholdPoa, ()));
+
+#[cfg(test)]
+fn pow_and_poa() -> PowAndPoa {
+    use super::{ConsensusAuthority, RetargetingPow, ThresholdPoa};
+    let pow = RetargetingPow {
+        interval: 2,
+        block_time: 1_000,
+        initial_target: u64::MAX / 4,
+        rules: super::TimestampRules::new(super::SystemClock),
+    };
+    let poa = ThresholdPoa::new(
+        vec![ConsensusAuthority::Alice],
+        1,
+        ConsensusAuthority::Alice,
+    );
+    (pow, (poa, ()))
+}
+
+#[cfg(test)]
+fn seal_chain<S: Fn(u64) -> usize>(
+    engine: &Interleaved<PowAndPoa, S>,
+    genesis: &<Interleaved<PowAndPoa, S> as Consensus>::Digest,
+    length: u64,
+) -> Vec<Header<<Interleaved<PowAndPoa, S> as Consensus>::Digest>> {
+    let mut parent = genesis.clone();
+    let mut chain = Vec::new();
+    for height in 1..=length {
+        let partial = Header {
+            parent: 0,
+            height,
+            state_root: 0,
+            extrinsics_root: 0,
+            timestamp: height * 1_000,
+            consensus_digest: (),
+        };
+        let header = engine.seal(&parent, partial).unwrap();
+        assert!(engine.validate(&parent, &header));
+        parent = header.consensus_digest.clone();
+        chain.push(header);
+    }
+    chain
+}
+
+#[test]
+fn engines_follow_the_schedule() {
+    let engines = pow_and_poa();
+    let genesis_latest = (engines.0.genesis_digest(0), (Default::default(), ()));
+    let engine = Interleaved::new(engines, |height| (height % 2 == 0) as usize);
+    let genesis = engine.genesis_digest(genesis_latest);
+    let chain = seal_chain(&engine, &genesis, 6);
+
+    for header in &chain {
+        let sealed = header.consensus_digest.sealed.as_ref().unwrap();
+        match header.height % 2 {
+            1 => assert!(matches!(sealed, Either::Head(_))),
+            _ => assert!(matches!(sealed, Either::Tail(Either::Head(_)))),
+        }
+    }
+
+    // A block sealed by the wrong engine for its height is invalid.
+    let mut wrong = chain[1].clone();
+    wrong.height = 3;
+    assert!(!engine.validate(&chain[1].consensus_digest, &wrong));
+
+    // Nothing can be sealed by an engine that is not in the list.
+    let engine = Interleaved::new(pow_and_poa(), |_| 2);
+    let partial = with_digest(&chain[0], ());
+    assert_eq!(engine.seal(&genesis, partial), None);
+}
+
+#[test]
+fn engines_see_their_own_latest_digest() {
+    let engines = pow_and_poa();
+    let genesis_latest = (engines.0.genesis_digest(0), (Default::default(), ()));
+    let engine = Interleaved::new(engines, |height| (height % 2 == 0) as usize);
+    let genesis = engine.genesis_digest(genesis_latest);
+    let chain = seal_chain(&engine, &genesis, 5);
+
+    // Block 3 was sealed by proof of work, on top of a proof of authority block. It was checked
+    // against block 1, the previous proof of work block, which is where its window started.
+    let Some(Either::Head(pow_1)) = chain[0].consensus_digest.sealed else {
+        panic!("block 1 is proof of work");
+    };
+    assert_eq!(
+        chain[2].consensus_digest.latest.0.window_start,
+        pow_1.window_start
+    );
+    assert_eq!(chain[1].consensus_digest.latest.0, pow_1);
+
+    // The latest digests cannot be altered, even those of engines that did not seal the block.
+    let mut tampered = chain[4].clone();
+    tampered.consensus_digest.latest.1 .0.signers.clear();
+    assert!(!engine.validate(&chain[3].consensus_digest, &tampered));
+
+    // Digests survive encoding.
+    let decoded = crate::codec::decode_all(&chain[4].encode()).unwrap();
+    assert_eq!(chain[4], decoded);
+}
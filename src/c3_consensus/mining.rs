@@ -0,0 +1,161 @@
+//! Sealing a proof of work block means trying nonces until the header's hash happens to fall
+//! below the threshold. Doing that on a single thread wastes every other core, and a search that
+//! cannot be stopped keeps going on a stale parent long after a better block has arrived.
+//!
+//! A `MiningJob` searches in the background instead. The nonce space is split between several
+//! threads, each of which tries every n-th nonce, so no nonce is ever tried twice. The job can be
+//! cancelled at any time, and it counts the hashes it has tried so the node can report its hashrate.
+
+use super::Header;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// How many nonces each thread tries between checks for cancellation.
+const BATCH_SIZE: u64 = 1024;
+
+/// A proof of work search running on background threads.
+///
+/// Dropping the job cancels it and waits for its threads to stop.
+pub struct MiningJob {
+    /// Set when the search should stop, either because it was cancelled or a seal was found.
+    stopped: Arc<AtomicBool>,
+    /// How many nonces have been tried so far, across all threads.
+    hashes: Arc<AtomicU64>,
+    started: Instant,
+    /// Receives the sealed header, if one is found.
+    found: mpsc::Receiver<Header<u64>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl MiningJob {
+    /// Start searching for a nonce that seals the partial header below the threshold, using the
+    /// given number of threads.
+    pub fn start(partial_header: Header<()>, threshold: u64, threads: usize) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let hashes = Arc::new(AtomicU64::new(0));
+        let (sender, found) = mpsc::channel();
+        let step = threads.max(1) as u64;
+        let threads = (0..step)
+            .map(|first_nonce| {
+                let header = Header {
+                    parent: partial_header.parent,
+                    height: partial_header.height,
+                    state_root: partial_header.state_root,
+                    extrinsics_root: partial_header.extrinsics_root,
+                    timestamp: partial_header.timestamp,
+                    consensus_digest: first_nonce,
+                };
+                let stopped = stopped.clone();
+                let hashes = hashes.clone();
+                let sender = sender.clone();
+                thread::spawn(move || search(header, threshold, step, &stopped, &hashes, &sender))
+            })
+            .collect();
+        MiningJob {
+            stopped,
+            hashes,
+            started: Instant::now(),
+            found,
+            threads,
+        }
+    }
+
+    /// Stop searching. A seal that was already found can still be collected.
+    pub fn cancel(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// How many nonces have been tried so far.
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    /// The average number of nonces tried per second since the job started.
+    pub fn hashrate(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        self.hashes() as f64 / elapsed
+    }
+
+    /// The sealed header, if it has been found. Does not block.
+    pub fn try_result(&self) -> Option<Header<u64>> {
+        self.found.try_recv().ok()
+    }
+
+    /// Block until the sealed header is found. Returns None if the job was cancelled first,
+    /// or if the entire nonce space was searched without success.
+    pub fn wait(&self) -> Option<Header<u64>> {
+        self.found.recv().ok()
+    }
+}
+
+impl Drop for MiningJob {
+    fn drop(&mut self) {
+        self.cancel();
+        for thread in self.threads.drain(..) {
+            // A panicking search thread has nothing left to clean up.
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Try every `step`-th nonce, starting from the one already in the header.
+fn search(
+    mut header: Header<u64>,
+    threshold: u64,
+    step: u64,
+    stopped: &AtomicBool,
+    hashes: &AtomicU64,
+    sender: &mpsc::Sender<Header<u64>>,
+) {
+    loop {
+        for tried in 1..=BATCH_SIZE {
+            if header.hash() < threshold {
+                hashes.fetch_add(tried, Ordering::Relaxed);
+                // Only the first thread to find a seal reports it.
+                if !stopped.swap(true, Ordering::Relaxed) {
+                    let _ = sender.send(header);
+                }
+                return;
+            }
+            match header.consensus_digest.checked_add(step) {
+                Some(nonce) => header.consensus_digest = nonce,
+                None => {
+                    hashes.fetch_add(tried, Ordering::Relaxed);
+                    return;
+                }
+            }
+        }
+        hashes.fetch_add(BATCH_SIZE, Ordering::Relaxed);
+        if stopped.load(Ordering::Relaxed) {
+            return;
+        }
+    }
+}
+
+#[test]
+fn threads_find_a_valid_seal() {
+    let threshold = u64::MAX / 10_000;
+    let job = MiningJob::start(Header::partial(0, 1, 0, 0, 0), threshold, 4);
+    let header = job.wait().unwrap();
+
+    assert!(header.hash() < threshold);
+    assert_eq!(header.height, 1);
+    assert!(job.hashes() > 0);
+    assert!(job.hashrate() > 0.0);
+}
+
+#[test]
+fn mining_can_be_cancelled() {
+    // Nothing hashes below zero, so this search would run for ever.
+    let job = MiningJob::start(Header::partial(0, 1, 0, 0, 0), 0, 2);
+    while job.hashes() == 0 {
+        std::thread::yield_now();
+    }
+    job.cancel();
+    assert_eq!(job.wait(), None);
+}
@@ -2,16 +2,41 @@
 //! We begin by re-implementing the proof of work consensus from the previous module, then look at PoA, and other consensus
 //! engines all implementing the same simple interface.
 
+mod babe;
+mod equivocation;
+mod fork_schedule;
+mod governed;
+mod interleaved;
+mod mining;
 mod p1_pow;
 mod p2_dictator;
 mod p3_poa; // exercise: dictator is a special case of poa. Create dictator in terms of PoA.
 mod p4_even_only;
 mod p5_interleave;
 mod p6_forking;
+mod retarget;
+mod staked_poa;
+mod tendermint;
+mod threshold_poa;
+mod timestamp;
+mod uncles;
+mod work;
 
 // Re-export some individual consensus engines so they can be be re-used in the Client chapter.
+pub use equivocation::{EquivocationDetector, EquivocationProof, Offence, SignedDigest};
+pub use fork_schedule::ForkSchedule;
+pub use mining::MiningJob;
 pub use p1_pow::Pow;
-pub use p3_poa::SimplePoa;
+pub use p3_poa::{PoaRoundRobinByHeight, SimplePoa};
+pub use retarget::{RetargetDigest, RetargetingPow};
+pub use tendermint::{CommitCertificate, Tendermint, TendermintDigest, Vote, VoteKind};
+pub use threshold_poa::{ThresholdPoa, ThresholdSeal};
+pub use timestamp::{RecentTimestamps, SystemClock, TimestampRules};
+pub use uncles::{UncleDigest, UnclePow};
+pub use work::expected_work;
+
+use crate::codec::{decode_tag, Decode, Encode, Error};
+use crate::json::{self, FromJson, Json};
 
 type Hash = u64;
 
@@ -29,14 +54,98 @@ pub struct Header<Digest> {
     height: u64,
     state_root: Hash,
     extrinsics_root: Hash,
+    /// When the block was authored, in milliseconds since the Unix epoch.
+    timestamp: u64,
     consensus_digest: Digest,
 }
+
+impl<Digest> Header<Digest> {
+    /// The hash of the parent header.
+    pub fn parent(&self) -> Hash {
+        self.parent
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn consensus_digest(&self) -> &Digest {
+        &self.consensus_digest
+    }
+}
+
+impl Header<()> {
+    /// A header that is ready to be sealed by a consensus engine. The fields are private to this
+    /// chapter, so this is how the rest of the crate builds one.
+    pub(crate) fn partial(
+        parent: Hash,
+        height: u64,
+        state_root: Hash,
+        extrinsics_root: Hash,
+        timestamp: u64,
+    ) -> Self {
+        Header {
+            parent,
+            height,
+            state_root,
+            extrinsics_root,
+            timestamp,
+            consensus_digest: (),
+        }
+    }
+
+    /// The same header with the given consensus digest. Engines seal headers by finding a valid
+    /// digest; this is for code that already has one, such as tests outside this chapter.
+    pub(crate) fn with_digest<D>(self, consensus_digest: D) -> Header<D> {
+        Header {
+            parent: self.parent,
+            height: self.height,
+            state_root: self.state_root,
+            extrinsics_root: self.extrinsics_root,
+            timestamp: self.timestamp,
+            consensus_digest,
+        }
+    }
+}
+
+impl<Digest: Encode> Header<Digest> {
+    /// The hash of this header, which its children refer to as their parent. It is defined
+    /// over the header's canonical encoding, so every node agrees on it.
+    pub fn hash(&self) -> Hash {
+        self.encoded_hash()
+    }
+}
+
+impl<Digest: Encode> Encode for Header<Digest> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.parent.encode_to(dest);
+        self.height.encode_to(dest);
+        self.state_root.encode_to(dest);
+        self.extrinsics_root.encode_to(dest);
+        self.timestamp.encode_to(dest);
+        self.consensus_digest.encode_to(dest);
+    }
+}
+
+impl<Digest: Decode> Decode for Header<Digest> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(Header {
+            parent: Hash::decode(input)?,
+            height: u64::decode(input)?,
+            state_root: Hash::decode(input)?,
+            extrinsics_root: Hash::decode(input)?,
+            timestamp: u64::decode(input)?,
+            consensus_digest: Digest::decode(input)?,
+        })
+    }
+}
+
 /// A Consensus Engine. Responsible for Sealing blocks and verifying their seals
 ///
 /// Consensus exists independently of execution logic, and therefore operates
 /// only on the block headers.
 pub trait Consensus {
-    type Digest: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash;
+    type Digest: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash + Encode;
 
     /// Validates that a header is valid according to consensus rules. This
     /// function checks ONLY consensus-related aspects such as the signature
@@ -87,6 +196,13 @@ pub trait Consensus {
     }
 }
 
+/// A consensus engine that knows the digest of the genesis block from its configuration. The
+/// genesis block is never sealed, but its children are checked against its digest, so a client
+/// needs it to start a chain.
+pub trait GenesisDigest: Consensus {
+    fn genesis_digest(&self) -> Self::Digest;
+}
+
 /// A trivial consensus engine that considers all blocks valid, and does not have
 /// a meaningful consensus digest.
 impl Consensus for () {
@@ -111,3 +227,74 @@ pub enum ConsensusAuthority {
     Bob,
     Charlie,
 }
+
+impl Encode for ConsensusAuthority {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        dest.push(*self as u8);
+    }
+}
+
+impl Decode for ConsensusAuthority {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(ConsensusAuthority::Alice),
+            1 => Ok(ConsensusAuthority::Bob),
+            2 => Ok(ConsensusAuthority::Charlie),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+impl FromJson for ConsensusAuthority {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        match j.as_str()? {
+            "Alice" => Ok(ConsensusAuthority::Alice),
+            "Bob" => Ok(ConsensusAuthority::Bob),
+            "Charlie" => Ok(ConsensusAuthority::Charlie),
+            other => Err(json::Error::UnknownVariant(other.into())),
+        }
+    }
+}
+
+/// Consensus engines are configured in chain specs by an object naming the engine along
+/// with its parameters, for example `{ "engine": "pow", "threshold": 1000 }`.
+///
+/// Check that the given engine configuration is for the expected engine.
+pub(crate) fn expect_engine(j: &Json, engine: &str) -> Result<(), json::Error> {
+    match j.parse_field::<String>("engine")? {
+        name if name == engine => Ok(()),
+        name => Err(json::Error::Invalid(format!(
+            "this chain needs the {engine} consensus engine, but the spec configures {name}"
+        ))),
+    }
+}
+
+#[test]
+fn header_codec_round_trip() {
+    use crate::codec::decode_all;
+
+    let header = Header {
+        parent: 7,
+        height: 1,
+        state_root: 8,
+        extrinsics_root: 9,
+        timestamp: 10,
+        consensus_digest: ConsensusAuthority::Bob,
+    };
+    let encoded = header.encode();
+    assert_eq!(encoded.len(), 5 * 8 + 1);
+    assert_eq!(decode_all(&encoded), Ok(header.clone()));
+
+    // Changing any field changes the hash of the encoding.
+    let sibling = Header {
+        consensus_digest: ConsensusAuthority::Charlie,
+        ..header.clone()
+    };
+    assert_ne!(header.hash(), sibling.hash());
+    assert_eq!(header.hash(), header.encoded_hash());
+
+    assert_eq!(
+        decode_all::<Header<ConsensusAuthority>>(&encoded[..encoded.len() - 1]),
+        Err(Error::UnexpectedEnd)
+    );
+}
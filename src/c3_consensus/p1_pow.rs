@@ -4,7 +4,8 @@
 //! This is the same logic we implemented previously. Here we re-implement it in the
 //! generic consensus framework that we will use throughout the rest of the chapter.
 
-use super::{Consensus, Header};
+use super::{expect_engine, Consensus, GenesisDigest, Header, MiningJob};
+use crate::json::{self, FromJson, Json};
 
 /// A Proof of Work consensus engine. This is the same consensus logic that we
 /// implemented in the previous chapter. Here we simply re-implement it in the
@@ -29,6 +30,42 @@ impl Consensus for Pow {
     }
 }
 
+impl Pow {
+    /// A PoW engine with the given threshold. Within this chapter the engine is built by the
+    /// exercises below; other chapters that need one for their own purposes use this.
+    pub(crate) fn new(threshold: u64) -> Self {
+        Pow { threshold }
+    }
+
+    /// Block hashes must be below this threshold.
+    pub fn threshold(&self) -> u64 {
+        self.threshold
+    }
+
+    /// Start sealing the partial header in the background on the given number of threads.
+    /// Unlike `seal`, the search can be cancelled, for example when a better parent arrives.
+    pub fn start_mining(&self, partial_header: Header<()>, threads: usize) -> MiningJob {
+        MiningJob::start(partial_header, self.threshold, threads)
+    }
+}
+
+/// Configured in a chain spec as `{ "engine": "pow", "threshold": <u64> }`
+impl FromJson for Pow {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        expect_engine(j, "pow")?;
+        Ok(Pow {
+            threshold: j.parse_field("threshold")?,
+        })
+    }
+}
+
+/// The genesis block is never mined, so its nonce is zero by convention.
+impl GenesisDigest for Pow {
+    fn genesis_digest(&self) -> u64 {
+        0
+    }
+}
+
 /// Create a PoW consensus engine that has a difficulty threshold such that roughly 1 in 100 blocks
 /// with randomly drawn nonces will be valid. That is: the threshold should be u64::max_value() / 100.
 pub fn moderate_difficulty_pow() -> Pow {
@@ -40,3 +77,15 @@ pub fn moderate_difficulty_pow() -> Pow {
 pub fn trivial_always_valid_pow() -> Pow {
     todo!("Exercise 4")
 }
+
+#[test]
+fn pow_from_chain_spec() {
+    let pow: Pow = json::from_str(r#"{ "engine": "pow", "threshold": 1000 }"#).unwrap();
+    assert_eq!(pow.threshold, 1000);
+
+    assert!(json::from_str::<Pow>(r#"{ "engine": "poa", "threshold": 1000 }"#).is_err());
+    assert_eq!(
+        json::from_str::<Pow>(r#"{ "engine": "pow" }"#).err(),
+        Some(json::Error::MissingField("threshold"))
+    );
+}
@@ -7,11 +7,16 @@
 //! and permissionless end of the spectrum by electing the authorities on-chain through an economic
 //! game in which users stake tokens. In such a configuration it is often known as "Proof of Stake".
 //! Even when using the Proof of Stake configuration, the underlying consensus logic is identical to
-//! the proof of authority we are writing here.
+//! the proof of authority we are writing here. The `staked_poa` module connects PoA to such an election.
 
-use super::{Consensus, ConsensusAuthority, Header};
+use super::equivocation::SignedDigest;
+use super::timestamp::slot_at;
+use super::{expect_engine, Consensus, ConsensusAuthority, Header};
+use crate::codec::{Decode, Encode, Error};
+use crate::json::{self, FromJson, Json};
 
 /// A Proof of Authority consensus engine. If any of the authorities have signed the block, it is valid.
+/// The `threshold_poa` module requires several of them to sign instead.
 pub struct SimplePoa {
     pub authorities: Vec<ConsensusAuthority>,
 }
@@ -32,11 +37,45 @@ impl Consensus for SimplePoa {
     }
 }
 
+/// Read a non-empty list of distinct authorities from a PoA engine's configuration.
+fn authorities_from_json(j: &Json) -> Result<Vec<ConsensusAuthority>, json::Error> {
+    let authorities: Vec<ConsensusAuthority> = j.parse_field("authorities")?;
+    if authorities.is_empty() {
+        return Err(json::Error::Invalid(
+            "a PoA chain needs at least one authority".into(),
+        ));
+    }
+    if (1..authorities.len()).any(|i| authorities[..i].contains(&authorities[i])) {
+        return Err(json::Error::Invalid("authorities must be unique".into()));
+    }
+    Ok(authorities)
+}
+
+/// Configured in a chain spec as `{ "engine": "poa", "authorities": ["Alice", ...] }`
+impl FromJson for SimplePoa {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        expect_engine(j, "poa")?;
+        Ok(SimplePoa {
+            authorities: authorities_from_json(j)?,
+        })
+    }
+}
+
 /// A Proof of Authority consensus engine. Only one authority is valid at each block height.
 /// As ever, the genesis block does not require a seal. After that the authorities take turns
 /// in order.
-struct PoaRoundRobinByHeight {
-    authorities: Vec<ConsensusAuthority>,
+pub struct PoaRoundRobinByHeight {
+    pub authorities: Vec<ConsensusAuthority>,
+}
+
+/// Configured in a chain spec as `{ "engine": "poa_round_robin_by_height", "authorities": [...] }`
+impl FromJson for PoaRoundRobinByHeight {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        expect_engine(j, "poa_round_robin_by_height")?;
+        Ok(PoaRoundRobinByHeight {
+            authorities: authorities_from_json(j)?,
+        })
+    }
 }
 
 impl Consensus for PoaRoundRobinByHeight {
@@ -63,17 +102,90 @@ impl Consensus for PoaRoundRobinByHeight {
 ///
 /// A common PoA scheme that works around these weaknesses is to divide time into slots, and then do a round robin
 /// by slot instead of by height
-struct PoaRoundRobinBySlot {
-    authorities: Vec<ConsensusAuthority>,
+///
+/// Round robin by slot still makes every slot's author public in advance, which makes them easy to attack.
+/// The `babe` module keeps slot leaders secret with a VRF lottery.
+pub struct PoaRoundRobinBySlot {
+    pub authorities: Vec<ConsensusAuthority>,
+    /// The length of each slot, in milliseconds.
+    pub slot_duration: u64,
+}
+
+impl PoaRoundRobinBySlot {
+    /// The slot that a header was authored in, according to its timestamp. Wrap this engine in
+    /// `Timestamped` so that authors cannot pick whatever timestamp gives them the slot they want.
+    pub fn slot_of<D>(&self, header: &Header<D>) -> u64 {
+        slot_at(header.timestamp, self.slot_duration)
+    }
+
+    /// The authority whose turn it is in the given slot.
+    pub fn author_of(&self, slot: u64) -> ConsensusAuthority {
+        self.authorities[(slot % self.authorities.len() as u64) as usize]
+    }
+}
+
+/// Configured in a chain spec as
+/// `{ "engine": "poa_round_robin_by_slot", "authorities": [...], "slot_duration": <u64> }`
+impl FromJson for PoaRoundRobinBySlot {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        expect_engine(j, "poa_round_robin_by_slot")?;
+        let slot_duration = j.parse_field("slot_duration")?;
+        if slot_duration == 0 {
+            return Err(json::Error::Invalid("slots must have a duration".into()));
+        }
+        Ok(PoaRoundRobinBySlot {
+            authorities: authorities_from_json(j)?,
+            slot_duration,
+        })
+    }
 }
 
 /// A digest used for PoaRoundRobinBySlot. The digest contains the slot number as well as the signature.
 /// In addition to checking that the right signer has signed for the slot, you must check that the slot is
 /// always strictly increasing. But remember that slots may be skipped.
+/// The slot is not the author's to choose. It must be the slot that the header's timestamp falls in,
+/// which `PoaRoundRobinBySlot::slot_of` calculates.
 #[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
-struct SlotDigest {
-    slot: u64,
-    signature: ConsensusAuthority,
+pub struct SlotDigest {
+    pub slot: u64,
+    pub signature: ConsensusAuthority,
+}
+
+impl SignedDigest for SlotDigest {
+    fn signer(&self) -> ConsensusAuthority {
+        self.signature
+    }
+
+    /// Authorities take turns by slot, not by height.
+    fn turn(&self, _: u64) -> u64 {
+        self.slot
+    }
+}
+
+impl Encode for SlotDigest {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.slot.encode_to(dest);
+        self.signature.encode_to(dest);
+    }
+}
+
+impl Decode for SlotDigest {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(SlotDigest {
+            slot: u64::decode(input)?,
+            signature: ConsensusAuthority::decode(input)?,
+        })
+    }
+}
+
+/// Written in a chain spec as `{ "slot": <u64>, "signature": "Alice" }`
+impl FromJson for SlotDigest {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        Ok(SlotDigest {
+            slot: j.parse_field("slot")?,
+            signature: j.parse_field("signature")?,
+        })
+    }
 }
 
 impl Consensus for PoaRoundRobinBySlot {
@@ -91,3 +203,31 @@ impl Consensus for PoaRoundRobinBySlot {
         todo!("Exercise 6")
     }
 }
+
+#[test]
+fn slots_follow_the_timestamp() {
+    use ConsensusAuthority::*;
+
+    let engine: PoaRoundRobinBySlot = crate::json::from_str(
+        r#"{ "engine": "poa_round_robin_by_slot", "authorities": ["Alice", "Bob"], "slot_duration": 6000 }"#,
+    )
+    .unwrap();
+    let header_at = |timestamp| Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp,
+        consensus_digest: (),
+    };
+    assert_eq!(engine.slot_of(&header_at(0)), 0);
+    assert_eq!(engine.slot_of(&header_at(5_999)), 0);
+    assert_eq!(engine.slot_of(&header_at(13_000)), 2);
+    assert_eq!(engine.author_of(2), Alice);
+    assert_eq!(engine.author_of(3), Bob);
+
+    assert!(crate::json::from_str::<PoaRoundRobinBySlot>(
+        r#"{ "engine": "poa_round_robin_by_slot", "authorities": ["Alice"], "slot_duration": 0 }"#,
+    )
+    .is_err());
+}
@@ -2,6 +2,8 @@
 //! one of them. But other chains would like consensus properties that fall in between. To achieve this
 //! we could consider interleaving PoW blocks with PoA blocks. Some very early designs of Ethereum considered
 //! this approach as a way to transition away from PoW.
+//!
+//! The `interleaved` module generalizes this to any number of engines on any schedule.
 
 /// A Consensus engine that alternates back and forth between PoW and PoA sealed blocks.
 ///
@@ -9,15 +11,41 @@
 /// Even blocks are PoA
 struct AlternatingPowPoa;
 use super::{Consensus, ConsensusAuthority, Header};
+use crate::codec::{decode_tag, Decode, Encode, Error};
 
 /// In order to implement a consensus that can be sealed with either work or a signature,
 /// we will need an enum that wraps the two individual digest types.
 #[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
-enum PowOrPoaDigest {
+pub enum PowOrPoaDigest {
     Pow(u64),
     Poa(ConsensusAuthority),
 }
 
+impl Encode for PowOrPoaDigest {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            PowOrPoaDigest::Pow(nonce) => {
+                dest.push(0);
+                nonce.encode_to(dest);
+            }
+            PowOrPoaDigest::Poa(authority) => {
+                dest.push(1);
+                authority.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl Decode for PowOrPoaDigest {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(PowOrPoaDigest::Pow(u64::decode(input)?)),
+            1 => Ok(PowOrPoaDigest::Poa(ConsensusAuthority::decode(input)?)),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
 impl From<u64> for PowOrPoaDigest {
     fn from(_: u64) -> Self {
         todo!("Exercise 1")
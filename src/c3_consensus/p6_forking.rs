@@ -5,6 +5,9 @@
 //! The consensus engine we implement here does not contain the specific consensus rules to
 //! be enforced before or after the fork, but rather delegates to existing consensus engines
 //! for that. Here we simply write the logic for detecting whether we are before or after the fork.
+//!
+//! Real chains fork many times. The `fork_schedule` module supports any number of forks, with
+//! engines that carry their own configuration.
 
 use std::marker::PhantomData;
 
@@ -20,7 +23,7 @@ struct Forked<D, Before, After> {
 
 impl<D, B, A> Consensus for Forked<D, B, A>
 where
-    D: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash,
+    D: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash + crate::codec::Encode,
     B: Consensus,
     A: Consensus,
     B::Digest: Into<D>,
@@ -0,0 +1,332 @@
+//! The `Pow` engine has a fixed threshold. When hashpower joins or leaves the network, blocks come
+//! faster or slower than intended, and the only fix is a hard fork to a new threshold. Real proof of
+//! work chains instead adjust their difficulty automatically, based on how long recent blocks took.
+//!
+//! Here we follow Bitcoin's approach. Blocks are grouped into windows of a fixed number of blocks.
+//! The first block of each window retargets: it scales the previous target by how long the previous
+//! window actually took compared to how long it should have taken. The adjustment is clamped to a
+//! factor of four either way, so a few misleading timestamps cannot swing the difficulty wildly.
+//!
+//! Timestamps decide the difficulty, so miners have every reason to lie about them. Stamping the
+//! blocks of a window far apart makes the window look slow, which eases the target at the next
+//! retarget. Repeated every window, this time warp makes blocks ever cheaper to mine. So timestamps
+//! must follow the rules of the `timestamp` module: later than the median time past, and not too
+//! far ahead of the validating node's clock.
+//!
+//! Consensus engines only see the parent's digest, so the digest records everything needed to
+//! check the next block. That is the target the block was mined against, the timestamp of the
+//! first block in the current window, and the timestamps of the most recent blocks.
+
+use super::timestamp::{Clock, RecentTimestamps, SystemClock, TimestampRules};
+use super::{expect_engine, Consensus, Header};
+use crate::codec::{Decode, Encode, Error};
+use crate::json::{self, FromJson, Json};
+
+/// The most that a single retarget may scale the target by, in either direction.
+pub const MAX_ADJUSTMENT: u64 = 4;
+
+/// A Proof of Work engine whose threshold adapts to the observed block time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetargetingPow<C: Clock = SystemClock> {
+    /// How many blocks are in each retarget window.
+    pub interval: u64,
+    /// The intended time between blocks, in milliseconds.
+    pub block_time: u64,
+    /// The target of the genesis window.
+    pub initial_target: u64,
+    /// The rules that every block's timestamp must follow.
+    pub rules: TimestampRules<C>,
+}
+
+/// The digest of a retargeting PoW block.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RetargetDigest {
+    pub nonce: u64,
+    /// The block's hash must be below this target.
+    pub target: u64,
+    /// The timestamp of the first block in the block's retarget window.
+    pub window_start: u64,
+    /// The timestamps of the most recent blocks, ending with this one.
+    pub recent: RecentTimestamps,
+}
+
+impl Encode for RetargetDigest {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.nonce.encode_to(dest);
+        self.target.encode_to(dest);
+        self.window_start.encode_to(dest);
+        self.recent.encode_to(dest);
+    }
+}
+
+impl Decode for RetargetDigest {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(RetargetDigest {
+            nonce: u64::decode(input)?,
+            target: u64::decode(input)?,
+            window_start: u64::decode(input)?,
+            recent: RecentTimestamps::decode(input)?,
+        })
+    }
+}
+
+impl<C: Clock> RetargetingPow<C> {
+    /// The digest of the genesis block, which was authored at the given time.
+    pub fn genesis_digest(&self, timestamp: u64) -> RetargetDigest {
+        RetargetDigest {
+            nonce: 0,
+            target: self.initial_target,
+            window_start: timestamp,
+            recent: RecentTimestamps::genesis(timestamp),
+        }
+    }
+
+    /// The target, window start, and recent timestamps that a child of a block with the given
+    /// digest must use. Only the child's height and timestamp matter.
+    pub fn next_digest<D>(&self, parent: &RetargetDigest, header: &Header<D>) -> RetargetDigest {
+        let recent = parent.recent.push(header.timestamp);
+        if !header.height.is_multiple_of(self.interval) {
+            return RetargetDigest {
+                nonce: 0,
+                recent,
+                ..*parent
+            };
+        }
+        let expected = self.interval.saturating_mul(self.block_time);
+        let actual = header.timestamp.saturating_sub(parent.window_start).clamp(
+            expected / MAX_ADJUSTMENT,
+            expected.saturating_mul(MAX_ADJUSTMENT),
+        );
+        // A longer window means blocks were too slow, so the target grows to make them easier.
+        let target = parent.target as u128 * actual as u128 / expected.max(1) as u128;
+        RetargetDigest {
+            nonce: 0,
+            target: target.clamp(1, u64::MAX as u128) as u64,
+            window_start: header.timestamp,
+            recent,
+        }
+    }
+
+    /// Whether the header's timestamp follows the timestamp rules, given its parent's digest.
+    fn timestamp_is_plausible<D>(&self, parent: &RetargetDigest, header: &Header<D>) -> bool {
+        self.rules
+            .check_timestamp(Some(parent.recent.median()), header.timestamp)
+            .is_ok()
+    }
+}
+
+impl<C: Clock> Consensus for RetargetingPow<C> {
+    type Digest = RetargetDigest;
+
+    /// Check that the header's timestamp is plausible, that it uses the correct target, window
+    /// and recent timestamps, and that its hash is below the target.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let expected = RetargetDigest {
+            nonce: header.consensus_digest.nonce,
+            ..self.next_digest(parent_digest, header)
+        };
+        self.timestamp_is_plausible(parent_digest, header)
+            && header.consensus_digest == expected
+            && header.hash() < expected.target
+    }
+
+    /// Mine a nonce against the correct target for the partial header. Returns None if the
+    /// partial header's timestamp is implausible.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        if !self.timestamp_is_plausible(parent_digest, &partial_header) {
+            return None;
+        }
+        let mut header = Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: self.next_digest(parent_digest, &partial_header),
+        };
+        for nonce in 0..=u64::MAX {
+            header.consensus_digest.nonce = nonce;
+            if header.hash() < header.consensus_digest.target {
+                return Some(header);
+            }
+        }
+        None
+    }
+
+    fn human_name() -> String {
+        "Retargeting Proof of Work".into()
+    }
+}
+
+/// Configured in a chain spec as
+/// `{ "engine": "retargeting_pow", "interval": <u64>, "block_time": <u64>, "initial_target": <u64> }`
+impl FromJson for RetargetingPow {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        expect_engine(j, "retargeting_pow")?;
+        let engine = RetargetingPow {
+            interval: j.parse_field("interval")?,
+            block_time: j.parse_field("block_time")?,
+            initial_target: j.parse_field("initial_target")?,
+            rules: TimestampRules::new(SystemClock),
+        };
+        if engine.interval == 0 || engine.block_time == 0 {
+            return Err(json::Error::Invalid(
+                "the retarget interval and block time must be positive".into(),
+            ));
+        }
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+fn mine_chain<C: Clock>(
+    engine: &RetargetingPow<C>,
+    genesis: RetargetDigest,
+    block_times: &[u64],
+) -> Vec<Header<RetargetDigest>> {
+    let mut parent = genesis;
+    let mut timestamp = genesis.window_start;
+    let mut chain = Vec::new();
+    for (i, block_time) in block_times.iter().enumerate() {
+        timestamp += block_time;
+        let partial = Header {
+            parent: 0,
+            height: i as u64 + 1,
+            state_root: 0,
+            extrinsics_root: 0,
+            timestamp,
+            consensus_digest: (),
+        };
+        let header = engine.seal(&parent, partial).unwrap();
+        assert!(engine.validate(&parent, &header));
+        parent = header.consensus_digest;
+        chain.push(header);
+    }
+    chain
+}
+
+#[test]
+fn target_follows_block_time() {
+    let engine = RetargetingPow {
+        interval: 4,
+        block_time: 1_000,
+        initial_target: u64::MAX / 16,
+        rules: TimestampRules::new(SystemClock),
+    };
+    let genesis = engine.genesis_digest(0);
+
+    // Blocks twice as fast as intended halve the target at the next window.
+    let fast = mine_chain(&engine, genesis, &[500; 5]);
+    assert_eq!(fast[2].consensus_digest.target, u64::MAX / 16);
+    assert_eq!(fast[3].consensus_digest.target, u64::MAX / 32);
+    assert_eq!(fast[3].consensus_digest.window_start, 2_000);
+    assert_eq!(fast[4].consensus_digest.target, u64::MAX / 32);
+
+    // Very slow blocks only ease the target by the maximum adjustment.
+    let slow = mine_chain(&engine, genesis, &[100_000; 4]);
+    assert_eq!(slow[3].consensus_digest.target, u64::MAX / 16 * 4);
+}
+
+#[test]
+fn blocks_must_use_the_correct_target() {
+    let engine = RetargetingPow {
+        interval: 2,
+        block_time: 1_000,
+        initial_target: u64::MAX / 2,
+        rules: TimestampRules::new(SystemClock),
+    };
+    let genesis = engine.genesis_digest(0);
+    let chain = mine_chain(&engine, genesis, &[1_000, 1_000]);
+
+    // Claiming an easier target than the rules allow is rejected, even with a valid hash.
+    let mut easier = chain[1].clone();
+    easier.consensus_digest.target = u64::MAX;
+    while easier.hash() >= u64::MAX / 2 {
+        easier.consensus_digest.nonce += 1;
+    }
+    assert!(!engine.validate(&chain[0].consensus_digest, &easier));
+
+    // The same goes for pretending the window started at a different time.
+    let mut moved = chain[1].clone();
+    moved.consensus_digest.window_start += 1;
+    assert!(!engine.validate(&chain[0].consensus_digest, &moved));
+}
+
+#[test]
+fn timestamps_cannot_warp_the_target() {
+    use super::timestamp::{MockClock, MAX_FUTURE_DRIFT};
+
+    let engine = RetargetingPow {
+        interval: 4,
+        block_time: 1_000,
+        initial_target: u64::MAX / 16,
+        rules: TimestampRules::new(MockClock::new(10_000)),
+    };
+    let genesis = engine.genesis_digest(0);
+    // A miner keeps time as slow as the median time past allows, to make the window look long
+    // once the retarget block claims a time far in the future.
+    let chain = mine_chain(&engine, genesis, &[1, 1, 1]);
+    let retarget = |timestamp| Header {
+        parent: 0,
+        height: 4,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp,
+        consensus_digest: (),
+    };
+
+    // The retarget block may not claim a time too far ahead of the validator's clock.
+    let far_future = 10_000 + MAX_FUTURE_DRIFT + 1;
+    assert!(engine
+        .seal(&chain[2].consensus_digest, retarget(far_future))
+        .is_none());
+    engine.rules.clock().set(far_future - MAX_FUTURE_DRIFT);
+    let warped = engine
+        .seal(&chain[2].consensus_digest, retarget(far_future))
+        .unwrap();
+    engine.rules.clock().set(10_000);
+    assert!(!engine.validate(&chain[2].consensus_digest, &warped));
+
+    // Nor may any block claim a time at or before the median time past.
+    assert!(engine
+        .seal(&chain[2].consensus_digest, retarget(0))
+        .is_none());
+    let parent = &chain[2].consensus_digest;
+    let partial = retarget(parent.recent.median());
+    let mut backwards = Header {
+        consensus_digest: engine.next_digest(parent, &partial),
+        parent: partial.parent,
+        height: partial.height,
+        state_root: partial.state_root,
+        extrinsics_root: partial.extrinsics_root,
+        timestamp: partial.timestamp,
+    };
+    while backwards.hash() >= backwards.consensus_digest.target {
+        backwards.consensus_digest.nonce += 1;
+    }
+    assert!(!engine.validate(parent, &backwards));
+
+    // The recent timestamps cannot be forged to move the median time past.
+    let mut forged = chain[1].clone();
+    forged.consensus_digest.recent = RecentTimestamps::genesis(0);
+    assert!(!engine.validate(&chain[0].consensus_digest, &forged));
+}
+
+#[test]
+fn retargeting_pow_from_chain_spec() {
+    let engine: RetargetingPow = json::from_str(
+        r#"{ "engine": "retargeting_pow", "interval": 10, "block_time": 6000, "initial_target": 99 }"#,
+    )
+    .unwrap();
+    assert_eq!(engine.interval, 10);
+    assert_eq!(engine.genesis_digest(5).target, 99);
+
+    assert!(json::from_str::<RetargetingPow>(
+        r#"{ "engine": "retargeting_pow", "interval": 0, "block_time": 6000, "initial_target": 99 }"#,
+    )
+    .is_err());
+}
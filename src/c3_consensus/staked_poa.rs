@@ -0,0 +1,196 @@
+//! The Proof of Authority module mentions that PoA becomes Proof of Stake when the authorities are
+//! elected on-chain by staking tokens. Here we connect the staking state machine to the consensus
+//! layer.
+//!
+//! Time is divided into eras of a fixed number of blocks. The staking state machine elects a new
+//! authority set at every era boundary, and the client enacts it in this consensus engine. Blocks
+//! in each era must then be signed by one of the authorities elected for that era. Changing the
+//! authorities only at era boundaries means every node agrees on exactly which blocks each
+//! authority set is responsible for.
+
+use super::{Consensus, ConsensusAuthority, Header};
+use crate::c1_state_machine::{StakingState, User};
+
+/// A Proof of Stake consensus engine whose authorities are elected by the staking state machine.
+pub struct ElectedPoa {
+    /// The number of blocks in each era.
+    era_length: u64,
+    /// The authority set of every era that has been enacted so far, starting with era 0.
+    authority_sets: Vec<Vec<ConsensusAuthority>>,
+    /// The authority that this node seals blocks as.
+    signer: ConsensusAuthority,
+}
+
+impl ElectedPoa {
+    /// Create an engine whose genesis era is signed by the given authorities.
+    pub fn new(
+        era_length: u64,
+        genesis_authorities: Vec<ConsensusAuthority>,
+        signer: ConsensusAuthority,
+    ) -> Self {
+        assert!(era_length > 0, "eras must contain at least one block");
+        ElectedPoa {
+            era_length,
+            authority_sets: vec![genesis_authorities],
+            signer,
+        }
+    }
+
+    /// The era that the block at the given height belongs to.
+    pub fn era_of(&self, height: u64) -> u64 {
+        height / self.era_length
+    }
+
+    /// The authorities of the given era, if it has been enacted.
+    pub fn authorities(&self, era: u64) -> Option<&[ConsensusAuthority]> {
+        self.authority_sets.get(era as usize).map(Vec::as_slice)
+    }
+
+    /// Enact the authority set elected by the staking state machine for its current era.
+    ///
+    /// Eras must be enacted in order. Enacting an era again is allowed as long as the election
+    /// result is the same, which lets a client re-import the same blocks. If nobody was elected,
+    /// the previous authorities carry on so that the chain does not halt.
+    ///
+    /// Returns false, changing nothing, if the era is out of order or conflicts with the set
+    /// that was already enacted.
+    pub fn enact(&mut self, staking: &StakingState) -> bool {
+        let era = staking.era() as usize;
+        let elected: Vec<ConsensusAuthority> = match staking.elected() {
+            [] if era > 0 => match self.authority_sets.get(era - 1) {
+                Some(previous) => previous.clone(),
+                None => return false,
+            },
+            elected => elected.iter().copied().map(Into::into).collect(),
+        };
+        match self.authority_sets.get(era) {
+            Some(enacted) => *enacted == elected,
+            None if era == self.authority_sets.len() => {
+                self.authority_sets.push(elected);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl From<User> for ConsensusAuthority {
+    fn from(user: User) -> Self {
+        match user {
+            User::Alice => ConsensusAuthority::Alice,
+            User::Bob => ConsensusAuthority::Bob,
+            User::Charlie => ConsensusAuthority::Charlie,
+        }
+    }
+}
+
+impl From<ConsensusAuthority> for User {
+    fn from(authority: ConsensusAuthority) -> Self {
+        match authority {
+            ConsensusAuthority::Alice => User::Alice,
+            ConsensusAuthority::Bob => User::Bob,
+            ConsensusAuthority::Charlie => User::Charlie,
+        }
+    }
+}
+
+impl Consensus for ElectedPoa {
+    type Digest = ConsensusAuthority;
+
+    /// A block is valid if it is signed by one of the authorities of its era.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        self.authorities(self.era_of(header.height))
+            .is_some_and(|authorities| authorities.contains(&header.consensus_digest))
+    }
+
+    /// Seal the block with this node's signer, if it is an authority in the block's era.
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        let authorities = self.authorities(self.era_of(partial_header.height))?;
+        if !authorities.contains(&self.signer) {
+            return None;
+        }
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: self.signer,
+        })
+    }
+
+    fn human_name() -> String {
+        "Elected Proof of Stake".into()
+    }
+}
+
+#[test]
+fn elected_authorities_take_over_at_era_boundary() {
+    use crate::c1_state_machine::{Staking, StakingTransaction, StateMachine};
+    use std::collections::HashMap;
+
+    let staking = StakingState::new(HashMap::from([(User::Bob, 50)]), 1, 10);
+    let mut bob = ElectedPoa::new(10, vec![ConsensusAuthority::Alice], ConsensusAuthority::Bob);
+
+    // Bob cannot seal in the genesis era, where only Alice is an authority.
+    assert!(bob
+        .seal(&ConsensusAuthority::Alice, Header::partial(0, 9, 0, 0, 0))
+        .is_none());
+
+    let staking = Staking::next_state(
+        &staking,
+        &StakingTransaction::Bond {
+            who: User::Bob,
+            amount: 50,
+        },
+    );
+    // The election happens as the first block of era 1 starts.
+    let staking = Staking::begin_block(&staking, 10);
+    assert!(bob.enact(&staking));
+    assert_eq!(bob.authorities(1), Some(&[ConsensusAuthority::Bob][..]));
+
+    let last_of_era_0 = bob.seal(&ConsensusAuthority::Alice, Header::partial(0, 9, 0, 0, 0));
+    let first_of_era_1 = bob
+        .seal(&ConsensusAuthority::Alice, Header::partial(0, 10, 0, 0, 0))
+        .unwrap();
+    assert!(last_of_era_0.is_none());
+    assert!(bob.validate(&ConsensusAuthority::Alice, &first_of_era_1));
+
+    // Alice's signature is only good until her era ends.
+    let alice_late = Header {
+        consensus_digest: ConsensusAuthority::Alice,
+        ..first_of_era_1
+    };
+    assert!(!bob.validate(&ConsensusAuthority::Bob, &alice_late));
+
+    // Eras that have not been enacted yet have no authorities.
+    let too_early = Header {
+        height: 20,
+        ..alice_late
+    };
+    assert!(!bob.validate(&ConsensusAuthority::Bob, &too_early));
+}
+
+#[test]
+fn empty_election_keeps_previous_authorities() {
+    use crate::c1_state_machine::{Staking, StateMachine};
+    use std::collections::HashMap;
+
+    let staking = StakingState::new(HashMap::new(), 2, 5);
+    let staking = Staking::begin_block(&staking, 5);
+    let mut engine = ElectedPoa::new(
+        5,
+        vec![ConsensusAuthority::Charlie],
+        ConsensusAuthority::Charlie,
+    );
+
+    assert!(engine.enact(&staking));
+    assert_eq!(
+        engine.authorities(1),
+        Some(&[ConsensusAuthority::Charlie][..])
+    );
+
+    // Skipping an era is not allowed.
+    let skipped = Staking::begin_block(&staking, 15);
+    assert!(!engine.enact(&skipped));
+}
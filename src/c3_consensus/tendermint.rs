@@ -0,0 +1,364 @@
+//! Every engine so far lets a single authority or miner seal a block on its own. Competing blocks
+//! can always appear, so a block is only ever final with some probability, which grows as more
+//! blocks are built on top of it.
+//!
+//! Byzantine fault tolerant engines, like Tendermint, instead have a set of validators vote on each
+//! block before it is added to the chain. A weighted proposer suggests a block, and the validators
+//! vote on it in two steps. First they prevote. A validator that sees prevotes for the block from
+//! more than two thirds of the voting weight precommits it. And a block with precommits from more
+//! than two thirds of the weight is committed, immediately and irreversibly. As long as less than a
+//! third of the weight is faulty, two conflicting blocks can never both be committed. If a round
+//! fails, because the proposer was offline or the votes were split, the validators move on to the
+//! next round with the next proposer.
+//!
+//! The precommits that committed a block make up its commit certificate. Each block carries the
+//! certificate of its parent, so that anyone can check that the chain they are following was
+//! really committed by the validators, without having watched the votes.
+//!
+//! As elsewhere in this tutorial, votes are "signed" by naming the validator that cast them.
+
+use super::{Consensus, ConsensusAuthority, Header};
+use crate::codec::{decode_tag, Decode, Encode, Error};
+use std::collections::HashSet;
+
+/// The two voting steps of each round.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+/// A validator's vote in one step of one round. A vote for no block is a vote to move on to the
+/// next round.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Vote {
+    pub kind: VoteKind,
+    pub height: u64,
+    pub round: u64,
+    pub block: Option<u64>,
+    pub validator: ConsensusAuthority,
+}
+
+/// Proof that a block was committed: the validators who precommitted it in one round.
+#[derive(Hash, Debug, PartialEq, Eq, Clone)]
+pub struct CommitCertificate {
+    pub height: u64,
+    pub round: u64,
+    /// The hash of the committed block.
+    pub block: u64,
+    pub signers: Vec<ConsensusAuthority>,
+}
+
+impl CommitCertificate {
+    /// Collect the precommits for the given block in the given round into a certificate.
+    pub fn from_precommits(height: u64, round: u64, block: u64, votes: &[Vote]) -> Self {
+        let mut signers: Vec<ConsensusAuthority> = votes
+            .iter()
+            .filter(|v| {
+                v.kind == VoteKind::Precommit
+                    && v.height == height
+                    && v.round == round
+                    && v.block == Some(block)
+            })
+            .map(|v| v.validator)
+            .collect();
+        signers.sort_by_key(|signer| *signer as u8);
+        signers.dedup();
+        CommitCertificate {
+            height,
+            round,
+            block,
+            signers,
+        }
+    }
+}
+
+/// The digest of a Tendermint block.
+#[derive(Hash, Debug, PartialEq, Eq, Clone)]
+pub struct TendermintDigest {
+    /// The round in which the block was proposed.
+    pub round: u64,
+    pub proposer: ConsensusAuthority,
+    /// The certificate that committed the parent. The genesis block is final by definition,
+    /// so the first block after it has none.
+    pub parent_commit: Option<CommitCertificate>,
+}
+
+impl Encode for CommitCertificate {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.height.encode_to(dest);
+        self.round.encode_to(dest);
+        self.block.encode_to(dest);
+        self.signers.encode_to(dest);
+    }
+}
+
+impl Decode for CommitCertificate {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(CommitCertificate {
+            height: u64::decode(input)?,
+            round: u64::decode(input)?,
+            block: u64::decode(input)?,
+            signers: Vec::decode(input)?,
+        })
+    }
+}
+
+impl Encode for TendermintDigest {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.round.encode_to(dest);
+        self.proposer.encode_to(dest);
+        self.parent_commit.encode_to(dest);
+    }
+}
+
+impl Decode for TendermintDigest {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(TendermintDigest {
+            round: u64::decode(input)?,
+            proposer: ConsensusAuthority::decode(input)?,
+            parent_commit: Option::decode(input)?,
+        })
+    }
+}
+
+impl Encode for VoteKind {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        dest.push(*self as u8);
+    }
+}
+
+impl Decode for VoteKind {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(VoteKind::Prevote),
+            1 => Ok(VoteKind::Precommit),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+/// A BFT consensus engine in which blocks are committed by a weighted validator set.
+pub struct Tendermint {
+    /// The active validators, each with its voting weight.
+    validators: Vec<(ConsensusAuthority, u64)>,
+    /// The validator that this node proposes blocks as.
+    signer: ConsensusAuthority,
+    /// The certificate of the latest block this node saw committed, for sealing its child.
+    last_commit: Option<CommitCertificate>,
+}
+
+impl Tendermint {
+    pub fn new(validators: Vec<(ConsensusAuthority, u64)>, signer: ConsensusAuthority) -> Self {
+        assert!(
+            validators.iter().any(|(_, weight)| *weight > 0),
+            "Tendermint needs at least one validator with weight"
+        );
+        Tendermint {
+            validators,
+            signer,
+            last_commit: None,
+        }
+    }
+
+    pub fn signer(&self) -> ConsensusAuthority {
+        self.signer
+    }
+
+    /// The combined weight of the given validators. Validators outside the active set count for
+    /// nothing, and each validator counts only once.
+    pub fn weight_of(&self, signers: impl IntoIterator<Item = ConsensusAuthority>) -> u64 {
+        let signers: HashSet<ConsensusAuthority> = signers.into_iter().collect();
+        self.validators
+            .iter()
+            .filter(|(validator, _)| signers.contains(validator))
+            .map(|(_, weight)| weight)
+            .sum()
+    }
+
+    /// Whether the given validators hold more than two thirds of the voting weight.
+    pub fn is_quorum(&self, signers: impl IntoIterator<Item = ConsensusAuthority>) -> bool {
+        let total: u64 = self.validators.iter().map(|(_, weight)| weight).sum();
+        3 * self.weight_of(signers) as u128 > 2 * total as u128
+    }
+
+    /// The validator who proposes at the given height and round. Proposers rotate through the
+    /// validators, each getting turns in proportion to its weight.
+    pub fn proposer(&self, height: u64, round: u64) -> ConsensusAuthority {
+        let total: u64 = self.validators.iter().map(|(_, weight)| weight).sum();
+        let mut turn = height.wrapping_add(round) % total;
+        for (validator, weight) in &self.validators {
+            if turn < *weight {
+                return *validator;
+            }
+            turn -= weight;
+        }
+        unreachable!("turns are always less than the total weight")
+    }
+
+    /// Check a certificate against the active validator set.
+    pub fn verify_certificate(&self, certificate: &CommitCertificate) -> bool {
+        self.is_quorum(certificate.signers.iter().copied())
+    }
+
+    /// Note that a block was committed, so this node can seal its child.
+    pub fn note_commit(&mut self, certificate: CommitCertificate) {
+        self.last_commit = Some(certificate);
+    }
+
+    /// Propose the partial header in the given round. Returns None if this node is not the round's
+    /// proposer, or if it has not seen the parent committed.
+    pub fn seal_in_round(
+        &self,
+        partial_header: Header<()>,
+        round: u64,
+    ) -> Option<Header<TendermintDigest>> {
+        if self.proposer(partial_header.height, round) != self.signer {
+            return None;
+        }
+        let parent_commit = match partial_header.height {
+            1 => None,
+            height => {
+                let commit = self.last_commit.clone()?;
+                if commit.height != height - 1 || commit.block != partial_header.parent {
+                    return None;
+                }
+                Some(commit)
+            }
+        };
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: TendermintDigest {
+                round,
+                proposer: self.signer,
+                parent_commit,
+            },
+        })
+    }
+}
+
+impl Consensus for Tendermint {
+    type Digest = TendermintDigest;
+
+    /// Check that the block was proposed by the right validator, and that it carries a valid
+    /// certificate committing its parent.
+    ///
+    /// This checks that the block is a valid proposal. The block itself is only committed once
+    /// its own certificate appears, in the digest of its child.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        if header.height == 0 || digest.proposer != self.proposer(header.height, digest.round) {
+            return false;
+        }
+        match (&digest.parent_commit, header.height) {
+            (None, 1) => true,
+            (Some(commit), height) if height > 1 => {
+                commit.height == height - 1
+                    && commit.block == header.parent
+                    && self.verify_certificate(commit)
+            }
+            _ => false,
+        }
+    }
+
+    /// Propose the partial header in the first round.
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        self.seal_in_round(partial_header, 0)
+    }
+
+    fn human_name() -> String {
+        "Tendermint".into()
+    }
+}
+
+#[cfg(test)]
+fn validators() -> Vec<(ConsensusAuthority, u64)> {
+    use ConsensusAuthority::*;
+    vec![(Alice, 2), (Bob, 1), (Charlie, 1)]
+}
+
+#[cfg(test)]
+fn precommits(height: u64, round: u64, block: u64, validators: &[ConsensusAuthority]) -> Vec<Vote> {
+    validators
+        .iter()
+        .map(|validator| Vote {
+            kind: VoteKind::Precommit,
+            height,
+            round,
+            block: Some(block),
+            validator: *validator,
+        })
+        .collect()
+}
+
+#[test]
+fn quorum_needs_more_than_two_thirds_of_the_weight() {
+    use ConsensusAuthority::*;
+    let engine = Tendermint::new(validators(), Alice);
+
+    assert!(!engine.is_quorum([Alice]));
+    assert!(!engine.is_quorum([Bob, Charlie]));
+    assert!(engine.is_quorum([Alice, Bob]));
+    // Counting a validator twice does not help.
+    assert!(!engine.is_quorum([Bob, Charlie, Bob, Charlie]));
+
+    // Alice holds half of the weight, so she gets half of the proposals.
+    let proposers: Vec<_> = (0..4).map(|round| engine.proposer(1, round)).collect();
+    assert_eq!(proposers, [Alice, Bob, Charlie, Alice]);
+}
+
+#[test]
+fn blocks_carry_their_parents_certificate() {
+    use ConsensusAuthority::*;
+    let mut alice = Tendermint::new(validators(), Alice);
+    let bob = Tendermint::new(validators(), Bob);
+
+    // Alice proposes the first block, and nobody else can.
+    let first = alice
+        .seal_in_round(Header::partial(0, 1, 0, 0, 0), 0)
+        .unwrap();
+    assert!(bob
+        .seal_in_round(Header::partial(0, 1, 0, 0, 0), 0)
+        .is_none());
+    assert!(bob.validate(&first.consensus_digest, &first));
+    let hash = first.hash();
+
+    // Alice can only propose the next block once she has seen the first one committed.
+    assert!(alice
+        .seal_in_round(Header::partial(hash, 2, 0, 0, 0), 2)
+        .is_none());
+    let commit =
+        CommitCertificate::from_precommits(1, 0, hash, &precommits(1, 0, hash, &[Bob, Alice]));
+    alice.note_commit(commit.clone());
+    let second = alice
+        .seal_in_round(Header::partial(hash, 2, 0, 0, 0), 2)
+        .unwrap();
+    assert!(bob.validate(&first.consensus_digest, &second));
+
+    // A certificate without quorum does not count.
+    let mut weak = second.clone();
+    weak.consensus_digest.parent_commit = Some(CommitCertificate {
+        signers: vec![Bob, Charlie],
+        ..commit.clone()
+    });
+    assert!(!bob.validate(&first.consensus_digest, &weak));
+
+    // Nor does a certificate for some other block, or a missing one.
+    let mut other = second.clone();
+    other.consensus_digest.parent_commit = Some(CommitCertificate {
+        block: hash + 1,
+        ..commit
+    });
+    assert!(!bob.validate(&first.consensus_digest, &other));
+    let mut missing = second.clone();
+    missing.consensus_digest.parent_commit = None;
+    assert!(!bob.validate(&first.consensus_digest, &missing));
+
+    // Certificates are checked against the active validator set.
+    let reshuffled = Tendermint::new(vec![(Alice, 1), (Bob, 1), (Charlie, 5)], Charlie);
+    assert!(!reshuffled.verify_certificate(second.consensus_digest.parent_commit.as_ref().unwrap()));
+}
@@ -0,0 +1,149 @@
+//! In `SimplePoa`, any one authority may seal a block on its own. So a single dishonest authority
+//! can sign blocks full of invalid transitions, and nobody can stop it from doing so.
+//!
+//! Here blocks need seals from several authorities instead. Each block must be signed by at least
+//! M of the N authorities. One authority proposes a block and signs it, then passes it around for
+//! the others to check and co-sign. A dishonest authority now needs M - 1 accomplices, and blocks
+//! keep coming as long as M honest authorities are online.
+//!
+//! Real chains often combine the signatures into a single threshold or aggregated signature, which
+//! is much smaller. Since our signatures are just authority names, we keep the list of signers.
+
+use super::{Consensus, ConsensusAuthority, Header};
+use crate::codec::{Decode, Encode, Error};
+use std::collections::HashSet;
+
+/// The seal of a threshold PoA block: the authorities that signed it.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Default)]
+pub struct ThresholdSeal {
+    pub signers: Vec<ConsensusAuthority>,
+}
+
+impl Encode for ThresholdSeal {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.signers.encode_to(dest);
+    }
+}
+
+impl Decode for ThresholdSeal {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(ThresholdSeal {
+            signers: Vec::decode(input)?,
+        })
+    }
+}
+
+/// A Proof of Authority consensus engine in which every block needs signatures from at least
+/// `threshold` distinct authorities.
+pub struct ThresholdPoa {
+    authorities: Vec<ConsensusAuthority>,
+    /// How many distinct authorities must sign each block.
+    threshold: usize,
+    /// The authority that this node signs blocks as.
+    signer: ConsensusAuthority,
+}
+
+impl ThresholdPoa {
+    pub fn new(
+        authorities: Vec<ConsensusAuthority>,
+        threshold: usize,
+        signer: ConsensusAuthority,
+    ) -> Self {
+        assert!(
+            threshold > 0 && threshold <= authorities.len(),
+            "the threshold must be between one and the number of authorities"
+        );
+        ThresholdPoa {
+            authorities,
+            threshold,
+            signer,
+        }
+    }
+
+    /// Add this node's signature to a header that another authority proposed. Signing the same
+    /// header twice changes nothing.
+    pub fn cosign(&self, mut header: Header<ThresholdSeal>) -> Header<ThresholdSeal> {
+        if !header.consensus_digest.signers.contains(&self.signer) {
+            header.consensus_digest.signers.push(self.signer);
+        }
+        header
+    }
+
+    /// Whether the header has been signed by enough distinct authorities, and only by authorities.
+    pub fn is_sealed(&self, header: &Header<ThresholdSeal>) -> bool {
+        let signers = &header.consensus_digest.signers;
+        let distinct: HashSet<&ConsensusAuthority> = signers.iter().collect();
+        distinct.len() == signers.len()
+            && signers
+                .iter()
+                .all(|signer| self.authorities.contains(signer))
+            && distinct.len() >= self.threshold
+    }
+}
+
+impl Consensus for ThresholdPoa {
+    type Digest = ThresholdSeal;
+
+    /// Check that at least `threshold` distinct authorities signed the header, and nobody else.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        self.is_sealed(header)
+    }
+
+    /// Propose the partial header, signed by this node only. Unless the threshold is one, the
+    /// header is not valid until enough other authorities `cosign` it.
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: ThresholdSeal {
+                signers: vec![self.signer],
+            },
+        })
+    }
+
+    fn human_name() -> String {
+        "Threshold Proof of Authority".into()
+    }
+}
+
+#[test]
+fn blocks_need_m_of_n_signatures() {
+    use ConsensusAuthority::*;
+    let authorities = vec![Alice, Bob, Charlie];
+    let alice = ThresholdPoa::new(authorities.clone(), 2, Alice);
+    let bob = ThresholdPoa::new(authorities.clone(), 2, Bob);
+    let parent = ThresholdSeal::default();
+
+    // Alice alone cannot seal a block.
+    let proposed = alice.seal(&parent, Header::partial(0, 1, 0, 0, 0)).unwrap();
+    assert!(!alice.validate(&parent, &proposed));
+    assert!(!alice.validate(&parent, &alice.cosign(proposed.clone())));
+
+    // With Bob's signature, everyone accepts it.
+    let sealed = bob.cosign(proposed);
+    assert!(alice.validate(&parent, &sealed));
+    assert!(bob.validate(&parent, &sealed));
+    assert_eq!(sealed.consensus_digest.signers, [Alice, Bob]);
+}
+
+#[test]
+fn signatures_must_be_distinct_authorities() {
+    use ConsensusAuthority::*;
+    let engine = ThresholdPoa::new(vec![Alice, Bob], 2, Alice);
+    let parent = ThresholdSeal::default();
+    let with_signers = |signers: Vec<ConsensusAuthority>| Header {
+        consensus_digest: ThresholdSeal { signers },
+        ..engine
+            .seal(&parent, Header::partial(0, 1, 0, 0, 0))
+            .unwrap()
+    };
+
+    assert!(engine.validate(&parent, &with_signers(vec![Bob, Alice])));
+    assert!(!engine.validate(&parent, &with_signers(vec![Alice, Alice])));
+    // Charlie is not an authority, so his signature does not count, and spoils the seal.
+    assert!(!engine.validate(&parent, &with_signers(vec![Alice, Charlie])));
+    assert!(!engine.validate(&parent, &with_signers(vec![Alice, Bob, Charlie])));
+}
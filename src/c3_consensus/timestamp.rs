@@ -0,0 +1,455 @@
+//! Headers carry the time at which their block was authored. Slot-based engines need it to relate
+//! slots to wall time, and proof of work needs it to notice when blocks are coming too fast or too
+//! slow and adjust the difficulty.
+//!
+//! Block authors pick their own timestamps, and there is no way to prove what time it really was.
+//! So rather than trusting timestamps, nodes only require them to be plausible. A timestamp must be
+//! later than the median of the timestamps of the last few ancestors, known as the median time
+//! past. Using the median means a few authors with bad clocks cannot drag time backwards. And a
+//! timestamp must not be too far ahead of the node's own clock, so authors cannot rush time forward.
+//!
+//! Comparing against the node's clock makes validation depend on when it happens. To keep that
+//! testable, the clock is injected through the `Clock` trait and tests use a `MockClock`.
+//!
+//! Consensus engines only see their parent's digest, not the whole chain. So the `Timestamped`
+//! engine carries the recent timestamps in its digest, and checks each header's timestamp before
+//! handing it to the engine it wraps. Since clients validate every block with their consensus
+//! engine as they import it, wrapping an engine is all it takes to enforce the timestamp rules.
+
+use super::{Consensus, Header};
+use crate::codec::{Decode, Encode, Error};
+use std::cell::Cell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many ancestors the median time past is taken over.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far ahead of the node's clock a timestamp may be, in milliseconds. Clocks on different
+/// machines never agree exactly, so some drift has to be tolerated.
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60 * 1000;
+
+/// A source of the current time, in milliseconds since the Unix epoch.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// The operating system's clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+}
+
+/// A clock that only moves when it is told to. Useful in tests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockClock {
+    now: Cell<u64>,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        MockClock {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.now.set(self.now.get() + millis);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+impl<Digest> Header<Digest> {
+    /// When the block was authored, in milliseconds since the Unix epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+/// The median timestamp of the last `MEDIAN_TIME_SPAN` headers, which must be given oldest first.
+/// Returns None if there are no headers.
+pub fn median_time_past<D>(ancestors: &[Header<D>]) -> Option<u64> {
+    let recent = &ancestors[ancestors.len().saturating_sub(MEDIAN_TIME_SPAN)..];
+    let mut timestamps: Vec<u64> = recent.iter().map(|h| h.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied()
+}
+
+/// The timestamps of the last `MEDIAN_TIME_SPAN` blocks, oldest first, for engines that carry
+/// them in their digests. At genesis, every entry is the genesis timestamp.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RecentTimestamps([u64; MEDIAN_TIME_SPAN]);
+
+impl RecentTimestamps {
+    pub fn genesis(timestamp: u64) -> Self {
+        RecentTimestamps([timestamp; MEDIAN_TIME_SPAN])
+    }
+
+    /// The median time past of a child of the newest block.
+    pub fn median(&self) -> u64 {
+        let mut timestamps = self.0;
+        timestamps.sort_unstable();
+        timestamps[MEDIAN_TIME_SPAN / 2]
+    }
+
+    /// The recent timestamps once a block with the given timestamp is added.
+    pub fn push(&self, timestamp: u64) -> Self {
+        let mut timestamps = self.0;
+        timestamps.rotate_left(1);
+        timestamps[MEDIAN_TIME_SPAN - 1] = timestamp;
+        RecentTimestamps(timestamps)
+    }
+}
+
+impl Encode for RecentTimestamps {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        for timestamp in self.0 {
+            timestamp.encode_to(dest);
+        }
+    }
+}
+
+impl Decode for RecentTimestamps {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let mut timestamps = [0; MEDIAN_TIME_SPAN];
+        for timestamp in &mut timestamps {
+            *timestamp = u64::decode(input)?;
+        }
+        Ok(RecentTimestamps(timestamps))
+    }
+}
+
+/// The slot that a timestamp falls in, for engines that divide time into slots of the given
+/// length in milliseconds.
+pub fn slot_at(timestamp: u64, slot_duration: u64) -> u64 {
+    timestamp / slot_duration
+}
+
+/// The ways in which a timestamp can be implausible.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TimestampError {
+    /// The timestamp is not later than the median time past.
+    TooOld { timestamp: u64, median: u64 },
+    /// The timestamp is further ahead of this node's clock than `MAX_FUTURE_DRIFT`.
+    TooNew { timestamp: u64, now: u64 },
+}
+
+/// Checks header timestamps against their ancestors and this node's clock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimestampRules<C: Clock> {
+    clock: C,
+}
+
+impl<C: Clock> TimestampRules<C> {
+    pub fn new(clock: C) -> Self {
+        TimestampRules { clock }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Check the timestamp of a header whose ancestors, oldest first and ending with its parent,
+    /// are given. Only the last `MEDIAN_TIME_SPAN` ancestors matter. The genesis header has no
+    /// ancestors, so only the clock is checked.
+    pub fn check<D>(
+        &self,
+        ancestors: &[Header<D>],
+        header: &Header<D>,
+    ) -> Result<(), TimestampError> {
+        self.check_timestamp(median_time_past(ancestors), header.timestamp)
+    }
+
+    /// Check a timestamp against the median time past, if there is one, and this node's clock.
+    pub fn check_timestamp(
+        &self,
+        median: Option<u64>,
+        timestamp: u64,
+    ) -> Result<(), TimestampError> {
+        if let Some(median) = median {
+            if timestamp <= median {
+                return Err(TimestampError::TooOld { timestamp, median });
+            }
+        }
+        let now = self.clock.now();
+        if timestamp > now.saturating_add(MAX_FUTURE_DRIFT) {
+            return Err(TimestampError::TooNew { timestamp, now });
+        }
+        Ok(())
+    }
+
+    /// Stamp a partial header with the current time, or with the earliest valid time if this
+    /// node's clock is behind the median time past.
+    pub fn stamp<D>(&self, ancestors: &[Header<D>], partial_header: Header<()>) -> Header<()> {
+        Header {
+            timestamp: self.now_after(median_time_past(ancestors)),
+            ..partial_header
+        }
+    }
+
+    /// The current time, or the earliest time after the median time past if that is later.
+    fn now_after(&self, median: Option<u64>) -> u64 {
+        let earliest = median.map_or(0, |median| median.saturating_add(1));
+        self.clock.now().max(earliest)
+    }
+}
+
+/// A consensus engine that checks each header's timestamp, and then has the wrapped engine
+/// check the rest of the header.
+pub struct Timestamped<E, C: Clock> {
+    pub engine: E,
+    pub rules: TimestampRules<C>,
+}
+
+/// The digest of a timestamped block.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TimestampedDigest<D> {
+    /// The digest of the wrapped engine.
+    pub inner: D,
+    /// The timestamps of the most recent blocks, ending with this one.
+    pub recent: RecentTimestamps,
+}
+
+impl<E: Consensus, C: Clock> Timestamped<E, C> {
+    pub fn new(engine: E, clock: C) -> Self {
+        Timestamped {
+            engine,
+            rules: TimestampRules::new(clock),
+        }
+    }
+
+    /// The digest of a genesis block authored at the given time.
+    pub fn genesis_digest(inner: E::Digest, timestamp: u64) -> TimestampedDigest<E::Digest> {
+        TimestampedDigest {
+            inner,
+            recent: RecentTimestamps::genesis(timestamp),
+        }
+    }
+}
+
+impl<E: Consensus, C: Clock> Consensus for Timestamped<E, C> {
+    type Digest = TimestampedDigest<E::Digest>;
+
+    /// Check the timestamp against the median time past and this node's clock, and that the
+    /// recent timestamps were carried over correctly, before validating with the wrapped engine.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let recent = &parent_digest.recent;
+        self.rules
+            .check_timestamp(Some(recent.median()), header.timestamp)
+            .is_ok()
+            && header.consensus_digest.recent == recent.push(header.timestamp)
+            && self.engine.validate(
+                &parent_digest.inner,
+                &Header {
+                    parent: header.parent,
+                    height: header.height,
+                    state_root: header.state_root,
+                    extrinsics_root: header.extrinsics_root,
+                    timestamp: header.timestamp,
+                    consensus_digest: header.consensus_digest.inner.clone(),
+                },
+            )
+    }
+
+    /// Stamp the header with the current time, and then seal it with the wrapped engine.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let timestamp = self.rules.now_after(Some(parent_digest.recent.median()));
+        let sealed = self.engine.seal(
+            &parent_digest.inner,
+            Header {
+                timestamp,
+                ..partial_header
+            },
+        )?;
+        Some(Header {
+            parent: sealed.parent,
+            height: sealed.height,
+            state_root: sealed.state_root,
+            extrinsics_root: sealed.extrinsics_root,
+            timestamp: sealed.timestamp,
+            consensus_digest: TimestampedDigest {
+                inner: sealed.consensus_digest,
+                recent: parent_digest.recent.push(sealed.timestamp),
+            },
+        })
+    }
+
+    fn human_name() -> String {
+        format!("Timestamped {}", E::human_name())
+    }
+}
+
+impl<D: Encode> Encode for TimestampedDigest<D> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.inner.encode_to(dest);
+        self.recent.encode_to(dest);
+    }
+}
+
+impl<D: Decode> Decode for TimestampedDigest<D> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(TimestampedDigest {
+            inner: D::decode(input)?,
+            recent: RecentTimestamps::decode(input)?,
+        })
+    }
+}
+
+#[test]
+fn median_time_past_uses_recent_ancestors() {
+    assert_eq!(median_time_past::<()>(&[]), None);
+
+    let ancestors: Vec<Header<()>> = [5, 1, 3]
+        .into_iter()
+        .map(|timestamp| Header::partial(0, 0, 0, 0, timestamp))
+        .collect();
+    assert_eq!(median_time_past(&ancestors), Some(3));
+
+    // Only the last eleven count, so the very old outliers at the start are ignored.
+    let ancestors: Vec<Header<()>> = [1000, 1000, 1000]
+        .into_iter()
+        .chain(1..=11)
+        .map(|timestamp| Header::partial(0, 0, 0, 0, timestamp))
+        .collect();
+    assert_eq!(median_time_past(&ancestors), Some(6));
+}
+
+#[test]
+fn timestamps_must_be_plausible() {
+    let rules = TimestampRules::new(MockClock::new(10_000));
+    let ancestors: Vec<Header<()>> = [100, 300, 200]
+        .into_iter()
+        .map(|timestamp| Header::partial(0, 0, 0, 0, timestamp))
+        .collect();
+
+    assert_eq!(
+        rules.check(&ancestors, &Header::partial(0, 0, 0, 0, 200)),
+        Err(TimestampError::TooOld {
+            timestamp: 200,
+            median: 200,
+        })
+    );
+    assert_eq!(
+        rules.check(&ancestors, &Header::partial(0, 0, 0, 0, 201)),
+        Ok(())
+    );
+
+    // Blocks from too far in the future become valid once the clock catches up.
+    let future = Header::partial(0, 0, 0, 0, 10_000 + MAX_FUTURE_DRIFT + 1);
+    assert!(matches!(
+        rules.check(&ancestors, &future),
+        Err(TimestampError::TooNew { now: 10_000, .. })
+    ));
+    rules.clock().advance(1);
+    assert_eq!(rules.check(&ancestors, &future), Ok(()));
+}
+
+#[test]
+fn stamping_never_goes_back_in_time() {
+    let rules = TimestampRules::new(MockClock::new(150));
+    let ancestors: Vec<Header<()>> = [100, 300, 200]
+        .into_iter()
+        .map(|timestamp| Header::partial(0, 0, 0, 0, timestamp))
+        .collect();
+
+    let stamped = rules.stamp(&ancestors, Header::partial(0, 0, 0, 0, 0));
+    assert_eq!(stamped.timestamp(), 201);
+    assert_eq!(rules.check(&ancestors, &stamped), Ok(()));
+
+    rules.clock().set(6_000);
+    assert_eq!(
+        rules
+            .stamp(&ancestors, Header::partial(0, 0, 0, 0, 0))
+            .timestamp(),
+        6_000
+    );
+    assert_eq!(slot_at(6_000, 2_000), 3);
+}
+
+#[test]
+fn recent_timestamps_track_the_median() {
+    let recent = RecentTimestamps::genesis(1_000);
+    assert_eq!(recent.median(), 1_000);
+
+    // A single block far in the future cannot drag the median forward.
+    let recent = recent.push(u64::MAX);
+    assert_eq!(recent.median(), 1_000);
+
+    // Once more than half of the recent blocks are later, the median moves.
+    let recent = (0..5).fold(recent, |recent, i| recent.push(2_000 + i));
+    assert_eq!(recent.median(), 2_000);
+
+    let decoded = crate::codec::decode_all(&recent.encode()).unwrap();
+    assert_eq!(recent, decoded);
+}
+
+#[test]
+fn timestamped_engine_checks_timestamps() {
+    use super::staked_poa::ElectedPoa;
+    use super::ConsensusAuthority::Alice;
+
+    let poa = ElectedPoa::new(1, vec![Alice], Alice);
+    let engine = Timestamped::new(poa, MockClock::new(5_000));
+    let genesis = Timestamped::<ElectedPoa, MockClock>::genesis_digest(Alice, 1_000);
+
+    let block_1 = engine
+        .seal(&genesis, Header::partial(0, 0, 0, 0, 0))
+        .unwrap();
+    assert_eq!(block_1.timestamp(), 5_000);
+    assert!(engine.validate(&genesis, &block_1));
+
+    // A clock that is behind the chain still produces valid timestamps.
+    engine.rules.clock().set(0);
+    let block_2 = engine
+        .seal(&block_1.consensus_digest, Header::partial(0, 0, 0, 0, 0))
+        .unwrap();
+    assert_eq!(block_2.timestamp(), 1_001);
+    assert!(engine.validate(&block_1.consensus_digest, &block_2));
+
+    // Timestamps at or before the median time past are rejected, however well sealed.
+    let stale = Header {
+        timestamp: 1_000,
+        consensus_digest: TimestampedDigest {
+            recent: genesis.recent.push(1_000),
+            ..block_1.consensus_digest.clone()
+        },
+        ..block_1.clone()
+    };
+    assert!(!engine.validate(&genesis, &stale));
+
+    // So are timestamps too far ahead of this node's clock, until the clock catches up.
+    let future = Header {
+        timestamp: MAX_FUTURE_DRIFT + 1,
+        consensus_digest: TimestampedDigest {
+            recent: genesis.recent.push(MAX_FUTURE_DRIFT + 1),
+            ..block_1.consensus_digest.clone()
+        },
+        ..block_1.clone()
+    };
+    assert!(!engine.validate(&genesis, &future));
+    engine.rules.clock().set(1);
+    assert!(engine.validate(&genesis, &future));
+
+    // The recent timestamps cannot be rewritten to move the median.
+    let mut rewritten = block_2.clone();
+    rewritten.consensus_digest.recent = RecentTimestamps::genesis(0).push(1_001);
+    assert!(!engine.validate(&block_1.consensus_digest, &rewritten));
+}
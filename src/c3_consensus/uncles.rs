@@ -0,0 +1,382 @@
+//! In proof of work, two miners sometimes find blocks at the same height at nearly the same time.
+//! Only one of them ends up in the canonical chain, and the other goes stale. Until now stale blocks
+//! were simply discarded. That wastes the work that went into them, and it hurts small and poorly
+//! connected miners most, because their blocks are the ones most likely to go stale.
+//!
+//! Ethereum's answer was to let blocks reference recent stale blocks as "uncles" (also known as
+//! ommers). An uncle is a block whose parent is a recent ancestor, but which is not itself an
+//! ancestor. Including uncles pays their miners most of a block reward, and pays the includer a
+//! small bonus for the trouble. Uncles also let every node learn about the work that went into
+//! stale blocks, even if they never saw them, which the GHOST fork choice rule needs.
+//!
+//! Some uncle rules only need the header itself: at most `MAX_UNCLES` uncles, no duplicates, a
+//! valid seal on each, and heights no more than `MAX_UNCLE_DEPTH` behind. Those are checked by the
+//! engine's `validate`. The rest depend on the chain: an uncle's parent must be an ancestor, the
+//! uncle must not be, and no uncle may be included twice. Those are checked by `check_uncles`,
+//! which is given the recent ancestors, just like timestamps are checked.
+//!
+//! The engine only works out who is owed what, through `UnclePow::rewards`. Consensus engines never
+//! touch the state, so paying the rewards is up to whoever executes the block.
+
+use super::{Consensus, Header};
+use crate::c1_state_machine::{AccountingTransaction, User};
+use crate::codec::{Decode, Encode, Error};
+use std::collections::HashSet;
+
+/// The most uncles a single header may reference.
+pub const MAX_UNCLES: usize = 2;
+
+/// How many blocks behind the including header an uncle may be.
+pub const MAX_UNCLE_DEPTH: u64 = 6;
+
+/// The reward for mining a canonical block.
+pub const BLOCK_REWARD: u64 = 32;
+
+/// A Proof of Work engine whose blocks pay their miners and may include uncles.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnclePow {
+    /// Block hashes must be below this threshold, and so must the hashes of uncles.
+    pub threshold: u64,
+    /// Who this node's mining rewards are paid to.
+    pub beneficiary: User,
+}
+
+/// The digest of a PoW block that may include uncles.
+#[derive(Hash, Debug, PartialEq, Eq, Clone)]
+pub struct UncleDigest {
+    pub nonce: u64,
+    /// Who the block's reward is paid to.
+    pub beneficiary: User,
+    /// The complete headers of the included uncles, so that their seals can be checked even by
+    /// nodes that never saw them.
+    pub uncles: Vec<Header<UncleDigest>>,
+}
+
+impl Encode for UncleDigest {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.nonce.encode_to(dest);
+        self.beneficiary.encode_to(dest);
+        self.uncles.encode_to(dest);
+    }
+}
+
+impl Decode for UncleDigest {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(UncleDigest {
+            nonce: u64::decode(input)?,
+            beneficiary: User::decode(input)?,
+            uncles: Vec::decode(input)?,
+        })
+    }
+}
+
+/// The ways in which a header's uncles can be invalid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UncleError {
+    /// The header references more than `MAX_UNCLES` uncles.
+    TooMany,
+    /// The same uncle is referenced twice in one header.
+    Duplicate(u64),
+    /// The uncle is not a valid proof of work block.
+    InvalidSeal(u64),
+    /// The uncle is not between 1 and `MAX_UNCLE_DEPTH` blocks behind the header.
+    BadDepth(u64),
+    /// The uncle's parent is not one of the header's recent ancestors.
+    NotASibling(u64),
+    /// The uncle is one of the header's ancestors, so it is not stale at all.
+    IsAncestor(u64),
+    /// The uncle was already included by one of the header's ancestors.
+    AlreadyIncluded(u64),
+}
+
+impl UnclePow {
+    pub fn new(threshold: u64, beneficiary: User) -> Self {
+        UnclePow {
+            threshold,
+            beneficiary,
+        }
+    }
+
+    /// Mine a seal for the partial header that includes the given uncles and pays this node.
+    pub fn seal_with_uncles(
+        &self,
+        partial_header: Header<()>,
+        uncles: Vec<Header<UncleDigest>>,
+    ) -> Option<Header<UncleDigest>> {
+        let mut header = Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            timestamp: partial_header.timestamp,
+            consensus_digest: UncleDigest {
+                nonce: 0,
+                beneficiary: self.beneficiary,
+                uncles,
+            },
+        };
+        for nonce in 0..=u64::MAX {
+            header.consensus_digest.nonce = nonce;
+            if header.hash() < self.threshold {
+                return Some(header);
+            }
+        }
+        None
+    }
+
+    /// Check the uncle rules that only depend on the header itself.
+    fn check_shape(&self, header: &Header<UncleDigest>) -> Result<(), UncleError> {
+        let uncles = &header.consensus_digest.uncles;
+        if uncles.len() > MAX_UNCLES {
+            return Err(UncleError::TooMany);
+        }
+        let mut seen = HashSet::new();
+        for uncle in uncles {
+            let hash = uncle.hash();
+            if !seen.insert(hash) {
+                return Err(UncleError::Duplicate(hash));
+            }
+            if hash >= self.threshold {
+                return Err(UncleError::InvalidSeal(hash));
+            }
+            let depth = header.height.saturating_sub(uncle.height);
+            if uncle.height == 0 || !(1..=MAX_UNCLE_DEPTH).contains(&depth) {
+                return Err(UncleError::BadDepth(hash));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a header's uncles against its ancestors, which must be given oldest first and end
+    /// with its parent. Only the last `MAX_UNCLE_DEPTH` ancestors matter for an uncle's parent,
+    /// but uncles included further back cannot be included again, so give as many as are known.
+    pub fn check_uncles(
+        &self,
+        ancestors: &[Header<UncleDigest>],
+        header: &Header<UncleDigest>,
+    ) -> Result<(), UncleError> {
+        self.check_shape(header)?;
+        let recent = &ancestors[ancestors.len().saturating_sub(MAX_UNCLE_DEPTH as usize)..];
+        let recent: HashSet<u64> = recent.iter().map(Header::hash).collect();
+        let ancestor_hashes: HashSet<u64> = ancestors.iter().map(Header::hash).collect();
+        let included: HashSet<u64> = ancestors
+            .iter()
+            .flat_map(|ancestor| &ancestor.consensus_digest.uncles)
+            .map(Header::hash)
+            .collect();
+        for uncle in &header.consensus_digest.uncles {
+            let hash = uncle.hash();
+            if ancestor_hashes.contains(&hash) {
+                return Err(UncleError::IsAncestor(hash));
+            }
+            if included.contains(&hash) {
+                return Err(UncleError::AlreadyIncluded(hash));
+            }
+            if !recent.contains(&uncle.parent) {
+                return Err(UncleError::NotASibling(hash));
+            }
+        }
+        Ok(())
+    }
+
+    /// The rewards a canonical block pays out, as currency transitions to apply to the state.
+    ///
+    /// This is only a helper. Nothing calls it during block import, so a client that pays
+    /// rewards must apply these transitions itself, before the block's own transitions.
+    ///
+    /// The block's miner earns `BLOCK_REWARD`, plus a thirty-second of it for each uncle. Each
+    /// uncle's miner earns an eighth less of `BLOCK_REWARD` for every block the uncle is behind.
+    pub fn rewards(header: &Header<UncleDigest>) -> Vec<AccountingTransaction> {
+        let uncles = &header.consensus_digest.uncles;
+        let inclusion_bonus = BLOCK_REWARD / 32 * uncles.len() as u64;
+        let mut rewards = vec![AccountingTransaction::Mint {
+            minter: header.consensus_digest.beneficiary,
+            amount: BLOCK_REWARD + inclusion_bonus,
+        }];
+        rewards.extend(uncles.iter().map(|uncle| {
+            let depth = header.height.saturating_sub(uncle.height);
+            AccountingTransaction::Mint {
+                minter: uncle.consensus_digest.beneficiary,
+                amount: BLOCK_REWARD * 8u64.saturating_sub(depth) / 8,
+            }
+        }));
+        rewards
+    }
+}
+
+impl Consensus for UnclePow {
+    type Digest = UncleDigest;
+
+    /// Check the seal, and the uncle rules that do not depend on the chain.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        header.hash() < self.threshold && self.check_shape(header).is_ok()
+    }
+
+    /// Mine a seal that includes no uncles. Use `seal_with_uncles` to include some.
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        self.seal_with_uncles(partial_header, Vec::new())
+    }
+
+    fn human_name() -> String {
+        "Proof of Work with Uncles".into()
+    }
+}
+
+#[cfg(test)]
+fn partial_child(parent: &Header<UncleDigest>, extrinsics_root: u64) -> Header<()> {
+    Header {
+        parent: parent.hash(),
+        height: parent.height + 1,
+        state_root: 0,
+        extrinsics_root,
+        timestamp: 0,
+        consensus_digest: (),
+    }
+}
+
+#[cfg(test)]
+fn uncle_chain(engine: &UnclePow, length: u64) -> Vec<Header<UncleDigest>> {
+    let genesis = Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        timestamp: 0,
+        consensus_digest: UncleDigest {
+            nonce: 0,
+            beneficiary: User::Alice,
+            uncles: Vec::new(),
+        },
+    };
+    let mut chain = vec![genesis];
+    for _ in 0..length {
+        let partial = partial_child(chain.last().unwrap(), 0);
+        chain.push(engine.seal(&chain[0].consensus_digest, partial).unwrap());
+    }
+    chain
+}
+
+#[test]
+fn uncles_are_validated_against_the_chain() {
+    let alice = UnclePow::new(u64::MAX / 4, User::Alice);
+    let bob = UnclePow::new(u64::MAX / 4, User::Bob);
+    let chain = uncle_chain(&alice, 3);
+    let digest = &chain[0].consensus_digest;
+
+    // Bob's block at height 2 lost the race to Alice's.
+    let stale = bob.seal(digest, partial_child(&chain[1], 1)).unwrap();
+    let header = alice
+        .seal_with_uncles(partial_child(&chain[3], 0), vec![stale.clone()])
+        .unwrap();
+    assert!(alice.validate(digest, &header));
+    assert_eq!(alice.check_uncles(&chain, &header), Ok(()));
+
+    // An ancestor is not an uncle.
+    let header = alice
+        .seal_with_uncles(partial_child(&chain[3], 0), vec![chain[2].clone()])
+        .unwrap();
+    let hash = chain[2].hash();
+    assert_eq!(
+        alice.check_uncles(&chain, &header),
+        Err(UncleError::IsAncestor(hash))
+    );
+
+    // Neither is a block on some unrelated chain.
+    let mut stranger = partial_child(&chain[1], 2);
+    stranger.parent = 42;
+    let stranger = bob.seal(digest, stranger).unwrap();
+    let header = alice
+        .seal_with_uncles(partial_child(&chain[3], 0), vec![stranger.clone()])
+        .unwrap();
+    assert_eq!(
+        alice.check_uncles(&chain, &header),
+        Err(UncleError::NotASibling(stranger.hash()))
+    );
+
+    // And an uncle can only be included once.
+    let with_uncle = alice
+        .seal_with_uncles(partial_child(&chain[3], 0), vec![stale.clone()])
+        .unwrap();
+    let mut longer = chain.clone();
+    longer.push(with_uncle);
+    let again = alice
+        .seal_with_uncles(partial_child(&longer[4], 0), vec![stale.clone()])
+        .unwrap();
+    assert_eq!(
+        alice.check_uncles(&longer, &again),
+        Err(UncleError::AlreadyIncluded(stale.hash()))
+    );
+}
+
+#[test]
+fn uncle_shape_is_checked_by_validate() {
+    let alice = UnclePow::new(u64::MAX / 4, User::Alice);
+    let chain = uncle_chain(&alice, 8);
+    let digest = &chain[0].consensus_digest;
+    let stale = |at: usize, root: u64| alice.seal(digest, partial_child(&chain[at], root)).unwrap();
+
+    let three = vec![stale(7, 1), stale(7, 2), stale(7, 3)];
+    let header = alice
+        .seal_with_uncles(partial_child(&chain[8], 0), three)
+        .unwrap();
+    assert!(!alice.validate(digest, &header));
+    assert_eq!(alice.check_shape(&header), Err(UncleError::TooMany));
+
+    let twice = vec![stale(7, 1), stale(7, 1)];
+    let header = alice
+        .seal_with_uncles(partial_child(&chain[8], 0), twice)
+        .unwrap();
+    assert!(!alice.validate(digest, &header));
+
+    // Seven blocks behind is too deep, six is fine.
+    let header = alice
+        .seal_with_uncles(partial_child(&chain[8], 0), vec![stale(1, 1)])
+        .unwrap();
+    assert!(!alice.validate(digest, &header));
+    let header = alice
+        .seal_with_uncles(partial_child(&chain[8], 0), vec![stale(2, 1)])
+        .unwrap();
+    assert!(alice.validate(digest, &header));
+
+    // Uncles must be sealed just like any other block.
+    let mut unsealed = stale(7, 1);
+    while unsealed.hash() < alice.threshold {
+        unsealed.consensus_digest.nonce += 1;
+    }
+    let header = alice
+        .seal_with_uncles(partial_child(&chain[8], 0), vec![unsealed])
+        .unwrap();
+    assert!(!alice.validate(digest, &header));
+}
+
+#[test]
+fn uncles_are_rewarded_through_the_currency() {
+    use crate::c1_state_machine::{AccountedCurrency, StateMachine};
+    use std::collections::HashMap;
+
+    let alice = UnclePow::new(u64::MAX / 4, User::Alice);
+    let bob = UnclePow::new(u64::MAX / 4, User::Bob);
+    let charlie = UnclePow::new(u64::MAX / 4, User::Charlie);
+    let chain = uncle_chain(&alice, 3);
+    let digest = &chain[0].consensus_digest;
+
+    let bobs = bob.seal(digest, partial_child(&chain[2], 1)).unwrap();
+    let charlies = charlie.seal(digest, partial_child(&chain[1], 1)).unwrap();
+    let header = alice
+        .seal_with_uncles(partial_child(&chain[3], 0), vec![bobs, charlies])
+        .unwrap();
+
+    let balances = UnclePow::rewards(&header)
+        .iter()
+        .fold(HashMap::new(), |state, reward| {
+            AccountedCurrency::next_state(&state, reward)
+        });
+    assert_eq!(
+        balances,
+        HashMap::from([
+            (User::Alice, BLOCK_REWARD + 2),
+            (User::Bob, BLOCK_REWARD * 7 / 8),
+            (User::Charlie, BLOCK_REWARD * 6 / 8),
+        ])
+    );
+}
@@ -0,0 +1,46 @@
+//! Proof of work chains are compared by how much work went into them. But nobody can measure the
+//! work that actually went into a block. A miner may have been lucky and found a seal on the first
+//! nonce, or unlucky and tried billions. What can be measured is how much work a block is expected
+//! to take, and that depends only on the target it was mined against.
+//!
+//! Hashes are uniformly distributed over the `2^64` possible values. A seal is valid when its hash
+//! is strictly below the target, so `target` of them are valid, and a miner is expected to try
+//! `2^64 / target` nonces before finding one.
+//! Crucially, the block's own hash plays no part. A lucky block with a tiny hash is worth exactly
+//! as much as any other block with the same target, so it cannot outweigh a long chain.
+
+use super::RetargetDigest;
+
+/// The expected number of hashes needed to find a seal below the given target.
+///
+/// A target of zero can never be met, so no valid block has one. It is given the work of a target
+/// of one, the hardest target that can be met, rather than dividing by zero.
+pub fn expected_work(target: u64) -> u128 {
+    (1u128 << 64) / target.max(1) as u128
+}
+
+impl RetargetDigest {
+    /// The expected work of the block carrying this digest.
+    pub fn work(&self) -> u128 {
+        expected_work(self.target)
+    }
+}
+
+#[test]
+fn work_is_inverse_to_target() {
+    assert_eq!(expected_work(u64::MAX), 1);
+    assert_eq!(expected_work(u64::MAX / 2), 2);
+    assert_eq!(expected_work(u64::MAX >> 10), 1024);
+    assert_eq!(expected_work(1 << 63), 2);
+    assert_eq!(expected_work(1), 1 << 64);
+    assert_eq!(expected_work(0), 1 << 64);
+
+    // The nonce does not matter, only the target does.
+    let digest = RetargetDigest {
+        nonce: 7,
+        target: u64::MAX / 4,
+        window_start: 0,
+        recent: super::RecentTimestamps::genesis(0),
+    };
+    assert_eq!(digest.work(), 4);
+}
@@ -0,0 +1,333 @@
+//! A BFT engine cannot commit blocks on its own. It needs the validators to exchange proposals
+//! and votes, and to give up on rounds that are going nowhere. Here we simulate a network of
+//! Tendermint validators, each running on its own thread and talking to the others over channels.
+//!
+//! Every height proceeds in rounds, and every round in three steps:
+//!
+//! 1. Propose. The round's proposer broadcasts a block. Everyone else waits for it.
+//! 2. Prevote. Each validator prevotes the proposal if it is valid, or nothing if it never came.
+//!    Then it waits for more than two thirds of the weight to prevote the same thing.
+//! 3. Precommit. A validator that saw a quorum of prevotes for a block locks on to it and
+//!    precommits it. Otherwise it precommits nothing. A quorum of precommits commits the block.
+//!
+//! Any step that takes longer than its timeout is abandoned, and the validator moves on. Timeouts
+//! grow with every round, so that validators whose clocks or messages are slow eventually line up.
+//! A validator that locked on to a block keeps voting for it, and proposes it again when it is its
+//! turn, which is what stops two different blocks from being committed at the same height.
+
+use super::Header;
+use crate::c3_consensus::{
+    CommitCertificate, Consensus, ConsensusAuthority, Tendermint, TendermintDigest, Vote, VoteKind,
+};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How a simulated validator network is set up.
+#[derive(Clone, Debug)]
+pub struct BftConfig {
+    /// The validators, each with its voting weight.
+    pub validators: Vec<(ConsensusAuthority, u64)>,
+    /// Validators that are offline. They never propose or vote.
+    pub offline: Vec<ConsensusAuthority>,
+    /// How many blocks to commit before stopping.
+    pub heights: u64,
+    /// How long each step of the first round may take. Each later round waits one timeout longer.
+    pub timeout: Duration,
+    /// How many rounds to try at each height before giving up.
+    pub max_rounds: u64,
+}
+
+/// The messages that validators broadcast to each other.
+#[derive(Clone, Debug)]
+enum Message {
+    Proposal {
+        round: u64,
+        header: Header<TendermintDigest>,
+    },
+    Vote(Vote),
+}
+
+/// One validator in the simulated network.
+struct Validator {
+    engine: Tendermint,
+    inbox: Receiver<Message>,
+    /// Senders to every validator, including this one.
+    peers: Vec<Sender<Message>>,
+    /// Every proposal received, by height and round.
+    proposals: HashMap<(u64, u64), Header<TendermintDigest>>,
+    /// Every vote received.
+    votes: Vec<Vote>,
+    /// The block this validator saw a quorum of prevotes for at the current height, if any.
+    locked: Option<Header<TendermintDigest>>,
+    /// The committed chain, not including genesis.
+    chain: Vec<Header<TendermintDigest>>,
+}
+
+/// Build the partial header of the block at the given height.
+fn partial_header(parent: u64, height: u64) -> Header<()> {
+    Header::partial(parent, height, 0, 0, 0)
+}
+
+impl Validator {
+    fn broadcast(&self, message: Message) {
+        for peer in &self.peers {
+            // Validators that have stopped no longer need any messages.
+            let _ = peer.send(message.clone());
+        }
+    }
+
+    fn vote(&self, kind: VoteKind, height: u64, round: u64, block: Option<u64>) {
+        self.broadcast(Message::Vote(Vote {
+            kind,
+            height,
+            round,
+            block,
+            validator: self.engine.signer(),
+        }));
+    }
+
+    fn receive(&mut self, message: Message) {
+        match message {
+            Message::Proposal { round, header } => {
+                self.proposals.insert((header.height(), round), header);
+            }
+            Message::Vote(vote) => {
+                if !self.votes.contains(&vote) {
+                    self.votes.push(vote);
+                }
+            }
+        }
+    }
+
+    /// Handle incoming messages until the condition holds or the deadline passes.
+    fn wait_until(&mut self, deadline: Instant, done: impl Fn(&Self) -> bool) {
+        while !done(self) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.inbox.recv_timeout(left) {
+                Ok(message) => self.receive(message),
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// What a quorum voted for in the given step, if a quorum agreed on anything.
+    fn quorum(&self, kind: VoteKind, height: u64, round: u64) -> Option<Option<u64>> {
+        let step: Vec<&Vote> = self
+            .votes
+            .iter()
+            .filter(|v| v.kind == kind && v.height == height && v.round == round)
+            .collect();
+        step.iter().map(|v| v.block).find(|block| {
+            let voters = step
+                .iter()
+                .filter(|v| v.block == *block)
+                .map(|v| v.validator);
+            self.engine.is_quorum(voters)
+        })
+    }
+
+    /// The round and block that were committed at the given height, if any.
+    fn decided(&self, height: u64) -> Option<(u64, u64)> {
+        self.votes
+            .iter()
+            .filter(|v| v.kind == VoteKind::Precommit && v.height == height)
+            .find_map(
+                |v| match self.quorum(VoteKind::Precommit, height, v.round) {
+                    Some(Some(block)) => Some((v.round, block)),
+                    _ => None,
+                },
+            )
+    }
+
+    /// Whether the proposal is a valid child of the given parent.
+    fn is_valid(&self, proposal: &Header<TendermintDigest>, parent: u64, height: u64) -> bool {
+        proposal.parent() == parent
+            && proposal.height() == height
+            && self.engine.validate(proposal.consensus_digest(), proposal)
+    }
+
+    /// Try to commit a block at the given height, one round after another.
+    /// Returns the committed block, or None if every round failed.
+    fn commit_height(
+        &mut self,
+        parent: u64,
+        height: u64,
+        config: &BftConfig,
+    ) -> Option<Header<TendermintDigest>> {
+        self.locked = None;
+        for round in 0..config.max_rounds {
+            let timeout = config.timeout * (round as u32 + 1);
+            if self.decided(height).is_some() {
+                break;
+            }
+
+            // Propose, or wait for the proposal.
+            let proposal = match &self.locked {
+                Some(locked) if self.engine.proposer(height, round) == self.engine.signer() => {
+                    Some(locked.clone())
+                }
+                _ => self
+                    .engine
+                    .seal_in_round(partial_header(parent, height), round),
+            };
+            if let Some(header) = proposal {
+                self.broadcast(Message::Proposal { round, header });
+            }
+            self.wait_until(Instant::now() + timeout, |v| {
+                v.proposals.contains_key(&(height, round)) || v.decided(height).is_some()
+            });
+
+            // Prevote.
+            let proposal = self
+                .proposals
+                .get(&(height, round))
+                .filter(|proposal| self.is_valid(proposal, parent, height));
+            let prevote = match (&self.locked, proposal) {
+                (Some(locked), _) => Some(locked.hash()),
+                (None, Some(proposal)) => Some(proposal.hash()),
+                (None, None) => None,
+            };
+            self.vote(VoteKind::Prevote, height, round, prevote);
+            self.wait_until(Instant::now() + timeout, |v| {
+                v.quorum(VoteKind::Prevote, height, round).is_some() || v.decided(height).is_some()
+            });
+
+            // Precommit.
+            let precommit = match self.quorum(VoteKind::Prevote, height, round) {
+                Some(Some(block)) => {
+                    let header = self.proposals.values().find(|h| h.hash() == block);
+                    self.locked = header.cloned().or(self.locked.take());
+                    Some(block)
+                }
+                _ => None,
+            };
+            self.vote(VoteKind::Precommit, height, round, precommit);
+            self.wait_until(Instant::now() + timeout, |v| {
+                v.quorum(VoteKind::Precommit, height, round).is_some()
+                    || v.decided(height).is_some()
+            });
+        }
+
+        // A quorum precommitted the block, so every honest validator proposed or prevoted it,
+        // and the proposal is on its way even if it has not arrived yet.
+        let (round, block) = self.decided(height)?;
+        let has_block = |v: &Self| v.proposals.values().any(|h| h.hash() == block);
+        self.wait_until(Instant::now() + config.timeout * 10, has_block);
+        let header = self.proposals.values().find(|h| h.hash() == block)?.clone();
+        self.engine.note_commit(CommitCertificate::from_precommits(
+            height,
+            round,
+            block,
+            &self.votes,
+        ));
+        Some(header)
+    }
+
+    fn run(mut self, config: &BftConfig) -> Vec<Header<TendermintDigest>> {
+        let mut parent = 0;
+        for height in 1..=config.heights {
+            match self.commit_height(parent, height, config) {
+                Some(header) => {
+                    parent = header.hash();
+                    self.chain.push(header);
+                }
+                None => break,
+            }
+        }
+        self.chain
+    }
+}
+
+/// Run a network of validators until each has committed `heights` blocks or given up.
+/// Returns the chain committed by each online validator.
+pub fn run_network(
+    config: &BftConfig,
+) -> HashMap<ConsensusAuthority, Vec<Header<TendermintDigest>>> {
+    let (peers, inboxes): (Vec<_>, Vec<_>) =
+        config.validators.iter().map(|_| mpsc::channel()).unzip();
+    let handles: Vec<_> = config
+        .validators
+        .iter()
+        .zip(inboxes)
+        .filter(|((validator, _), _)| !config.offline.contains(validator))
+        .map(|((validator, _), inbox)| {
+            let node = Validator {
+                engine: Tendermint::new(config.validators.clone(), *validator),
+                inbox,
+                peers: peers.clone(),
+                proposals: HashMap::new(),
+                votes: Vec::new(),
+                locked: None,
+                chain: Vec::new(),
+            };
+            let config = config.clone();
+            (*validator, thread::spawn(move || node.run(&config)))
+        })
+        .collect();
+    handles
+        .into_iter()
+        .map(|(validator, handle)| (validator, handle.join().expect("validator panicked")))
+        .collect()
+}
+
+#[cfg(test)]
+fn config(offline: Vec<ConsensusAuthority>, heights: u64, max_rounds: u64) -> BftConfig {
+    use ConsensusAuthority::*;
+    BftConfig {
+        validators: vec![(Alice, 2), (Bob, 1), (Charlie, 1)],
+        offline,
+        heights,
+        timeout: Duration::from_millis(50),
+        max_rounds,
+    }
+}
+
+#[cfg(test)]
+fn assert_certified(engine: &Tendermint, chain: &[Header<TendermintDigest>]) {
+    for pair in chain.windows(2) {
+        let commit = pair[1].consensus_digest().parent_commit.as_ref().unwrap();
+        assert_eq!(commit.block, pair[0].hash());
+        assert!(engine.validate(pair[0].consensus_digest(), &pair[1]));
+    }
+}
+
+#[test]
+fn validators_commit_the_same_chain() {
+    let config = config(Vec::new(), 4, 10);
+    let chains = run_network(&config);
+    assert_eq!(chains.len(), 3);
+
+    let alice = &chains[&ConsensusAuthority::Alice];
+    assert_eq!(alice.len(), 4);
+    assert!(chains.values().all(|chain| chain == alice));
+    assert_certified(
+        &Tendermint::new(config.validators, ConsensusAuthority::Bob),
+        alice,
+    );
+}
+
+#[test]
+fn rounds_time_out_when_the_proposer_is_offline() {
+    use ConsensusAuthority::*;
+    // Alice and Bob hold three quarters of the weight, which is enough without Charlie.
+    let config = config(vec![Charlie], 4, 10);
+    let chains = run_network(&config);
+    let alice = &chains[&Alice];
+    assert_eq!(alice.len(), 4);
+    assert_eq!(&chains[&Bob], alice);
+    assert_certified(&Tendermint::new(config.validators, Bob), alice);
+
+    // Charlie was due to propose at height 3, so that height needed another round.
+    assert_eq!(alice[2].consensus_digest().round, 1);
+}
+
+#[test]
+fn nothing_is_committed_without_a_quorum() {
+    use ConsensusAuthority::*;
+    // Bob and Charlie only hold half of the weight.
+    let chains = run_network(&config(vec![Alice], 2, 2));
+    assert_eq!(chains[&Bob], Vec::new());
+    assert_eq!(chains[&Charlie], Vec::new());
+}
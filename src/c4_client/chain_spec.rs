@@ -0,0 +1,170 @@
+//! So far, every chain's genesis has been a Rust value compiled into the client. That is fine for
+//! a tutorial, but real networks are launched often, and each launch has its own name, consensus
+//! parameters, and initial balances. Recompiling the client for each of them is not practical.
+//!
+//! Instead, clients read a chain specification, or "chain spec", from a JSON file at startup.
+//! A chain spec names the chain, configures the consensus engine, and describes the genesis state.
+//!
+//! ```json
+//! {
+//!     "name": "Local Testnet",
+//!     "consensus": { "engine": "poa", "authorities": ["Alice", "Bob"] },
+//!     "genesis": { "Alice": 1000, "Bob": 1000 },
+//!     "finality_voters": { "Alice": 1, "Bob": 1 }
+//! }
+//! ```
+//!
+//! The format of the `consensus` and `genesis` sections is defined by the consensus engine and
+//! the state machine's state respectively, through their `FromJson` implementations. The optional
+//! `finality_voters` section gives the voting weight of each authority in the finality gadget.
+//! Networks that plan to change their consensus rules configure a `ForkSchedule`, which lists the
+//! engine to use from each fork height.
+
+use crate::c3_consensus::ConsensusAuthority;
+use crate::json::{self, FromJson, Json};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// Everything needed to launch a chain, as read from a chain spec.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainSpec<C, S> {
+    /// A human-readable name for the chain, like "Local Testnet".
+    pub name: String,
+    /// The consensus engine, configured with this chain's parameters.
+    pub consensus: C,
+    /// The state in which the chain begins.
+    pub genesis: S,
+    /// The voting weight of each authority in the finality gadget. Without any voters, no block
+    /// is ever finalized by a justification.
+    pub finality_voters: HashMap<ConsensusAuthority, u64>,
+}
+
+/// Everything that can go wrong while loading a chain spec file.
+#[derive(Debug)]
+pub enum SpecError {
+    /// The file could not be read.
+    Io(std::io::Error),
+    /// The file is not a valid chain spec.
+    Json(json::Error),
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpecError::Io(e) => write!(f, "could not read the chain spec: {e}"),
+            SpecError::Json(e) => write!(f, "invalid chain spec: {e:?}"),
+        }
+    }
+}
+
+impl std::error::Error for SpecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SpecError::Io(e) => Some(e),
+            SpecError::Json(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SpecError {
+    fn from(e: std::io::Error) -> Self {
+        SpecError::Io(e)
+    }
+}
+
+impl From<json::Error> for SpecError {
+    fn from(e: json::Error) -> Self {
+        SpecError::Json(e)
+    }
+}
+
+impl<C: FromJson, S: FromJson> FromJson for ChainSpec<C, S> {
+    fn from_json(j: &Json) -> Result<Self, json::Error> {
+        Ok(ChainSpec {
+            name: j.parse_field("name")?,
+            consensus: j.parse_field("consensus")?,
+            genesis: j.parse_field("genesis")?,
+            finality_voters: match j.get("finality_voters")? {
+                Some(voters) => HashMap::from_json(voters)?,
+                None => HashMap::new(),
+            },
+        })
+    }
+}
+
+impl<C: FromJson, S: FromJson> ChainSpec<C, S> {
+    /// Read a chain spec from JSON text.
+    pub fn from_json_str(text: &str) -> Result<Self, json::Error> {
+        json::from_str(text)
+    }
+
+    /// Read a chain spec from a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SpecError> {
+        Ok(Self::from_json_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+/// The engines of the local testnet: any of its authorities from genesis, then round robin with
+/// a third authority from height 1000.
+#[cfg(test)]
+pub(crate) type LocalTestnetEngines = (
+    crate::c3_consensus::SimplePoa,
+    (crate::c3_consensus::PoaRoundRobinByHeight, ()),
+);
+
+#[cfg(test)]
+pub(crate) const LOCAL_TESTNET: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/chain_specs/local_testnet.json"
+);
+
+#[test]
+fn chain_spec_loads_local_testnet() {
+    use crate::c1_state_machine::User;
+    use crate::c3_consensus::ForkSchedule;
+    use std::collections::HashMap;
+
+    let spec =
+        ChainSpec::<ForkSchedule<LocalTestnetEngines>, HashMap<User, u64>>::load(LOCAL_TESTNET)
+            .unwrap();
+
+    assert_eq!(spec.name, "Local Testnet");
+    assert_eq!(spec.consensus.activation_height(1), Some(1000));
+    assert_eq!(spec.consensus.active_at(999), 0);
+    assert_eq!(spec.consensus.active_at(1000), 1);
+    assert_eq!(
+        spec.genesis,
+        HashMap::from([(User::Alice, 1000), (User::Bob, 1000), (User::Charlie, 10)])
+    );
+    assert_eq!(
+        spec.finality_voters,
+        HashMap::from([
+            (ConsensusAuthority::Alice, 1),
+            (ConsensusAuthority::Bob, 1),
+            (ConsensusAuthority::Charlie, 1)
+        ])
+    );
+}
+
+#[test]
+fn chain_spec_rejects_mismatched_engine() {
+    use crate::c1_state_machine::User;
+    use crate::c3_consensus::Pow;
+    use std::collections::HashMap;
+
+    let text = r#"{
+        "name": "Wrong Engine",
+        "consensus": { "engine": "poa", "authorities": ["Alice"] },
+        "genesis": {}
+    }"#;
+    let result = ChainSpec::<Pow, HashMap<User, u64>>::from_json_str(text);
+    assert!(matches!(result, Err(json::Error::Invalid(_))));
+
+    let missing = ChainSpec::<Pow, HashMap<User, u64>>::load("/no/such/chain_spec.json");
+    let error = missing.err().unwrap();
+    assert!(matches!(error, SpecError::Io(_)));
+    assert!(error
+        .to_string()
+        .starts_with("could not read the chain spec: "));
+}
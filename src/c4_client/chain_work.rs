@@ -0,0 +1,99 @@
+//! The heaviest chain is the one with the most cumulative work, that is the sum of the expected
+//! work of every block from genesis to its tip. Summing the whole chain on every comparison would
+//! make fork choice slower and slower as the chain grows. Instead, the client caches each block's
+//! cumulative work when it is imported. A child's cumulative work is just its parent's plus its own,
+//! so each import is a single lookup.
+
+use super::Header;
+use crate::c3_consensus::expected_work;
+use crate::codec::Encode;
+use std::collections::HashMap;
+
+/// The cumulative work of every imported block, by block hash.
+#[derive(Clone, Debug, Default)]
+pub struct ChainWork {
+    total: HashMap<u64, u128>,
+    /// The block with the most cumulative work, and that work. Ties go to the block seen first.
+    heaviest: Option<(u64, u128)>,
+}
+
+impl ChainWork {
+    /// Record a block that was mined against the given target, and return its cumulative work.
+    ///
+    /// The genesis block is not mined, so it has no work. Any other block must be imported after
+    /// its parent. Otherwise its cumulative work is unknown and None is returned.
+    pub fn import<D: Encode>(&mut self, header: &Header<D>, target: u64) -> Option<u128> {
+        let total = match header.height() {
+            0 => 0,
+            _ => self
+                .total
+                .get(&header.parent())?
+                .saturating_add(expected_work(target)),
+        };
+        let hash = header.hash();
+        self.total.insert(hash, total);
+        if self.heaviest.is_none_or(|(_, most)| total > most) {
+            self.heaviest = Some((hash, total));
+        }
+        Some(total)
+    }
+
+    /// The cumulative work of the block with the given hash, if it has been imported.
+    pub fn total(&self, hash: u64) -> Option<u128> {
+        self.total.get(&hash).copied()
+    }
+
+    /// The hash of the block with the most cumulative work.
+    pub fn heaviest(&self) -> Option<u64> {
+        self.heaviest.map(|(hash, _)| hash)
+    }
+}
+
+#[cfg(test)]
+fn child(parent: &Header<()>, extrinsics_root: u64) -> Header<()> {
+    Header::partial(parent.hash(), parent.height() + 1, 0, extrinsics_root, 0)
+}
+
+#[test]
+fn work_accumulates_along_the_chain() {
+    let genesis = Header::partial(0, 0, 0, 0, 0);
+    let mut work = ChainWork::default();
+    assert_eq!(work.import(&genesis, 0), Some(0));
+
+    let b1 = child(&genesis, 0);
+    let b2 = child(&b1, 0);
+    assert_eq!(work.import(&b1, u64::MAX >> 3), Some(8));
+    assert_eq!(work.import(&b2, u64::MAX >> 4), Some(24));
+    assert_eq!(work.total(b2.hash()), Some(24));
+    assert_eq!(work.heaviest(), Some(b2.hash()));
+
+    // A block whose parent was never imported has no known work.
+    let orphan = child(&child(&b2, 0), 0);
+    assert_eq!(work.import(&orphan, u64::MAX >> 3), None);
+}
+
+#[test]
+fn harder_blocks_outweigh_longer_chains() {
+    let genesis = Header::partial(0, 0, 0, 0, 0);
+    let mut work = ChainWork::default();
+    work.import(&genesis, 0);
+
+    // Three easy blocks on one side of the fork.
+    let mut easy = genesis.clone();
+    for _ in 0..3 {
+        easy = child(&easy, 1);
+        work.import(&easy, u64::MAX >> 5);
+    }
+    assert_eq!(work.heaviest(), Some(easy.hash()));
+
+    // A single block mined against a much lower target on the other side is worth more.
+    let hard = child(&genesis, 2);
+    work.import(&hard, u64::MAX >> 7);
+    assert_eq!(work.heaviest(), Some(hard.hash()));
+
+    // Extending the easy side until it only draws level is not enough to reorg back to it.
+    let rival = child(&easy, 3);
+    work.import(&rival, u64::MAX >> 5);
+    assert_eq!(work.total(rival.hash()), work.total(hard.hash()));
+    assert_eq!(work.heaviest(), Some(hard.hash()));
+}
@@ -0,0 +1,414 @@
+//! Manually finalizing blocks requires trusting whoever asks for it. Real chains instead let a set
+//! of authorities vote on finality. Unlike Tendermint, the finality gadget here is decoupled from
+//! block production: blocks are still produced by PoW, PoA or any other engine, and are finalized
+//! afterwards, possibly many at once. This is how GRANDPA works in Polkadot.
+//!
+//! Authorities do not vote on single blocks. Each vote is for the voter's best block, which is
+//! also a vote for every ancestor of that block. So while the voters may disagree about which fork
+//! is best, they often agree about an older common ancestor. The gadget finalizes the highest block
+//! that more than two thirds of the voting weight voted for, directly or through a descendant.
+//!
+//! Sometimes a round cannot finalize anything. The voters may be split evenly between two forks,
+//! or some of them may be offline. Each voter votes only once per round, so such a round would
+//! block finality forever. Instead, a round completes as soon as the votes cast so far rule out
+//! any supermajority, and a round that is taking too long can be timed out. Either way, a new
+//! round begins, and the voters vote again for their best blocks, which have hopefully converged.
+//!
+//! The votes that finalized a block make up its justification, which is stored alongside the block.
+//! A new node can check a justification without having seen the votes live. It only needs the
+//! voter set and the headers linking each vote to the finalized block, which the justification
+//! carries along.
+//!
+//! The gadget only looks at headers' parent links and heights, so it works with any digest.
+
+use super::Header;
+use crate::c3_consensus::ConsensusAuthority;
+use crate::codec::Encode;
+use std::collections::{HashMap, HashSet};
+
+/// An authority's vote for a block, and so for all of its ancestors, in a voting round.
+#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FinalityVote {
+    pub round: u64,
+    /// The hash of the voted-for block.
+    pub target: u64,
+    pub voter: ConsensusAuthority,
+}
+
+/// Proof that a block was finalized: a supermajority of votes for it or its descendants, and the
+/// headers linking those votes back to it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Justification<D> {
+    pub round: u64,
+    /// The hash of the finalized block.
+    pub target: u64,
+    pub votes: Vec<FinalityVote>,
+    /// The headers from each vote's target back to, but not including, the finalized block.
+    pub ancestry: Vec<Header<D>>,
+}
+
+/// Whether the given voters hold more than two thirds of the voting weight.
+fn is_supermajority(
+    voters: &[(ConsensusAuthority, u64)],
+    signers: impl IntoIterator<Item = ConsensusAuthority>,
+) -> bool {
+    let signers: HashSet<ConsensusAuthority> = signers.into_iter().collect();
+    let total: u64 = voters.iter().map(|(_, weight)| weight).sum();
+    let weight: u64 = voters
+        .iter()
+        .filter(|(voter, _)| signers.contains(voter))
+        .map(|(_, weight)| weight)
+        .sum();
+    3 * weight as u128 > 2 * total as u128
+}
+
+impl<D: Encode> Justification<D> {
+    /// Check the justification against the voter set. Every vote must be from the justification's
+    /// round and lead back to the target through the included headers, and together the votes
+    /// must hold a supermajority.
+    pub fn verify(&self, voters: &[(ConsensusAuthority, u64)]) -> bool {
+        let parents: HashMap<u64, u64> = self
+            .ancestry
+            .iter()
+            .map(|header| (header.hash(), header.parent()))
+            .collect();
+        let leads_to_target = |vote: &FinalityVote| {
+            let mut block = vote.target;
+            // Each step follows a distinct header, so this always ends.
+            for _ in 0..=parents.len() {
+                if block == self.target {
+                    return true;
+                }
+                match parents.get(&block) {
+                    Some(parent) => block = *parent,
+                    None => return false,
+                }
+            }
+            false
+        };
+        let signers = self
+            .votes
+            .iter()
+            .filter(|vote| vote.round == self.round && leads_to_target(vote))
+            .map(|vote| vote.voter);
+        is_supermajority(voters, signers)
+    }
+}
+
+/// Finalizes blocks produced by any engine, based on votes from a weighted set of authorities.
+pub struct FinalityGadget<D> {
+    /// The authorities who vote on finality, each with its voting weight.
+    voters: Vec<(ConsensusAuthority, u64)>,
+    /// Every imported header, by hash.
+    headers: HashMap<u64, Header<D>>,
+    /// The current voting round. A new round begins whenever a block is finalized, a round
+    /// completes without finalizing anything, or a round times out.
+    round: u64,
+    /// The votes of the current round, by voter. Each voter votes at most once per round.
+    votes: HashMap<ConsensusAuthority, FinalityVote>,
+    /// The hash of the latest finalized block.
+    finalized: u64,
+    /// The justifications of finalized blocks, by block hash. Genesis needs none.
+    justifications: HashMap<u64, Justification<D>>,
+}
+
+impl<D: Encode + Clone> FinalityGadget<D> {
+    /// Create a gadget that starts from the given genesis header, which is final by definition.
+    pub fn new(voters: Vec<(ConsensusAuthority, u64)>, genesis: Header<D>) -> Self {
+        let finalized = genesis.hash();
+        FinalityGadget {
+            voters,
+            headers: HashMap::from([(finalized, genesis)]),
+            round: 0,
+            votes: HashMap::new(),
+            finalized,
+            justifications: HashMap::new(),
+        }
+    }
+
+    /// Note a block produced by the block production engine.
+    pub fn import(&mut self, header: Header<D>) {
+        self.headers.insert(header.hash(), header);
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    /// The hash of the latest finalized block.
+    pub fn finalized(&self) -> u64 {
+        self.finalized
+    }
+
+    /// The justification that finalized the given block, if it was finalized by vote.
+    pub fn justification(&self, hash: u64) -> Option<&Justification<D>> {
+        self.justifications.get(&hash)
+    }
+
+    /// The hashes from the given block back to, but not including, the latest finalized block.
+    /// Returns None if the block is unknown or does not descend from the latest finalized block.
+    fn route_to_finalized(&self, mut block: u64) -> Option<Vec<u64>> {
+        let finalized_height = self.headers[&self.finalized].height();
+        let mut route = Vec::new();
+        while block != self.finalized {
+            let header = self.headers.get(&block)?;
+            if header.height() <= finalized_height {
+                return None;
+            }
+            route.push(block);
+            block = header.parent();
+        }
+        Some(route)
+    }
+
+    /// The vote that the given authority casts in the current round for its best block.
+    pub fn vote(&self, voter: ConsensusAuthority, best_block: u64) -> FinalityVote {
+        FinalityVote {
+            round: self.round,
+            target: best_block,
+            voter,
+        }
+    }
+
+    /// Count a vote, and finalize whatever it allows. Returns the hash of the newly finalized
+    /// block, if any. If the round can no longer finalize anything, a new round begins.
+    ///
+    /// Votes from other rounds, from non-voters, for blocks that do not extend the finalized chain,
+    /// and repeated votes from the same voter are all ignored.
+    pub fn observe(&mut self, vote: FinalityVote) -> Option<u64> {
+        let is_voter = self.voters.iter().any(|(voter, _)| *voter == vote.voter);
+        if vote.round != self.round
+            || !is_voter
+            || self.votes.contains_key(&vote.voter)
+            || self.route_to_finalized(vote.target).is_none()
+        {
+            return None;
+        }
+        self.votes.insert(vote.voter, vote);
+        let finalized = self.try_finalize();
+        if finalized.is_none() && !self.can_still_finalize() {
+            self.next_round();
+        }
+        finalized
+    }
+
+    /// Give up on the current round and begin the next one. Call this when a round has gone on
+    /// too long, for example because too many voters are offline to reach a supermajority.
+    pub fn time_out_round(&mut self) {
+        self.next_round();
+    }
+
+    fn next_round(&mut self) {
+        self.round += 1;
+        self.votes.clear();
+    }
+
+    /// The voters who voted for each block above the finalized one, directly or through a
+    /// descendant.
+    fn supporters(&self) -> HashMap<u64, Vec<ConsensusAuthority>> {
+        let mut supporters: HashMap<u64, Vec<ConsensusAuthority>> = HashMap::new();
+        for vote in self.votes.values() {
+            // Votes are only counted if they extend the finalized block, so the route exists.
+            for block in self.route_to_finalized(vote.target).unwrap_or_default() {
+                supporters.entry(block).or_default().push(vote.voter);
+            }
+        }
+        supporters
+    }
+
+    /// Whether the voters who have not voted yet could still give some block a supermajority,
+    /// whether or not anyone has voted for it so far.
+    fn can_still_finalize(&self) -> bool {
+        let undecided: Vec<ConsensusAuthority> = self
+            .voters
+            .iter()
+            .map(|(voter, _)| *voter)
+            .filter(|voter| !self.votes.contains_key(voter))
+            .collect();
+        is_supermajority(&self.voters, undecided.iter().copied())
+            || self.supporters().values().any(|signers| {
+                is_supermajority(&self.voters, signers.iter().chain(&undecided).copied())
+            })
+    }
+
+    /// Finalize the highest block that a supermajority voted for, directly or through a descendant.
+    fn try_finalize(&mut self) -> Option<u64> {
+        let supporters = self.supporters();
+        let (target, _) = supporters
+            .iter()
+            .filter(|(_, signers)| is_supermajority(&self.voters, signers.iter().copied()))
+            .max_by_key(|(block, _)| self.headers[*block].height())?;
+        let target = *target;
+
+        // Only the votes for the target or its descendants support it. Votes for other forks
+        // are left out, so the justification only needs the headers on the supporting votes'
+        // routes above the target.
+        let mut votes = Vec::new();
+        let mut ancestry = HashSet::new();
+        for vote in self.votes.values() {
+            let route = self.route_to_finalized(vote.target).unwrap_or_default();
+            if route.contains(&target) {
+                votes.push(*vote);
+                ancestry.extend(route.into_iter().take_while(|block| *block != target));
+            }
+        }
+        let justification = Justification {
+            round: self.round,
+            target,
+            votes,
+            ancestry: ancestry
+                .iter()
+                .map(|hash| self.headers[hash].clone())
+                .collect(),
+        };
+        self.justifications.insert(target, justification);
+        self.finalized = target;
+        self.next_round();
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+fn child<D: Encode>(parent: &Header<D>, extrinsics_root: u64, digest: D) -> Header<D> {
+    Header::partial(parent.hash(), parent.height() + 1, 0, extrinsics_root, 0).with_digest(digest)
+}
+
+#[cfg(test)]
+fn voters() -> Vec<(ConsensusAuthority, u64)> {
+    use ConsensusAuthority::*;
+    vec![(Alice, 1), (Bob, 1), (Charlie, 1)]
+}
+
+#[test]
+fn finalizes_the_highest_block_with_supermajority_ancestry() {
+    use ConsensusAuthority::*;
+
+    // Proof of work headers. The digest is the nonce.
+    let genesis = Header::partial(0, 0, 0, 0, 0).with_digest(0u64);
+    let a1 = child(&genesis, 0, 0);
+    let a2 = child(&a1, 0, 0);
+    let a3 = child(&a2, 0, 0);
+    let b3 = child(&a2, 1, 0);
+    let mut gadget = FinalityGadget::new(voters(), genesis.clone());
+    for header in [&a1, &a2, &a3, &b3] {
+        gadget.import(header.clone());
+    }
+
+    // The voters disagree about the best block, but all of them build on a2.
+    assert_eq!(gadget.observe(gadget.vote(Alice, a3.hash())), None);
+    assert_eq!(gadget.observe(gadget.vote(Bob, b3.hash())), None);
+    let finalized = gadget.observe(gadget.vote(Charlie, a3.hash()));
+    assert_eq!(finalized, Some(a2.hash()));
+    assert_eq!(gadget.finalized(), a2.hash());
+    assert_eq!(gadget.round(), 1);
+
+    // A new node can check the justification with nothing but the voter set.
+    let justification = gadget.justification(a2.hash()).unwrap();
+    assert!(justification.verify(&voters()));
+    let mut replayed = justification.clone();
+    replayed.round += 1;
+    assert!(!replayed.verify(&voters()));
+    let mut stripped = justification.clone();
+    stripped.ancestry.clear();
+    assert!(!stripped.verify(&voters()));
+
+    // The next round can only finalize descendants of a2, so b3 is now doomed.
+    let a4 = child(&a3, 0, 0);
+    gadget.import(a4.clone());
+    assert_eq!(gadget.observe(gadget.vote(Alice, genesis.hash())), None);
+    assert_eq!(gadget.observe(gadget.vote(Alice, a4.hash())), None);
+    assert_eq!(gadget.observe(gadget.vote(Bob, a4.hash())), None);
+    assert_eq!(
+        gadget.observe(gadget.vote(Charlie, a3.hash())),
+        Some(a3.hash())
+    );
+    assert!(gadget.justification(a3.hash()).unwrap().verify(&voters()));
+}
+
+#[test]
+fn votes_are_counted_once_per_voter_and_round() {
+    use ConsensusAuthority::*;
+
+    // Proof of authority headers. The digest is the signer.
+    let genesis = Header::partial(0, 0, 0, 0, 0).with_digest(Alice);
+    let b1 = child(&genesis, 0, Bob);
+    let mut gadget = FinalityGadget::new(vec![(Alice, 1), (Bob, 1)], genesis);
+    gadget.import(b1.clone());
+    let target = b1.hash();
+
+    // Alice voting twice is still only half of the weight.
+    assert_eq!(gadget.observe(gadget.vote(Alice, target)), None);
+    assert_eq!(gadget.observe(gadget.vote(Alice, target)), None);
+    // Charlie is not a voter, and votes from other rounds do not count.
+    assert_eq!(gadget.observe(gadget.vote(Charlie, target)), None);
+    let mut stale = gadget.vote(Bob, target);
+    stale.round = 7;
+    assert_eq!(gadget.observe(stale), None);
+
+    assert_eq!(gadget.observe(gadget.vote(Bob, target)), Some(target));
+}
+
+#[test]
+fn split_votes_do_not_block_finality() {
+    use ConsensusAuthority::*;
+
+    let genesis = Header::partial(0, 0, 0, 0, 0).with_digest(0u64);
+    let a1 = child(&genesis, 0, 0);
+    let b1 = child(&genesis, 1, 0);
+    let a2 = child(&a1, 0, 0);
+    let voters = vec![(Alice, 2), (Bob, 1), (Charlie, 1)];
+    let mut gadget = FinalityGadget::new(voters.clone(), genesis);
+    for header in [&a1, &b1, &a2] {
+        gadget.import(header.clone());
+    }
+
+    // Once the weight is split evenly, neither fork can get a supermajority, so the round
+    // completes without finalizing anything.
+    assert_eq!(gadget.observe(gadget.vote(Alice, a1.hash())), None);
+    assert_eq!(gadget.observe(gadget.vote(Bob, b1.hash())), None);
+    assert_eq!(gadget.round(), 0);
+    assert_eq!(gadget.observe(gadget.vote(Charlie, b1.hash())), None);
+    assert_eq!(gadget.round(), 1);
+
+    // Once the voters converge on one fork, the next round finalizes it. Charlie's vote for the
+    // other fork is left out of the justification, along with the header it points to.
+    assert_eq!(gadget.observe(gadget.vote(Alice, a2.hash())), None);
+    assert_eq!(gadget.observe(gadget.vote(Charlie, b1.hash())), None);
+    assert_eq!(gadget.observe(gadget.vote(Bob, a2.hash())), Some(a2.hash()));
+    let justification = gadget.justification(a2.hash()).unwrap();
+    assert!(justification.verify(&voters));
+    assert_eq!(justification.votes.len(), 2);
+    assert!(justification.ancestry.is_empty());
+}
+
+#[test]
+fn stalled_rounds_can_time_out() {
+    use ConsensusAuthority::*;
+
+    let genesis = Header::partial(0, 0, 0, 0, 0).with_digest(0u64);
+    let a1 = child(&genesis, 0, 0);
+    let b1 = child(&genesis, 1, 0);
+    let mut gadget = FinalityGadget::new(voters(), genesis);
+    gadget.import(a1.clone());
+    gadget.import(b1.clone());
+
+    // Bob and Charlie are offline. They could still finalize a1, so the round waits for them.
+    assert_eq!(gadget.observe(gadget.vote(Alice, a1.hash())), None);
+    assert_eq!(gadget.round(), 0);
+
+    // After the timeout, votes from the old round no longer count.
+    gadget.time_out_round();
+    assert_eq!(gadget.round(), 1);
+    let stale = FinalityVote {
+        round: 0,
+        target: a1.hash(),
+        voter: Bob,
+    };
+    assert_eq!(gadget.observe(stale), None);
+    assert_eq!(gadget.observe(gadget.vote(Alice, b1.hash())), None);
+    assert_eq!(gadget.observe(gadget.vote(Bob, b1.hash())), None);
+    assert_eq!(
+        gadget.observe(gadget.vote(Charlie, b1.hash())),
+        Some(b1.hash())
+    );
+}
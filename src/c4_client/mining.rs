@@ -0,0 +1,139 @@
+//! A mining client should always be mining on top of its best block. As soon as a better block is
+//! imported, whatever it was mining on has become stale, and every hash spent on it is wasted.
+//!
+//! Fork choice rules already see every imported header through their import hook, so we restart
+//! mining by wrapping whichever fork choice rule the client uses. Whenever the best block changes,
+//! the current mining job is cancelled and a new one starts on top of the new best block. The
+//! client polls the worker for sealed headers, and importing them restarts mining on top of them.
+
+use super::{ForkChoice, Header};
+use crate::c3_consensus::{MiningJob, Pow};
+use std::collections::HashMap;
+
+/// A fork choice rule that keeps a background PoW mining job on top of the best block,
+/// and otherwise behaves exactly like the rule that it wraps.
+pub struct MiningWorker<FC, F> {
+    /// The fork choice rule that actually chooses the best block.
+    pub inner: FC,
+    engine: Pow,
+    /// How many threads each mining job uses.
+    threads: usize,
+    /// Builds the partial header of a new block on top of the given parent. This is where the
+    /// client executes transactions from its pool to calculate the new state root.
+    prepare: F,
+    /// Every imported header by hash, so that mining can start on whichever one becomes best.
+    headers: HashMap<u64, Header<u64>>,
+    /// The current job, along with the hash of the parent it is mining on.
+    job: Option<(u64, MiningJob)>,
+}
+
+impl<FC, F> MiningWorker<FC, F>
+where
+    F: Fn(&Header<u64>) -> Header<()>,
+{
+    pub fn new(inner: FC, engine: Pow, threads: usize, prepare: F) -> Self {
+        MiningWorker {
+            inner,
+            engine,
+            threads,
+            prepare,
+            headers: HashMap::new(),
+            job: None,
+        }
+    }
+
+    /// The hash of the block currently being mined on, if any.
+    pub fn mining_on(&self) -> Option<u64> {
+        self.job.as_ref().map(|(parent, _)| *parent)
+    }
+
+    /// The hashrate of the current mining job.
+    pub fn hashrate(&self) -> f64 {
+        self.job.as_ref().map_or(0.0, |(_, job)| job.hashrate())
+    }
+
+    /// Take the sealed header, if the current job has found one. Does not block.
+    ///
+    /// The job is finished once it finds a seal, so mining pauses until the client imports
+    /// the sealed header or some other better block.
+    pub fn take_sealed(&mut self) -> Option<Header<u64>> {
+        let sealed = self.job.as_ref()?.1.try_result()?;
+        self.job = None;
+        Some(sealed)
+    }
+
+    /// Cancel the current job, if any, and start mining on top of the given block.
+    fn restart(&mut self, parent_hash: u64) {
+        // Dropping the old job cancels it.
+        self.job = None;
+        if let Some(parent) = self.headers.get(&parent_hash) {
+            let job = self
+                .engine
+                .start_mining((self.prepare)(parent), self.threads);
+            self.job = Some((parent_hash, job));
+        }
+    }
+}
+
+impl<FC, F> ForkChoice<Pow> for MiningWorker<FC, F>
+where
+    FC: ForkChoice<Pow>,
+    F: Fn(&Header<u64>) -> Header<()>,
+{
+    fn best_block(&self, header: Header<u64>) -> Option<u64> {
+        self.inner.best_block(header)
+    }
+
+    fn import_hook(&mut self, header: Header<u64>) {
+        self.inner.import_hook(header.clone());
+        self.headers.insert(header.hash(), header.clone());
+        let best = self.inner.best_block(header);
+        if let Some(best) = best.filter(|best| self.mining_on() != Some(*best)) {
+            self.restart(best);
+        }
+    }
+}
+
+#[test]
+fn mining_restarts_when_best_block_changes() {
+    use super::simulation::FirstSeenLongest;
+    use crate::json;
+
+    let child_of = |parent: &Header<u64>| {
+        Header::partial(parent.hash(), parent.height() + 1, 0, 0, parent.timestamp())
+    };
+    let genesis = Header::partial(0, 0, 0, 0, 0).with_digest(0);
+
+    let pow: Pow = json::from_str(r#"{ "engine": "pow", "threshold": 1000000000000000 }"#).unwrap();
+    let mut worker = MiningWorker::new(FirstSeenLongest::default(), pow, 2, child_of);
+    assert_eq!(worker.mining_on(), None);
+
+    assert_eq!(worker.hashrate(), 0.0);
+
+    ForkChoice::<Pow>::import_hook(&mut worker, genesis.clone());
+    assert_eq!(worker.mining_on(), Some(genesis.hash()));
+    // The job reports its progress while it searches.
+    while worker.hashrate() == 0.0 {
+        std::thread::yield_now();
+    }
+
+    let sealed = loop {
+        if let Some(sealed) = worker.take_sealed() {
+            break sealed;
+        }
+        std::thread::yield_now();
+    };
+    assert_eq!(sealed.parent(), genesis.hash());
+    assert!(sealed.hash() < 1_000_000_000_000_000);
+    assert_eq!(worker.mining_on(), None);
+    assert_eq!(worker.hashrate(), 0.0);
+
+    // Importing the sealed block restarts mining on top of it.
+    ForkChoice::<Pow>::import_hook(&mut worker, sealed.clone());
+    assert_eq!(worker.mining_on(), Some(sealed.hash()));
+
+    // A block that does not change the best block leaves the current job alone.
+    let sibling = Header::partial(genesis.hash(), 1, 1, 0, 0).with_digest(0);
+    ForkChoice::<Pow>::import_hook(&mut worker, sibling);
+    assert_eq!(worker.mining_on(), Some(sealed.hash()));
+}
@@ -14,17 +14,26 @@
 
 use crate::{
     c1_state_machine::StateMachine,
-    c3_consensus::{Consensus, Header},
+    c3_consensus::{Consensus, ConsensusAuthority, Header},
 };
+use grandpa::Justification;
 use p1_data_structure::Block;
 use p3_fork_choice::ForkChoice;
+use std::collections::HashMap;
 
+mod bft;
+mod chain_spec;
+mod chain_work;
+mod grandpa;
+mod mining;
 mod p1_data_structure;
 mod p2_importing_blocks;
 mod p3_fork_choice;
 mod p4_transaction_pool;
 mod p5_authoring_blocks;
 mod p6_finality;
+mod simulation;
+mod slashing;
 
 type Hash = u64;
 
@@ -47,9 +56,10 @@ type Hash = u64;
 /// FC: ForkChoice<C>
 /// P: TransactionPool<SM>
 ///
-/// But we leave them unconstrained here to avoid repeating many where clauses throughout the section.
-/// Instead we bind them on impl blocks.
-pub struct FullClient<C, SM, FC, P> {
+/// The consensus engine and state machine are bound here, because the client stores values of their
+/// associated types, like the genesis state. We leave the others unconstrained to avoid repeating many where clauses throughout
+/// the section. Instead we bind them on impl blocks.
+pub struct FullClient<C: Consensus, SM: StateMachine, FC, P> {
     /// The consensus engine used by this client.
     consensus_engine: C,
     /// The state machine used by this client.
@@ -58,6 +68,18 @@ pub struct FullClient<C, SM, FC, P> {
     fork_choice: FC,
     /// The transaction pool used by this client.
     transaction_pool: P,
+    /// A human-readable name for the chain this client follows, like "Local Testnet".
+    chain_name: String,
+    /// The state in which the chain began.
+    genesis_state: SM::State,
+    /// Every block the client knows, by hash, starting with genesis.
+    blocks: HashMap<Hash, Block<C, SM>>,
+    /// The verified justifications that the finality gadget produced, by finalized block hash.
+    justifications: HashMap<Hash, Justification<C::Digest>>,
+    /// The authorities voting in the finality gadget, with their voting weights.
+    finality_voters: Vec<(ConsensusAuthority, u64)>,
+    /// The most recent final block. It and all of its ancestors will never be reverted.
+    finalized: Hash,
     // TODO: You are free to add more fields here, and you will probably need to.
     // Please document them as you add them.
 }
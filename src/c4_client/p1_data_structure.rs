@@ -18,7 +18,11 @@
 
 use super::{Consensus, ForkChoice, Header, StateMachine};
 
+use super::chain_spec::ChainSpec;
 use super::FullClient;
+use crate::c3_consensus::GenesisDigest;
+use crate::codec::{Decode, Encode, Error};
+use std::collections::HashMap;
 type Hash = u64;
 
 impl<Digest> Header<Digest> {
@@ -44,8 +48,36 @@ impl<Digest> Header<Digest> {
 }
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Block<C: Consensus, SM: StateMachine> {
-    header: Header<C::Digest>,
-    body: Vec<SM::Transition>,
+    pub(crate) header: Header<C::Digest>,
+    pub(crate) body: Vec<SM::Transition>,
+}
+
+impl<C, SM> Encode for Block<C, SM>
+where
+    C: Consensus,
+    C::Digest: Encode,
+    SM: StateMachine,
+    SM::Transition: Encode,
+{
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.header.encode_to(dest);
+        self.body.encode_to(dest);
+    }
+}
+
+impl<C, SM> Decode for Block<C, SM>
+where
+    C: Consensus,
+    C::Digest: Decode,
+    SM: StateMachine,
+    SM::Transition: Decode,
+{
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(Block {
+            header: Header::decode(input)?,
+            body: Vec::decode(input)?,
+        })
+    }
 }
 
 impl<C: Consensus, SM: StateMachine> Block<C, SM> {
@@ -79,11 +111,68 @@ fn create_empty_chain<C: Consensus, SM: StateMachine>(
 // genesis block.
 impl<C, SM, FC, P> FullClient<C, SM, FC, P>
 where
+    C: Consensus,
     SM: StateMachine,
 {
     fn new(genesis_state: SM::State) -> Self {
         todo!("Exercise 9")
     }
+
+    /// The human-readable name of the chain this client follows.
+    pub fn chain_name(&self) -> &str {
+        &self.chain_name
+    }
+
+    /// The state in which the chain began.
+    pub fn genesis_state(&self) -> &SM::State {
+        &self.genesis_state
+    }
+}
+
+impl<C, SM, FC, P> FullClient<C, SM, FC, P>
+where
+    C: GenesisDigest,
+    SM: StateMachine,
+    SM::State: Encode,
+    SM::Transition: Encode,
+    FC: ForkChoice<C>,
+{
+    /// Create a new client for the chain described by a chain spec. The client follows the named
+    /// chain from the spec's genesis state, using the consensus engine as configured by the spec.
+    ///
+    /// The genesis block has no parent and no extrinsics. It commits to the genesis state and
+    /// carries the engine's genesis digest. It is the first block in the client's database, and
+    /// the fork choice rule imports it, so it starts out as the best block. Genesis is final from
+    /// the start, and the spec's finality voters are the ones whose justifications the client
+    /// accepts.
+    ///
+    /// The remaining parts of the client are given explicitly, so none of them need a default.
+    /// If you add fields to the client, initialize them here as well as in `new`.
+    pub fn from_chain_spec(
+        spec: ChainSpec<C, SM::State>,
+        state_machine: SM,
+        mut fork_choice: FC,
+        transaction_pool: P,
+    ) -> Self {
+        let body: Vec<SM::Transition> = Vec::new();
+        let header = Header::partial(0, 0, spec.genesis.encoded_hash(), body.encoded_hash(), 0)
+            .with_digest(spec.consensus.genesis_digest());
+        fork_choice.import_hook(header.clone());
+        let genesis = Block { header, body };
+        let genesis_hash = genesis.header.hash();
+        FullClient {
+            consensus_engine: spec.consensus,
+            state_machine,
+            fork_choice,
+            transaction_pool,
+            chain_name: spec.name,
+            genesis_state: spec.genesis,
+            blocks: HashMap::from([(genesis_hash, genesis)]),
+            justifications: HashMap::new(),
+            finality_voters: spec.finality_voters.into_iter().collect(),
+            finalized: genesis_hash,
+        }
+    }
 }
 
 // The default client is initialized with the default genesis state.
@@ -101,4 +190,66 @@ where
     }
 }
 
+#[test]
+fn block_codec_round_trip() {
+    use crate::c1_state_machine::{AccountedCurrency, AccountingTransaction, User};
+    use crate::c3_consensus::Pow;
+    use crate::codec::decode_all;
+
+    let header = Header::partial(0, 0, 0, 0, 0).with_digest(0);
+    let block = Block::<Pow, AccountedCurrency> {
+        header,
+        body: vec![AccountingTransaction::Mint {
+            minter: User::Alice,
+            amount: 10,
+        }],
+    };
+    let decoded: Block<Pow, AccountedCurrency> = decode_all(&block.encode()).unwrap();
+    assert_eq!(decoded.header, block.header);
+    assert_eq!(decoded.encode(), block.encode());
+    assert_eq!(decoded.encoded_hash(), block.encoded_hash());
+}
+
+#[test]
+fn client_from_local_testnet_chain_spec() {
+    use super::chain_spec::{LocalTestnetEngines, LOCAL_TESTNET};
+    use super::p3_fork_choice::LongestChain;
+    use super::p4_transaction_pool::SimplePool;
+    use crate::c1_state_machine::{AccountedCurrency, AccountingTransaction, User};
+    use crate::c3_consensus::ForkSchedule;
+
+    let spec = ChainSpec::load(LOCAL_TESTNET).unwrap();
+    let client =
+        FullClient::<ForkSchedule<LocalTestnetEngines>, AccountedCurrency, _, _>::from_chain_spec(
+            spec,
+            AccountedCurrency,
+            LongestChain::default(),
+            SimplePool::<AccountedCurrency>::default(),
+        );
+    assert_eq!(client.chain_name(), "Local Testnet");
+    assert_eq!(client.genesis_state().get(&User::Alice), Some(&1000));
+    assert_eq!(client.consensus_engine.active_at(1000), 1);
+
+    // The genesis block is built from the spec, and is the best block.
+    let [(hash, genesis)] = client.blocks.iter().collect::<Vec<_>>()[..] else {
+        panic!("the client starts with exactly one block");
+    };
+    let body: Vec<AccountingTransaction> = Vec::new();
+    let expected = Header::partial(
+        0,
+        0,
+        client.genesis_state().encoded_hash(),
+        body.encoded_hash(),
+        0,
+    )
+    .with_digest(client.consensus_engine.genesis_digest());
+    assert_eq!(genesis.header, expected);
+    assert!(genesis.body.is_empty());
+    assert_eq!(*hash, expected.hash());
+    assert_eq!(
+        ForkChoice::<ForkSchedule<LocalTestnetEngines>>::best_block(&client.fork_choice, expected),
+        Some(*hash)
+    );
+}
+
 //TODO tests
@@ -9,6 +9,8 @@ use super::{Block, Consensus, FullClient, StateMachine};
 /// to access data about imported blocks.
 pub trait ImportBlock<C: Consensus, SM: StateMachine> {
     /// Attempt to import a block.
+    /// The block's state is calculated from its parent's state by first calling
+    /// `StateMachine::begin_block` with the block's height, and then applying its transitions.
     /// Returns whether the import was successful or not.
     fn import_block(&mut self, _: Block<C, SM>) -> bool;
 
@@ -4,8 +4,10 @@
 //! The concepts are identical here, but now that we have a client tracking a proper block database,
 //! we can explore more advanced fork choice algorithms. In particular, we can now explore GHOST.
 
-use super::{Consensus, FullClient, Header};
-use crate::c3_consensus::{ConsensusAuthority, Pow, SimplePoa};
+use super::chain_work::ChainWork;
+use super::{Consensus, FullClient, Header, StateMachine};
+use crate::c3_consensus::{ConsensusAuthority, Pow, SimplePoa, UncleDigest, UnclePow};
+use std::collections::{HashMap, HashSet};
 
 /// A means for a blockchain client to decide which chain is best among the many
 /// that it potentially knows about.
@@ -24,34 +26,66 @@ pub trait ForkChoice<C: Consensus> {
 }
 
 /// The chain with the highest block height is the best
+#[derive(Default)]
 pub struct LongestChain {
-    // You may add fields here if you need to.
+    /// Every imported block, by hash.
+    imported: HashSet<u64>,
+    /// The highest imported block, as its height and hash. Ties go to the block imported first.
+    best: Option<(u64, u64)>,
 }
 
 impl<C: Consensus> ForkChoice<C> for LongestChain {
-    fn best_block(&self, header: Header<C::Digest>) -> Option<u64> {
-        todo!("Exercise 1")
+    /// The best block is tracked as blocks are imported, so the given header is not needed.
+    fn best_block(&self, _: Header<C::Digest>) -> Option<u64> {
+        self.best.map(|(_, hash)| hash)
     }
 
+    /// Blocks whose parent has not been imported are ignored.
     fn import_hook(&mut self, header: Header<C::Digest>) {
-        todo!("Exercise 2")
+        if header.height() > 0 && !self.imported.contains(&header.parent()) {
+            return;
+        }
+        let hash = header.hash();
+        self.imported.insert(hash);
+        if self.best.is_none_or(|(height, _)| header.height() > height) {
+            self.best = Some((header.height(), hash));
+        }
     }
 }
 
 /// The chain with the most accumulated proof of work is the best.
 /// This fork choice rule only makes sense with the PoW consensus engine
 /// and the generics reflect that.
+///
+/// A block's work is the number of hashes it is expected to take, which depends only on the
+/// target it was mined against and never on how lucky its hash was. See `expected_work`.
+/// Cumulative work is cached for every imported block in a `ChainWork`, so comparing chains
+/// never requires walking back to genesis.
 pub struct HeaviestChain {
-    // You may add fields here if you need to.
+    /// The threshold that every block was mined against.
+    threshold: u64,
+    /// The cumulative work of every imported block.
+    work: ChainWork,
+}
+
+impl HeaviestChain {
+    pub fn new(engine: &Pow) -> Self {
+        HeaviestChain {
+            threshold: engine.threshold(),
+            work: ChainWork::default(),
+        }
+    }
 }
 
 impl ForkChoice<Pow> for HeaviestChain {
-    fn best_block(&self, header: Header<u64>) -> Option<u64> {
-        todo!("Exercise 3")
+    /// The heaviest block is tracked as blocks are imported, so the given header is not needed.
+    fn best_block(&self, _: Header<u64>) -> Option<u64> {
+        self.work.heaviest()
     }
 
+    /// Blocks whose parent has not been imported are ignored, since their work is unknown.
     fn import_hook(&mut self, header: Header<u64>) {
-        todo!("Exercise 4")
+        self.work.import(&header, self.threshold);
     }
 }
 
@@ -75,24 +109,123 @@ impl ForkChoice<SimplePoa> for MostAliceSigs {
 /// In the Greedy Heaviest Observed Subtree rule, the fork choice is iterative.
 /// You start from the genesis block, and at each fork, you choose the side of the fork
 /// that has the most accumulated proof of work on _all_ of its descendants.
+///
+/// Stale blocks count too. That is the whole point of GHOST. But a node only knows about
+/// the stale blocks it happened to receive. With the `UnclePow` engine, canonical blocks
+/// carry the headers of recent stale blocks as uncles, so every node learns about them.
+/// When importing such a block, each uncle's work counts toward the subtree of the uncle's
+/// parent, whether or not this node ever imported the uncle itself.
+///
+/// Every block of a chain is mined against the same threshold, so each block counts as one
+/// unit of work.
+#[derive(Default)]
 pub struct Ghost {
-    // You may add fields here if you need to.
+    /// The hash of the genesis block, where every walk down the tree starts.
+    genesis: Option<u64>,
+    /// The parent of every imported block, by hash.
+    parents: HashMap<u64, u64>,
+    /// The children of every imported block, in the order they were imported.
+    children: HashMap<u64, Vec<u64>>,
+    /// The work of every imported block and all of its known descendants, including uncles.
+    subtree_work: HashMap<u64, u64>,
+    /// The blocks whose work has already been counted, whether they were imported or included
+    /// as uncles. Counting a block twice would favour whichever subtree learns of it twice.
+    counted: HashSet<u64>,
+    // You may add more fields here if you need to.
+}
+
+impl Ghost {
+    /// Place an imported block in the tree and count its work. Returns false, leaving the tree
+    /// alone, if the block was already imported or its parent was not.
+    fn insert(&mut self, hash: u64, parent: u64, height: u64) -> bool {
+        if self.subtree_work.contains_key(&hash) {
+            return false;
+        }
+        if height == 0 {
+            self.genesis.get_or_insert(hash);
+        } else if self.subtree_work.contains_key(&parent) {
+            self.parents.insert(hash, parent);
+            self.children.entry(parent).or_default().push(hash);
+        } else {
+            return false;
+        }
+
+        // A block that was already counted as an uncle joins the tree without adding work again.
+        if self.counted.insert(hash) {
+            self.credit(hash);
+        } else {
+            self.subtree_work.insert(hash, 1);
+        }
+        true
+    }
+
+    /// Add one unit of work to the subtree of the given block, and so to each of its ancestors.
+    fn credit(&mut self, mut block: u64) {
+        loop {
+            *self.subtree_work.entry(block).or_insert(0) += 1;
+            match self.parents.get(&block) {
+                Some(parent) => block = *parent,
+                None => return,
+            }
+        }
+    }
+
+    /// Walk down from genesis, always into the child with the heaviest subtree. Ties go to the
+    /// child imported first.
+    fn heaviest_leaf(&self) -> Option<u64> {
+        let mut block = self.genesis?;
+        while let Some(children) = self.children.get(&block) {
+            let mut heaviest = children[0];
+            for child in &children[1..] {
+                if self.subtree_work[child] > self.subtree_work[&heaviest] {
+                    heaviest = *child;
+                }
+            }
+            block = heaviest;
+        }
+        Some(block)
+    }
 }
 
 impl ForkChoice<Pow> for Ghost {
-    fn best_block(&self, header: Header<u64>) -> Option<u64> {
-        todo!("Exercise 7")
+    /// The heaviest subtree is tracked as blocks are imported, so the given header is not needed.
+    fn best_block(&self, _: Header<u64>) -> Option<u64> {
+        self.heaviest_leaf()
     }
 
+    /// Blocks whose parent has not been imported are ignored.
     fn import_hook(&mut self, header: Header<u64>) {
-        todo!("Exercise 8")
+        self.insert(header.hash(), header.parent(), header.height());
+    }
+}
+
+// GHOST is most faithful to the paper when blocks include uncles. Uncles are never imported on their
+// own, so the import hook must count them when importing the block that includes them.
+impl ForkChoice<UnclePow> for Ghost {
+    /// The heaviest subtree is tracked as blocks are imported, so the given header is not needed.
+    fn best_block(&self, _: Header<UncleDigest>) -> Option<u64> {
+        self.heaviest_leaf()
+    }
+
+    /// Blocks whose parent has not been imported are ignored. Uncles whose parent has not been
+    /// imported cannot be placed in the tree, so their work is not counted.
+    fn import_hook(&mut self, header: Header<UncleDigest>) {
+        if !self.insert(header.hash(), header.parent(), header.height()) {
+            return;
+        }
+        for uncle in &header.consensus_digest().uncles {
+            if self.subtree_work.contains_key(&uncle.parent()) && self.counted.insert(uncle.hash())
+            {
+                self.credit(uncle.parent());
+            }
+        }
     }
 }
 
 // Finally, we will provide a convenience method directly on our client that simply calls
 // into the corresponding method on the ForkChoice rule. You may need to add some trait
 // bounds to make this work.
-impl<C, SM, FC, P> FullClient<C, SM, FC, P> {
+impl<C: Consensus, SM: StateMachine, FC, P> FullClient<C, SM, FC, P> {
     /// Return the hash of the best block currently known to the client
     fn best_block(&self) -> u64 {
         todo!("Exercise 9")
@@ -102,3 +235,150 @@ impl<C, SM, FC, P> FullClient<C, SM, FC, P> {
 //TODO lots of tests for all the algos.
 // Especially a subtle one in Ghost, where importing a new
 // header causes a re-org to a different header than the one that was imported.
+
+/// Seal a header for a trivial PoW engine, whose threshold almost every nonce meets.
+#[cfg(test)]
+fn mined(partial: Header<()>) -> Header<u64> {
+    (0..=u64::MAX)
+        .map(|nonce| partial.clone().with_digest(nonce))
+        .find(|header| header.hash() < u64::MAX)
+        .unwrap()
+}
+
+/// Seal a child of the given block. The extrinsics root tells siblings apart.
+#[cfg(test)]
+fn mined_child(parent: &Header<u64>, extrinsics_root: u64) -> Header<u64> {
+    mined(Header::partial(
+        parent.hash(),
+        parent.height() + 1,
+        0,
+        extrinsics_root,
+        0,
+    ))
+}
+
+#[test]
+fn longest_chain_follows_the_highest_block() {
+    let rule: &mut dyn ForkChoice<Pow> = &mut LongestChain::default();
+    let genesis = mined(Header::partial(0, 0, 0, 0, 0));
+    assert_eq!(rule.best_block(genesis.clone()), None);
+    rule.import_hook(genesis.clone());
+    assert_eq!(rule.best_block(genesis.clone()), Some(genesis.hash()));
+
+    let a1 = mined_child(&genesis, 1);
+    let b1 = mined_child(&genesis, 2);
+    rule.import_hook(a1.clone());
+    rule.import_hook(b1.clone());
+    // Ties go to the block imported first.
+    assert_eq!(rule.best_block(b1.clone()), Some(a1.hash()));
+
+    let b2 = mined_child(&b1, 2);
+    rule.import_hook(b2.clone());
+    assert_eq!(rule.best_block(a1.clone()), Some(b2.hash()));
+
+    // Blocks whose parent is unknown are ignored.
+    rule.import_hook(mined_child(&mined_child(&b2, 3), 3));
+    assert_eq!(rule.best_block(b2.clone()), Some(b2.hash()));
+}
+
+#[test]
+fn heaviest_chain_follows_cumulative_work() {
+    let pow = Pow::new(u64::MAX >> 4);
+    let mut rule = HeaviestChain::new(&pow);
+    let genesis = mined(Header::partial(0, 0, 0, 0, 0));
+    rule.import_hook(genesis.clone());
+    assert_eq!(rule.best_block(genesis.clone()), Some(genesis.hash()));
+
+    let a1 = mined_child(&genesis, 1);
+    let a2 = mined_child(&a1, 1);
+    for header in [&a1, &a2] {
+        rule.import_hook(header.clone());
+    }
+    assert_eq!(rule.best_block(a2.clone()), Some(a2.hash()));
+
+    // A fork only takes over once it has strictly more work, whichever header is passed in.
+    let b1 = mined_child(&genesis, 2);
+    let b2 = mined_child(&b1, 2);
+    rule.import_hook(b1.clone());
+    rule.import_hook(b2.clone());
+    assert_eq!(rule.best_block(b2.clone()), Some(a2.hash()));
+    let b3 = mined_child(&b2, 2);
+    rule.import_hook(b3.clone());
+    assert_eq!(rule.best_block(a2.clone()), Some(b3.hash()));
+
+    // Blocks whose parent is unknown are ignored.
+    rule.import_hook(mined_child(&mined_child(&b3, 3), 3));
+    assert_eq!(rule.best_block(b3.clone()), Some(b3.hash()));
+}
+
+#[test]
+fn ghost_follows_the_heaviest_subtree() {
+    let ghost: &mut dyn ForkChoice<Pow> = &mut Ghost::default();
+    let genesis = mined(Header::partial(0, 0, 0, 0, 0));
+    ghost.import_hook(genesis.clone());
+
+    // Three blocks in a row on one side of the fork.
+    let a1 = mined_child(&genesis, 1);
+    let a2 = mined_child(&a1, 1);
+    let a3 = mined_child(&a2, 1);
+    for header in [&a1, &a2, &a3] {
+        ghost.import_hook(header.clone());
+    }
+    assert_eq!(ghost.best_block(a3.clone()), Some(a3.hash()));
+
+    // The other side is shorter, but once it holds more blocks in total it wins, even though its
+    // blocks compete with each other.
+    let b1 = mined_child(&genesis, 2);
+    let b2 = [2, 3, 4].map(|root| mined_child(&b1, root));
+    ghost.import_hook(b1.clone());
+    for header in &b2[..2] {
+        ghost.import_hook(header.clone());
+    }
+    assert_eq!(ghost.best_block(a3.clone()), Some(a3.hash()));
+    ghost.import_hook(b2[2].clone());
+    assert_eq!(ghost.best_block(a3.clone()), Some(b2[0].hash()));
+}
+
+#[test]
+fn ghost_counts_uncle_work() {
+    use crate::c1_state_machine::User;
+
+    let alice = UnclePow::new(u64::MAX / 4, User::Alice);
+    let bob = UnclePow::new(u64::MAX / 4, User::Bob);
+    let child = |engine: &UnclePow, parent: &Header<UncleDigest>, root, uncles| {
+        let partial = Header::partial(parent.hash(), parent.height() + 1, 0, root, 0);
+        engine.seal_with_uncles(partial, uncles).unwrap()
+    };
+    let genesis = alice
+        .seal_with_uncles(Header::partial(0, 0, 0, 0, 0), Vec::new())
+        .unwrap();
+    // Ghost works with both PoW engines, so name the one these blocks come from.
+    let ghost: &mut dyn ForkChoice<UnclePow> = &mut Ghost::default();
+    ghost.import_hook(genesis.clone());
+
+    // Alice's branch is the longest, with three blocks.
+    let a1 = child(&alice, &genesis, 1, Vec::new());
+    let a2 = child(&alice, &a1, 1, Vec::new());
+    let a3 = child(&alice, &a2, 1, Vec::new());
+    for header in [&a1, &a2, &a3] {
+        ghost.import_hook(header.clone());
+    }
+
+    // Bob's branch has only two blocks, but two more went stale on top of its first block.
+    // This node never saw them, but the second block includes them as uncles.
+    let b1 = child(&bob, &genesis, 2, Vec::new());
+    ghost.import_hook(b1.clone());
+    assert_eq!(ghost.best_block(b1.clone()), Some(a3.hash()));
+    let stale = [3, 4].map(|root| child(&bob, &b1, root, Vec::new()));
+    let b2 = child(&bob, &b1, 2, stale.to_vec());
+    ghost.import_hook(b2.clone());
+    assert_eq!(ghost.best_block(b2.clone()), Some(b2.hash()));
+
+    // An uncle that turns up later is not counted twice. So when Alice's branch draws level, the
+    // tie goes to her branch, which was imported first.
+    ghost.import_hook(stale[0].clone());
+    assert_eq!(ghost.best_block(b2.clone()), Some(b2.hash()));
+    let a4 = child(&alice, &a3, 1, Vec::new());
+    ghost.import_hook(a4.clone());
+    assert_eq!(ghost.best_block(a4.clone()), Some(a4.hash()));
+}
@@ -11,7 +11,7 @@
 
 use std::{collections::VecDeque, marker::PhantomData};
 
-use super::{FullClient, StateMachine};
+use super::{Consensus, FullClient, StateMachine};
 
 /// An abstraction over the notion of transaction pool.
 pub trait TransactionPool<SM: StateMachine> {
@@ -40,6 +40,7 @@ pub trait TransactionPool<SM: StateMachine> {
 // These are basically wrappers around methods that the pool itself provides.
 impl<C, SM, FC, P> FullClient<C, SM, FC, P>
 where
+    C: Consensus,
     SM: StateMachine,
 {
     /// Submit a transaction to the client's transaction pool to hopefully
@@ -63,6 +64,12 @@ where
 /// A simple state machine that is just a first-in-first-out queue.
 pub struct SimplePool<SM: StateMachine>(VecDeque<SM::Transition>);
 
+impl<SM: StateMachine> Default for SimplePool<SM> {
+    fn default() -> Self {
+        SimplePool(VecDeque::new())
+    }
+}
+
 impl<SM: StateMachine> TransactionPool<SM> for SimplePool<SM> {
     fn try_insert(&mut self, t: <SM as StateMachine>::Transition) -> bool {
         todo!()
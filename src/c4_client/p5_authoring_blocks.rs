@@ -1,11 +1,12 @@
 //! We are now ready to give out client the ability to author blocks.
 //! Clients that perform this task are usually known as "miners", "authors", or "authorities".
 
-use super::{FullClient, StateMachine};
+use super::{Consensus, FullClient, StateMachine};
 
 // You may need to add trait bounds to make this work.
 impl<C, SM, FC, P> FullClient<C, SM, FC, P>
 where
+    C: Consensus,
     SM: StateMachine,
 {
     /// Author a new block with the given transactions on top of the given parent
@@ -4,15 +4,136 @@
 //!
 //! Although we elide the details of the game itself, this model still allows us to explore
 //! the consequences of having some blocks that are never reverted.
+//!
+//! The `grandpa` module plays the game for real. Authorities vote on chains, and the blocks they
+//! finalize come with justifications that any node can check before finalizing them itself.
 
-use super::FullClient;
+use super::grandpa::Justification;
+use super::{Consensus, FullClient, Hash, StateMachine};
 
-impl<C, SM, FC, P> FullClient<C, SM, FC, P> {
+impl<C: Consensus, SM: StateMachine, FC, P> FullClient<C, SM, FC, P> {
     /// Mark the given block as final so that it will never be reverted.
     /// Returns whether or not the block was known and marked successfully.
+    ///
+    /// Only descendants of the most recent final block can be finalized, because finalizing
+    /// anything else would revert a final block. Finalizing a block that is already final
+    /// succeeds and changes nothing.
     pub fn manually_finalize_block(&mut self, block_hash: u64) -> bool {
-        todo!("Exercise 1")
+        if self.is_ancestor(block_hash, self.finalized) {
+            return true;
+        }
+        if !self.is_ancestor(self.finalized, block_hash) {
+            return false;
+        }
+        self.finalized = block_hash;
+        true
+    }
+
+    /// The most recent final block.
+    pub fn finalized_block(&self) -> Hash {
+        self.finalized
+    }
+
+    /// Whether `ancestor` is a known block that `block` builds on, or is `block` itself.
+    fn is_ancestor(&self, ancestor: Hash, block: Hash) -> bool {
+        let mut block = block;
+        while let Some(known) = self.blocks.get(&block) {
+            if block == ancestor {
+                return true;
+            }
+            block = known.header.parent();
+        }
+        false
     }
 }
 
+impl<C: Consensus, SM: StateMachine, FC, P> FullClient<C, SM, FC, P> {
+    /// Check a justification from the finality gadget against the client's finality voters, and
+    /// if it is valid, finalize its target. The justification is stored with the block it
+    /// finalizes so that it can be served to other nodes. Returns whether the target was
+    /// finalized.
+    ///
+    /// A valid justification is all the proof needed to finalize its target, but the target must
+    /// already be imported. Justifications for unknown blocks are rejected, and should be
+    /// imported again with the block.
+    pub fn import_justification(&mut self, justification: Justification<C::Digest>) -> bool {
+        if !self.blocks.contains_key(&justification.target)
+            || !justification.verify(&self.finality_voters)
+            || !self.manually_finalize_block(justification.target)
+        {
+            return false;
+        }
+        self.justifications
+            .insert(justification.target, justification);
+        true
+    }
+
+    /// The stored justification for the given block, if it was finalized by the finality gadget.
+    pub fn justification(&self, block_hash: u64) -> Option<&Justification<C::Digest>> {
+        self.justifications.get(&block_hash)
+    }
+}
+
+#[test]
+fn valid_justifications_finalize_known_blocks() {
+    use super::chain_spec::{ChainSpec, LocalTestnetEngines, LOCAL_TESTNET};
+    use super::grandpa::FinalityVote;
+    use super::p1_data_structure::Block;
+    use super::p3_fork_choice::LongestChain;
+    use super::p4_transaction_pool::SimplePool;
+    use crate::c1_state_machine::AccountedCurrency;
+    use crate::c3_consensus::{ConsensusAuthority::*, ForkSchedule, Header};
+
+    let mut client =
+        FullClient::<ForkSchedule<LocalTestnetEngines>, AccountedCurrency, _, _>::from_chain_spec(
+            ChainSpec::load(LOCAL_TESTNET).unwrap(),
+            AccountedCurrency,
+            LongestChain::default(),
+            SimplePool::<AccountedCurrency>::default(),
+        );
+    let genesis = client.finalized_block();
+    let digest = *client.blocks[&genesis].header.consensus_digest();
+    let header = Header::partial(genesis, 1, 0, 0, 1).with_digest(digest);
+    let child = header.hash();
+    client.blocks.insert(
+        child,
+        Block {
+            header,
+            body: Vec::new(),
+        },
+    );
+    let justification_for = |target, voters: &[_]| Justification {
+        round: 0,
+        target,
+        votes: voters
+            .iter()
+            .map(|&voter| FinalityVote {
+                round: 0,
+                target,
+                voter,
+            })
+            .collect(),
+        ancestry: Vec::new(),
+    };
+
+    // Blocks the client has not imported cannot be finalized.
+    assert!(!client.import_justification(justification_for(42, &[Alice, Bob, Charlie])));
+    assert_eq!(client.justification(42), None);
+
+    // Two of three votes is not a supermajority.
+    assert!(!client.import_justification(justification_for(child, &[Alice, Bob])));
+    assert_eq!(client.justification(child), None);
+    assert_eq!(client.finalized_block(), genesis);
+
+    let justification = justification_for(child, &[Alice, Bob, Charlie]);
+    assert!(client.import_justification(justification.clone()));
+    assert_eq!(client.justification(child), Some(&justification));
+    assert_eq!(client.finalized_block(), child);
+
+    // Genesis is already final, and nothing else can be finalized in its place.
+    assert!(client.manually_finalize_block(genesis));
+    assert_eq!(client.finalized_block(), child);
+    assert!(!client.manually_finalize_block(42));
+}
+
 //TODO tests
@@ -0,0 +1,523 @@
+//! Fork choice rules are hard to evaluate by reading them. How often a rule orphans honest blocks,
+//! how deep its reorgs get, and whether it can be gamed all depend on how many miners there are,
+//! how much hashpower each one has, and how long blocks take to travel between them.
+//!
+//! Here we build a deterministic discrete-event simulator of Nakamoto consensus. Several miners
+//! extend a shared tree of proof of work headers, and each honest miner follows the best chain
+//! according to its own instance of a chosen `ForkChoice` rule. Rather than actually searching for
+//! nonces, which would make long simulations painfully slow, each miner finds blocks after an
+//! exponentially distributed delay proportional to its share of the total hashpower. That is
+//! exactly how long a real nonce search against a fixed threshold takes on average.
+//!
+//! Miners can also be selfish. A selfish miner withholds the blocks it finds and only publishes
+//! them when the honest network is about to catch up, hoping to orphan honest blocks and earn more
+//! than its fair share of the rewards. The simulator reports the orphan rate, the distribution of
+//! reorg depths seen by honest miners, and each miner's share of the final chain, which is how a
+//! selfish strategy's profitability is measured.
+
+use super::ForkChoice;
+use crate::c1_state_machine::Rng;
+use crate::c3_consensus::{Header, Pow};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+
+/// The configuration of a single simulated miner.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MinerConfig {
+    /// The miner's hashpower, relative to the other miners.
+    pub hashrate: u64,
+    /// How long the blocks this miner publishes take to reach every other miner, in milliseconds.
+    pub propagation_delay: u64,
+    /// Whether the miner withholds blocks to orphan honest ones.
+    pub selfish: bool,
+}
+
+/// The configuration of a whole simulation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimulationConfig {
+    pub miners: Vec<MinerConfig>,
+    /// The average time between blocks across the whole network, in milliseconds.
+    pub block_time: u64,
+    /// How long to simulate for, in milliseconds.
+    pub duration: u64,
+    /// Simulations with the same configuration and seed always produce the same report.
+    pub seed: u64,
+}
+
+impl SimulationConfig {
+    /// The fraction of the total hashpower that belongs to the given miner. If no miner has any
+    /// hashpower, every share is zero and no blocks are ever mined.
+    pub fn hashrate_share(&self, miner: usize) -> f64 {
+        let total: u64 = self.miners.iter().map(|m| m.hashrate).sum();
+        if total == 0 {
+            return 0.0;
+        }
+        self.miners[miner].hashrate as f64 / total as f64
+    }
+}
+
+/// The outcome of a simulation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationReport {
+    /// How many blocks were mined in total, not counting genesis.
+    pub blocks_mined: usize,
+    /// How many blocks of the final best chain each miner mined, not counting genesis.
+    /// The final best chain is the one followed by the first honest miner.
+    pub canonical_by_miner: Vec<usize>,
+    /// How many reorgs of each depth the honest miners went through. The depth is the number
+    /// of blocks that were removed from the miner's best chain.
+    pub reorg_depths: BTreeMap<u64, usize>,
+}
+
+impl SimulationReport {
+    /// The length of the final best chain, not counting genesis.
+    pub fn canonical_length(&self) -> usize {
+        self.canonical_by_miner.iter().sum()
+    }
+
+    /// The fraction of mined blocks that did not make it into the final best chain.
+    pub fn orphan_rate(&self) -> f64 {
+        if self.blocks_mined == 0 {
+            return 0.0;
+        }
+        1.0 - self.canonical_length() as f64 / self.blocks_mined as f64
+    }
+
+    /// The fraction of the final best chain that the given miner mined. A strategy is profitable
+    /// when this exceeds the miner's share of the hashpower.
+    pub fn revenue_share(&self, miner: usize) -> f64 {
+        match self.canonical_length() {
+            0 => 0.0,
+            length => self.canonical_by_miner[miner] as f64 / length as f64,
+        }
+    }
+}
+
+/// Run a simulation in which every honest miner uses its own instance of the fork choice rule
+/// returned by `fork_choice`. After each import, the rule is asked for the best block, given the
+/// header that was just imported.
+pub fn simulate<FC, F>(config: &SimulationConfig, fork_choice: F) -> SimulationReport
+where
+    FC: ForkChoice<Pow>,
+    F: Fn() -> FC,
+{
+    let mut simulation = Simulation::new(config, fork_choice);
+    while let Some(Reverse((time, _, event))) = simulation.queue.pop() {
+        if time > config.duration {
+            break;
+        }
+        match event {
+            Event::Mined { miner } => simulation.on_mined(miner, time),
+            Event::Deliver { to, block } => simulation.on_deliver(to, block, time),
+        }
+    }
+    simulation.report()
+}
+
+/// A block in the shared tree. Every miner's view is a subset of this tree.
+struct SimBlock {
+    header: Header<u64>,
+    hash: u64,
+    /// The index of the parent block, or None for genesis.
+    parent: Option<usize>,
+    height: u64,
+    /// The miner who found the block, or None for genesis.
+    miner: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Event {
+    /// The miner finds a block on top of whatever it is currently mining on.
+    Mined { miner: usize },
+    /// A published block reaches a miner.
+    Deliver { to: usize, block: usize },
+}
+
+struct Miner<FC> {
+    config: MinerConfig,
+    fork_choice: FC,
+    /// Which blocks of the shared tree this miner knows about, by index.
+    known: Vec<bool>,
+    /// The block this miner is mining on. For a selfish miner, the tip of its private chain.
+    head: usize,
+    /// The height of the best chain that a selfish miner knows the honest miners have.
+    public_height: u64,
+    /// Blocks a selfish miner has found but not yet published, oldest first.
+    unpublished: Vec<usize>,
+    /// Whether a selfish miner has published a chain that ties the honest chain, and is racing
+    /// to extend it first.
+    racing: bool,
+}
+
+struct Simulation<'a, FC> {
+    config: &'a SimulationConfig,
+    /// The engine that seals every block. Its threshold is trivial, because the simulation decides
+    /// when blocks are found, rather than the nonce search.
+    pow: Pow,
+    rng: Rng,
+    blocks: Vec<SimBlock>,
+    by_hash: HashMap<u64, usize>,
+    miners: Vec<Miner<FC>>,
+    /// Pending events by time. The sequence number breaks ties in the order events were scheduled.
+    queue: BinaryHeap<Reverse<(u64, u64, Event)>>,
+    next_sequence: u64,
+    reorg_depths: BTreeMap<u64, usize>,
+}
+
+impl<'a, FC: ForkChoice<Pow>> Simulation<'a, FC> {
+    fn new(config: &'a SimulationConfig, fork_choice: impl Fn() -> FC) -> Self {
+        let mut simulation = Simulation {
+            config,
+            pow: Pow::new(u64::MAX),
+            rng: Rng::seeded(config.seed),
+            blocks: Vec::new(),
+            by_hash: HashMap::new(),
+            miners: config
+                .miners
+                .iter()
+                .map(|miner| Miner {
+                    config: miner.clone(),
+                    fork_choice: fork_choice(),
+                    known: Vec::new(),
+                    head: 0,
+                    public_height: 0,
+                    unpublished: Vec::new(),
+                    racing: false,
+                })
+                .collect(),
+            queue: BinaryHeap::new(),
+            next_sequence: 0,
+            reorg_depths: BTreeMap::new(),
+        };
+        let genesis = simulation.seal(Header::partial(0, 0, 0, 0, 0));
+        simulation.add_block(genesis, None, None);
+        for miner in &mut simulation.miners {
+            miner.known[0] = true;
+            miner
+                .fork_choice
+                .import_hook(simulation.blocks[0].header.clone());
+        }
+        for miner in 0..config.miners.len() {
+            simulation.schedule_next_block(miner, 0);
+        }
+        simulation
+    }
+
+    /// Seal a header with the simulation's engine. The search runs inline, because almost every
+    /// nonce is below the maximum threshold.
+    fn seal(&self, partial_header: Header<()>) -> Header<u64> {
+        (0..=u64::MAX)
+            .map(|nonce| partial_header.clone().with_digest(nonce))
+            .find(|header| header.hash() < self.pow.threshold())
+            .expect("almost every nonce is below the maximum threshold")
+    }
+
+    fn schedule(&mut self, time: u64, event: Event) {
+        self.queue.push(Reverse((time, self.next_sequence, event)));
+        self.next_sequence += 1;
+    }
+
+    /// Schedule the miner's next block after an exponentially distributed delay, whose mean is the
+    /// network block time divided by the miner's share of the hashpower.
+    fn schedule_next_block(&mut self, miner: usize, now: u64) {
+        let share = self.config.hashrate_share(miner);
+        if share == 0.0 {
+            return;
+        }
+        let mean = self.config.block_time as f64 / share;
+        // A uniform draw from (0, 1], so that the logarithm is finite.
+        let uniform = ((self.rng.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        let delay = -uniform.ln() * mean;
+        self.schedule(now + delay.round() as u64, Event::Mined { miner });
+    }
+
+    fn add_block(
+        &mut self,
+        header: Header<u64>,
+        parent: Option<usize>,
+        miner: Option<usize>,
+    ) -> usize {
+        let index = self.blocks.len();
+        let hash = header.hash();
+        self.by_hash.insert(hash, index);
+        self.blocks.push(SimBlock {
+            height: header.height(),
+            header,
+            hash,
+            parent,
+            miner,
+        });
+        for miner in &mut self.miners {
+            miner.known.push(false);
+        }
+        index
+    }
+
+    /// Whether `ancestor` is `block` or one of its ancestors.
+    fn is_ancestor(&self, ancestor: usize, mut block: usize) -> bool {
+        while self.blocks[block].height > self.blocks[ancestor].height {
+            block = self.blocks[block]
+                .parent
+                .expect("only genesis has no parent");
+        }
+        block == ancestor
+    }
+
+    /// The last common ancestor of two blocks.
+    fn common_ancestor(&self, mut a: usize, mut b: usize) -> usize {
+        while a != b {
+            if self.blocks[a].height >= self.blocks[b].height {
+                a = self.blocks[a].parent.expect("only genesis has no parent");
+            } else {
+                b = self.blocks[b].parent.expect("only genesis has no parent");
+            }
+        }
+        a
+    }
+
+    /// Send a block to every other miner.
+    fn publish(&mut self, from: usize, block: usize, now: u64) {
+        let delay = self.miners[from].config.propagation_delay;
+        for to in (0..self.miners.len()).filter(|to| *to != from) {
+            self.schedule(now + delay, Event::Deliver { to, block });
+        }
+    }
+
+    /// Make a block and all of its ancestors known to an honest miner, importing the oldest first.
+    /// Blocks are relayed along with any ancestors the receiver is missing.
+    fn import(&mut self, miner: usize, block: usize) {
+        let mut missing = Vec::new();
+        let mut next = Some(block);
+        while let Some(b) = next.filter(|b| !self.miners[miner].known[*b]) {
+            missing.push(b);
+            next = self.blocks[b].parent;
+        }
+        for b in missing.into_iter().rev() {
+            let header = self.blocks[b].header.clone();
+            let m = &mut self.miners[miner];
+            m.known[b] = true;
+            m.fork_choice.import_hook(header.clone());
+            let best = m.fork_choice.best_block(header);
+            if let Some(&best) = best.and_then(|hash| self.by_hash.get(&hash)) {
+                self.set_head(miner, best);
+            }
+        }
+    }
+
+    /// Switch an honest miner to a new best block, recording a reorg if the old one is abandoned.
+    fn set_head(&mut self, miner: usize, new: usize) {
+        let old = self.miners[miner].head;
+        if !self.is_ancestor(old, new) {
+            let depth =
+                self.blocks[old].height - self.blocks[self.common_ancestor(old, new)].height;
+            *self.reorg_depths.entry(depth).or_insert(0) += 1;
+        }
+        self.miners[miner].head = new;
+    }
+
+    fn on_mined(&mut self, miner: usize, now: u64) {
+        let parent = self.miners[miner].head;
+        // The miner goes in the state root and the block's index in the extrinsics root, so that
+        // no two blocks ever collide.
+        let header = self.seal(Header::partial(
+            self.blocks[parent].hash,
+            self.blocks[parent].height + 1,
+            miner as u64,
+            self.blocks.len() as u64,
+            now,
+        ));
+        let block = self.add_block(header, Some(parent), Some(miner));
+        if self.miners[miner].config.selfish {
+            let m = &mut self.miners[miner];
+            m.known[block] = true;
+            m.head = block;
+            m.unpublished.push(block);
+            // Having found a block during a race, publish it to win the race outright.
+            if m.racing {
+                m.racing = false;
+                self.publish_private(miner, u64::MAX, now);
+            }
+        } else {
+            self.import(miner, block);
+            self.publish(miner, block, now);
+        }
+        self.schedule_next_block(miner, now);
+    }
+
+    fn on_deliver(&mut self, to: usize, block: usize, now: u64) {
+        if self.miners[to].known[block] {
+            return;
+        }
+        if !self.miners[to].config.selfish {
+            self.import(to, block);
+            return;
+        }
+
+        let mut next = Some(block);
+        while let Some(b) = next.filter(|b| !self.miners[to].known[*b]) {
+            self.miners[to].known[b] = true;
+            next = self.blocks[b].parent;
+        }
+        let height = self.blocks[block].height;
+        if height <= self.miners[to].public_height {
+            return;
+        }
+        self.miners[to].public_height = height;
+        let private = self.blocks[self.miners[to].head].height;
+        let m = &mut self.miners[to];
+        m.racing = false;
+        if private < height {
+            // The honest chain is ahead, so give up on the private chain.
+            m.head = block;
+            m.unpublished.clear();
+        } else if private == height {
+            // Publish everything to tie, and hope the honest miners build on our chain.
+            m.racing = true;
+            self.publish_private(to, u64::MAX, now);
+        } else if private == height + 1 {
+            // Publishing everything orphans the honest block.
+            self.publish_private(to, u64::MAX, now);
+        } else {
+            // Comfortably ahead. Publish just enough to keep the honest miners wasting work.
+            self.publish_private(to, height, now);
+        }
+    }
+
+    /// Publish a selfish miner's withheld blocks up to the given height, oldest first.
+    fn publish_private(&mut self, miner: usize, up_to: u64, now: u64) {
+        let unpublished = std::mem::take(&mut self.miners[miner].unpublished);
+        let (publish, keep): (Vec<usize>, Vec<usize>) = unpublished
+            .into_iter()
+            .partition(|b| self.blocks[*b].height <= up_to);
+        for block in publish {
+            let m = &mut self.miners[miner];
+            m.public_height = m.public_height.max(self.blocks[block].height);
+            self.publish(miner, block, now);
+        }
+        self.miners[miner].unpublished = keep;
+    }
+
+    fn report(&self) -> SimulationReport {
+        let follower = self
+            .miners
+            .iter()
+            .position(|m| !m.config.selfish)
+            .unwrap_or(0);
+        let mut canonical_by_miner = vec![0; self.miners.len()];
+        let mut next = self.miners.get(follower).map(|m| m.head);
+        while let Some(block) = next {
+            if let Some(miner) = self.blocks[block].miner {
+                canonical_by_miner[miner] += 1;
+            }
+            next = self.blocks[block].parent;
+        }
+        SimulationReport {
+            blocks_mined: self.blocks.len() - 1,
+            canonical_by_miner,
+            reorg_depths: self.reorg_depths.clone(),
+        }
+    }
+}
+
+/// The longest chain rule, breaking ties in favour of the block seen first. The exercise version
+/// of this rule lives in the fork choice module; this one only exists to drive the simulator.
+#[cfg(test)]
+#[derive(Default)]
+pub(super) struct FirstSeenLongest {
+    best: Option<(u64, u64)>,
+}
+
+#[cfg(test)]
+impl ForkChoice<Pow> for FirstSeenLongest {
+    fn best_block(&self, _: Header<u64>) -> Option<u64> {
+        self.best.map(|(_, hash)| hash)
+    }
+
+    fn import_hook(&mut self, header: Header<u64>) {
+        if self.best.is_none_or(|(height, _)| header.height() > height) {
+            self.best = Some((header.height(), header.hash()));
+        }
+    }
+}
+
+#[cfg(test)]
+fn honest_miners(propagation_delay: u64) -> SimulationConfig {
+    SimulationConfig {
+        miners: [50, 30, 20]
+            .into_iter()
+            .map(|hashrate| MinerConfig {
+                hashrate,
+                propagation_delay,
+                selfish: false,
+            })
+            .collect(),
+        block_time: 10_000,
+        duration: 10_000 * 2_000,
+        seed: 42,
+    }
+}
+
+#[test]
+fn fast_propagation_rarely_orphans() {
+    let config = honest_miners(0);
+    let report = simulate(&config, FirstSeenLongest::default);
+
+    assert!(report.blocks_mined > 1_800);
+    assert!(report.orphan_rate() < 0.01);
+    assert!(report.reorg_depths.keys().all(|depth| *depth <= 1));
+    // Without selfish miners, rewards follow hashpower.
+    assert!((report.revenue_share(0) - 0.5).abs() < 0.05);
+
+    // The simulation is deterministic.
+    assert_eq!(simulate(&config, FirstSeenLongest::default), report);
+}
+
+#[test]
+fn miners_without_hashpower_never_mine() {
+    let mut config = honest_miners(0);
+    for miner in &mut config.miners {
+        miner.hashrate = 0;
+    }
+    assert_eq!(config.hashrate_share(0), 0.0);
+
+    let report = simulate(&config, FirstSeenLongest::default);
+    assert_eq!(report.blocks_mined, 0);
+    assert_eq!(report.orphan_rate(), 0.0);
+}
+
+#[test]
+fn slow_propagation_causes_orphans_and_reorgs() {
+    let fast = simulate(&honest_miners(100), FirstSeenLongest::default);
+    let slow = simulate(&honest_miners(5_000), FirstSeenLongest::default);
+
+    assert!(slow.orphan_rate() > fast.orphan_rate());
+    assert!(slow.orphan_rate() > 0.1);
+    assert!(slow.reorg_depths.values().sum::<usize>() > fast.reorg_depths.values().sum());
+}
+
+#[test]
+fn selfish_mining_beats_its_hashrate() {
+    let mut config = honest_miners(1_000);
+    config.miners = vec![
+        MinerConfig {
+            hashrate: 40,
+            propagation_delay: 0,
+            selfish: true,
+        },
+        MinerConfig {
+            hashrate: 30,
+            propagation_delay: 1_000,
+            selfish: false,
+        },
+        MinerConfig {
+            hashrate: 30,
+            propagation_delay: 1_000,
+            selfish: false,
+        },
+    ];
+    let report = simulate(&config, FirstSeenLongest::default);
+
+    assert!(report.revenue_share(0) > config.hashrate_share(0));
+    // Honest miners suffer deeper reorgs as the selfish miner overrides their blocks.
+    assert!(report.reorg_depths.keys().any(|depth| *depth >= 2));
+}
@@ -0,0 +1,92 @@
+//! A client sees every header that is imported, including headers on forks that never become
+//! part of the best chain. That makes block import the natural place to catch authorities that
+//! sign conflicting headers.
+//!
+//! Fork choice rules already receive every imported header through their import hook, so we
+//! detect equivocations by wrapping whichever fork choice rule the client uses. The proofs that
+//! are found can then be submitted to the chain, typically as staking transactions that slash
+//! the offender.
+
+use super::{Consensus, ForkChoice, Header};
+use crate::c3_consensus::{EquivocationDetector, EquivocationProof, SignedDigest};
+
+/// A fork choice rule that watches imported headers for equivocations, and otherwise
+/// behaves exactly like the rule that it wraps.
+pub struct ReportEquivocations<FC, D> {
+    /// The fork choice rule that actually chooses the best block.
+    pub inner: FC,
+    detector: EquivocationDetector<D>,
+    /// Proofs that have been found but not yet taken for submission.
+    proofs: Vec<EquivocationProof<D>>,
+}
+
+impl<FC, D> ReportEquivocations<FC, D> {
+    pub fn new(inner: FC) -> Self {
+        ReportEquivocations {
+            inner,
+            detector: EquivocationDetector::default(),
+            proofs: Vec::new(),
+        }
+    }
+
+    /// Take all the equivocation proofs found since the last call.
+    pub fn take_proofs(&mut self) -> Vec<EquivocationProof<D>> {
+        std::mem::take(&mut self.proofs)
+    }
+}
+
+impl<C, FC> ForkChoice<C> for ReportEquivocations<FC, C::Digest>
+where
+    C: Consensus,
+    C::Digest: SignedDigest,
+    FC: ForkChoice<C>,
+{
+    fn best_block(&self, header: Header<C::Digest>) -> Option<u64> {
+        self.inner.best_block(header)
+    }
+
+    fn import_hook(&mut self, header: Header<C::Digest>) {
+        if let Some(proof) = self.detector.observe(&header) {
+            self.proofs.push(proof);
+        }
+        self.inner.import_hook(header);
+    }
+}
+
+#[test]
+fn import_hook_reports_equivocations() {
+    use crate::c3_consensus::{ConsensusAuthority, SimplePoa};
+
+    /// A fork choice rule that just counts the headers it has seen.
+    struct CountImports(u64);
+
+    impl ForkChoice<SimplePoa> for CountImports {
+        fn best_block(&self, _: Header<ConsensusAuthority>) -> Option<u64> {
+            Some(self.0)
+        }
+
+        fn import_hook(&mut self, _: Header<ConsensusAuthority>) {
+            self.0 += 1;
+        }
+    }
+
+    let header = |height: u64, state_root: u64, signer: ConsensusAuthority| {
+        Header::partial(0, height, state_root, 0, 0).with_digest(signer)
+    };
+
+    let mut fork_choice = ReportEquivocations::new(CountImports(0));
+    let imports = [
+        header(1, 10, ConsensusAuthority::Alice),
+        header(1, 20, ConsensusAuthority::Bob),
+        header(1, 30, ConsensusAuthority::Alice),
+    ];
+    for h in imports.iter().cloned() {
+        ForkChoice::<SimplePoa>::import_hook(&mut fork_choice, h);
+    }
+
+    assert_eq!(fork_choice.inner.0, 3);
+    let proofs = fork_choice.take_proofs();
+    assert_eq!(proofs.len(), 1);
+    assert_eq!(proofs[0].offender(), ConsensusAuthority::Alice);
+    assert!(fork_choice.take_proofs().is_empty());
+}
@@ -0,0 +1,411 @@
+//! Blocks, headers, and states all need to leave the memory of a single process at some point.
+//! They are written to disk, sent across the network, and most importantly, hashed. If two nodes
+//! disagree about how a block is represented as bytes, they will disagree about its hash, and
+//! therefore about the entire chain built on top of it.
+//!
+//! This module provides a small binary codec modeled after Substrate's SCALE codec. The encoding
+//! is canonical: every value has exactly one valid encoding, and decoding rejects anything else.
+//! * Fixed width integers are encoded little endian.
+//! * Lengths are encoded as compact integers, which take fewer bytes for smaller values.
+//! * Enum variants are encoded as a single byte index followed by their fields.
+//! * Vectors and strings are prefixed with their length.
+//! * Maps and sets are sorted by the encoding of their keys, so their iteration order does not matter.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// Everything that can go wrong while decoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The input ended before the value was complete.
+    UnexpectedEnd,
+    /// An enum variant, bool, or option had a tag that does not correspond to any variant.
+    InvalidTag(u8),
+    /// The value has a different, canonical encoding than the one given.
+    NonCanonical,
+    /// A string was not valid UTF-8.
+    InvalidUtf8,
+    /// The value was decoded successfully, but there were bytes left over.
+    TrailingBytes,
+}
+
+/// A type that can be encoded to its canonical bytes.
+pub trait Encode {
+    /// Append the encoding of this value to the destination.
+    fn encode_to(&self, dest: &mut Vec<u8>);
+
+    /// Encode this value into a fresh vector of bytes.
+    fn encode(&self) -> Vec<u8> {
+        let mut dest = Vec::new();
+        self.encode_to(&mut dest);
+        dest
+    }
+
+    /// Hash the canonical encoding of this value. Unlike the standard library's `Hash` trait,
+    /// this hash does not depend on the layout of the type in memory or on the iteration order
+    /// of any maps or sets it contains, so every node agrees on it.
+    fn encoded_hash(&self) -> u64 {
+        crate::hash(&self.encode())
+    }
+}
+
+/// A type that can be decoded from its canonical bytes.
+pub trait Decode: Sized {
+    /// Decode a value from the front of the input, advancing the input past it.
+    fn decode(input: &mut &[u8]) -> Result<Self, Error>;
+}
+
+/// Decode a value that must span the entire input.
+pub fn decode_all<T: Decode>(mut input: &[u8]) -> Result<T, Error> {
+    let value = T::decode(&mut input)?;
+    if !input.is_empty() {
+        return Err(Error::TrailingBytes);
+    }
+    Ok(value)
+}
+
+/// Split the first `n` bytes off of the input.
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+    if input.len() < n {
+        return Err(Error::UnexpectedEnd);
+    }
+    let (front, rest) = input.split_at(n);
+    *input = rest;
+    Ok(front)
+}
+
+/// Read the tag of an enum variant. Enums in this crate have few enough variants that
+/// a single byte is always enough.
+pub fn decode_tag(input: &mut &[u8]) -> Result<u8, Error> {
+    Ok(take(input, 1)?[0])
+}
+
+/// Append the compact encoding of an integer.
+///
+/// The two lowest bits of the first byte select the mode:
+/// * `0b00` - A single byte holding values up to 2^6 - 1
+/// * `0b01` - Two bytes holding values up to 2^14 - 1
+/// * `0b10` - Four bytes holding values up to 2^30 - 1
+/// * `0b11` - The upper six bits hold the number of bytes that follow, minus four
+pub fn encode_compact(value: u64, dest: &mut Vec<u8>) {
+    match value {
+        0..=0x3f => dest.push((value as u8) << 2),
+        0x40..=0x3fff => dest.extend_from_slice(&(((value as u16) << 2) | 0b01).to_le_bytes()),
+        0x4000..=0x3fff_ffff => {
+            dest.extend_from_slice(&(((value as u32) << 2) | 0b10).to_le_bytes())
+        }
+        _ => {
+            let bytes = 8 - value.leading_zeros() as usize / 8;
+            dest.push((((bytes - 4) as u8) << 2) | 0b11);
+            dest.extend_from_slice(&value.to_le_bytes()[..bytes]);
+        }
+    }
+}
+
+/// Decode a compact integer, rejecting any encoding that is longer than necessary.
+pub fn decode_compact(input: &mut &[u8]) -> Result<u64, Error> {
+    let first = *input.first().ok_or(Error::UnexpectedEnd)?;
+    let (value, min) = match first & 0b11 {
+        0b00 => (u64::from(decode_tag(input)? >> 2), 0),
+        0b01 => (u64::from(u16::decode(input)? >> 2), 0x40),
+        0b10 => (u64::from(u32::decode(input)? >> 2), 0x4000),
+        _ => {
+            let bytes = usize::from(decode_tag(input)? >> 2) + 4;
+            if bytes > 8 {
+                return Err(Error::NonCanonical);
+            }
+            let mut le = [0; 8];
+            le[..bytes].copy_from_slice(take(input, bytes)?);
+            if le[bytes - 1] == 0 {
+                return Err(Error::NonCanonical);
+            }
+            (u64::from_le_bytes(le), 0x4000_0000)
+        }
+    };
+    if value < min {
+        return Err(Error::NonCanonical);
+    }
+    Ok(value)
+}
+
+/// Decode a compact length prefix, along with how many items it is safe to preallocate room for.
+///
+/// The length itself cannot be checked up front, because zero-sized items like `()` take no input
+/// at all. A bogus length for other items fails once decoding reaches the end of the input, but
+/// every item takes at least one byte, so preallocating more than the remaining input could only
+/// serve an attacker.
+fn decode_len(input: &mut &[u8]) -> Result<(usize, usize), Error> {
+    let len = usize::try_from(decode_compact(input)?).map_err(|_| Error::UnexpectedEnd)?;
+    Ok((len, len.min(input.len())))
+}
+
+macro_rules! impl_codec_for_integer {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode_to(&self, dest: &mut Vec<u8>) {
+                dest.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+                let bytes = take(input, std::mem::size_of::<$t>())?;
+                Ok(<$t>::from_le_bytes(bytes.try_into().expect("took exactly the right length")))
+            }
+        }
+    )*};
+}
+
+impl_codec_for_integer!(u8, u16, u32, u64, u128);
+
+impl Encode for () {
+    fn encode_to(&self, _: &mut Vec<u8>) {}
+}
+
+impl Decode for () {
+    fn decode(_: &mut &[u8]) -> Result<Self, Error> {
+        Ok(())
+    }
+}
+
+impl Encode for bool {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        dest.push(u8::from(*self));
+    }
+}
+
+impl Decode for bool {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            None => dest.push(0),
+            Some(t) => {
+                dest.push(1);
+                t.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        match decode_tag(input)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(input)?)),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        encode_compact(self.len() as u64, dest);
+        for t in self {
+            t.encode_to(dest);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.as_slice().encode_to(dest);
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        let (len, capacity) = decode_len(input)?;
+        let mut items = Vec::with_capacity(capacity);
+        for _ in 0..len {
+            items.push(T::decode(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Encode for String {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.as_bytes().encode_to(dest);
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        String::from_utf8(Vec::<u8>::decode(input)?).map_err(|_| Error::InvalidUtf8)
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.0.encode_to(dest);
+        self.1.encode_to(dest);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok((A::decode(input)?, B::decode(input)?))
+    }
+}
+
+/// Encode a collection of entries sorted by the encoding of their keys.
+fn encode_sorted<'a, K, V>(entries: impl Iterator<Item = (&'a K, &'a V)>, dest: &mut Vec<u8>)
+where
+    K: Encode + 'a,
+    V: Encode + 'a,
+{
+    let mut entries: Vec<(Vec<u8>, &V)> = entries.map(|(k, v)| (k.encode(), v)).collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    encode_compact(entries.len() as u64, dest);
+    for (key, value) in entries {
+        dest.extend_from_slice(&key);
+        value.encode_to(dest);
+    }
+}
+
+/// Decode a collection of entries, making sure their keys are strictly sorted by their encoding.
+/// This rejects both unsorted and duplicate keys, which would otherwise give the same collection
+/// several different encodings.
+fn decode_sorted<K: Decode, V: Decode>(input: &mut &[u8]) -> Result<Vec<(K, V)>, Error> {
+    let (len, capacity) = decode_len(input)?;
+    let mut entries = Vec::with_capacity(capacity);
+    let mut previous_key: Option<&[u8]> = None;
+    for _ in 0..len {
+        let before = *input;
+        let key = K::decode(input)?;
+        let key_bytes = &before[..before.len() - input.len()];
+        if previous_key.is_some_and(|previous| previous >= key_bytes) {
+            return Err(Error::NonCanonical);
+        }
+        previous_key = Some(key_bytes);
+        entries.push((key, V::decode(input)?));
+    }
+    Ok(entries)
+}
+
+impl<K: Encode, V: Encode> Encode for HashMap<K, V> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        encode_sorted(self.iter(), dest);
+    }
+}
+
+impl<K: Decode + Hash + Eq, V: Decode> Decode for HashMap<K, V> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(decode_sorted(input)?.into_iter().collect())
+    }
+}
+
+impl<T: Encode> Encode for HashSet<T> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        encode_sorted(self.iter().map(|t| (t, &())), dest);
+    }
+}
+
+impl<T: Decode + Hash + Eq> Decode for HashSet<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self, Error> {
+        Ok(decode_sorted::<T, ()>(input)?
+            .into_iter()
+            .map(|(t, ())| t)
+            .collect())
+    }
+}
+
+#[test]
+fn codec_compact_boundaries() {
+    let cases: [(u64, &[u8]); 7] = [
+        (0, &[0x00]),
+        (63, &[0xfc]),
+        (64, &[0x01, 0x01]),
+        (0x3fff, &[0xfd, 0xff]),
+        (0x4000, &[0x02, 0x00, 0x01, 0x00]),
+        (0x4000_0000, &[0x03, 0x00, 0x00, 0x00, 0x40]),
+        (
+            u64::MAX,
+            &[0x13, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ),
+    ];
+    for (value, expected) in cases {
+        let mut encoded = Vec::new();
+        encode_compact(value, &mut encoded);
+        assert_eq!(encoded, expected);
+        assert_eq!(decode_compact(&mut &encoded[..]), Ok(value));
+    }
+}
+
+#[test]
+fn codec_compact_rejects_overlong_encodings() {
+    // 1 encoded in two byte mode
+    assert_eq!(
+        decode_compact(&mut &[0x05, 0x00][..]),
+        Err(Error::NonCanonical)
+    );
+    // 64 encoded in four byte mode
+    assert_eq!(
+        decode_compact(&mut &[0x02, 0x01, 0x00, 0x00][..]),
+        Err(Error::NonCanonical)
+    );
+    // 2^30 encoded with a redundant zero byte
+    assert_eq!(
+        decode_compact(&mut &[0x07, 0x00, 0x00, 0x00, 0x40, 0x00][..]),
+        Err(Error::NonCanonical)
+    );
+}
+
+#[test]
+fn codec_primitives_round_trip() {
+    let value = (
+        Some(vec![1u64, u64::MAX]),
+        (String::from("blockchain"), (true, None::<u32>)),
+    );
+    let encoded = value.encode();
+    assert_eq!(decode_all(&encoded), Ok(value));
+
+    assert_eq!(7u32.encode(), vec![7, 0, 0, 0]);
+    assert_eq!(vec![7u8, 8].encode(), vec![0x08, 7, 8]);
+}
+
+#[test]
+fn codec_maps_are_canonical() {
+    let forward: HashMap<u32, bool> = (0..100).map(|i| (i, i % 3 == 0)).collect();
+    let backward: HashMap<u32, bool> = (0..100).rev().map(|i| (i, i % 3 == 0)).collect();
+    assert_eq!(forward.encode(), backward.encode());
+    assert_eq!(forward.encoded_hash(), backward.encoded_hash());
+    assert_eq!(decode_all(&forward.encode()), Ok(forward));
+
+    // The same set with its two entries swapped
+    let unsorted = [0x08, 2, 0, 1, 0];
+    assert_eq!(
+        decode_all::<HashSet<u16>>(&unsorted),
+        Err(Error::NonCanonical)
+    );
+    let duplicated = [0x08, 1, 0, 1, 0];
+    assert_eq!(
+        decode_all::<HashSet<u16>>(&duplicated),
+        Err(Error::NonCanonical)
+    );
+}
+
+#[test]
+fn codec_rejects_malformed_input() {
+    assert_eq!(decode_all::<u64>(&[1, 2, 3]), Err(Error::UnexpectedEnd));
+    assert_eq!(decode_all::<u8>(&[1, 2]), Err(Error::TrailingBytes));
+    assert_eq!(decode_all::<bool>(&[2]), Err(Error::InvalidTag(2)));
+    assert_eq!(decode_all::<String>(&[0x04, 0xff]), Err(Error::InvalidUtf8));
+    // A length prefix far longer than the input must not be trusted
+    assert_eq!(
+        decode_all::<Vec<u8>>(&[0x13, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]),
+        Err(Error::UnexpectedEnd)
+    );
+    // Zero-sized items take no input, so only their length prefix is encoded
+    assert_eq!(vec![(); 3].encode(), vec![0x0c]);
+    assert_eq!(decode_all::<Vec<()>>(&[0x0c]), Ok(vec![(); 3]));
+}
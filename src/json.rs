@@ -0,0 +1,428 @@
+//! A small JSON parser for human-written configuration files such as chain specs.
+//!
+//! Configuration is written by people and read by the client, which is the opposite of the
+//! binary codec, which is read and written by machines. So this module only parses JSON, and
+//! favours clear error messages over speed.
+//!
+//! Blockchain quantities like balances and thresholds routinely exceed the 53 bits that a
+//! floating point number can represent exactly. Therefore, numbers are restricted to non-negative
+//! integers and are parsed directly into a `u64`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// A parsed JSON value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    /// The fields of an object in the order they were written. Keys are unique.
+    Object(Vec<(String, Json)>),
+}
+
+/// Everything that can go wrong while reading JSON.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The text is not valid JSON. Contains the byte offset at which parsing failed.
+    Syntax {
+        offset: usize,
+        message: &'static str,
+    },
+    /// The JSON is valid, but a value has the wrong type.
+    Expected { expected: &'static str, found: Json },
+    /// An object is missing a required field.
+    MissingField(&'static str),
+    /// A string does not name any of the known variants.
+    UnknownVariant(String),
+    /// The value is well formed but does not make sense.
+    Invalid(String),
+}
+
+impl Json {
+    fn expected<T>(&self, expected: &'static str) -> Result<T, Error> {
+        Err(Error::Expected {
+            expected,
+            found: self.clone(),
+        })
+    }
+
+    pub fn as_u64(&self) -> Result<u64, Error> {
+        match self {
+            Json::Number(n) => Ok(*n),
+            _ => self.expected("a number"),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, Error> {
+        match self {
+            Json::String(s) => Ok(s),
+            _ => self.expected("a string"),
+        }
+    }
+
+    pub fn as_array(&self) -> Result<&[Json], Error> {
+        match self {
+            Json::Array(items) => Ok(items),
+            _ => self.expected("an array"),
+        }
+    }
+
+    pub fn as_object(&self) -> Result<&[(String, Json)], Error> {
+        match self {
+            Json::Object(fields) => Ok(fields),
+            _ => self.expected("an object"),
+        }
+    }
+
+    /// Look up an optional field of an object.
+    pub fn get(&self, name: &str) -> Result<Option<&Json>, Error> {
+        Ok(self
+            .as_object()?
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value))
+    }
+
+    /// Look up a required field of an object.
+    pub fn field(&self, name: &'static str) -> Result<&Json, Error> {
+        self.get(name)?.ok_or(Error::MissingField(name))
+    }
+
+    /// Look up a required field of an object and convert it.
+    pub fn parse_field<T: FromJson>(&self, name: &'static str) -> Result<T, Error> {
+        T::from_json(self.field(name)?)
+    }
+}
+
+/// A type that can be read from a JSON value.
+pub trait FromJson: Sized {
+    fn from_json(json: &Json) -> Result<Self, Error>;
+}
+
+/// Parse some JSON text and convert it into the requested type.
+pub fn from_str<T: FromJson>(text: &str) -> Result<T, Error> {
+    T::from_json(&parse(text)?)
+}
+
+impl FromJson for Json {
+    fn from_json(json: &Json) -> Result<Self, Error> {
+        Ok(json.clone())
+    }
+}
+
+impl FromJson for u64 {
+    fn from_json(json: &Json) -> Result<Self, Error> {
+        json.as_u64()
+    }
+}
+
+impl FromJson for u32 {
+    fn from_json(json: &Json) -> Result<Self, Error> {
+        u32::try_from(json.as_u64()?).or_else(|_| json.expected("a 32 bit number"))
+    }
+}
+
+impl FromJson for bool {
+    fn from_json(json: &Json) -> Result<Self, Error> {
+        match json {
+            Json::Bool(b) => Ok(*b),
+            _ => json.expected("a bool"),
+        }
+    }
+}
+
+impl FromJson for String {
+    fn from_json(json: &Json) -> Result<Self, Error> {
+        json.as_str().map(String::from)
+    }
+}
+
+impl<T: FromJson> FromJson for Vec<T> {
+    fn from_json(json: &Json) -> Result<Self, Error> {
+        json.as_array()?.iter().map(T::from_json).collect()
+    }
+}
+
+/// JSON object keys are always strings, so the keys of a map are converted from a string value.
+impl<K: FromJson + Hash + Eq, V: FromJson> FromJson for HashMap<K, V> {
+    fn from_json(json: &Json) -> Result<Self, Error> {
+        json.as_object()?
+            .iter()
+            .map(|(key, value)| {
+                Ok((
+                    K::from_json(&Json::String(key.clone()))?,
+                    V::from_json(value)?,
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Parse JSON text into a value.
+pub fn parse(text: &str) -> Result<Json, Error> {
+    let mut parser = Parser { text, offset: 0 };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.offset != text.len() {
+        return parser.error("unexpected text after the value");
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    offset: usize,
+}
+
+impl Parser<'_> {
+    fn error<T>(&self, message: &'static str) -> Result<T, Error> {
+        Err(Error::Syntax {
+            offset: self.offset,
+            message,
+        })
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.offset).copied()
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.offset += 1;
+        }
+    }
+
+    /// Skip whitespace, then consume the expected byte.
+    fn expect(&mut self, byte: u8, message: &'static str) -> Result<(), Error> {
+        self.whitespace();
+        if self.peek() != Some(byte) {
+            return self.error(message);
+        }
+        self.offset += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, Error> {
+        self.whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'0'..=b'9') => self.number(),
+            Some(b'-') => self.error("negative numbers are not supported"),
+            Some(_) => self.literal(),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Json, Error> {
+        for (word, value) in [
+            ("null", Json::Null),
+            ("true", Json::Bool(true)),
+            ("false", Json::Bool(false)),
+        ] {
+            if self.text[self.offset..].starts_with(word) {
+                self.offset += word.len();
+                return Ok(value);
+            }
+        }
+        self.error("expected a value")
+    }
+
+    fn number(&mut self) -> Result<Json, Error> {
+        let start = self.offset;
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.offset += 1;
+        }
+        if matches!(self.peek(), Some(b'.' | b'e' | b'E')) {
+            return self.error("only whole numbers are supported");
+        }
+        let digits = &self.text[start..self.offset];
+        if digits.len() > 1 && digits.starts_with('0') {
+            self.offset = start;
+            return self.error("numbers must not have leading zeros");
+        }
+        match digits.parse() {
+            Ok(n) => Ok(Json::Number(n)),
+            Err(_) => {
+                self.offset = start;
+                self.error("number does not fit in 64 bits")
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect(b'"', "expected a string")?;
+        let mut s = String::new();
+        loop {
+            let Some(c) = self.text[self.offset..].chars().next() else {
+                return self.error("unterminated string");
+            };
+            self.offset += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => s.push(self.escape()?),
+                c if c < ' ' => {
+                    self.offset -= 1;
+                    return self.error("control characters must be escaped");
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, Error> {
+        let c = match self.peek() {
+            Some(b'"') => '"',
+            Some(b'\\') => '\\',
+            Some(b'/') => '/',
+            Some(b'b') => '\u{8}',
+            Some(b'f') => '\u{c}',
+            Some(b'n') => '\n',
+            Some(b'r') => '\r',
+            Some(b't') => '\t',
+            Some(b'u') => {
+                self.offset += 1;
+                let high = self.hex4()?;
+                if !(0xd800..0xdc00).contains(&high) {
+                    return char::from_u32(high).map_or_else(|| self.error("invalid escape"), Ok);
+                }
+                // Characters outside the basic multilingual plane are escaped as a surrogate pair.
+                if !self.text[self.offset..].starts_with("\\u") {
+                    return self.error("unpaired surrogate");
+                }
+                self.offset += 2;
+                let low = self.hex4()?;
+                if !(0xdc00..0xe000).contains(&low) {
+                    return self.error("unpaired surrogate");
+                }
+                let c = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+                return char::from_u32(c).map_or_else(|| self.error("invalid escape"), Ok);
+            }
+            _ => return self.error("invalid escape"),
+        };
+        self.offset += 1;
+        Ok(c)
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let digits = self
+            .text
+            .get(self.offset..self.offset + 4)
+            .filter(|d| d.bytes().all(|b| b.is_ascii_hexdigit()));
+        match digits {
+            Some(d) => {
+                self.offset += 4;
+                Ok(u32::from_str_radix(d, 16).expect("checked that these are hex digits"))
+            }
+            None => self.error("expected four hex digits"),
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, Error> {
+        self.expect(b'[', "expected an array")?;
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(b']') {
+            self.offset += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.offset += 1,
+                Some(b']') => {
+                    self.offset += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return self.error("expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, Error> {
+        self.expect(b'{', "expected an object")?;
+        let mut fields: Vec<(String, Json)> = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(b'}') {
+            self.offset += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.whitespace();
+            let key_offset = self.offset;
+            let key = self.string()?;
+            if fields.iter().any(|(k, _)| *k == key) {
+                self.offset = key_offset;
+                return self.error("duplicate key");
+            }
+            self.expect(b':', "expected ':'")?;
+            fields.push((key, self.value()?));
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.offset += 1,
+                Some(b'}') => {
+                    self.offset += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return self.error("expected ',' or '}'"),
+            }
+        }
+    }
+}
+
+#[test]
+fn json_parses_nested_values() {
+    let text = r#" { "name": "Test\tnet é😀", "ok": true, "none": null,
+                     "nums": [0, 18446744073709551615], "empty": {} } "#;
+    let expected = Json::Object(vec![
+        ("name".into(), Json::String("Test\tnet é😀".into())),
+        ("ok".into(), Json::Bool(true)),
+        ("none".into(), Json::Null),
+        (
+            "nums".into(),
+            Json::Array(vec![Json::Number(0), Json::Number(u64::MAX)]),
+        ),
+        ("empty".into(), Json::Object(vec![])),
+    ]);
+    assert_eq!(parse(text), Ok(expected));
+}
+
+#[test]
+fn json_rejects_malformed_text() {
+    let syntax_error = |text| match parse(text) {
+        Err(Error::Syntax { offset, .. }) => offset,
+        other => panic!("expected a syntax error for {text}, got {other:?}"),
+    };
+    assert_eq!(syntax_error("[1, 2"), 5);
+    assert_eq!(syntax_error("[1 2]"), 3);
+    assert_eq!(syntax_error("1.5"), 1);
+    assert_eq!(syntax_error("-1"), 0);
+    assert_eq!(syntax_error("012"), 0);
+    assert_eq!(syntax_error("18446744073709551616"), 0);
+    assert_eq!(syntax_error(r#"{"a": 1, "a": 2}"#), 9);
+    assert_eq!(syntax_error(r#""\ud83d""#), 7);
+    assert_eq!(syntax_error("true false"), 5);
+}
+
+#[test]
+fn json_converts_to_rust_types() {
+    let balances: HashMap<String, u32> = from_str(r#"{"a": 1, "b": 2}"#).unwrap();
+    assert_eq!(balances, HashMap::from([("a".into(), 1), ("b".into(), 2)]));
+
+    assert_eq!(
+        from_str::<u32>("4294967296"),
+        Err(Error::Expected {
+            expected: "a 32 bit number",
+            found: Json::Number(1 << 32)
+        })
+    );
+    assert_eq!(
+        parse(r#"{"a": 1}"#).unwrap().parse_field::<u64>("b"),
+        Err(Error::MissingField("b"))
+    );
+}